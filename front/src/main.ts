@@ -9,8 +9,37 @@ import './main.css'
     return
   }
 
+  const boxTemp = document.querySelector('#box-temp') as HTMLTemplateElement
+  const boxContainer = document.querySelector('.boxes')
+
+  if (boxContainer && boxTemp) {
+    fetch(`${baseUrl}/boxes`)
+      .then(x => x.json() as Promise<string[]>)
+      .then(x => {
+        x.forEach(name => {
+          const node = document.importNode(boxTemp.content, true)
+          ;(
+            node.querySelector('.box') as HTMLAnchorElement
+          ).href = `${baseUrl}/rss/${name}`
+          const icon = node.querySelector('.box-icon') as HTMLImageElement
+          icon.src = `${baseUrl}/boxes/${name}/icon`
+          icon.onerror = () => (icon.style.visibility = 'hidden')
+          node.querySelector('.box-name').textContent = name
+          boxContainer.appendChild(node)
+        })
+      })
+  }
+
   const url = new URL(document.location.toString())
-  await fetch(`${baseUrl}/feeds${url.search}`)
+  const query = url.searchParams.get('q')
+  const searchInput = document.querySelector(
+    '.search input'
+  ) as HTMLInputElement
+  if (searchInput && query) {
+    searchInput.value = query
+  }
+
+  await fetch(`${baseUrl}/${query ? 'search' : 'feeds'}${url.search}`)
     .then(x => x.json() as Promise<{ items: FeedSummary[] }>)
     .then(x => {
       x.items.forEach(x => {
@@ -30,6 +59,11 @@ import './main.css'
         node.querySelector('.summary-id').textContent = '#' + x.id
         node.querySelector('.summary-title').textContent = x.title
         node.querySelector('.summary-date').textContent = datetime
+        // Snippets are escaped by the server, only `<mark>` is left as HTML
+        node.querySelector('.summary-snippet').innerHTML = x.snippet ?? ''
+        if (!x.snippet && x.summary) {
+          node.querySelector('.summary-snippet').textContent = x.summary
+        }
         container.appendChild(node)
       })
     })
@@ -39,4 +73,6 @@ interface FeedSummary {
   title: string
   create_at: string
   id: string
+  snippet?: string
+  summary?: string
 }
@@ -0,0 +1,316 @@
+//! Changes to items and boxes made by admins, shared by the REST routes and
+//! GraphQL mutations. Each is recorded in the `audit` collection.
+
+use chrono::Utc;
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    audit::{self, AuditLog},
+    blob::{self, Blobs},
+    db::{sender_filter, Feed, Feeds},
+    error::{ApiError, ApiResult},
+    events, fulltext, mirror, notify,
+    registry::{self, BoxRecord, Registry},
+    store,
+    text::{normalize_subject, normalize_tags},
+    tombstone::{self, Tombstones},
+    validator, websub, welcome,
+};
+
+/// Drop what is kept of deleted items `ids` besides themselves, leaving
+/// tombstones
+async fn forget(
+    blobs: &Blobs,
+    tombstones: &Tombstones,
+    ids: &[String],
+    action: &str,
+) -> ApiResult<()> {
+    tombstone::bury(tombstones, ids, action).await?;
+    fulltext::remove(ids);
+    mirror::unmirror(ids);
+    validator::touch();
+    if let Err(e) = blob::remove(blobs, ids).await {
+        warn!(target: "Database", "Error deleting content chunks: {}", e)
+    }
+    Ok(())
+}
+
+/// Delete an item, e.g. spam that slipped into a box
+pub async fn delete_item(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    audit: &AuditLog,
+    tombstones: &Tombstones,
+    key: &str,
+) -> ApiResult<u64> {
+    let res = feeds.delete_one(doc! { "id": key }, None).await?;
+    if res.deleted_count == 0 {
+        return Err(ApiError::not_found(format!("Cannot find {}", key)));
+    }
+    forget(blobs, tombstones, &[key.to_owned()], "delete_item").await?;
+    audit::record(audit, "delete_item", key, res.deleted_count).await;
+    Ok(res.deleted_count)
+}
+
+/// Delete every item authored by `address`, across all boxes
+pub async fn erase_sender(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    audit: &AuditLog,
+    tombstones: &Tombstones,
+    address: &str,
+) -> ApiResult<u64> {
+    let ids = feeds
+        .distinct("id", sender_filter(address), None)
+        .await?
+        .into_iter()
+        .filter_map(|x| x.as_str().map(ToOwned::to_owned))
+        .collect::<Vec<_>>();
+    let res = feeds.delete_many(sender_filter(address), None).await?;
+    forget(blobs, tombstones, &ids, "erase_sender").await?;
+    audit::record(audit, "erase_sender", address, res.deleted_count).await;
+    Ok(res.deleted_count)
+}
+
+#[derive(Deserialize)]
+pub struct ItemEdit {
+    pub title: Option<String>,
+    pub from_box: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// Correct the title, box or tags of an item, e.g. a newsletter that landed
+/// in the wrong box. Returns the item with every field.
+pub async fn edit_item(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    audit: &AuditLog,
+    key: &str,
+    edit: ItemEdit,
+) -> ApiResult<Feed> {
+    let mut update = Document::new();
+    if let Some(title) = &edit.title {
+        let title = title.trim();
+        if title.is_empty() {
+            return Err(ApiError::bad_request("Title cannot be empty"));
+        }
+        update.insert("title", title);
+        update.insert("subject_key", normalize_subject(title));
+    }
+    if let Some(from_box) = &edit.from_box {
+        if !registry::is_valid_name(from_box) {
+            return Err(ApiError::bad_request(format!(
+                "{} is not an address",
+                from_box
+            )));
+        }
+        update.insert("from_box", registry::resolve(from_box));
+    }
+    if let Some(tags) = edit.tags {
+        update.insert("tags", normalize_tags(tags));
+    }
+    if update.is_empty() {
+        return Err(ApiError::bad_request(
+            "Nothing to change, give title, from_box or tags",
+        ));
+    }
+
+    let res = feeds
+        .update_one(doc! { "id": key }, doc! { "$set": update }, None)
+        .await?;
+    if res.matched_count == 0 {
+        return Err(ApiError::not_found(format!("Cannot find {}", key)));
+    }
+    let feed = store::get(feeds, blobs, key)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Cannot find {}", key)))?;
+    if edit.title.is_some() {
+        fulltext::remove(&[key.to_owned()]);
+        if let Some(index) = fulltext::index() {
+            if let Err(e) = tokio::task::block_in_place(|| index.add(&[feed.clone()])) {
+                warn!(target: "Search", "Error adding to index: {}", e)
+            }
+        }
+    }
+    validator::touch();
+    audit::record(audit, "edit_item", key, res.modified_count).await;
+    Ok(feed)
+}
+
+/// Replace user-assigned tags of an item. Returns them normalized.
+pub async fn set_tags(feeds: &Feeds, key: &str, tags: Vec<String>) -> ApiResult<Vec<String>> {
+    let tags = normalize_tags(tags);
+    let res = feeds
+        .update_one(
+            doc! { "id": key },
+            doc! { "$set": { "tags": tags.clone() } },
+            None,
+        )
+        .await?;
+    if res.matched_count == 0 {
+        return Err(ApiError::not_found(format!("Cannot find {}", key)));
+    }
+    validator::touch();
+    Ok(tags)
+}
+
+/// Publish an item waiting in a moderated box, announcing it as the pipeline
+/// does items of boxes without moderation
+pub async fn approve(feeds: &Feeds, blobs: &Blobs, audit: &AuditLog, key: &str) -> ApiResult<()> {
+    let res = feeds
+        .find_one_and_update(
+            doc! { "id": key, "pending": true },
+            doc! { "$set": { "pending": false } },
+            None,
+        )
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("No pending item {}", key)))?;
+    validator::schedule(res.publish_at.unwrap_or_else(Utc::now));
+    websub::ping(&res.from_box, res.publish_at);
+    audit::record(audit, "approve", key, 1).await;
+
+    // Announcements went without the item while it was pending
+    let mut feed = Feed {
+        pending: false,
+        ..res
+    };
+    // Only the welcome reply reads the source, for its recipient
+    if let Err(e) = blob::fill_raw(blobs, &mut feed).await {
+        warn!(target: "Database", "Error loading the source of {}: {}", key, e)
+    }
+    events::publish(&feed);
+    notify::send(feeds, &feed);
+    if let Err(e) = welcome::greet(feeds, &feed).await {
+        warn!(target: "Mailer", "Error checking for a new box reply to {}: {}", key, e)
+    }
+    Ok(())
+}
+
+/// Delete an item waiting in a moderated box
+pub async fn reject(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    audit: &AuditLog,
+    tombstones: &Tombstones,
+    key: &str,
+) -> ApiResult<()> {
+    let res = feeds
+        .delete_one(doc! { "id": key, "pending": true }, None)
+        .await?;
+    if res.deleted_count == 0 {
+        return Err(ApiError::not_found(format!("No pending item {}", key)));
+    }
+    forget(blobs, tombstones, &[key.to_owned()], "reject").await?;
+    audit::record(audit, "reject", key, res.deleted_count).await;
+    Ok(())
+}
+
+fn check_name(name: &str) -> ApiResult<()> {
+    match registry::is_valid_name(name) {
+        true => Ok(()),
+        false => Err(ApiError::bad_request(format!("{} is not an address", name))),
+    }
+}
+
+async fn check_exists(feeds: &Feeds, name: &str) -> ApiResult<()> {
+    match registry::exists(feeds, name).await? {
+        true => Ok(()),
+        false => Err(ApiError::not_found(format!("Cannot find box {}", name))),
+    }
+}
+
+/// Register a box before mail comes in, e.g. to set its token
+pub async fn create_box(
+    feeds: &Feeds,
+    registry: &Registry,
+    audit: &AuditLog,
+    name: &str,
+) -> ApiResult<BoxRecord> {
+    check_name(name)?;
+    if registry::exists(feeds, name).await? {
+        return Err(ApiError::conflict(format!("Box {} exists", name)));
+    }
+    let record = registry::create(registry, name).await?;
+    audit::record(audit, "create_box", name, 0).await;
+    Ok(record)
+}
+
+/// Rename a box, redirecting the old name. Returns the number of items moved.
+pub async fn rename_box(
+    feeds: &Feeds,
+    registry: &Registry,
+    audit: &AuditLog,
+    from: &str,
+    to: &str,
+) -> ApiResult<u64> {
+    check_name(to)?;
+    check_exists(feeds, from).await?;
+    if registry::exists(feeds, to).await? {
+        return Err(ApiError::conflict(format!("Box {} exists", to)));
+    }
+    let moved = registry::rename(registry, feeds, from, to).await?;
+    audit::record(audit, "rename_box", &format!("{} -> {}", from, to), moved).await;
+    Ok(moved)
+}
+
+/// Move the items of a box into another existing one, e.g. after changing a
+/// catch-all alias. Returns the number of items moved.
+pub async fn merge_box(
+    feeds: &Feeds,
+    registry: &Registry,
+    audit: &AuditLog,
+    from: &str,
+    into: &str,
+) -> ApiResult<u64> {
+    let into = registry::resolve(into);
+    if from == into {
+        return Err(ApiError::bad_request("Cannot merge a box into itself"));
+    }
+    check_exists(feeds, from).await?;
+    check_exists(feeds, &into).await?;
+    let moved = registry::merge(registry, feeds, from, &into).await?;
+    audit::record(audit, "merge_box", &format!("{} -> {}", from, into), moved).await;
+    Ok(moved)
+}
+
+/// Archive a box, or take it out of the archive
+pub async fn set_archived(
+    feeds: &Feeds,
+    registry: &Registry,
+    audit: &AuditLog,
+    name: &str,
+    archived: bool,
+) -> ApiResult<()> {
+    check_exists(feeds, name).await?;
+    registry::set_archived(registry, name, archived).await?;
+    let action = match archived {
+        true => "archive_box",
+        false => "unarchive_box",
+    };
+    audit::record(audit, action, name, 0).await;
+    Ok(())
+}
+
+/// Delete a box with its items. Returns the number of items deleted.
+pub async fn delete_box(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    registry: &Registry,
+    audit: &AuditLog,
+    tombstones: &Tombstones,
+    name: &str,
+) -> ApiResult<u64> {
+    check_exists(feeds, name).await?;
+    let ids = feeds
+        .distinct("id", doc! { "from_box": name }, None)
+        .await?
+        .into_iter()
+        .filter_map(|x| x.as_str().map(ToOwned::to_owned))
+        .collect::<Vec<_>>();
+    let deleted = registry::delete(registry, feeds, name).await?;
+    forget(blobs, tombstones, &ids, "delete_box").await?;
+    audit::record(audit, "delete_box", name, deleted).await;
+    Ok(deleted)
+}
@@ -0,0 +1,87 @@
+//! Webhook events on ingestion failures, see `FAILURE_WEBHOOK`
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{client::http_client, config::get_config};
+
+/// Recent SMTP rejections, and when the rate was last reported
+static REJECTIONS: Lazy<Mutex<(VecDeque<Instant>, Option<Instant>)>> =
+    Lazy::new(|| Mutex::new((VecDeque::new(), None)));
+
+#[derive(Serialize)]
+struct Event<'a> {
+    /// `parse_failed`, `insert_failed` or `reject_rate`
+    event: &'a str,
+    reason: &'a str,
+    message_id: Option<&'a str>,
+    domain: &'a str,
+    at: String,
+}
+
+/// Post an event to the webhook, if configured, without waiting for it
+pub fn report(event: &str, reason: &str, message_id: Option<&str>) {
+    let config = get_config();
+    let url = match &config.failure_webhook {
+        Some(x) => x,
+        None => return,
+    };
+    let body = Event {
+        event,
+        reason,
+        message_id,
+        domain: &config.domain,
+        at: Utc::now().to_rfc3339(),
+    };
+    let req = http_client().post(url).json(&body);
+    tokio::spawn(async move {
+        if let Err(e) = req.send().await.and_then(|x| x.error_for_status()) {
+            warn!(target: "Alert", "Error calling failure webhook: {}", e)
+        }
+    });
+}
+
+/// Count an SMTP rejection, reporting once per window when there are at least
+/// `REJECT_ALERT_THRESHOLD` of them within `REJECT_ALERT_WINDOW` minutes
+pub fn rejected(reason: &str, message_id: Option<&str>) {
+    let config = get_config();
+    if config.failure_webhook.is_none() || config.reject_alert_threshold == 0 {
+        return;
+    }
+    let window = Duration::from_secs(config.reject_alert_window * 60);
+    let now = Instant::now();
+    let count = {
+        let mut guard = REJECTIONS.lock().expect("rejections poisoned");
+        let (times, last_report) = &mut *guard;
+        times.push_back(now);
+        while times
+            .front()
+            .map_or(false, |x| now.duration_since(*x) > window)
+        {
+            times.pop_front();
+        }
+        if times.len() < config.reject_alert_threshold
+            || last_report.map_or(false, |x| now.duration_since(x) < window)
+        {
+            return;
+        }
+        *last_report = Some(now);
+        times.len()
+    };
+    report(
+        "reject_rate",
+        &format!(
+            "{} rejections in the last {} minutes, latest: {}",
+            count, config.reject_alert_window, reason
+        ),
+        message_id,
+    );
+}
@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::{serde::ts_milliseconds, DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, from_document},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub type Hits = Collection<Hit>;
+
+/// A fetch of one of the feed endpoints
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hit {
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: DateTime<Utc>,
+    pub route: String,
+    pub from_box: Option<String>,
+    pub user_agent: String,
+    pub subscribers: Option<u32>,
+    /// Client address, resolved through trusted proxies
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReaderStats {
+    /// `None` for the feed of all boxes
+    #[serde(rename = "_id")]
+    pub from_box: Option<String>,
+    pub hits: i64,
+    /// Distinct user agents
+    pub readers: i64,
+    /// Sum of subscriber counts reported by aggregators, other readers count as one
+    pub subscribers: i64,
+}
+
+/// Extract subscriber count hinted by aggregators, e.g.
+/// `Feedly/1.0 (+http://www.feedly.com/fetcher.html; 42 subscribers; )`
+pub fn parse_subscribers(user_agent: &str) -> Option<u32> {
+    let idx = user_agent.find(" subscriber")?;
+    user_agent[..idx]
+        .rsplit(|x: char| !x.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Record a hit in background so the response is not held back
+pub fn record(hits: Hits, route: &str, from_box: Option<&str>, user_agent: &str, client: IpAddr) {
+    let hit = Hit {
+        created_at: Utc::now(),
+        route: route.to_owned(),
+        from_box: from_box.map(ToOwned::to_owned),
+        user_agent: user_agent.to_owned(),
+        subscribers: parse_subscribers(user_agent),
+        client: Some(client.to_string()),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = hits.insert_one(hit, None).await {
+            warn!(target: "Analytics", "Error insert doc: {}", e)
+        }
+    });
+}
+
+/// Readers per box over the last `days` days
+pub async fn readers(hits: Hits, days: i64) -> Result<Vec<ReaderStats>> {
+    let since = Utc::now() - Duration::days(days);
+    let pipeline = vec![
+        doc! { "$match": { "created_at": { "$gte": since.timestamp_millis() } } },
+        doc! { "$sort": { "created_at": 1 } },
+        doc! {
+            "$group": {
+                "_id": { "from_box": "$from_box", "user_agent": "$user_agent" },
+                "hits": { "$sum": 1 },
+                "subscribers": { "$last": "$subscribers" },
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": "$_id.from_box",
+                "hits": { "$sum": "$hits" },
+                "readers": { "$sum": 1 },
+                "subscribers": { "$sum": { "$ifNull": ["$subscribers", 1] } },
+            }
+        },
+        doc! { "$sort": { "subscribers": -1, "hits": -1 } },
+    ];
+
+    let ret = hits
+        .aggregate(pipeline, None)
+        .await?
+        .try_filter_map(|x| async move { Ok(from_document::<ReaderStats>(x).ok()) })
+        .try_collect()
+        .await?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_subscribers() {
+        assert_eq!(
+            parse_subscribers("Feedly/1.0 (+http://www.feedly.com/fetcher.html; 42 subscribers; )"),
+            Some(42)
+        );
+        assert_eq!(
+            parse_subscribers("Inoreader/1.0 (+http://www.inoreader.com; 1 subscriber; )"),
+            Some(1)
+        );
+        assert_eq!(parse_subscribers("Mozilla/5.0"), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! Revocable keys for scripts and people, managed through `/admin/keys` and
+//! sent as `Authorization: Bearer <key>`. Only a hash of each key is stored.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use anyhow::Result;
+use chrono::{
+    serde::{ts_milliseconds, ts_milliseconds_option},
+    DateTime, Duration, Utc,
+};
+use futures::TryStreamExt;
+use mongodb::{bson::doc, Collection};
+use once_cell::sync::Lazy;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub type ApiKeys = Collection<ApiKey>;
+
+/// Characters of new keys
+const KEY_LENGTH: usize = 40;
+
+/// Uses within this time of the recorded one are not written to the database
+const LAST_USED_PRECISION: i64 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Reading routes, as with `READER_USERNAME`
+    Read,
+    /// `POST /ingest`
+    Ingest,
+    /// Everything, as with `AUTH_USERNAME`
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    /// Who or what the key is for
+    pub name: String,
+    /// SHA-256 of the key, hex-encoded
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(digest::digest(&digest::SHA256, key.as_bytes()))
+}
+
+/// Keys by hash, read on every request so that authorization needs no query
+static KEYS: Lazy<RwLock<HashMap<String, ApiKey>>> = Lazy::new(Default::default);
+
+/// Read all keys into memory, after any change
+pub async fn load(keys: &ApiKeys) -> Result<()> {
+    let loaded = keys
+        .find(None, None)
+        .await?
+        .map_ok(|x| (x.hash.clone(), x))
+        .try_collect::<HashMap<_, _>>()
+        .await?;
+    *KEYS.write().expect("keys poisoned") = loaded;
+    Ok(())
+}
+
+/// Keys without their hashes, oldest first
+pub fn list() -> Vec<ApiKey> {
+    let mut ret = KEYS
+        .read()
+        .expect("keys poisoned")
+        .values()
+        .map(|x| ApiKey {
+            hash: String::new(),
+            ..x.clone()
+        })
+        .collect::<Vec<_>>();
+    ret.sort_by_key(|x| x.created_at);
+    ret
+}
+
+/// Store a new key. Returns it with the key itself, which is not kept.
+pub async fn create(keys: &ApiKeys, name: &str, scopes: Vec<Scope>) -> Result<(ApiKey, String)> {
+    let secret = nanoid::nanoid!(KEY_LENGTH);
+    let key = ApiKey {
+        id: nanoid::nanoid!(10),
+        name: name.to_owned(),
+        hash: hash(&secret),
+        scopes,
+        created_at: Utc::now(),
+        last_used_at: None,
+    };
+    keys.insert_one(&key, None).await?;
+    load(keys).await?;
+    Ok((
+        ApiKey {
+            hash: String::new(),
+            ..key
+        },
+        secret,
+    ))
+}
+
+/// Revoke a key. Returns whether it existed.
+pub async fn revoke(keys: &ApiKeys, id: &str) -> Result<bool> {
+    let deleted = keys
+        .delete_one(doc! { "id": id }, None)
+        .await?
+        .deleted_count;
+    load(keys).await?;
+    Ok(deleted > 0)
+}
+
+/// Scopes of a key, recording its use
+pub fn scopes(keys: &ApiKeys, secret: &str) -> Option<Vec<Scope>> {
+    let hash = hash(secret);
+    let now = Utc::now();
+    let mut loaded = KEYS.write().expect("keys poisoned");
+    let key = loaded.get_mut(&hash)?;
+    let stale = key
+        .last_used_at
+        .map_or(true, |x| now - x > Duration::seconds(LAST_USED_PRECISION));
+    if stale {
+        key.last_used_at = Some(now);
+        let keys = keys.clone();
+        tokio::spawn(async move {
+            let res = keys
+                .update_one(
+                    doc! { "hash": &hash },
+                    doc! { "$set": { "last_used_at": now.timestamp_millis() } },
+                    None,
+                )
+                .await;
+            if let Err(e) = res {
+                warn!(target: "Database", "Error recording key use: {}", e)
+            }
+        });
+    }
+    Some(key.scopes.clone())
+}
@@ -0,0 +1,157 @@
+//! Atom 1.0 (RFC 4287) rendering of the same items as the RSS feeds
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::get_config,
+    db::Feed,
+    text::{escape_xml, percent_encode},
+};
+
+/// Namespace of feed history elements, see RFC 5005
+pub const HISTORY_NAMESPACE: &str = "http://purl.org/syndication/history/1.0";
+
+/// Channel-level data of an Atom feed
+pub struct AtomFeed<'a> {
+    pub title: &'a str,
+    pub subtitle: Option<&'a str>,
+    /// URL of the feed itself, also used as its id
+    pub id: &'a str,
+    /// `(rel, href)` of navigation links, e.g. `("next", ...)`
+    pub links: Vec<(&'a str, String)>,
+    pub icon: Option<String>,
+    /// An archive document, see RFC 5005 section 4
+    pub archive: bool,
+}
+
+impl Feed {
+    /// Last time the item changed, i.e. its last collapsed repetition
+    fn updated(&self) -> DateTime<Utc> {
+        self.last_seen_at.unwrap_or(self.created_at)
+    }
+
+    fn into_atom(self) -> String {
+        let config = get_config();
+        let link = self.link();
+        let feed = self.redacted();
+        // Items without a stored GUID keep the id they were first served with
+        let id = match feed.guid.is_empty() {
+            true => format!("https://{}/feeds/{}", config.web_domain, feed.id),
+            false => format!("mid:{}", percent_encode(&feed.guid)),
+        };
+        let mut ret = String::from("<entry>\n");
+        ret.push_str(&format!("<id>{}</id>\n", escape_xml(&id)));
+        ret.push_str(&format!(
+            "<title type=\"text\">{}</title>\n",
+            escape_xml(&feed.display_title())
+        ));
+        ret.push_str(&format!(
+            "<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n",
+            escape_xml(&link)
+        ));
+        ret.push_str(&format!(
+            "<published>{}</published>\n",
+            feed.created_at.to_rfc3339()
+        ));
+        ret.push_str(&format!(
+            "<updated>{}</updated>\n",
+            feed.updated().to_rfc3339()
+        ));
+        ret.push_str(&format!(
+            "<author><name>{}</name></author>\n",
+            escape_xml(&feed.author)
+        ));
+        for (n, x) in feed.attachments.iter().enumerate() {
+            ret.push_str(&format!(
+                "<link rel=\"enclosure\" type=\"{}\" length=\"{}\" href=\"{}\"/>\n",
+                escape_xml(x.mime_type()),
+                x.size,
+                escape_xml(&feed.attachment_url(n))
+            ));
+        }
+        for tag in &feed.tags {
+            ret.push_str(&format!("<category term=\"{}\"/>\n", escape_xml(tag)));
+        }
+        if let Some(summary) = &feed.summary {
+            ret.push_str(&format!(
+                "<summary type=\"text\">{}</summary>\n",
+                escape_xml(summary)
+            ));
+        }
+        ret.push_str(&format!(
+            "<content type=\"html\">{}</content>\n",
+            escape_xml(&feed.content)
+        ));
+        ret.push_str("</entry>\n");
+        ret
+    }
+}
+
+/// Render `items`, newest first, as an Atom feed document
+pub fn render_atom(feed: AtomFeed, items: Vec<Feed>) -> String {
+    let updated = items
+        .iter()
+        .map(Feed::updated)
+        .max()
+        .unwrap_or_else(Utc::now);
+    let mut ret = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    match feed.archive {
+        true => ret.push_str(&format!(
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:fh=\"{}\">\n<fh:archive/>\n",
+            HISTORY_NAMESPACE
+        )),
+        false => ret.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n"),
+    }
+    ret.push_str(&format!("<id>{}</id>\n", escape_xml(feed.id)));
+    ret.push_str(&format!(
+        "<title type=\"text\">{}</title>\n",
+        escape_xml(feed.title)
+    ));
+    if let Some(subtitle) = feed.subtitle {
+        ret.push_str(&format!(
+            "<subtitle type=\"text\">{}</subtitle>\n",
+            escape_xml(subtitle)
+        ));
+    }
+    ret.push_str(&format!("<updated>{}</updated>\n", updated.to_rfc3339()));
+    ret.push_str(
+        "<generator uri=\"http://github.com/George-Miao/mail-list-rss\">mail-list-rss</generator>\n",
+    );
+    for (rel, href) in &feed.links {
+        ret.push_str(&format!(
+            "<link rel=\"{}\" href=\"{}\"/>\n",
+            rel,
+            escape_xml(href)
+        ));
+    }
+    if let Some(icon) = &feed.icon {
+        ret.push_str(&format!("<icon>{}</icon>\n", escape_xml(icon)));
+    }
+    for item in items {
+        ret.push_str(&item.into_atom());
+    }
+    ret.push_str("</feed>\n");
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_atom() {
+        let feed = AtomFeed {
+            title: "Mail List",
+            subtitle: None,
+            id: "https://example.com/atom?tag=a&b",
+            links: vec![("self", "https://example.com/atom?tag=a&b".to_owned())],
+            icon: None,
+            archive: false,
+        };
+        let xml = render_atom(feed, vec![]);
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.contains("<id>https://example.com/atom?tag=a&amp;b</id>"));
+        assert!(xml.contains("<link rel=\"self\" href=\"https://example.com/atom?tag=a&amp;b\"/>"));
+        assert!(xml.ends_with("</feed>\n"));
+    }
+}
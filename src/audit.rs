@@ -0,0 +1,29 @@
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+pub type AuditLog = Collection<AuditEntry>;
+
+/// A record of an administrative action
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: DateTime<Utc>,
+    pub action: String,
+    pub subject: String,
+    pub affected: u64,
+}
+
+pub async fn record(audit: &AuditLog, action: &str, subject: &str, affected: u64) {
+    info!(target: "Audit", action, subject, affected, "Admin action");
+    let entry = AuditEntry {
+        created_at: Utc::now(),
+        action: action.to_owned(),
+        subject: subject.to_owned(),
+        affected,
+    };
+    if let Err(e) = audit.insert_one(entry, None).await {
+        warn!(target: "Audit", "Error insert doc: {}", e)
+    }
+}
@@ -0,0 +1,249 @@
+//! Access to the web routes with `AUTH_USERNAME` set: the credentials open
+//! every route, `READER_USERNAME` and `READER_PASSWORD` only open reading
+//! routes, API keys open what their scopes allow, and the feed token of a box
+//! opens its feeds, e.g. `/rss/news@example.com?token=...`, for readers
+//! without basic auth
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use axum::{
+    body::BoxBody,
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    response::{Headers, IntoResponse, Response},
+};
+use ring::constant_time::verify_slices_are_equal;
+use tower_http::auth::AuthorizeRequest;
+
+use crate::{
+    apikeys::{self, ApiKeys, Scope},
+    registry,
+    text::percent_decode,
+};
+
+/// Characters of new feed tokens
+const TOKEN_LENGTH: usize = 32;
+
+/// Routes needing the admin credentials even to read, e.g. full dumps
+const ADMIN_PREFIXES: &[&str] = &["/admin", "/export", "/ingest", "/metrics", "/stats"];
+
+pub fn new_token() -> String {
+    nanoid::nanoid!(TOKEN_LENGTH)
+}
+
+fn same(a: &[u8], b: &[u8]) -> bool {
+    verify_slices_are_equal(a, b).is_ok()
+}
+
+/// Box whose feed `path` is, e.g. `/rss/:box` or `/atom/:box`
+fn feed_box(path: &str) -> Option<String> {
+    let mut segments = path.split('/').skip(1);
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some("rss" | "atom"), Some(name), None, None)
+        | (Some("rss"), Some(name), Some("digest"), None) => Some(percent_decode(name)),
+        _ => None,
+    }
+}
+
+/// Value of `name` in a query string
+fn query_value(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|x| x.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| percent_decode(v))
+}
+
+/// Whether the request is for a feed of a box, with its token
+fn has_feed_token<B>(req: &Request<B>) -> bool {
+    let token = match req.uri().query().and_then(|x| query_value(x, "token")) {
+        Some(x) => x,
+        None => return false,
+    };
+    feed_box(req.uri().path())
+        .and_then(|x| registry::token(&x))
+        .map_or(false, |x| same(x.as_bytes(), token.as_bytes()))
+}
+
+/// Whether reader credentials open a request: reading anything but admin
+/// routes
+pub fn is_reading(method: &Method, path: &str) -> bool {
+    let admin = ADMIN_PREFIXES.iter().any(|x| {
+        path.strip_prefix(x)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    });
+    (method == Method::GET || method == Method::HEAD) && !admin
+}
+
+/// Whether reading credentials may `POST` to `path`, e.g. GraphQL queries.
+/// Such requests are marked `Restricted`.
+pub fn is_query(method: &Method, path: &str) -> bool {
+    method == Method::POST && path == "/graphql"
+}
+
+/// Marks requests allowed without the admin credentials or an admin key, for
+/// routes checking further, e.g. GraphQL mutations
+#[derive(Clone, Copy, Debug)]
+pub struct Restricted;
+
+/// Hash of the credentials or feed token a request was let in with, telling
+/// clients apart in `ratelimit`. Unchecked ones are never hashed, so that made
+/// up values do not make new clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Credential(pub u64);
+
+impl Credential {
+    fn of(value: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+fn permits(scope: Scope, method: &Method, path: &str) -> bool {
+    match scope {
+        Scope::Read => is_reading(method, path),
+        Scope::Ingest => method == Method::POST && path == "/ingest",
+        Scope::Admin => true,
+    }
+}
+
+fn forbidden(message: &'static str) -> Response {
+    (StatusCode::FORBIDDEN, message).into_response()
+}
+
+fn basic_header(username: &str, password: &str) -> HeaderValue {
+    let encoded = base64::encode(format!("{}:{}", username, password));
+    format!("Basic {}", encoded)
+        .parse()
+        .expect("credentials should make a header")
+}
+
+#[derive(Clone)]
+pub struct Authorize {
+    admin: HeaderValue,
+    reader: Option<HeaderValue>,
+    keys: ApiKeys,
+}
+
+impl Authorize {
+    pub fn new(admin: (&str, &str), reader: Option<(&str, &str)>, keys: ApiKeys) -> Self {
+        Self {
+            admin: basic_header(admin.0, admin.1),
+            reader: reader.map(|(username, password)| basic_header(username, password)),
+            keys,
+        }
+    }
+}
+
+impl<B> AuthorizeRequest<B> for Authorize {
+    type ResponseBody = BoxBody;
+
+    fn authorize(&mut self, req: &mut Request<B>) -> Result<(), Response> {
+        let given = req.headers().get(header::AUTHORIZATION);
+        let is = |expected: &HeaderValue| {
+            given.map_or(false, |x| same(x.as_bytes(), expected.as_bytes()))
+        };
+        let admin = is(&self.admin);
+        let by_token = !admin && has_feed_token(req);
+        let restricted = !admin && (by_token || self.check(req)?);
+        let credential = match by_token {
+            true => req
+                .uri()
+                .query()
+                .and_then(|x| query_value(x, "token"))
+                .map(|x| Credential::of(x.as_bytes())),
+            false => given.map(|x| Credential::of(x.as_bytes())),
+        };
+        if let Some(credential) = credential {
+            req.extensions_mut().insert(credential);
+        }
+        if restricted {
+            req.extensions_mut().insert(Restricted);
+        }
+        Ok(())
+    }
+}
+
+impl Authorize {
+    /// Access by anything but the admin credentials and feed tokens. Tells
+    /// whether the request is `Restricted`.
+    fn check<B>(&self, req: &Request<B>) -> Result<bool, Response> {
+        let given = req.headers().get(header::AUTHORIZATION);
+        let is = |expected: &HeaderValue| {
+            given.map_or(false, |x| same(x.as_bytes(), expected.as_bytes()))
+        };
+        let (method, path) = (req.method(), req.uri().path());
+        if self.reader.as_ref().map_or(false, is) {
+            if permits(Scope::Read, method, path) || is_query(method, path) {
+                return Ok(true);
+            }
+            return Err(forbidden("Reader credentials cannot access this"));
+        }
+        let bearer = given
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        if let Some(scopes) = bearer.and_then(|x| apikeys::scopes(&self.keys, x.trim())) {
+            if scopes.contains(&Scope::Admin) {
+                return Ok(false);
+            }
+            let query = scopes.contains(&Scope::Read) && is_query(method, path);
+            if query || scopes.into_iter().any(|x| permits(x, method, path)) {
+                return Ok(true);
+            }
+            return Err(forbidden("The API key has no scope for this"));
+        }
+        Err((
+            StatusCode::UNAUTHORIZED,
+            Headers(vec![(header::WWW_AUTHENTICATE, "Basic")]),
+        )
+            .into_response())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_feed_box() {
+        assert_eq!(
+            feed_box("/rss/a%40example.com").as_deref(),
+            Some("a@example.com")
+        );
+        assert_eq!(
+            feed_box("/atom/a@example.com").as_deref(),
+            Some("a@example.com")
+        );
+        assert_eq!(
+            feed_box("/rss/a@example.com/digest").as_deref(),
+            Some("a@example.com")
+        );
+        assert_eq!(feed_box("/atom/a@example.com/digest"), None);
+        assert_eq!(feed_box("/rss"), None);
+        assert_eq!(feed_box("/feeds/abc"), None);
+        assert_eq!(feed_box("/admin/boxes/a@example.com"), None);
+    }
+
+    #[test]
+    fn test_is_reading() {
+        assert!(is_reading(&Method::GET, "/rss/a@example.com"));
+        assert!(is_reading(&Method::HEAD, "/feeds"));
+        assert!(is_reading(&Method::GET, "/statistics"));
+        assert!(!is_reading(&Method::DELETE, "/feeds/abc"));
+        assert!(!is_reading(&Method::PUT, "/feeds/abc/tags"));
+        assert!(!is_reading(&Method::GET, "/admin/boxes"));
+        assert!(!is_reading(&Method::GET, "/export/a@example.com"));
+        assert!(!is_reading(&Method::GET, "/stats/readers"));
+        assert!(!is_reading(&Method::POST, "/graphql"));
+        assert!(is_query(&Method::POST, "/graphql"));
+        assert!(!is_query(&Method::POST, "/ingest"));
+    }
+}
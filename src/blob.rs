@@ -0,0 +1,254 @@
+//! Raw sources, and contents over `MAX_CONTENT_SIZE`, kept in chunks apart
+//! from items so that item documents and feeds stay small
+
+use anyhow::Result;
+use futures::{Stream, TryStreamExt};
+use mongodb::{
+    bson::{doc, to_document, Document},
+    options::FindOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    config::get_config,
+    db::{Feed, Feeds},
+    text::{escape_html, strip_html, truncate},
+};
+
+pub type Blobs = Collection<Chunk>;
+
+/// Well under the 16 MB limit of documents
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Characters of text kept as preview
+const PREVIEW_LEN: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Chunk {
+    /// Id of the item
+    pub id: String,
+    /// Whether a piece of the raw source rather than of the content
+    #[serde(default)]
+    pub raw: bool,
+    /// Position of the chunk, from 0
+    pub n: u32,
+    pub content: String,
+}
+
+/// Split `content` into pieces of at most `size` bytes, cut after a `>` when
+/// possible so that tags stay whole within a chunk
+fn split(content: &str, size: usize) -> Vec<&str> {
+    let mut ret = vec![];
+    let mut rest = content;
+    while rest.len() > size {
+        let mut end = size;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let end = rest[..end].rfind('>').map_or(end, |x| x + 1);
+        let (head, tail) = rest.split_at(end);
+        ret.push(head);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        ret.push(rest);
+    }
+    ret
+}
+
+fn preview(content: &str, id: &str) -> String {
+    format!(
+        r#"<p>{}</p><p><a href="https://{}/feeds/{}">Read the full message</a></p>"#,
+        escape_html(&truncate(&strip_html(content), PREVIEW_LEN)),
+        get_config().web_domain,
+        id
+    )
+}
+
+fn chunks(id: &str, content: &str, raw: bool) -> Vec<Chunk> {
+    split(content, CHUNK_SIZE)
+        .into_iter()
+        .enumerate()
+        .map(|(n, x)| Chunk {
+            id: id.to_owned(),
+            raw,
+            n: n as u32,
+            content: x.to_owned(),
+        })
+        .collect()
+}
+
+/// Move the content of `feed` to chunks if over `MAX_CONTENT_SIZE`, leaving a
+/// preview in its place. Returns whether it was moved.
+pub async fn overflow(blobs: &Blobs, feed: &mut Feed) -> Result<bool> {
+    let max = get_config().max_content_size;
+    if max == 0 || feed.content.len() <= max {
+        return Ok(false);
+    }
+    blobs
+        .insert_many(chunks(&feed.id, &feed.content, false), None)
+        .await?;
+    feed.content = preview(&feed.content, &feed.id);
+    feed.overflow = true;
+    if feed.text.len() > max {
+        // Taken from the raw source when needed
+        feed.text.clear();
+    }
+    Ok(true)
+}
+
+/// Move the raw source of `feed` to chunks, to be left out of the stored item,
+/// see `stored`. It stays on `feed` for the rest of the pipeline.
+pub async fn put_raw(blobs: &Blobs, feed: &mut Feed) -> Result<()> {
+    if feed.raw_chunked || feed.raw.is_empty() {
+        return Ok(());
+    }
+    blobs
+        .insert_many(chunks(&feed.id, &feed.raw, true), None)
+        .await?;
+    feed.raw_chunked = true;
+    Ok(())
+}
+
+/// Move raw sources of items stored before they were kept apart to chunks
+pub async fn backfill_raw(feeds: Feeds, blobs: Blobs) -> Result<()> {
+    let filter = doc! { "raw_chunked": { "$ne": true }, "raw": { "$nin": [null, ""] } };
+    let option = FindOptions::builder()
+        .projection(doc! { "id": 1, "raw": 1 })
+        .build();
+    let mut cursor = feeds
+        .clone_with_type::<Document>()
+        .find(filter, option)
+        .await?;
+    let mut count = 0;
+    while let Some(item) = cursor.try_next().await? {
+        let id = item.get_str("id").unwrap_or_default();
+        let raw = item.get_str("raw").unwrap_or_default();
+        // Left by a run stopped halfway
+        blobs
+            .delete_many(doc! { "id": id, "raw": true }, None)
+            .await?;
+        blobs.insert_many(chunks(id, raw, true), None).await?;
+        feeds
+            .update_one(
+                doc! { "_id": item.get("_id").cloned() },
+                doc! { "$set": { "raw_chunked": true }, "$unset": { "raw": "" } },
+                None,
+            )
+            .await?;
+        count += 1;
+    }
+    if count > 0 {
+        info!(target: "Database", count, "Moved raw sources of existing items to chunks");
+    }
+    Ok(())
+}
+
+/// Document of `feed` to insert, without the raw source if in chunks
+pub fn stored(feed: &Feed) -> Result<Document> {
+    let mut ret = to_document(feed)?;
+    if feed.raw_chunked {
+        ret.remove("raw");
+    }
+    Ok(ret)
+}
+
+/// Give `feed` the id `id`, moving its chunks along, e.g. when its own turns
+/// out taken on insert
+pub async fn rekey(blobs: &Blobs, feed: &mut Feed, id: String) -> Result<()> {
+    if feed.overflow || feed.raw_chunked {
+        blobs
+            .update_many(
+                doc! { "id": &feed.id },
+                doc! { "$set": { "id": &id } },
+                None,
+            )
+            .await?;
+    }
+    if feed.overflow {
+        // The preview links to the item
+        feed.content = feed.content.replace(
+            &format!("/feeds/{}\"", feed.id),
+            &format!("/feeds/{}\"", id),
+        );
+    }
+    feed.id = id;
+    Ok(())
+}
+
+async fn pieces(
+    blobs: &Blobs,
+    id: &str,
+    raw: bool,
+) -> Result<impl Stream<Item = Result<String, mongodb::error::Error>>> {
+    let option = FindOptions::builder().sort(doc! { "n": 1 }).build();
+    // Chunks stored before raw sources had any lack the field
+    let filter = match raw {
+        true => doc! { "id": id, "raw": true },
+        false => doc! { "id": id, "raw": { "$ne": true } },
+    };
+    let cursor = blobs.find(filter, option).await?;
+    Ok(cursor.map_ok(|x| x.content))
+}
+
+/// Chunks of the full content of an item, in order
+pub async fn stream(
+    blobs: &Blobs,
+    id: &str,
+) -> Result<impl Stream<Item = Result<String, mongodb::error::Error>>> {
+    pieces(blobs, id, false).await
+}
+
+/// Full content of an item
+pub async fn load(blobs: &Blobs, id: &str) -> Result<String> {
+    Ok(stream(blobs, id).await?.try_collect::<String>().await?)
+}
+
+/// Raw source of an item kept in chunks
+pub async fn load_raw(blobs: &Blobs, id: &str) -> Result<String> {
+    Ok(pieces(blobs, id, true)
+        .await?
+        .try_collect::<String>()
+        .await?)
+}
+
+/// Fill in the raw source of `feed` if kept in chunks
+pub async fn fill_raw(blobs: &Blobs, feed: &mut Feed) -> Result<()> {
+    if feed.raw_chunked && feed.raw.is_empty() {
+        feed.raw = load_raw(blobs, &feed.id).await?;
+    }
+    Ok(())
+}
+
+/// Delete chunks of the items
+pub async fn remove(blobs: &Blobs, ids: &[String]) -> Result<()> {
+    blobs
+        .delete_many(doc! { "id": { "$in": ids.to_vec() } }, None)
+        .await?;
+    Ok(())
+}
+
+pub async fn ensure_indexes(blobs: &Blobs) -> Result<()> {
+    blobs
+        .create_index(
+            IndexModel::builder().keys(doc! { "id": 1, "n": 1 }).build(),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("", 4), Vec::<&str>::new());
+        assert_eq!(split("<p>a</p>", 16), vec!["<p>a</p>"]);
+        assert_eq!(split("<p>a</p><p>b</p>", 10), vec!["<p>a</p>", "<p>b</p>"]);
+        assert_eq!(split("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(split("ééé", 3), vec!["é", "é", "é"]);
+    }
+}
@@ -0,0 +1,175 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::bail;
+use serde::Deserialize;
+
+use crate::notify::Target;
+
+pub type BoxConfigs = HashMap<String, BoxConfig>;
+
+/// Per-box settings, keyed by box address in `BOX_FILE`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BoxConfig {
+    /// Only these senders (envelope or `From` addresses) may post into the
+    /// box. Empty means everyone is allowed.
+    pub allowed_senders: Vec<String>,
+    /// Number of items in the feed of this box, overrides `PER_PAGE`
+    pub per_page: Option<u16>,
+    /// Minutes readers may cache the feed, emitted as `<ttl>`
+    pub ttl: Option<u32>,
+    /// Hours (0-23, GMT) readers should not poll, emitted as `<skipHours>`
+    pub skip_hours: Vec<u8>,
+    /// Days (e.g. `Saturday`) readers should not poll, emitted as `<skipDays>`
+    pub skip_days: Vec<String>,
+    /// What to do with auto-replies and bulk mail, overrides `AUTO_SUBMITTED`
+    pub auto_submitted: Option<AutoSubmittedAction>,
+    /// Minutes after receipt before items show up in feeds and listings
+    pub publish_delay: Option<u32>,
+    /// Hold items at `/admin/pending` until approved
+    pub moderated: bool,
+    /// What `<link>` of items points at
+    pub link: LinkTarget,
+    /// Where new items are announced
+    pub notify: Vec<Target>,
+    /// Title of the feeds of this box instead of its address, e.g. `Money Stuff`
+    pub title: Option<String>,
+    /// Overrides `CHANNEL_DESCRIPTION`
+    pub description: Option<String>,
+    /// URL of an image of the feeds of this box instead of its icon
+    pub image: Option<String>,
+    /// Overrides `CHANNEL_LANGUAGE`
+    pub language: Option<String>,
+}
+
+/// Target of item links in feeds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkTarget {
+    /// The item page, `/feeds/:key`
+    Archive,
+    /// The web version linked from the body, or else the `List-Archive`
+    /// header, falling back to the item page
+    Original,
+}
+
+impl Default for LinkTarget {
+    fn default() -> Self {
+        LinkTarget::Archive
+    }
+}
+
+/// Handling of messages marked by `Auto-Submitted` or `Precedence` headers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoSubmittedAction {
+    /// Store as usual
+    Keep,
+    /// Store, recording the kind in `Feed::auto_submitted`
+    Tag,
+    /// Discard
+    Drop,
+}
+
+impl Default for AutoSubmittedAction {
+    fn default() -> Self {
+        AutoSubmittedAction::Keep
+    }
+}
+
+impl FromStr for AutoSubmittedAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(AutoSubmittedAction::Keep),
+            "tag" => Ok(AutoSubmittedAction::Tag),
+            "drop" => Ok(AutoSubmittedAction::Drop),
+            _ => bail!("Unknown auto submitted action {}", s),
+        }
+    }
+}
+
+impl BoxConfig {
+    pub fn accepts(&self, senders: &[String]) -> bool {
+        self.allowed_senders.is_empty()
+            || senders.iter().any(|sender| {
+                self.allowed_senders
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(sender))
+            })
+    }
+}
+
+/// Reduce plus-addressed (`news+tag@example.com`) and VERP-encoded
+/// (`bounce-12345-news=example.com@example.com`) recipients to their base
+/// address, returning the tag of plus-addressing if any
+pub fn resolve_address(address: &str) -> (String, Option<String>) {
+    let (local, domain) = match address.rsplit_once('@') {
+        Some(x) => x,
+        None => return (address.to_owned(), None),
+    };
+
+    // VERP puts the original address as `prefix-local=domain`, where the
+    // prefix usually ends with a numeric id
+    let (local, domain) = match local.rsplit_once('=') {
+        Some((prefix, original)) if original.contains('.') => {
+            let segments = prefix.split('-').collect::<Vec<_>>();
+            let start = segments
+                .iter()
+                .rposition(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()))
+                .map_or(0, |x| x + 1);
+            match segments.get(start..).filter(|x| !x.is_empty()) {
+                Some(rest) => (rest.join("-"), original),
+                None => (local.to_owned(), domain),
+            }
+        }
+        _ => (local.to_owned(), domain),
+    };
+
+    let tag = local
+        .split_once('+')
+        .map(|(_, tag)| tag.to_owned())
+        .filter(|x| !x.is_empty());
+    let base = local.split('+').next().unwrap_or_default();
+    (format!("{}@{}", base, domain), tag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_address() {
+        assert_eq!(
+            resolve_address("news@example.com"),
+            ("news@example.com".to_owned(), None)
+        );
+        assert_eq!(
+            resolve_address("news+rust@example.com"),
+            ("news@example.com".to_owned(), Some("rust".to_owned()))
+        );
+        assert_eq!(
+            resolve_address("bounce-12345-news=example.com@example.com"),
+            ("news@example.com".to_owned(), None)
+        );
+        assert_eq!(
+            resolve_address("bounce-12345-mail-list+x=example.com@relay.example.net"),
+            ("mail-list@example.com".to_owned(), Some("x".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_accepts() {
+        let open = BoxConfig::default();
+        assert!(open.accepts(&["anyone@example.com".to_owned()]));
+
+        let closed = BoxConfig {
+            allowed_senders: vec!["list@example.com".to_owned()],
+            ..Default::default()
+        };
+        assert!(closed.accepts(&["List@Example.com".to_owned()]));
+        assert!(!closed.accepts(&["spammer@example.net".to_owned()]));
+        assert!(!closed.accepts(&[]));
+    }
+}
@@ -0,0 +1,41 @@
+//! Title, description and other channel-level metadata of feeds, from the
+//! `CHANNEL_*` settings and the settings of each box in `BOX_FILE`
+
+use crate::config::get_config;
+
+/// Metadata of a feed, shared by its RSS, Atom and JSON Feed versions
+pub struct Channel {
+    pub title: String,
+    pub description: Option<String>,
+    /// Language code, e.g. `en-us`
+    pub language: Option<String>,
+    /// URL of an image or logo
+    pub image: Option<String>,
+    /// Minutes readers may cache the feed
+    pub ttl: Option<u32>,
+}
+
+impl Channel {
+    /// Metadata of the feed of `from_box`, or of all boxes
+    pub fn of(from_box: Option<&str>) -> Self {
+        let config = get_config();
+        let box_config = from_box.and_then(|x| config.box_config(x));
+        let icon = from_box.map(|x| format!("https://{}/boxes/{}/icon", config.web_domain, x));
+        Self {
+            title: box_config
+                .and_then(|x| x.title.clone())
+                .unwrap_or_else(|| from_box.unwrap_or(&config.channel_title).to_owned()),
+            description: box_config
+                .and_then(|x| x.description.clone())
+                .or_else(|| config.channel_description.clone()),
+            language: box_config
+                .and_then(|x| x.language.clone())
+                .or_else(|| config.channel_language.clone()),
+            image: box_config
+                .and_then(|x| x.image.clone())
+                .or(icon)
+                .or_else(|| config.channel_image.clone()),
+            ttl: box_config.and_then(|x| x.ttl).or(config.channel_ttl),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use once_cell::sync::Lazy;
+use reqwest::{redirect::Policy, Client, ClientBuilder};
+use tokio::net::lookup_host;
+
+use crate::proxy::Cidr;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn builder() -> ClientBuilder {
+    Client::builder()
+        .user_agent(concat!("mail-list-rss/", env!("CARGO_PKG_VERSION")))
+        .timeout(TIMEOUT)
+}
+
+static CLIENT: Lazy<Client> = Lazy::new(|| builder().build().unwrap());
+
+/// Shared client for outgoing HTTP requests
+#[inline]
+pub fn http_client<'a>() -> &'a Client {
+    &CLIENT
+}
+
+/// Ranges requests to hosts named by senders must not reach: this host,
+/// private networks, link-local cloud metadata and other non-public ones
+static NON_PUBLIC: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/3",
+        "::/128",
+        "::1/128",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|x| x.parse().expect("valid range"))
+    .collect()
+});
+
+pub fn is_public(ip: IpAddr) -> bool {
+    !NON_PUBLIC.iter().any(|x| x.contains(ip))
+}
+
+/// Client for a request to `host` named by a sender, e.g. in its address.
+/// It only connects to the public address `host` resolves to now, so that
+/// later answers of its DNS cannot point it elsewhere, and does not follow
+/// redirects.
+pub async fn sender_client(host: &str, port: u16) -> Result<Client> {
+    if host.is_empty()
+        || !host
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"-.".contains(&x))
+    {
+        bail!("Bad host name {:?}", host);
+    }
+    let addrs = lookup_host((host, port))
+        .await?
+        .collect::<Vec<SocketAddr>>();
+    if let Some(x) = addrs.iter().find(|x| !is_public(x.ip())) {
+        bail!("{} resolves to non-public address {}", host, x.ip());
+    }
+    let addr = addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("{} does not resolve", host))?;
+    Ok(builder()
+        .redirect(Policy::none())
+        .resolve(host, addr)
+        .build()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.20.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -1,53 +1,180 @@
-use std::{env::var, fs};
+use std::{
+    env::var,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+};
 
-use anyhow::Result;
-use once_cell::sync::Lazy;
+use anyhow::{bail, Context, Result};
+use axum::http::uri::Authority;
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
 use serde_json::from_str;
 use tracing::warn;
 
-use crate::rule::{Rule, RuleFilter};
+use crate::{
+    boxes::{AutoSubmittedAction, BoxConfig, BoxConfigs},
+    pipeline,
+    proxy::Cidr,
+    rule::{Rule, RuleFilter},
+    sieve::Script,
+    translate::Backend,
+    web::RenderMode,
+};
 
-static CONFIG: Lazy<Config> = Lazy::new(|| Config::from_env().unwrap());
+static CONFIG: OnceCell<Config> = OnceCell::new();
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub web_port: u16,
+    /// Address the web server listens on
+    pub web_bind: IpAddr,
+    /// Unix socket the web server listens on instead of `web_bind`
+    pub web_socket: Option<String>,
+    /// PEM certificate chain and private key to serve HTTPS with
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
     pub smtp_port: u16,
     pub per_page: u16,
+    /// Largest `limit` accepted on feeds
+    pub max_per_page: u64,
     pub domain: String,
     pub mongo_con_str: String,
     pub mongo_db_name: String,
     pub web_domain: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Credentials only opening reading routes, see `auth`
+    pub reader_username: Option<String>,
+    pub reader_password: Option<String>,
     pub rules: Vec<Rule>,
+    /// Sieve script from `SIEVE_FILE`, run before the rules
+    pub sieve: Option<Script>,
+    pub boxes: BoxConfigs,
     pub disable_rcpt_filter: bool,
     pub default_page_limit: i64,
+    pub obfuscate_emails: bool,
+    pub collapse_window_hours: Option<i64>,
+    pub max_hops: usize,
+    /// Bytes of content kept in items, larger ones are moved to chunks, 0 to disable
+    pub max_content_size: usize,
+    pub auto_submitted: AutoSubmittedAction,
+    pub smarthost: Option<String>,
+    pub smarthost_port: u16,
+    pub smarthost_starttls: bool,
+    pub smarthost_username: Option<String>,
+    pub smarthost_password: Option<String>,
+    pub send_dsn: bool,
+    /// `sender` or an address to tell about the feed of a new box
+    pub new_box_reply: Option<String>,
+    /// Names of the stages accepted messages go through, in order
+    pub pipeline: Vec<String>,
+    /// Milters (`host:port`) incoming mail is passed through, in order
+    pub milters: Vec<String>,
+    pub milter_timeout: u64,
+    /// Reverse proxies whose `X-Forwarded-For` and PROXY headers are believed
+    pub trusted_proxies: Vec<Cidr>,
+    pub proxy_protocol: bool,
+    /// Redirect requests proxies received over plain HTTP to HTTPS, as told by
+    /// `X-Forwarded-Proto`
+    pub https_redirect: bool,
+    pub security_headers: bool,
+    /// Seconds of `Strict-Transport-Security`, 0 to disable
+    pub hsts_max_age: u64,
+    pub referrer_policy: String,
+    pub frame_options: String,
+    /// `Content-Security-Policy` of rendered mail
+    pub content_csp: String,
+    /// `Content-Security-Policy` of the app's own pages
+    pub app_csp: String,
+    /// Directory of a frontend build served instead of the embedded one
+    pub static_dir: Option<String>,
+    /// Directory of templates overriding the embedded ones
+    pub template_dir: Option<String>,
+    /// `robots.txt` read from `ROBOTS_FILE`, instead of the default one
+    pub robots_txt: Option<String>,
+    /// Icon served as `/favicon.ico` instead of the embedded one
+    pub favicon_file: Option<String>,
+    /// URL prefix remote images in rendered mail are loaded through
+    pub image_proxy: Option<String>,
+    pub render_mode: RenderMode,
+    /// Directory of the tantivy index, searching with regexes if not set
+    pub search_index_dir: Option<String>,
+    /// Base URL of an OpenAI-compatible API new items are summarized with
+    pub summary_api_url: Option<String>,
+    pub summary_api_key: Option<String>,
+    pub summary_model: String,
+    pub translate_backend: Option<Backend>,
+    /// Endpoint of the backend, required for LibreTranslate
+    pub translate_api_url: Option<String>,
+    pub translate_api_key: Option<String>,
+    /// Language new items are translated into
+    pub translate_target: Option<String>,
+    /// Languages items may be translated into on demand, `translate_target`
+    /// if not set
+    pub translate_languages: Vec<String>,
+    /// URL ingestion failures are posted to as JSON
+    pub failure_webhook: Option<String>,
+    /// Rejections within `reject_alert_window` minutes reported as an event, 0
+    /// to disable
+    pub reject_alert_threshold: usize,
+    pub reject_alert_window: u64,
+    /// Requests per minute a client may make to `/rss` and `/atom` feeds, and
+    /// to other routes, 0 for no limit
+    pub rate_limit_feeds: u32,
+    pub rate_limit_api: u32,
+    /// Items a client may have translated on demand per minute, 0 for no limit
+    pub rate_limit_translate: u32,
+    /// Requests refused for their credentials per minute from an address
+    /// before it is answered 429, 0 for no limit
+    pub rate_limit_auth: u32,
+    /// Rendered feeds kept until items change, 0 to disable, see `feedcache`
+    pub feed_cache_size: usize,
+    /// Seconds a web request may take before 504, 0 to disable
+    pub request_timeout: u64,
+    /// `(path prefix, seconds)` overriding `request_timeout`
+    pub route_timeouts: Vec<(String, u64)>,
+    /// Characters of new item ids
+    pub id_length: usize,
+    /// Characters new item ids are made of
+    pub id_alphabet: Vec<char>,
+    /// S3-compatible storage received messages are copied to, e.g.
+    /// `https://s3.us-east-1.amazonaws.com`
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    /// Prepended to object keys, e.g. `archive/`
+    pub s3_prefix: String,
+    /// WebSub hub feeds advertise and ping as items are published
+    pub websub_hub: Option<String>,
+    /// Title of the feeds of all boxes, see `channel`
+    pub channel_title: String,
+    pub channel_description: Option<String>,
+    pub channel_language: Option<String>,
+    /// URL of the image of the feeds of all boxes
+    pub channel_image: Option<String>,
+    /// Minutes readers may cache feeds, unless set for the box
+    pub channel_ttl: Option<u32>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
-        let rules = match var("RULE_FILE") {
-            Ok(path) => match fs::read_to_string(path) {
-                Ok(text) => match from_str::<Vec<Rule>>(&text) {
-                    Ok(rules) => rules,
-                    Err(e) => {
-                        warn!("Error parsing rules: {}", e);
-                        vec![]
-                    }
-                },
-                Err(e) => {
-                    warn!("Error parsing rules: {}", e);
-                    vec![]
-                }
-            },
-            Err(_e) => vec![],
-        };
+        let rules: Vec<Rule> = read_json_file("RULE_FILE", "rules");
+        let boxes: BoxConfigs = read_json_file("BOX_FILE", "box configs");
+        let sieve = read_sieve_file();
+        let image_proxy = var("IMAGE_PROXY").ok().filter(|x| !x.is_empty());
         let domain = var("DOMAIN").unwrap_or_else(|_| "example.com".to_owned());
         let ret = Self {
             web_port: var("WEB_PORT").map_or_else(|_| Ok(8080), |x| x.parse())?,
+            web_bind: var("WEB_BIND")
+                .map_or_else(|_| Ok(Ipv4Addr::UNSPECIFIED.into()), |x| x.parse())?,
+            web_socket: var("WEB_SOCKET").ok().filter(|x| !x.is_empty()),
+            tls_cert: var("TLS_CERT").ok().filter(|x| !x.is_empty()),
+            tls_key: var("TLS_KEY").ok().filter(|x| !x.is_empty()),
             smtp_port: var("SMTP_PORT").map_or_else(|_| Ok(10000), |x| x.parse())?,
             per_page: var("PER_PAGE").map_or_else(|_| Ok(10), |x| x.parse())?,
+            max_per_page: var("MAX_PER_PAGE").map_or_else(|_| Ok(100), |x| x.parse())?,
             domain: domain.clone(),
             mongo_con_str: var("MONGO_CON_STR")
                 .unwrap_or_else(|_| "mongodb://localhost:27017".to_owned()),
@@ -61,6 +188,8 @@ impl Config {
             }),
             username: var("AUTH_USERNAME").ok(),
             password: var("AUTH_PASSWORD").ok(),
+            reader_username: var("READER_USERNAME").ok(),
+            reader_password: var("READER_PASSWORD").ok(),
             disable_rcpt_filter: rules
                 .iter()
                 .filter(|rule| {
@@ -71,21 +200,264 @@ impl Config {
                         .is_some()
                 })
                 .next()
-                .is_some(),
+                .is_some()
+                // A script may file mail to any recipient, so it has to see all of it
+                || sieve.is_some(),
             rules,
+            sieve,
+            boxes,
             default_page_limit: var("DEFAULT_PAGE_LIMIT").map_or_else(|_| Ok(30), |x| x.parse())?,
+            obfuscate_emails: var("OBFUSCATE_EMAILS").map_or_else(|_| Ok(false), |x| x.parse())?,
+            max_hops: var("MAX_HOPS").map_or_else(|_| Ok(30), |x| x.parse())?,
+            max_content_size: var("MAX_CONTENT_SIZE")
+                .map_or_else(|_| Ok(1024 * 1024), |x| x.parse())?,
+            auto_submitted: var("AUTO_SUBMITTED")
+                .map_or_else(|_| Ok(AutoSubmittedAction::default()), |x| x.parse())?,
+            smarthost: var("SMARTHOST").ok(),
+            smarthost_port: var("SMARTHOST_PORT").map_or_else(|_| Ok(25), |x| x.parse())?,
+            smarthost_starttls: var("SMARTHOST_STARTTLS")
+                .map_or_else(|_| Ok(false), |x| x.parse())?,
+            smarthost_username: var("SMARTHOST_USERNAME").ok(),
+            smarthost_password: var("SMARTHOST_PASSWORD").ok(),
+            send_dsn: var("SEND_DSN").map_or_else(|_| Ok(false), |x| x.parse())?,
+            new_box_reply: var("NEW_BOX_REPLY").ok().filter(|x| !x.is_empty()),
+            pipeline: var("PIPELINE")
+                .map(|x| {
+                    x.split(',')
+                        .map(str::trim)
+                        .filter(|x| !x.is_empty())
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_else(|_| pipeline::DEFAULT.iter().map(|x| x.to_string()).collect()),
+            milters: var("MILTERS")
+                .map(|x| {
+                    x.split(',')
+                        .map(str::trim)
+                        .filter(|x| !x.is_empty())
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            milter_timeout: var("MILTER_TIMEOUT").map_or_else(|_| Ok(10), |x| x.parse())?,
+            request_timeout: var("REQUEST_TIMEOUT").map_or_else(|_| Ok(30), |x| x.parse())?,
+            route_timeouts: var("ROUTE_TIMEOUTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(|x| match x.split_once('=') {
+                    Some((prefix, secs)) => Ok((prefix.trim().to_owned(), secs.trim().parse()?)),
+                    None => bail!("Bad route timeout {}, expecting e.g. /rss=10", x),
+                })
+                .collect::<Result<_>>()?,
+            trusted_proxies: var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(|x| x.parse())
+                .collect::<Result<_>>()?,
+            proxy_protocol: var("PROXY_PROTOCOL").map_or_else(|_| Ok(false), |x| x.parse())?,
+            https_redirect: var("HTTPS_REDIRECT").map_or_else(|_| Ok(true), |x| x.parse())?,
+            security_headers: var("SECURITY_HEADERS").map_or_else(|_| Ok(true), |x| x.parse())?,
+            hsts_max_age: var("HSTS_MAX_AGE").map_or_else(|_| Ok(31536000), |x| x.parse())?,
+            referrer_policy: var("REFERRER_POLICY").unwrap_or_else(|_| "no-referrer".to_owned()),
+            frame_options: var("FRAME_OPTIONS").unwrap_or_else(|_| "DENY".to_owned()),
+            content_csp: var("CONTENT_CSP")
+                .unwrap_or_else(|_| default_content_csp(image_proxy.as_deref())),
+            app_csp: var("APP_CSP").unwrap_or_else(|_| DEFAULT_APP_CSP.to_owned()),
+            static_dir: var("STATIC_DIR").ok().filter(|x| !x.is_empty()),
+            template_dir: var("TEMPLATE_DIR").ok().filter(|x| !x.is_empty()),
+            robots_txt: var("ROBOTS_FILE")
+                .ok()
+                .map(|x| fs::read_to_string(&x).with_context(|| format!("Cannot read {}", x)))
+                .transpose()?,
+            favicon_file: var("FAVICON_FILE").ok().filter(|x| !x.is_empty()),
+            image_proxy,
+            search_index_dir: var("SEARCH_INDEX_DIR").ok(),
+            summary_api_url: var("SUMMARY_API_URL").ok().filter(|x| !x.is_empty()),
+            summary_api_key: var("SUMMARY_API_KEY").ok(),
+            summary_model: var("SUMMARY_MODEL").unwrap_or_else(|_| "gpt-4o-mini".to_owned()),
+            translate_backend: var("TRANSLATE_BACKEND")
+                .ok()
+                .map(|x| x.parse())
+                .transpose()?,
+            translate_api_url: var("TRANSLATE_API_URL").ok().filter(|x| !x.is_empty()),
+            translate_api_key: var("TRANSLATE_API_KEY").ok(),
+            translate_target: var("TRANSLATE_TARGET").ok().filter(|x| !x.is_empty()),
+            translate_languages: match var("TRANSLATE_LANGUAGES") {
+                Ok(x) => x
+                    .split(',')
+                    .map(|x| x.trim().to_lowercase())
+                    .filter(|x| !x.is_empty())
+                    .collect(),
+                Err(_) => var("TRANSLATE_TARGET")
+                    .ok()
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_lowercase())
+                    .into_iter()
+                    .collect(),
+            },
+            failure_webhook: var("FAILURE_WEBHOOK").ok().filter(|x| !x.is_empty()),
+            reject_alert_threshold: var("REJECT_ALERT_THRESHOLD")
+                .map_or_else(|_| Ok(20), |x| x.parse())?,
+            reject_alert_window: var("REJECT_ALERT_WINDOW")
+                .map_or_else(|_| Ok(60), |x| x.parse())?,
+            rate_limit_feeds: var("RATE_LIMIT_FEEDS").map_or_else(|_| Ok(0), |x| x.parse())?,
+            rate_limit_api: var("RATE_LIMIT_API").map_or_else(|_| Ok(0), |x| x.parse())?,
+            rate_limit_translate: var("RATE_LIMIT_TRANSLATE")
+                .map_or_else(|_| Ok(10), |x| x.parse())?,
+            rate_limit_auth: var("RATE_LIMIT_AUTH").map_or_else(|_| Ok(10), |x| x.parse())?,
+            feed_cache_size: var("FEED_CACHE_SIZE").map_or_else(|_| Ok(256), |x| x.parse())?,
+            render_mode: var("RENDER_MODE")
+                .map_or_else(|_| Ok(RenderMode::Direct), |x| x.parse())?,
+            collapse_window_hours: var("COLLAPSE_WINDOW_HOURS")
+                .ok()
+                .map(|x| x.parse())
+                .transpose()?,
+            id_length: var("ID_LENGTH").map_or_else(|_| Ok(10), |x| x.parse())?,
+            id_alphabet: var("ID_ALPHABET")
+                .map_or_else(|_| nanoid::alphabet::SAFE.to_vec(), |x| x.chars().collect()),
+            s3_endpoint: var("S3_ENDPOINT").ok().filter(|x| !x.is_empty()),
+            s3_bucket: var("S3_BUCKET").ok().filter(|x| !x.is_empty()),
+            s3_region: var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+            s3_access_key: var("S3_ACCESS_KEY").ok(),
+            s3_secret_key: var("S3_SECRET_KEY").ok(),
+            s3_prefix: var("S3_PREFIX").unwrap_or_default(),
+            websub_hub: var("WEBSUB_HUB").ok().filter(|x| !x.is_empty()),
+            channel_title: var("CHANNEL_TITLE").unwrap_or_else(|_| "Mail List".to_owned()),
+            channel_description: var("CHANNEL_DESCRIPTION").ok().filter(|x| !x.is_empty()),
+            channel_language: var("CHANNEL_LANGUAGE").ok().filter(|x| !x.is_empty()),
+            channel_image: var("CHANNEL_IMAGE").ok().filter(|x| !x.is_empty()),
+            channel_ttl: var("CHANNEL_TTL").ok().map(|x| x.parse()).transpose()?,
         };
 
+        if ret.tls_cert.is_some() != ret.tls_key.is_some() {
+            bail!("TLS_CERT and TLS_KEY should be set together");
+        }
+        if ret.https_redirect && ret.web_domain.parse::<Authority>().is_err() {
+            bail!(
+                "Domain {} cannot be redirected to, fix DOMAIN or set HTTPS_REDIRECT=false",
+                ret.web_domain
+            );
+        }
+        if ret.id_length < 6 {
+            bail!("ID_LENGTH should be at least 6");
+        }
+        let mut alphabet = ret.id_alphabet.clone();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.len() < 16 || alphabet.len() != ret.id_alphabet.len() {
+            bail!("ID_ALPHABET should have at least 16 distinct characters, each once");
+        }
+        if !ret
+            .id_alphabet
+            .iter()
+            .all(|x| x.is_ascii_alphanumeric() || "-_.~".contains(*x))
+        {
+            bail!("ID_ALPHABET should only have letters, digits and -_.~");
+        }
+
         if ret.username.is_some() ^ ret.password.is_some() {
             // Only one exist and the other is not set
             panic!("Both username and password should be set or not set");
         }
+        if ret.reader_username.is_some() != ret.reader_password.is_some() {
+            bail!("READER_USERNAME and READER_PASSWORD should be set together");
+        }
+        if ret.reader_username.is_some() && ret.username.is_none() {
+            bail!("READER_USERNAME needs AUTH_USERNAME, as everything is open without it");
+        }
 
         Ok(ret)
     }
+
+    /// Timeout of requests to `path`, from the longest matching prefix in
+    /// `ROUTE_TIMEOUTS`, or else `REQUEST_TIMEOUT`
+    pub fn timeout_for(&self, path: &str) -> u64 {
+        self.route_timeouts
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.request_timeout, |(_, secs)| *secs)
+    }
+
+    #[inline]
+    pub fn box_config(&self, name: &str) -> Option<&BoxConfig> {
+        self.boxes.get(name)
+    }
+}
+
+/// The front end is a single file with inlined scripts and styles
+const DEFAULT_APP_CSP: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; \
+    style-src 'self' 'unsafe-inline'; img-src 'self' data:; frame-ancestors 'none'";
+
+/// No scripts, forms or plugins in mail. Images are only allowed from the image
+/// proxy when there is one.
+fn default_content_csp(image_proxy: Option<&str>) -> String {
+    let img_src = match image_proxy {
+        Some(proxy) => {
+            let origin_end = proxy
+                .find("://")
+                .and_then(|x| proxy[x + 3..].find('/').map(|y| x + 3 + y))
+                .unwrap_or(proxy.len());
+            format!("{} data: cid:", &proxy[..origin_end])
+        }
+        None => "https: http: data: cid:".to_owned(),
+    };
+    format!(
+        "default-src 'none'; img-src {}; style-src 'unsafe-inline'; font-src https: data:; \
+        form-action 'none'; base-uri 'none'; frame-ancestors 'self'",
+        img_src
+    )
+}
+
+/// Read and parse the JSON file pointed by env `key`, falling back to default
+fn read_json_file<T: DeserializeOwned + Default>(key: &str, what: &str) -> T {
+    match var(key) {
+        Ok(path) => match fs::read_to_string(path) {
+            Ok(text) => match from_str::<T>(&text) {
+                Ok(ret) => ret,
+                Err(e) => {
+                    warn!("Error parsing {}: {}", what, e);
+                    T::default()
+                }
+            },
+            Err(e) => {
+                warn!("Error parsing {}: {}", what, e);
+                T::default()
+            }
+        },
+        Err(_e) => T::default(),
+    }
+}
+
+/// Read and parse the Sieve script pointed by env `SIEVE_FILE`
+fn read_sieve_file() -> Option<Script> {
+    let path = var("SIEVE_FILE").ok()?;
+    match fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|text| Script::parse(&text))
+    {
+        Ok(ret) => Some(ret),
+        Err(e) => {
+            warn!("Error parsing sieve script: {}", e);
+            None
+        }
+    }
 }
 
 #[inline]
 pub fn get_config<'a>() -> &'a Config {
-    &CONFIG
+    CONFIG.get_or_init(|| Config::from_env().unwrap())
+}
+
+/// Use `config` rather than one read from the environment, which only works
+/// before anything asked for the configuration
+#[cfg(all(test, feature = "test-support"))]
+pub fn install(config: Config) -> Result<()> {
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("configuration already read"))
 }
@@ -1,12 +1,35 @@
-use anyhow::{bail, Result};
-use chrono::{serde::ts_milliseconds, DateTime, Utc};
-use mail_parser::{HeaderValue, Message};
-use mongodb::Collection;
-use rss::{GuidBuilder, Item, ItemBuilder};
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{
+    serde::{ts_milliseconds, ts_milliseconds_option},
+    DateTime, Duration, TimeZone, Utc,
+};
+use futures::TryStreamExt;
+use mail_parser::{BodyPart, HeaderValue, Message};
+use mongodb::{
+    bson::{doc, to_bson, Bson, Document},
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use ring::digest;
+use rss::{CategoryBuilder, EnclosureBuilder, GuidBuilder, Item, ItemBuilder};
 use serde::{Deserialize, Serialize};
-use tracing::{info, info_span, warn, Instrument};
+use tracing::info;
 
-use crate::{config::get_config, RX};
+use crate::{
+    boxes::{resolve_address, LinkTarget},
+    config::get_config,
+    headers::parse_headers,
+    metrics,
+    pipeline::Pipeline,
+    text::{
+        derive_title, escape_regex, normalize_subject, normalize_tags, obfuscate_emails,
+        percent_encode, slugify, strip_html, web_version_link,
+    },
+    validator, RX,
+};
 
 pub type Feeds = Collection<Feed>;
 
@@ -17,9 +40,117 @@ pub struct Feed {
     pub created_at: DateTime<Utc>,
     pub title: String,
     pub author: String,
+    /// Left out by `store::meta_only`
+    #[serde(default)]
     pub content: String,
+    /// Plain text of the message, empty for items stored before it was, or
+    /// over `MAX_CONTENT_SIZE`
+    #[serde(default)]
+    pub text: String,
+    /// Left out by most reads, see `store`
+    #[serde(default)]
     pub raw: String,
     pub from_box: String,
+    /// Normalized title used to collapse repetitions, see `COLLAPSE_WINDOW_HOURS`
+    #[serde(default)]
+    pub subject_key: String,
+    /// Number of collapsed repetitions, including the first one
+    #[serde(default = "default_occurrences")]
+    pub occurrences: u32,
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub last_seen_at: Option<DateTime<Utc>>,
+    /// Kind of automatic message, e.g. `auto-replied` or `bulk`, when tagged
+    #[serde(default)]
+    pub auto_submitted: Option<String>,
+    /// Tag of plus-addressed recipient, e.g. `rust` for `news+rust@example.com`
+    #[serde(default)]
+    pub address_tag: Option<String>,
+    /// Short summary by the model at `SUMMARY_API_URL`, when enabled
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Machine translations by lowercase language code, see `TRANSLATE_BACKEND`
+    #[serde(default)]
+    pub translations: HashMap<String, Translation>,
+    /// Tags assigned by users, see `PUT /feeds/:key/tags`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Headers of the message in order, for `/search/headers`
+    #[serde(default)]
+    pub headers: Vec<StoredHeader>,
+    /// When the item shows up in feeds, see `publish_delay` of boxes
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub publish_at: Option<DateTime<Utc>>,
+    /// Waiting for approval in a moderated box
+    #[serde(default)]
+    pub pending: bool,
+    /// `content` is a preview, the full one being stored in chunks, see
+    /// `MAX_CONTENT_SIZE`
+    #[serde(default)]
+    pub overflow: bool,
+    /// `raw` is stored in chunks rather than in the item, as for every item
+    /// since raw sources were kept apart
+    #[serde(default)]
+    pub raw_chunked: bool,
+    /// SMTP envelope the message came with, for items received since it was
+    /// kept
+    #[serde(default)]
+    pub envelope: Option<SmtpEnvelope>,
+    /// Attachments of the message, served at `/feeds/:key/attachments/:n`,
+    /// for items received since they were kept
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Slug of the title in the permalink, see `Feed::permalink`, empty for
+    /// items stored before slugs
+    #[serde(default)]
+    pub slug: String,
+    /// GUID of the item in feeds, from the `Message-ID` of the message, empty
+    /// for items stored before GUIDs or messages without one
+    #[serde(default)]
+    pub guid: String,
+}
+
+/// What the sending server said outside of the message, see
+/// `/admin/feeds/:key`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SmtpEnvelope {
+    /// `MAIL FROM` address, empty for bounces
+    pub mail_from: String,
+    /// `RCPT TO` addresses, all of them and as given
+    pub rcpt_to: Vec<String>,
+    pub client_ip: Option<String>,
+    /// Name given in `HELO` or `EHLO`
+    pub helo: String,
+    /// Whether the session was encrypted
+    pub tls: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredHeader {
+    /// Lowercase, so that lookups are case-insensitive
+    pub name: String,
+    pub value: String,
+}
+
+impl StoredHeader {
+    pub fn parse_all(raw: &str) -> Vec<Self> {
+        parse_headers(raw)
+            .into_iter()
+            .map(|(name, value)| Self {
+                name: name.to_ascii_lowercase(),
+                value,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Translation {
+    pub title: String,
+    pub content: String,
+}
+
+fn default_occurrences() -> u32 {
+    1
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -28,24 +159,147 @@ pub struct Index {
 }
 
 impl Feed {
-    pub fn into_rss(self) -> Item {
+    /// Whether the item shows up publicly, as matched by `published`
+    pub fn is_published(&self) -> bool {
+        !self.pending && self.publish_at.map_or(true, |x| x <= Utc::now())
+    }
+
+    /// Strip what should not be shown publicly, according to config
+    pub fn redacted(self) -> Self {
+        self.redact(get_config().obfuscate_emails)
+    }
+
+    /// Hide email addresses if `obfuscate`, see `OBFUSCATE_EMAILS`
+    pub fn redact(mut self, obfuscate: bool) -> Self {
+        if obfuscate {
+            self.author = obfuscate_emails(&self.author);
+            self.content = obfuscate_emails(&self.content);
+            self.summary = self.summary.map(|x| obfuscate_emails(&x));
+        }
+        self
+    }
+
+    /// Use the translation into `lang` as title and content, if there is one
+    pub fn translated(mut self, lang: Option<&str>) -> Self {
+        if let Some(x) = lang.and_then(|x| self.translations.remove(&x.to_lowercase())) {
+            self.title = x.title;
+            self.content = x.content;
+        }
+        self
+    }
+
+    /// Title with the number of collapsed repetitions, if any
+    pub fn display_title(&self) -> String {
+        match self.occurrences {
+            0 | 1 => self.title.clone(),
+            n => format!("{} (×{})", self.title, n),
+        }
+    }
+
+    /// Plain text of the message, from the raw source if not stored
+    pub fn plain_text(&self) -> String {
+        if !self.text.is_empty() {
+            return self.text.clone();
+        }
+        Message::parse(self.raw.as_bytes())
+            .map(|x| body_text(&x))
+            .unwrap_or_default()
+    }
+
+    /// Web version linked from the body, or else the list archive
+    pub fn original_url(&self) -> Option<String> {
+        web_version_link(&self.content).or_else(|| {
+            self.headers
+                .iter()
+                .filter(|x| x.name == "list-archive")
+                .flat_map(|x| x.value.split(','))
+                .map(|x| x.trim().trim_start_matches('<').trim_end_matches('>'))
+                .find(|x| x.starts_with("http://") || x.starts_with("https://"))
+                .map(ToOwned::to_owned)
+        })
+    }
+
+    /// Slug of the permalink, made from the title for items stored before
+    /// slugs
+    pub fn slug(&self) -> String {
+        match self.slug.is_empty() {
+            true => item_slug(&self.title),
+            false => self.slug.clone(),
+        }
+    }
+
+    /// GUID of the item in feeds, the item id for items without a stored one,
+    /// see `message_guid`
+    pub fn guid(&self) -> &str {
+        match self.guid.is_empty() {
+            true => &self.id,
+            false => &self.guid,
+        }
+    }
+
+    /// Path of the item page, e.g. `/feeds/abc/rust-1-58-is-out`, or just
+    /// `/feeds/abc` for titles without letters or digits
+    pub fn permalink(&self) -> String {
+        let id = percent_encode(&self.id);
+        match self.slug() {
+            x if x.is_empty() => format!("/feeds/{}", id),
+            x => format!("/feeds/{}/{}", id, percent_encode(&x)),
+        }
+    }
+
+    /// Link of the item in feeds, see `link` of boxes
+    pub fn link(&self) -> String {
         let config = get_config();
+        let archive = format!("https://{}{}", config.web_domain, self.permalink());
+        match config.box_config(&self.from_box).map(|x| x.link) {
+            Some(LinkTarget::Original) => self.original_url().unwrap_or(archive),
+            _ => archive,
+        }
+    }
+
+    /// URL of attachment `n`, as an enclosure
+    pub fn attachment_url(&self, n: usize) -> String {
+        format!(
+            "https://{}/feeds/{}/attachments/{}",
+            get_config().web_domain,
+            self.id,
+            n
+        )
+    }
+
+    pub fn into_rss(self) -> Item {
+        let link = self.link();
+        let feed = self.redacted();
+        // RSS allows a single enclosure per item
+        let enclosure = feed.attachments.first().map(|x| {
+            EnclosureBuilder::default()
+                .url(feed.attachment_url(0))
+                .length(x.size.to_string())
+                .mime_type(x.mime_type())
+                .build()
+        });
 
+        // Items without a stored GUID keep the one they were first served with
         let guid = GuidBuilder::default()
-            .permalink(true)
-            .value(format!("{}", self.id))
+            .permalink(feed.guid.is_empty())
+            .value(feed.guid().to_owned())
             .build();
 
         ItemBuilder::default()
-            .title(self.title)
-            .link(Some(format!(
-                "https://{}/feeds/{}",
-                config.web_domain, self.id
-            )))
-            .author(Some(self.author))
-            .pub_date(Some(self.created_at.to_rfc2822()))
+            .title(feed.display_title())
+            .link(Some(link))
+            .author(Some(feed.author))
+            .pub_date(Some(feed.created_at.to_rfc2822()))
             .guid(Some(guid))
-            .content(Some(self.content))
+            .enclosure(enclosure)
+            .categories(
+                feed.tags
+                    .into_iter()
+                    .map(|x| CategoryBuilder::default().name(x).build())
+                    .collect::<Vec<_>>(),
+            )
+            .description(feed.summary)
+            .content(Some(feed.content))
             .build()
     }
 
@@ -112,14 +366,77 @@ impl<'a> ToVec for HeaderValue<'a> {
     }
 }
 
-impl<'a> TryFrom<(&'a Vec<u8>, Message<'a>)> for Feed {
+/// Plain text of the message, from text bodies or stripped HTML ones
+pub fn body_text(val: &Message) -> String {
+    let text = val
+        .get_text_bodies()
+        .flat_map(|x| x.get_contents().to_vec())
+        .collect::<Vec<_>>();
+    if !text.is_empty() {
+        return String::from_utf8_lossy(&text).into_owned();
+    }
+    let html = val
+        .get_html_bodies()
+        .flat_map(|x| x.get_contents().to_vec())
+        .collect::<Vec<_>>();
+    strip_html(&String::from_utf8_lossy(&html))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub name: Option<String>,
+    pub content_type: Option<String>,
+    pub size: usize,
+}
+
+impl Attachment {
+    pub fn mime_type(&self) -> &str {
+        self.content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream")
+    }
+}
+
+/// Attachments of the message, without their contents
+pub fn attachments(val: &Message) -> Vec<Attachment> {
+    val.get_attachments()
+        .map(|x| Attachment {
+            name: x.get_attachment_name().map(ToOwned::to_owned),
+            content_type: x.get_content_type().map(|x| match x.get_subtype() {
+                Some(subtype) => format!("{}/{}", x.get_type(), subtype),
+                None => x.get_type().to_owned(),
+            }),
+            size: x.len(),
+        })
+        .collect()
+}
+
+/// Attachment `n` of the message, with its contents
+pub fn attachment(val: &Message, n: usize) -> Option<(Attachment, Vec<u8>)> {
+    let contents = val.get_attachments().nth(n)?.get_contents().to_vec();
+    Some((attachments(val).into_iter().nth(n)?, contents))
+}
+
+impl<'a> TryFrom<(&'a [u8], Message<'a>)> for Feed {
     type Error = anyhow::Error;
-    fn try_from((raw, val): (&'a Vec<u8>, Message<'a>)) -> Result<Self> {
+    fn try_from((raw, val): (&'a [u8], Message<'a>)) -> Result<Self> {
         let config = get_config();
-        let from_box = match get_box(&val) {
+        let (from_box, address_tag) = match get_box(&val) {
             Some(x) => x,
             None => bail!("Not sending to {}, blocked", config.domain),
         };
+        Feed::from_message(raw, val, from_box, address_tag)
+    }
+}
+
+impl Feed {
+    /// Build a feed of the message in a known box
+    pub fn from_message(
+        raw: &[u8],
+        val: Message,
+        from_box: String,
+        address_tag: Option<String>,
+    ) -> Result<Self> {
         let author = match val.get_from() {
             HeaderValue::Address(addr) => match (addr.address.as_ref(), addr.name.as_ref()) {
                 (Some(addr), Some(name)) => format!("{} ({})", addr, name),
@@ -129,39 +446,262 @@ impl<'a> TryFrom<(&'a Vec<u8>, Message<'a>)> for Feed {
             },
             _ => "Unknown".to_owned(),
         };
-        let title = val.get_subject().unwrap_or("Unknown Title").to_owned();
         let created_at = Utc::now();
-        let content = val
-            .get_html_bodies()
-            .flat_map(|x| x.get_contents().to_vec())
-            .collect::<Vec<_>>();
+        let box_config = get_config().box_config(&from_box);
+        let publish_at = box_config
+            .and_then(|x| x.publish_delay)
+            .map(|x| created_at + Duration::minutes(x as i64));
+        let pending = box_config.map_or(false, |x| x.moderated);
+        let content = String::from_utf8(
+            val.get_html_bodies()
+                .flat_map(|x| x.get_contents().to_vec())
+                .collect::<Vec<_>>(),
+        )?;
+        let text = body_text(&val);
+        let tags = rule_tags(&val);
+        let attachments = attachments(&val);
+        let title = match val.get_subject().map(str::trim) {
+            Some(subject) if !subject.is_empty() => subject.to_owned(),
+            _ => derive_title(&content, &text).unwrap_or_else(|| "Unknown Title".to_owned()),
+        };
+        let raw = String::from_utf8(raw.to_owned())?;
         Ok(Feed {
-            raw: String::from_utf8(raw.to_owned())?,
-            content: String::from_utf8(content)?,
+            headers: StoredHeader::parse_all(&raw),
+            raw,
+            content,
+            text,
             created_at,
+            subject_key: normalize_subject(&title),
+            occurrences: 1,
+            last_seen_at: None,
+            auto_submitted: None,
+            address_tag,
+            summary: None,
+            translations: HashMap::new(),
+            tags,
+            publish_at,
+            pending,
+            overflow: false,
+            raw_chunked: false,
+            envelope: None,
+            attachments,
+            slug: item_slug(&title),
+            guid: val
+                .get_message_id()
+                .and_then(message_guid)
+                .unwrap_or_default(),
             title,
             author,
             from_box,
-            id: nanoid::nanoid!(10),
+            id: new_id(),
         })
     }
 }
 
-pub async fn database_servo(collection: Feeds, rx: RX) {
+pub async fn database_servo(pipeline: Pipeline, rx: RX) {
     info!(target: "Database", "Starting");
 
     while let Ok(feed) = rx.recv().await {
-        let span = info_span!("Database.insert");
+        let _done = metrics::Dequeue(feed.id.clone());
         feed.trace();
-        if let Err(e) = collection.insert_one(feed, None).instrument(span).await {
-            warn!(target: "Database", "Error insert doc: {}", e)
-        }
+        pipeline.process(feed).await;
     }
 
     info!(target: "Database", "Stopping");
 }
 
-fn get_box(val: &Message) -> Option<String> {
+/// Random item id, see `ID_LENGTH` and `ID_ALPHABET`
+pub fn new_id() -> String {
+    let config = get_config();
+    nanoid::nanoid!(config.id_length, &config.id_alphabet)
+}
+
+/// Longest `Message-ID` used as a GUID as is
+const MAX_GUID_LEN: usize = 200;
+
+/// GUID of a message with `Message-ID` `message_id`, so that items keep theirs
+/// if the archive is imported again. Unusual IDs are hashed with SHA-256.
+pub fn message_guid(message_id: &str) -> Option<String> {
+    let id = message_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    if id.is_empty() {
+        return None;
+    }
+    let usable = id.len() <= MAX_GUID_LEN
+        && id
+            .bytes()
+            .all(|x| x.is_ascii_graphic() && x != b'<' && x != b'>');
+    Some(match usable {
+        true => id.to_owned(),
+        false => hex::encode(digest::digest(&digest::SHA256, id.as_bytes())),
+    })
+}
+
+/// Routes under `/feeds/:key` that slugs must not take
+const RESERVED_SLUGS: &[&str] = &[
+    "attachments",
+    "eml",
+    "full",
+    "json",
+    "pdf",
+    "raw",
+    "related",
+    "tags",
+];
+
+/// Slug of an item titled `title`, see `Feed::permalink`
+pub fn item_slug(title: &str) -> String {
+    let slug = slugify(title);
+    match RESERVED_SLUGS.contains(&slug.as_str()) {
+        true => format!("{}-item", slug),
+        false => slug,
+    }
+}
+
+/// Whether `e` is a write rejected by a unique index, e.g. of an id taken
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(&*e.kind, ErrorKind::Write(WriteFailure::WriteError(x)) if x.code == 11000)
+}
+
+/// Give `feed` a new id while its own is taken
+pub async fn claim_id(collection: &Feeds, feed: &mut Feed) -> Result<()> {
+    while collection
+        .count_documents(doc! { "id": &feed.id }, None)
+        .await?
+        > 0
+    {
+        feed.id = new_id();
+    }
+    Ok(())
+}
+
+/// Create indexes the queries rely on, if missing
+pub async fn ensure_indexes(collection: &Feeds) -> Result<()> {
+    collection
+        .create_index(IndexModel::builder().keys(doc! { "tags": 1 }).build(), None)
+        .await?;
+    collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "headers.name": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+    // For listings in each `Sort`, see `Cursor`
+    for key in ["created_at", "title", "author"] {
+        collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { key: -1, "id": -1 })
+                    .build(),
+                None,
+            )
+            .await?;
+    }
+    // For `/search` without `SEARCH_INDEX_DIR`
+    collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "title": "text", "author": "text", "content": "text" })
+                .options(
+                    IndexOptions::builder()
+                        .weights(doc! { "title": 10, "author": 5, "content": 1 })
+                        // Mail comes in any language, so words are not stemmed
+                        .default_language("none".to_owned())
+                        .build(),
+                )
+                .build(),
+            None,
+        )
+        .await?;
+    // Last, as it fails while duplicates are left, see `/admin/verify`
+    collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Have validators of feeds change when delayed items stored before start
+/// get published
+pub async fn schedule_delayed(collection: Feeds) -> Result<()> {
+    let filter = doc! {
+        "publish_at": { "$gt": Utc::now().timestamp_millis() },
+        "pending": { "$ne": true },
+    };
+    let mut cursor = collection.find(filter, None).await?;
+    while let Some(feed) = cursor.try_next().await? {
+        if let Some(at) = feed.publish_at {
+            validator::schedule(at);
+        }
+    }
+    Ok(())
+}
+
+/// Store parsed headers of items received before they were stored
+pub async fn backfill_headers(collection: Feeds) -> Result<()> {
+    let mut cursor = collection
+        .find(doc! { "headers": { "$exists": false } }, None)
+        .await?;
+    let mut count = 0;
+    while let Some(feed) = cursor.try_next().await? {
+        let headers = to_bson(&StoredHeader::parse_all(&feed.raw))?;
+        collection
+            .update_one(
+                doc! { "id": &feed.id },
+                doc! { "$set": { "headers": headers } },
+                None,
+            )
+            .await?;
+        count += 1;
+    }
+    if count > 0 {
+        info!(target: "Database", count, "Stored headers of existing items");
+    }
+    Ok(())
+}
+
+/// Count `feed` as a repetition of an item with the same subject received within
+/// `COLLAPSE_WINDOW_HOURS`. Returns whether such item exists.
+pub async fn collapse(collection: &Feeds, feed: &Feed) -> Result<bool> {
+    let window = match get_config().collapse_window_hours {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+    let since = feed.created_at - Duration::hours(window);
+    let filter = doc! {
+        "from_box": &feed.from_box,
+        "subject_key": &feed.subject_key,
+        "created_at": { "$gte": since.timestamp_millis() },
+    };
+    let update = doc! {
+        "$inc": { "occurrences": 1 },
+        "$set": { "last_seen_at": feed.created_at.timestamp_millis() },
+    };
+    match collection.find_one_and_update(filter, update, None).await? {
+        Some(existing) => {
+            info!(
+                target: "Database",
+                id = existing.id.as_str(),
+                occurrences = existing.occurrences + 1,
+                "Collapsed into existing feed"
+            );
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Box of the message, with the tag of plus-addressed recipient if any
+fn get_box(val: &Message) -> Option<(String, Option<String>)> {
     let config = get_config();
     let mut receivers = val.get_to().to_vec();
     receivers.sort();
@@ -173,33 +713,245 @@ fn get_box(val: &Message) -> Option<String> {
         .filter(|x| x.contains(&domain_suffix))
         .next();
     if ret.is_some() {
-        return Some(ret.unwrap().to_owned());
+        return Some(resolve_address(ret.unwrap()));
     }
 
     // Check the rules
     let rules = &config.rules;
     return rules
         .iter()
-        .filter(|rule| {
-            rule.filter
-                .iter()
-                .filter(|fl| fl.matches(&val))
-                .next()
-                .is_some()
-        })
-        .map(|x| x.to_box.to_owned())
+        .filter(|rule| rule.matches(val))
+        .map(|x| (x.to_box.to_owned(), None))
         .next();
 }
 
+/// Tags of the rules matching the message
+fn rule_tags(val: &Message) -> Vec<String> {
+    normalize_tags(
+        get_config()
+            .rules
+            .iter()
+            .filter(|x| x.matches(val))
+            .flat_map(|x| x.tags.clone())
+            .collect(),
+    )
+}
+
+/// Restrict `filter` to approved items past their `publish_at`
+pub fn published(mut filter: Document) -> Document {
+    filter.insert(
+        "publish_at",
+        doc! { "$not": { "$gt": Utc::now().timestamp_millis() } },
+    );
+    filter.insert("pending", doc! { "$ne": true });
+    filter
+}
+
+/// Parse a time given as RFC 3339 or as unix milliseconds
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(ms) = value.parse::<i64>() {
+        return Utc
+            .timestamp_millis_opt(ms)
+            .single()
+            .ok_or_else(|| anyhow!("Time out of range: {}", value));
+    }
+    Ok(DateTime::parse_from_rfc3339(value)
+        .map_err(|e| anyhow!("Bad time {}: {}", value, e))?
+        .with_timezone(&Utc))
+}
+
+/// Restrict `filter` to items created from `since` up to, not including,
+/// `until`, see `parse_time`
+pub fn created_between(
+    mut filter: Document,
+    since: Option<&str>,
+    until: Option<&str>,
+) -> Result<Document> {
+    let mut range = doc! {};
+    if let Some(since) = since {
+        range.insert("$gte", parse_time(since)?.timestamp_millis());
+    }
+    if let Some(until) = until {
+        range.insert("$lt", parse_time(until)?.timestamp_millis());
+    }
+    if !range.is_empty() {
+        filter.insert("created_at", range);
+    }
+    Ok(filter)
+}
+
+/// Filter matching feeds authored by `address`, see `author` in `Feed::try_from`
+pub fn sender_filter(address: &str) -> Document {
+    doc! {
+        "author": {
+            "$regex": format!("^{}( \\(|$)", escape_regex(address)),
+            "$options": "i",
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Summary {
     pub title: String,
     pub create_at: String,
     pub id: String,
+    /// Highlighted excerpt of matched content, only in search results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 #[derive(Deserialize, Serialize)]
 pub struct List {
     pub items: Vec<Summary>,
+    /// Where the items are among all matching ones, on `/feeds`
+    #[serde(flatten)]
+    pub page: Option<Pagination>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Pagination {
+    /// Number of matching items
+    pub total: u64,
+    pub limit: i64,
+    pub skip: u64,
+    /// Whether items are left past this page
+    pub has_more: bool,
+    /// `after` cursor of the next page, if items are left
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// Fields listings can be sorted by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    CreatedAt,
+    Title,
+    Author,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Order of listings, ties broken by id so that cursors are exact
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sort {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+impl Sort {
+    /// Sort by `field`, newest first or alphabetically unless `order` is given
+    pub fn new(field: Option<SortField>, order: Option<SortOrder>) -> Self {
+        let field = field.unwrap_or(SortField::CreatedAt);
+        let order = order.unwrap_or(match field {
+            SortField::CreatedAt => SortOrder::Desc,
+            SortField::Title | SortField::Author => SortOrder::Asc,
+        });
+        Self { field, order }
+    }
+
+    fn key(&self) -> &'static str {
+        match self.field {
+            SortField::CreatedAt => "created_at",
+            SortField::Title => "title",
+            SortField::Author => "author",
+        }
+    }
+
+    fn direction(&self) -> i32 {
+        match self.order {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        }
+    }
+
+    pub fn document(&self) -> Document {
+        doc! { self.key(): self.direction(), "id": self.direction() }
+    }
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum CursorValue {
+    Time(i64),
+    Text(String),
+}
+
+/// Position after an item in listings, given to clients as an opaque string.
+/// Unlike `skip`, it stays put as new items arrive and is found through the
+/// index however deep it is.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Cursor {
+    /// Field the listing is sorted by, which `value` is of
+    field: SortField,
+    value: CursorValue,
+    id: String,
+}
+
+impl Cursor {
+    pub fn of(feed: &Feed, sort: &Sort) -> Self {
+        let value = match sort.field {
+            SortField::CreatedAt => CursorValue::Time(feed.created_at.timestamp_millis()),
+            SortField::Title => CursorValue::Text(feed.title.clone()),
+            SortField::Author => CursorValue::Text(feed.author.clone()),
+        };
+        Self {
+            field: sort.field,
+            value,
+            id: feed.id.clone(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor serializes");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(value: &str) -> Result<Self> {
+        let decoded = base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| anyhow!("Bad cursor {}", value))?;
+        serde_json::from_slice(&decoded).map_err(|_| anyhow!("Bad cursor {}", value))
+    }
+
+    /// Restrict `filter` to items listed after this one in `sort`
+    pub fn filter(&self, filter: Document, sort: &Sort) -> Result<Document> {
+        if self.field != sort.field {
+            bail!("Cursor is of a listing sorted by another field");
+        }
+        let op = match sort.order {
+            SortOrder::Asc => "$gt",
+            SortOrder::Desc => "$lt",
+        };
+        let value = match &self.value {
+            CursorValue::Time(x) => Bson::Int64(*x),
+            CursorValue::Text(x) => Bson::String(x.clone()),
+        };
+        Ok(doc! {
+            "$and": [
+                filter,
+                {
+                    "$or": [
+                        { sort.key(): { op: value.clone() } },
+                        { sort.key(): value, "id": { op: &self.id } },
+                    ]
+                },
+            ]
+        })
+    }
 }
 
 #[test]
@@ -208,3 +960,61 @@ fn test() {
     let parsed = mail_parser::Message::parse(RAW.as_bytes()).unwrap();
     println!("{:#?}", parsed);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_guid() {
+        assert_eq!(
+            message_guid("<abc.123@example.com>").as_deref(),
+            Some("abc.123@example.com")
+        );
+        assert_eq!(message_guid(" <> "), None);
+        let hashed = message_guid("a b@example.com").unwrap();
+        assert_eq!(hashed.len(), 64);
+        assert_eq!(message_guid("<a b@example.com>"), Some(hashed));
+        assert_eq!(message_guid(&"a".repeat(300)).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_redact() {
+        let raw = b"From: alice@secret.test\r\nSubject: Hi\r\n\r\nHi\r\n";
+        let parsed = Message::parse(raw).unwrap();
+        let mut feed =
+            Feed::from_message(raw, parsed, "list@example.org".to_owned(), None).unwrap();
+        feed.summary = Some("Alice asks to write to bob@hidden.test".to_owned());
+        let feed = feed.redact(true);
+        assert_eq!(feed.author, "alice@secret…");
+        assert_eq!(
+            feed.summary.as_deref(),
+            Some("Alice asks to write to bob@hidden…")
+        );
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor {
+            field: SortField::CreatedAt,
+            value: CursorValue::Time(1646092800000),
+            id: "a:b_c".to_owned(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        let cursor = Cursor {
+            field: SortField::Title,
+            value: CursorValue::Text("1646092800000".to_owned()),
+            id: "x".to_owned(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode(&base64::encode_config("abc:x", base64::URL_SAFE_NO_PAD)).is_err());
+
+        let by_date = Sort::default();
+        assert_eq!(by_date.order, SortOrder::Desc);
+        assert!(cursor.filter(doc! {}, &by_date).is_err());
+        let by_title = Sort::new(Some(SortField::Title), None);
+        assert_eq!(by_title.order, SortOrder::Asc);
+        assert!(cursor.filter(doc! {}, &by_title).is_ok());
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions};
+use rss::{GuidBuilder, Item, ItemBuilder};
+use serde::Deserialize;
+
+use crate::{
+    channel::Channel,
+    config::get_config,
+    db::{published, Feed, Feeds},
+    store,
+    text::{escape_html, strip_html, truncate},
+};
+
+const EXCERPT_LEN: usize = 200;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Default for Period {
+    fn default() -> Self {
+        Period::Daily
+    }
+}
+
+impl Period {
+    fn name(self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+
+    fn length(self) -> Duration {
+        match self {
+            Period::Daily => Duration::days(1),
+            Period::Weekly => Duration::weeks(1),
+        }
+    }
+
+    /// Start of the period containing `time`. Weeks start on Monday.
+    fn start_of(self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let day = time.date().and_hms(0, 0, 0);
+        match self {
+            Period::Daily => day,
+            Period::Weekly => day - Duration::days(time.weekday().num_days_from_monday() as i64),
+        }
+    }
+}
+
+/// Render the last `per_page` finished periods of a box, one item per period
+pub async fn render_digest(feeds: Feeds, from_box: &str, period: Period) -> Result<String> {
+    let config = get_config();
+    let end = period.start_of(Utc::now());
+    let start = end - period.length() * config.per_page as i32;
+    let filter = doc! {
+        "from_box": from_box,
+        "created_at": {
+            "$gte": start.timestamp_millis(),
+            "$lt": end.timestamp_millis(),
+        },
+    };
+    let option = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .projection(store::without_raw())
+        .build();
+
+    let mut groups: Vec<(DateTime<Utc>, Vec<Feed>)> = vec![];
+    let mut cursor = feeds.find(published(filter), option).await?;
+    while let Some(feed) = cursor.try_next().await? {
+        let feed = feed.redacted();
+        let start = period.start_of(feed.created_at);
+        match groups.last_mut() {
+            Some((last, group)) if *last == start => group.push(feed),
+            _ => groups.push((start, vec![feed])),
+        }
+    }
+
+    let items = groups
+        .into_iter()
+        .map(|(start, group)| digest_item(from_box, period, start, group))
+        .collect::<Vec<_>>();
+
+    let ret = rss::ChannelBuilder::default()
+        .title(format!(
+            "{} - {} ({} digest)",
+            config.channel_title,
+            Channel::of(Some(from_box)).title,
+            period.name()
+        ))
+        .generator(Some("http://github.com/George-Miao/mail-list-rss".into()))
+        .link(format!("https://{}/rss/{}", config.web_domain, from_box))
+        .pub_date(Utc::now().to_rfc2822())
+        .items(items)
+        .build()
+        .to_string();
+    Ok(ret)
+}
+
+fn digest_item(from_box: &str, period: Period, start: DateTime<Utc>, feeds: Vec<Feed>) -> Item {
+    let config = get_config();
+    let date = start.format("%Y-%m-%d").to_string();
+
+    let guid = GuidBuilder::default()
+        .permalink(false)
+        .value(format!("{}/{}/{}", from_box, period.name(), date))
+        .build();
+
+    let content = feeds
+        .iter()
+        .map(|feed| {
+            format!(
+                r#"<li><a href="https://{}/feeds/{}">{}</a><p>{}</p></li>"#,
+                config.web_domain,
+                feed.id,
+                escape_html(&feed.title),
+                escape_html(&truncate(&strip_html(&feed.content), EXCERPT_LEN)),
+            )
+        })
+        .collect::<String>();
+
+    ItemBuilder::default()
+        .title(Some(format!(
+            "{} {} digest: {} ({} messages)",
+            from_box,
+            period.name(),
+            date,
+            feeds.len()
+        )))
+        .link(Some(format!(
+            "https://{}/rss/{}",
+            config.web_domain, from_box
+        )))
+        .pub_date(Some((start + period.length()).to_rfc2822()))
+        .guid(Some(guid))
+        .content(Some(format!("<ul>{}</ul>", content)))
+        .build()
+}
@@ -0,0 +1,70 @@
+use chrono::Utc;
+
+use crate::{config::get_config, headers::header_section};
+
+/// Build an RFC 3464 delivery status notification telling `return_path` that
+/// the message `raw` sent to `recipients` was not archived
+pub fn build(return_path: &str, recipients: &[String], reason: &str, raw: &str) -> String {
+    let config = get_config();
+    let boundary = format!("dsn-{}", nanoid::nanoid!(16));
+    let now = Utc::now().to_rfc2822();
+    // Keep header lines in the report free of line breaks
+    let reason = reason.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let per_recipient = recipients
+        .iter()
+        .map(|rcpt| {
+            format!(
+                "Final-Recipient: rfc822; {}\r\n\
+                 Action: failed\r\n\
+                 Status: 5.0.0\r\n\
+                 Diagnostic-Code: smtp; 550 {}\r\n",
+                rcpt, reason
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+
+    format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{domain}>\r\n\
+         To: <{to}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {now}\r\n\
+         Message-ID: <{id}@{domain}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         Your message could not be delivered to the archive at {domain}.\r\n\
+         \r\n\
+         Reason: {reason}\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {domain}\r\n\
+         Arrival-Date: {now}\r\n\
+         \r\n\
+         {per_recipient}\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/rfc822-headers\r\n\
+         \r\n\
+         {headers}\r\n\
+         \r\n\
+         --{boundary}--\r\n",
+        domain = config.domain,
+        to = return_path,
+        now = now,
+        id = nanoid::nanoid!(16),
+        boundary = boundary,
+        reason = reason,
+        per_recipient = per_recipient,
+        headers = header_section(raw)
+            .replace("\r\n", "\n")
+            .replace('\n', "\r\n"),
+    )
+}
@@ -0,0 +1,322 @@
+use anyhow::Result;
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use futures::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions};
+
+use crate::{
+    db::{published, Feed, Feeds},
+    error::ApiError,
+    store,
+    text::{escape_xml, html_paragraphs},
+};
+
+/// Most items bundled into one book
+const MAX_CHAPTERS: i64 = 1000;
+
+/// Bundle items of a box, optionally within a date range (inclusive), into an
+/// EPUB with one chapter per message
+pub async fn render_epub(
+    feeds: Feeds,
+    from_box: &str,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+) -> Result<Vec<u8>> {
+    let mut range = doc! {};
+    if let Some(since) = since {
+        range.insert(
+            "$gte",
+            Utc.from_utc_date(&since)
+                .and_hms(0, 0, 0)
+                .timestamp_millis(),
+        );
+    }
+    if let Some(until) = until {
+        let end = Utc.from_utc_date(&until).and_hms(0, 0, 0) + Duration::days(1);
+        range.insert("$lt", end.timestamp_millis());
+    }
+    let mut filter = doc! { "from_box": from_box };
+    if !range.is_empty() {
+        filter.insert("created_at", range);
+    }
+    let option = FindOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .limit(MAX_CHAPTERS)
+        .projection(store::without_raw())
+        .build();
+    let items = feeds
+        .find(published(filter), option)
+        .await?
+        .map_ok(Feed::redacted)
+        .try_collect::<Vec<_>>()
+        .await?;
+    if items.is_empty() {
+        return Err(
+            ApiError::not_found(format!("No items in {} for the given range", from_box)).into(),
+        );
+    }
+
+    let title = match (since, until) {
+        (None, None) => from_box.to_owned(),
+        (since, until) => format!(
+            "{} ({} – {})",
+            from_box,
+            since.map(|x| x.to_string()).unwrap_or_default(),
+            until.map(|x| x.to_string()).unwrap_or_default()
+        ),
+    };
+    let identifier = format!(
+        "urn:mail-list-rss:{}:{}:{}",
+        from_box,
+        since.map(|x| x.to_string()).unwrap_or_default(),
+        until.map(|x| x.to_string()).unwrap_or_default()
+    );
+
+    let mut zip = Zip::default();
+    // Must come first, uncompressed
+    zip.file("mimetype", b"application/epub+zip");
+    zip.file(
+        "META-INF/container.xml",
+        br#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>
+"#,
+    );
+    for (idx, item) in items.iter().enumerate() {
+        zip.file(
+            &format!("OEBPS/{}", chapter_name(idx)),
+            chapter(item).as_bytes(),
+        );
+    }
+    zip.file("OEBPS/nav.xhtml", nav(&title, &items).as_bytes());
+    zip.file("OEBPS/toc.ncx", ncx(&identifier, &title, &items).as_bytes());
+    zip.file(
+        "OEBPS/content.opf",
+        package(&identifier, &title, items.len()).as_bytes(),
+    );
+    Ok(zip.finish())
+}
+
+fn chapter_name(idx: usize) -> String {
+    format!("chapter-{:04}.xhtml", idx + 1)
+}
+
+/// Mail HTML is rarely well-formed XML, so only its text is kept
+fn chapter(item: &Feed) -> String {
+    let paragraphs = html_paragraphs(&item.content)
+        .iter()
+        .map(|x| format!("<p>{}</p>", escape_xml(x)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>{title}</title></head>
+<body>
+<h1>{title}</h1>
+<p><small>{author} · {date}</small></p>
+{paragraphs}
+</body>
+</html>
+"#,
+        title = escape_xml(&item.display_title()),
+        author = escape_xml(&item.author),
+        date = item.created_at.format("%Y-%m-%d %H:%M"),
+        paragraphs = paragraphs
+    )
+}
+
+fn nav(title: &str, items: &[Feed]) -> String {
+    let entries = items
+        .iter()
+        .enumerate()
+        .map(|(idx, item)| {
+            format!(
+                r#"<li><a href="{}">{}</a></li>"#,
+                chapter_name(idx),
+                escape_xml(&item.display_title())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>{title}</title></head>
+<body>
+<nav epub:type="toc"><h1>{title}</h1><ol>
+{entries}
+</ol></nav>
+</body>
+</html>
+"#,
+        title = escape_xml(title),
+        entries = entries
+    )
+}
+
+/// Table of contents for EPUB 2 readers
+fn ncx(identifier: &str, title: &str, items: &[Feed]) -> String {
+    let points = items
+        .iter()
+        .enumerate()
+        .map(|(idx, item)| {
+            format!(
+                r#"<navPoint id="p{n}" playOrder="{n}"><navLabel><text>{}</text></navLabel><content src="{}"/></navPoint>"#,
+                escape_xml(&item.display_title()),
+                chapter_name(idx),
+                n = idx + 1
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head><meta name="dtb:uid" content="{}"/></head>
+<docTitle><text>{}</text></docTitle>
+<navMap>
+{}
+</navMap>
+</ncx>
+"#,
+        escape_xml(identifier),
+        escape_xml(title),
+        points
+    )
+}
+
+fn package(identifier: &str, title: &str, chapters: usize) -> String {
+    let manifest = (0..chapters)
+        .map(|idx| {
+            format!(
+                r#"<item id="c{}" href="{}" media-type="application/xhtml+xml"/>"#,
+                idx + 1,
+                chapter_name(idx)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let spine = (0..chapters)
+        .map(|idx| format!(r#"<itemref idref="c{}"/>"#, idx + 1))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="uid">{identifier}</dc:identifier>
+<dc:title>{title}</dc:title>
+<dc:language>und</dc:language>
+<dc:creator>mail-list-rss</dc:creator>
+<meta property="dcterms:modified">{modified}</meta>
+</metadata>
+<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+{manifest}
+</manifest>
+<spine toc="ncx">
+{spine}
+</spine>
+</package>
+"#,
+        identifier = escape_xml(identifier),
+        title = escape_xml(title),
+        modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        manifest = manifest,
+        spine = spine
+    )
+}
+
+/// Writer of ZIP archives with stored (uncompressed) entries
+#[derive(Default)]
+struct Zip {
+    out: Vec<u8>,
+    central: Vec<u8>,
+    entries: u16,
+}
+
+impl Zip {
+    fn file(&mut self, name: &str, data: &[u8]) {
+        let offset = self.out.len() as u32;
+        let crc = crc32(data);
+        // Version, flags, method, DOS time and date (1980-01-01)
+        let common = [20u16, 0, 0, 0, 0x21]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .chain(crc.to_le_bytes())
+            .chain((data.len() as u32).to_le_bytes())
+            .chain((data.len() as u32).to_le_bytes())
+            .chain((name.len() as u16).to_le_bytes())
+            .chain(0u16.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        self.out.extend(0x04034b50u32.to_le_bytes());
+        self.out.extend(&common);
+        self.out.extend(name.as_bytes());
+        self.out.extend(data);
+
+        self.central.extend(0x02014b50u32.to_le_bytes());
+        self.central.extend(20u16.to_le_bytes());
+        self.central.extend(&common);
+        // Comment length, disk, internal and external attributes
+        self.central.extend([0u8; 10]);
+        self.central.extend(offset.to_le_bytes());
+        self.central.extend(name.as_bytes());
+        self.entries += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let offset = self.out.len() as u32;
+        let size = self.central.len() as u32;
+        self.out.extend(&self.central);
+        self.out.extend(0x06054b50u32.to_le_bytes());
+        self.out.extend([0u8; 4]);
+        self.out.extend(self.entries.to_le_bytes());
+        self.out.extend(self.entries.to_le_bytes());
+        self.out.extend(size.to_le_bytes());
+        self.out.extend(offset.to_le_bytes());
+        self.out.extend(0u16.to_le_bytes());
+        self.out
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for x in data {
+        crc ^= *x as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_zip() {
+        let mut zip = Zip::default();
+        zip.file("mimetype", b"application/epub+zip");
+        let data = zip.finish();
+        assert_eq!(&data[..4], b"PK\x03\x04");
+        assert_eq!(&data[30..38], b"mimetype");
+        // Single entry in the end of central directory record
+        assert_eq!(&data[data.len() - 22..data.len() - 18], b"PK\x05\x06");
+        assert_eq!(data[data.len() - 12], 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Errors of web routes, answered as JSON on API routes and as a page on
+//! browser routes. Internal errors are logged and answered without details.
+
+use std::fmt;
+
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::templates;
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    pub fn gone(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::GONE, message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// So that helpers returning `anyhow::Result` can fail with a status
+impl std::error::Error for ApiError {}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: "web", "{:#}", e);
+                Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+            }
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for ApiError {
+    fn from(e: mongodb::error::Error) -> Self {
+        anyhow::Error::from(e).into()
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            status: self.status.as_u16(),
+            error: &self.message,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Error of a route opened in browsers, answered with a page
+#[derive(Debug)]
+pub struct PageError(pub ApiError);
+
+pub type PageResult<T> = Result<T, PageError>;
+
+impl From<ApiError> for PageError {
+    fn from(e: ApiError) -> Self {
+        Self(e)
+    }
+}
+
+impl From<anyhow::Error> for PageError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(e.into())
+    }
+}
+
+impl From<mongodb::error::Error> for PageError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self(e.into())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorPage<'a> {
+    title: &'a str,
+    message: &'a str,
+}
+
+impl IntoResponse for PageError {
+    fn into_response(self) -> Response {
+        let ApiError { status, message } = self.0;
+        let title = format!(
+            "{} {}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("Error")
+        );
+        let page = templates::render(
+            "error.html",
+            &ErrorPage {
+                title: &title,
+                message: &message,
+            },
+        );
+        match page {
+            Ok(page) => (status, Html(page)).into_response(),
+            Err(e) => {
+                warn!(target: "web", "Error rendering error page: {}", e);
+                (status, message).into_response()
+            }
+        }
+    }
+}
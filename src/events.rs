@@ -0,0 +1,50 @@
+//! New items broadcast to subscribers of `/events` and `/ws`
+
+use futures::{stream, Stream};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::db::Feed;
+
+/// Events kept for subscribers falling behind, older ones are skipped
+const CAPACITY: usize = 64;
+
+static CHANNEL: Lazy<broadcast::Sender<NewItem>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NewItem {
+    pub id: String,
+    pub title: String,
+    #[serde(rename = "box")]
+    pub from_box: String,
+    pub link: String,
+}
+
+/// Tell subscribers about a stored item, unless held for moderation or
+/// published later
+pub fn publish(feed: &Feed) {
+    if feed.pending || feed.publish_at.is_some() {
+        return;
+    }
+    // Fails only without subscribers
+    let _ = CHANNEL.send(NewItem {
+        id: feed.id.clone(),
+        title: feed.title.clone(),
+        from_box: feed.from_box.clone(),
+        link: feed.link(),
+    });
+}
+
+/// Items stored from now on. Too slow a reader misses some.
+pub fn subscribe() -> impl Stream<Item = NewItem> {
+    stream::unfold(CHANNEL.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(item) => return Some((item, rx)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
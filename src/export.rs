@@ -0,0 +1,143 @@
+//! Dumps of stored items for backups, see `/export`
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+};
+use serde::Deserialize;
+
+use crate::{
+    blob::{self, Blobs},
+    db::Feeds,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One JSON document per line, as stored
+    Ndjson,
+    /// The raw messages, in mboxrd format
+    Mbox,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Ndjson
+    }
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson; charset=utf-8",
+            Format::Mbox => "application/mbox",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Ndjson => "ndjson",
+            Format::Mbox => "mbox",
+        }
+    }
+}
+
+/// Message as an mboxrd entry: a `From ` line, the raw source with LF line
+/// endings and `From ` lines quoted, and a blank line
+fn mbox_entry(raw: &str, mail_from: Option<&str>, at: DateTime<Utc>) -> String {
+    let sender = mail_from
+        .map(|x| x.trim_matches(&['<', '>'][..]))
+        .filter(|x| !x.is_empty())
+        .unwrap_or("MAILER-DAEMON");
+    let mut ret = format!("From {} {}\n", sender, at.format("%a %b %e %H:%M:%S %Y"));
+    for line in raw.lines() {
+        if line.trim_start_matches('>').starts_with("From ") {
+            ret.push('>');
+        }
+        ret.push_str(line);
+        ret.push('\n');
+    }
+    ret.push('\n');
+    ret
+}
+
+/// Every item, or those of `from_box`, oldest first. Items held for moderation
+/// or not yet published are included.
+pub async fn stream(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    from_box: Option<&str>,
+    format: Format,
+) -> Result<impl Stream<Item = Result<String>>> {
+    let filter = from_box.map(|x| doc! { "from_box": x });
+    let option = FindOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .projection(match format {
+            Format::Ndjson => None,
+            Format::Mbox => Some(doc! { "content": 0, "text": 0, "translations": 0 }),
+        })
+        .build();
+    let blobs = blobs.clone();
+    Ok(match format {
+        Format::Ndjson => feeds
+            .clone_with_type::<Document>()
+            .find(filter, option)
+            .await?
+            .map_err(anyhow::Error::from)
+            .and_then(move |mut x| {
+                let blobs = blobs.clone();
+                async move {
+                    // Raw sources kept in chunks go along, for the dump to be whole
+                    if x.get_bool("raw_chunked").unwrap_or(false) {
+                        let id = x.get_str("id").unwrap_or_default().to_owned();
+                        x.insert("raw", blob::load_raw(&blobs, &id).await?);
+                    }
+                    let mut line = serde_json::to_string(&x).expect("documents should serialize");
+                    line.push('\n');
+                    Ok(line)
+                }
+            })
+            .left_stream(),
+        Format::Mbox => feeds
+            .find(filter, option)
+            .await?
+            .map_err(anyhow::Error::from)
+            .and_then(move |mut x| {
+                let blobs = blobs.clone();
+                async move {
+                    blob::fill_raw(&blobs, &mut x).await?;
+                    let mail_from = x.envelope.as_ref().map(|x| x.mail_from.as_str());
+                    Ok(mbox_entry(&x.raw, mail_from, x.created_at))
+                }
+            })
+            .right_stream(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_mbox_entry() {
+        let raw = "Subject: Hi\r\n\r\nFrom here on\r\n>From there\r\nbye\r\n";
+        let at = Utc.ymd(2022, 3, 4).and_hms(5, 6, 7);
+        assert_eq!(
+            mbox_entry(raw, None, at),
+            "From MAILER-DAEMON Fri Mar  4 05:06:07 2022\n\
+             Subject: Hi\n\
+             \n\
+             >From here on\n\
+             >>From there\n\
+             bye\n\
+             \n"
+        );
+        assert!(mbox_entry(raw, Some("<list@example.org>"), at)
+            .starts_with("From list@example.org Fri Mar  4"));
+    }
+}
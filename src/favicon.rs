@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{serde::ts_milliseconds, DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, spec::BinarySubtype, Binary, Document},
+    options::{FindOptions, ReplaceOptions},
+    Collection,
+};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{client::sender_client, db::Feeds};
+
+pub type Favicons = Collection<Favicon>;
+
+/// Number of recent items used to find the dominant sender domain of a box
+const SAMPLE_SIZE: i64 = 50;
+const REFRESH_DAYS: i64 = 7;
+/// Largest icon kept, in bytes
+const MAX_SIZE: usize = 256 * 1024;
+
+/// Cached favicon of a sender domain. `data` is empty when the domain has none,
+/// so that it won't be fetched again until refresh.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Favicon {
+    pub domain: String,
+    pub content_type: String,
+    pub data: Binary,
+    #[serde(with = "ts_milliseconds")]
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl Favicon {
+    fn new(domain: &str, content_type: String, data: Vec<u8>) -> Self {
+        Self {
+            domain: domain.to_owned(),
+            content_type,
+            data: Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: data,
+            },
+            fetched_at: Utc::now(),
+        }
+    }
+
+    fn found(self) -> Option<Self> {
+        Some(self).filter(|x| !x.data.bytes.is_empty())
+    }
+}
+
+/// Favicon of the dominant sender domain of a box, fetched if not cached
+pub async fn for_box(
+    feeds: &Feeds,
+    favicons: &Favicons,
+    from_box: &str,
+) -> Result<Option<Favicon>> {
+    let domain = match dominant_domain(feeds, from_box).await? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    if let Some(icon) = favicons.find_one(doc! { "domain": &domain }, None).await? {
+        if Utc::now() - icon.fetched_at < Duration::days(REFRESH_DAYS) {
+            return Ok(icon.found());
+        }
+    }
+
+    let icon = fetch(&domain).await;
+    favicons
+        .replace_one(
+            doc! { "domain": &domain },
+            &icon,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(icon.found())
+}
+
+async fn dominant_domain(feeds: &Feeds, from_box: &str) -> Result<Option<String>> {
+    let option = FindOptions::builder()
+        .limit(SAMPLE_SIZE)
+        .sort(doc! { "created_at": -1 })
+        .projection(doc! { "author": 1 })
+        .build();
+    let mut cursor = feeds
+        .clone_with_type::<Document>()
+        .find(doc! { "from_box": from_box }, option)
+        .await?;
+
+    let mut counts = HashMap::<String, usize>::new();
+    while let Some(doc) = cursor.try_next().await? {
+        if let Some(domain) = doc.get_str("author").ok().and_then(sender_domain) {
+            *counts.entry(domain).or_default() += 1;
+        }
+    }
+    Ok(counts.into_iter().max_by_key(|(_, x)| *x).map(|(x, _)| x))
+}
+
+/// Domain of the address in `author`, which looks like `addr (name)`
+fn sender_domain(author: &str) -> Option<String> {
+    let address = author.split_whitespace().next()?;
+    let (_, domain) = address.rsplit_once('@')?;
+    Some(domain.to_ascii_lowercase())
+}
+
+/// Try the domain itself, then its parent, e.g. `mail.example.com` -> `example.com`
+async fn fetch(domain: &str) -> Favicon {
+    let mut candidates = vec![domain];
+    if domain.matches('.').count() > 1 {
+        candidates.extend(domain.split_once('.').map(|(_, parent)| parent));
+    }
+
+    for candidate in candidates {
+        match download(candidate).await {
+            Ok(Some((content_type, data))) => return Favicon::new(domain, content_type, data),
+            Ok(None) => {}
+            Err(e) => debug!(target: "Favicon", "Error fetching {}: {}", candidate, e),
+        }
+    }
+    Favicon::new(domain, String::new(), vec![])
+}
+
+/// Domains come from senders, so only public addresses are fetched from,
+/// see `sender_client`, and redirects are not followed
+async fn download(domain: &str) -> Result<Option<(String, Vec<u8>)>> {
+    let mut res = sender_client(domain, 443)
+        .await?
+        .get(format!("https://{}/favicon.ico", domain))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Ok(None);
+    }
+    if res.content_length().map_or(false, |x| x > MAX_SIZE as u64) {
+        return Ok(None);
+    }
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or("image/x-icon")
+        .to_owned();
+    if !content_type.starts_with("image/") {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    while let Some(chunk) = res.chunk().await? {
+        if data.len() + chunk.len() > MAX_SIZE {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some((content_type, data)))
+}
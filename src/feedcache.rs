@@ -0,0 +1,51 @@
+//! Rendered feeds by format and URL, kept until items change, see `validator`,
+//! so that readers polling the same feeds do not each have them queried and
+//! rendered again. Items stored by the pipeline change the validator, which
+//! drops every entry at once.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use once_cell::sync::Lazy;
+
+use crate::config::get_config;
+
+struct Entry {
+    /// `validator` tag the feed was rendered at
+    tag: String,
+    content: String,
+}
+
+static CACHE: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(Default::default);
+
+/// Feed `key` as rendered at validator tag `tag`, if cached
+pub fn get(tag: &str, key: &str) -> Option<String> {
+    CACHE
+        .lock()
+        .expect("feed cache poisoned")
+        .get(key)
+        .filter(|x| x.tag == tag)
+        .map(|x| x.content.clone())
+}
+
+/// Keep feed `key` rendered at validator tag `tag`, up to `FEED_CACHE_SIZE`
+/// feeds
+pub fn put(tag: &str, key: String, content: String) {
+    let max = get_config().feed_cache_size;
+    if max == 0 {
+        return;
+    }
+    let mut cache = CACHE.lock().expect("feed cache poisoned");
+    if cache.len() >= max && !cache.contains_key(&key) {
+        cache.retain(|_, x| x.tag == tag);
+        if cache.len() >= max {
+            cache.clear();
+        }
+    }
+    cache.insert(
+        key,
+        Entry {
+            tag: tag.to_owned(),
+            content,
+        },
+    );
+}
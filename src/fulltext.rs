@@ -0,0 +1,263 @@
+//! Embedded tantivy index, an alternative to searching MongoDB with regexes.
+//! Enabled by `SEARCH_INDEX_DIR`, ranks results and supports phrase queries
+//! and CJK text.
+
+use std::{fs, sync::Mutex};
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use mongodb::bson::doc;
+use once_cell::sync::Lazy;
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    query::QueryParser,
+    schema::{Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, STORED, STRING},
+    tokenizer::{BoxTokenStream, TextAnalyzer, Token, TokenStream, Tokenizer},
+    Document, Index, IndexReader, IndexWriter, ReloadPolicy, Term,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::get_config,
+    db::{Feed, Feeds},
+    text::strip_html,
+};
+
+const TOKENIZER: &str = "mixed";
+const WRITER_HEAP: usize = 50_000_000;
+
+static INDEX: Lazy<Option<SearchIndex>> = Lazy::new(|| {
+    let dir = get_config().search_index_dir.as_ref()?;
+    match SearchIndex::open(dir) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!(target: "Search", "Error opening index at {}: {}", dir, e);
+            None
+        }
+    }
+});
+
+#[inline]
+pub fn index<'a>() -> Option<&'a SearchIndex> {
+    INDEX.as_ref()
+}
+
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    id: Field,
+    title: Field,
+    content: Field,
+}
+
+impl SearchIndex {
+    fn open(dir: &str) -> Result<Self> {
+        let text = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(TOKENIZER)
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+        );
+        let mut schema = Schema::builder();
+        let id = schema.add_text_field("id", STRING | STORED);
+        let title = schema.add_text_field("title", text.clone());
+        let content = schema.add_text_field("content", text);
+
+        fs::create_dir_all(dir)?;
+        let index = Index::open_or_create(MmapDirectory::open(dir)?, schema.build())?;
+        index
+            .tokenizers()
+            .register(TOKENIZER, TextAnalyzer::from(MixedTokenizer));
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .try_into()?;
+        let writer = Mutex::new(index.writer(WRITER_HEAP)?);
+        Ok(Self {
+            index,
+            reader,
+            writer,
+            id,
+            title,
+            content,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.searcher().num_docs() == 0
+    }
+
+    pub fn add(&self, feeds: &[Feed]) -> Result<()> {
+        let mut writer = self.writer.lock().expect("index writer poisoned");
+        for feed in feeds {
+            let mut doc = Document::default();
+            doc.add_text(self.id, &feed.id);
+            doc.add_text(self.title, &feed.title);
+            doc.add_text(self.content, &strip_html(&feed.content));
+            writer.add_document(doc)?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    pub fn delete(&self, ids: &[String]) -> Result<()> {
+        let mut writer = self.writer.lock().expect("index writer poisoned");
+        for id in ids {
+            writer.delete_term(Term::from_field_text(self.id, id));
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Ids of items matching `query` in the query language of tantivy, best
+    /// first, at least one asked for
+    pub fn search(&self, query: &str, limit: usize, skip: usize) -> Result<Vec<String>> {
+        let searcher = self.reader.searcher();
+        let mut parser = QueryParser::for_index(&self.index, vec![self.title, self.content]);
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(self.title, 2.0);
+        let query = parser.parse_query(query)?;
+        let ret = searcher
+            .search(&query, &TopDocs::with_limit(limit.max(1)).and_offset(skip))?
+            .into_iter()
+            .filter_map(|(_, address)| {
+                searcher
+                    .doc(address)
+                    .ok()?
+                    .get_first(self.id)?
+                    .as_text()
+                    .map(ToOwned::to_owned)
+            })
+            .collect();
+        Ok(ret)
+    }
+}
+
+/// Index items removed from the database, if the index is enabled
+pub fn remove(ids: &[String]) {
+    if let Some(index) = index() {
+        if let Err(e) = tokio::task::block_in_place(|| index.delete(ids)) {
+            warn!(target: "Search", "Error removing from index: {}", e)
+        }
+    }
+}
+
+/// Index every stored item when the index is freshly created
+pub async fn backfill(feeds: Feeds) -> Result<()> {
+    let index = match index() {
+        Some(x) if x.is_empty() => x,
+        _ => return Ok(()),
+    };
+    info!(target: "Search", "Building index");
+    let mut cursor = feeds.find(doc! {}, None).await?;
+    let mut batch = vec![];
+    let mut count = 0;
+    while let Some(feed) = cursor.try_next().await? {
+        batch.push(feed);
+        if batch.len() == 500 {
+            count += batch.len();
+            tokio::task::block_in_place(|| index.add(&batch))?;
+            batch.clear();
+        }
+    }
+    count += batch.len();
+    tokio::task::block_in_place(|| index.add(&batch))?;
+    info!(target: "Search", count, "Index built");
+    Ok(())
+}
+
+fn is_cjk(x: char) -> bool {
+    matches!(x as u32,
+        0x3040..=0x30FF // Hiragana and Katakana
+        | 0x3400..=0x4DBF // CJK Extension A
+        | 0x4E00..=0x9FFF // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF // Hangul Syllables
+        | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+        | 0x20000..=0x2FA1F) // CJK Extensions B to F and supplement
+}
+
+/// Lowercased words for alphabetic scripts and overlapping bigrams for CJK,
+/// which is written without spaces
+#[derive(Clone)]
+struct MixedTokenizer;
+
+impl Tokenizer for MixedTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(VecTokenStream {
+            tokens: tokenize(text),
+            idx: None,
+        })
+    }
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut ret = vec![];
+    let mut push = |from: usize, to: usize| {
+        ret.push(Token {
+            offset_from: from,
+            offset_to: to,
+            position: ret.len(),
+            text: text[from..to].to_lowercase(),
+            position_length: 1,
+        })
+    };
+    let chars = text.char_indices().collect::<Vec<_>>();
+    let end_of = |idx: usize| chars.get(idx + 1).map_or(text.len(), |x| x.0);
+    let mut idx = 0;
+    while idx < chars.len() {
+        let (from, x) = chars[idx];
+        if is_cjk(x) {
+            match chars.get(idx + 1) {
+                Some((_, next)) if is_cjk(*next) => push(from, end_of(idx + 1)),
+                // Lone characters are kept unless they end a run
+                _ if idx == 0 || !is_cjk(chars[idx - 1].1) => push(from, end_of(idx)),
+                _ => {}
+            }
+            idx += 1;
+        } else if x.is_alphanumeric() {
+            while idx < chars.len() && chars[idx].1.is_alphanumeric() && !is_cjk(chars[idx].1) {
+                idx += 1;
+            }
+            push(from, end_of(idx - 1));
+        } else {
+            idx += 1;
+        }
+    }
+    ret
+}
+
+struct VecTokenStream {
+    tokens: Vec<Token>,
+    idx: Option<usize>,
+}
+
+impl TokenStream for VecTokenStream {
+    fn advance(&mut self) -> bool {
+        let next = self.idx.map_or(0, |x| x + 1);
+        self.idx = Some(next);
+        next < self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.idx.unwrap_or_default()]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.idx.unwrap_or_default()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("Rust 机器学习, 中 x")
+            .into_iter()
+            .map(|x| x.text)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec!["rust", "机器", "器学", "学习", "中", "x"]);
+    }
+}
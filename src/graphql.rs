@@ -0,0 +1,434 @@
+//! GraphQL API at `/graphql`: queries of items, boxes and search, and
+//! mutations for the admin operations of the REST routes. Readers and
+//! read-scoped keys may query, mutations need the admin credentials or an
+//! admin key, see `auth::Restricted`.
+
+use async_graphql::{
+    Context, EmptySubscription, Enum, Error, ErrorExtensions, Object, Result, Schema, SimpleObject,
+};
+use axum::http::StatusCode;
+use futures::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions};
+
+use crate::{
+    admin::{self, ItemEdit},
+    audit::AuditLog,
+    auth::Restricted,
+    blob::{self, Blobs},
+    channel::Channel,
+    config::get_config,
+    db::{Feed, Feeds, Sort, SortField, SortOrder},
+    error::ApiError,
+    registry::{self, Registry},
+    store,
+    text::{percent_encode, proxy_images},
+    tombstone::Tombstones,
+    web::{list_filter, list_page, search_items, FeedsQuery},
+};
+
+pub type ApiSchema = Schema<Query, Mutation, EmptySubscription>;
+
+/// Deepest nesting of fields a query may have
+const MAX_DEPTH: usize = 8;
+
+pub fn schema(
+    feeds: Feeds,
+    blobs: Blobs,
+    registry: Registry,
+    audit: AuditLog,
+    tombstones: Tombstones,
+) -> ApiSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .data(feeds)
+        .data(blobs)
+        .data(registry)
+        .data(audit)
+        .data(tombstones)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+/// Error with the status the REST routes would answer, as `status` in its
+/// extensions
+fn error(e: impl Into<ApiError>) -> Error {
+    let ApiError { status, message } = e.into();
+    Error::new(message).extend_with(|_, x| x.set("status", status.as_u16()))
+}
+
+fn admin_only(ctx: &Context<'_>) -> Result<()> {
+    match ctx.data_opt::<Restricted>() {
+        Some(_) => Err(error(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Only admins can do this",
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub struct Item(Feed);
+
+impl Item {
+    fn new(feed: Feed) -> Self {
+        Self(feed.redacted())
+    }
+}
+
+#[Object]
+impl Item {
+    async fn id(&self) -> &str {
+        &self.0.id
+    }
+
+    async fn title(&self) -> String {
+        self.0.display_title()
+    }
+
+    async fn author(&self) -> &str {
+        &self.0.author
+    }
+
+    #[graphql(name = "box")]
+    async fn from_box(&self) -> &str {
+        &self.0.from_box
+    }
+
+    /// RFC 3339
+    async fn created_at(&self) -> String {
+        self.0.created_at.to_rfc3339()
+    }
+
+    async fn tags(&self) -> &Vec<String> {
+        &self.0.tags
+    }
+
+    async fn summary(&self) -> &Option<String> {
+        &self.0.summary
+    }
+
+    /// Link of the item in feeds
+    async fn link(&self) -> String {
+        self.0.link()
+    }
+
+    /// GUID of the item in feeds
+    async fn guid(&self) -> &str {
+        self.0.guid()
+    }
+
+    /// Number of collapsed repetitions, including the first one
+    async fn occurrences(&self) -> u32 {
+        self.0.occurrences
+    }
+
+    /// Waiting for approval in a moderated box
+    async fn pending(&self) -> bool {
+        self.0.pending
+    }
+
+    /// HTML body, as served on `/feeds/:key?bare=1`
+    async fn content(&self, ctx: &Context<'_>) -> Result<String> {
+        let mut feed = store::get_content(ctx.data()?, &self.0.id)
+            .await
+            .map_err(error)?
+            .ok_or_else(|| error(ApiError::not_found(format!("Cannot find {}", self.0.id))))?;
+        if feed.overflow {
+            feed.content = blob::load(ctx.data()?, &feed.id).await.map_err(error)?;
+        }
+        let content = feed.redacted().content;
+        Ok(match &get_config().image_proxy {
+            Some(proxy) => proxy_images(&content, proxy),
+            None => content,
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct ItemPage {
+    items: Vec<Item>,
+    /// Number of matching items
+    total: u64,
+    limit: i64,
+    skip: u64,
+    /// Whether items are left past this page
+    has_more: bool,
+    /// `after` cursor of the next page, if items are left
+    next: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct BoxInfo {
+    name: String,
+    title: String,
+    description: Option<String>,
+    archived: bool,
+    rss: String,
+    atom: String,
+    /// Web page listing its items
+    page: String,
+}
+
+impl BoxInfo {
+    fn new(name: String) -> Self {
+        let config = get_config();
+        let channel = Channel::of(Some(&name));
+        let encoded = percent_encode(&name);
+        Self {
+            title: channel.title,
+            description: channel.description,
+            archived: registry::is_archived(&name),
+            rss: format!("https://{}/rss/{}", config.web_domain, encoded),
+            atom: format!("https://{}/atom/{}", config.web_domain, encoded),
+            page: format!("https://{}/boxes/{}", config.web_domain, encoded),
+            name,
+        }
+    }
+}
+
+/// Newest items first unless given
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "SortField")]
+pub enum ItemSort {
+    CreatedAt,
+    Title,
+    Author,
+}
+
+/// Descending for `CREATED_AT`, ascending for the others unless given
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "SortOrder")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Published items, newest first, filtered as on `/feeds`, `limit` at most
+    /// `MAX_PER_PAGE`
+    #[allow(clippy::too_many_arguments)]
+    async fn items(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "box")] from_box: Option<String>,
+        tag: Option<String>,
+        author: Option<String>,
+        address_tag: Option<String>,
+        #[graphql(desc = "RFC 3339 or unix milliseconds")] since: Option<String>,
+        #[graphql(desc = "RFC 3339 or unix milliseconds, exclusive")] until: Option<String>,
+        limit: Option<i64>,
+        skip: Option<u64>,
+        #[graphql(desc = "`next` of the previous page, instead of `skip`")] after: Option<String>,
+        sort: Option<ItemSort>,
+        order: Option<Order>,
+    ) -> Result<ItemPage> {
+        let config = get_config();
+        let limit = limit
+            .unwrap_or(config.default_page_limit)
+            .clamp(1, config.max_per_page.max(1) as i64);
+        let mut filter = list_filter(&FeedsQuery {
+            tag,
+            author,
+            address_tag,
+            since,
+            until,
+            ..Default::default()
+        })
+        .map_err(error)?;
+        if let Some(from_box) = from_box {
+            filter.insert("from_box", registry::resolve(&from_box));
+        }
+        let sort = Sort::new(sort.map(Into::into), order.map(Into::into));
+        let (items, page) = list_page(ctx.data()?, filter, sort, limit, skip, after.as_deref())
+            .await
+            .map_err(error)?;
+        Ok(ItemPage {
+            items: items.into_iter().map(Item::new).collect(),
+            total: page.total,
+            limit: page.limit,
+            skip: page.skip,
+            has_more: page.has_more,
+            next: page.next,
+        })
+    }
+
+    async fn item(&self, ctx: &Context<'_>, id: String) -> Result<Option<Item>> {
+        let feed = store::get_meta(ctx.data()?, &id).await.map_err(error)?;
+        // Items not published yet are for admins only, as on `pending`
+        Ok(feed
+            .filter(|x| x.is_published() || admin_only(ctx).is_ok())
+            .map(Item::new))
+    }
+
+    /// Items containing every term of `q`, best matches first, as on `/search`
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        q: String,
+        limit: Option<i64>,
+        skip: Option<u64>,
+    ) -> Result<Vec<Item>> {
+        let limit = limit.unwrap_or(get_config().default_page_limit);
+        let found = search_items(ctx.data()?, &q, limit, skip.unwrap_or(0))
+            .await
+            .map_err(error)?;
+        Ok(found.into_iter().map(Item::new).collect())
+    }
+
+    async fn boxes(&self, ctx: &Context<'_>) -> Result<Vec<BoxInfo>> {
+        let names = registry::names(ctx.data()?).await.map_err(error)?;
+        Ok(names.into_iter().map(BoxInfo::new).collect())
+    }
+
+    /// Items of moderated boxes waiting for approval, oldest first. Admins only.
+    async fn pending(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "box")] from_box: Option<String>,
+    ) -> Result<Vec<Item>> {
+        admin_only(ctx)?;
+        let mut filter = doc! { "pending": true };
+        if let Some(from_box) = from_box {
+            filter.insert("from_box", from_box);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .projection(store::meta_only())
+            .build();
+        ctx.data::<Feeds>()?
+            .find(filter, options)
+            .await
+            .map_err(error)?
+            .map_ok(Item::new)
+            .try_collect()
+            .await
+            .map_err(error)
+    }
+}
+
+/// The admin operations of the REST routes, see `admin`
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Correct the title, box or tags of an item
+    async fn edit_item(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        title: Option<String>,
+        #[graphql(name = "box")] from_box: Option<String>,
+        tags: Option<Vec<String>>,
+    ) -> Result<Item> {
+        admin_only(ctx)?;
+        let edit = ItemEdit {
+            title,
+            from_box,
+            tags,
+        };
+        let feed = admin::edit_item(ctx.data()?, ctx.data()?, ctx.data()?, &id, edit)
+            .await
+            .map_err(error)?;
+        Ok(Item::new(feed))
+    }
+
+    /// Replace the tags of an item. Returns them normalized.
+    async fn set_tags(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        tags: Vec<String>,
+    ) -> Result<Vec<String>> {
+        admin_only(ctx)?;
+        admin::set_tags(ctx.data()?, &id, tags).await.map_err(error)
+    }
+
+    /// Delete an item. Returns the number of items deleted.
+    async fn delete_item(&self, ctx: &Context<'_>, id: String) -> Result<u64> {
+        admin_only(ctx)?;
+        admin::delete_item(ctx.data()?, ctx.data()?, ctx.data()?, ctx.data()?, &id)
+            .await
+            .map_err(error)
+    }
+
+    /// Delete every item sent from `address`. Returns the number of items
+    /// deleted.
+    async fn erase_sender(&self, ctx: &Context<'_>, address: String) -> Result<u64> {
+        admin_only(ctx)?;
+        admin::erase_sender(ctx.data()?, ctx.data()?, ctx.data()?, ctx.data()?, &address)
+            .await
+            .map_err(error)
+    }
+
+    async fn approve_item(&self, ctx: &Context<'_>, id: String) -> Result<bool> {
+        admin_only(ctx)?;
+        admin::approve(ctx.data()?, ctx.data()?, ctx.data()?, &id)
+            .await
+            .map_err(error)?;
+        Ok(true)
+    }
+
+    async fn reject_item(&self, ctx: &Context<'_>, id: String) -> Result<bool> {
+        admin_only(ctx)?;
+        admin::reject(ctx.data()?, ctx.data()?, ctx.data()?, ctx.data()?, &id)
+            .await
+            .map_err(error)?;
+        Ok(true)
+    }
+
+    async fn create_box(&self, ctx: &Context<'_>, name: String) -> Result<BoxInfo> {
+        admin_only(ctx)?;
+        let record = admin::create_box(ctx.data()?, ctx.data()?, ctx.data()?, &name)
+            .await
+            .map_err(error)?;
+        Ok(BoxInfo::new(record.name))
+    }
+
+    /// Rename a box, redirecting the old name. Returns the number of items
+    /// moved.
+    async fn rename_box(&self, ctx: &Context<'_>, name: String, to: String) -> Result<u64> {
+        admin_only(ctx)?;
+        admin::rename_box(ctx.data()?, ctx.data()?, ctx.data()?, &name, &to)
+            .await
+            .map_err(error)
+    }
+
+    /// Move the items of a box into another one. Returns the number of items
+    /// moved.
+    async fn merge_box(&self, ctx: &Context<'_>, name: String, into: String) -> Result<u64> {
+        admin_only(ctx)?;
+        admin::merge_box(ctx.data()?, ctx.data()?, ctx.data()?, &name, &into)
+            .await
+            .map_err(error)
+    }
+
+    /// Archive a box, or take it out of the archive with `archived: false`
+    async fn archive_box(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default = true)] archived: bool,
+    ) -> Result<bool> {
+        admin_only(ctx)?;
+        admin::set_archived(ctx.data()?, ctx.data()?, ctx.data()?, &name, archived)
+            .await
+            .map_err(error)?;
+        Ok(archived)
+    }
+
+    /// Delete a box with its items. Returns the number of items deleted.
+    async fn delete_box(&self, ctx: &Context<'_>, name: String) -> Result<u64> {
+        admin_only(ctx)?;
+        admin::delete_box(
+            ctx.data()?,
+            ctx.data()?,
+            ctx.data()?,
+            ctx.data()?,
+            ctx.data()?,
+            &name,
+        )
+        .await
+        .map_err(error)
+    }
+}
@@ -0,0 +1,200 @@
+//! The whole service on a throwaway database, for end-to-end tests submitting
+//! mail over SMTP and reading the web routes. Built with `--features
+//! test-support`; the tests are ignored unless asked for with `--ignored`,
+//! and then fail without `TEST_MONGO_CON_STR`.
+
+use std::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use mongodb::Client;
+use tokio::{net::TcpStream, time::sleep};
+
+use crate::{
+    client::http_client,
+    config::{self, get_config, Config},
+    selftest, serve,
+};
+
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct Harness {
+    smtp_port: u16,
+    web_port: u16,
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .expect("a port should be free")
+        .port()
+}
+
+impl Harness {
+    /// Configure the service before anything reads the configuration, run it
+    /// on its own runtime so that it outlives the test, and wait till it
+    /// accepts mail
+    pub async fn start() -> Harness {
+        let con_str = std::env::var("TEST_MONGO_CON_STR")
+            .expect("TEST_MONGO_CON_STR should point to a MongoDB for end-to-end tests");
+        let (smtp_port, web_port) = (free_port(), free_port());
+        let config = Config {
+            mongo_con_str: con_str,
+            mongo_db_name: format!("mail-list-rss-test-{}", nanoid::nanoid!(8)),
+            smtp_port,
+            web_port,
+            domain: "example.com".to_owned(),
+            web_domain: "example.com".to_owned(),
+            ..Config::from_env().expect("configuration should be valid")
+        };
+        config::install(config).expect("end-to-end tests should run alone, with --ignored");
+
+        std::thread::spawn(|| {
+            let config = get_config();
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("runtime should build");
+            let res = runtime.block_on(async {
+                let client = Client::with_uri_str(&config.mongo_con_str).await?;
+                serve(&client.database(&config.mongo_db_name)).await
+            });
+            if let Err(e) = res {
+                panic!("Harness stopped: {}", e)
+            }
+        });
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", smtp_port)).await.is_err() {
+            assert!(
+                started.elapsed() < READY_TIMEOUT,
+                "SMTP server not listening"
+            );
+            sleep(POLL_INTERVAL).await;
+        }
+        Harness {
+            smtp_port,
+            web_port,
+        }
+    }
+
+    /// Drop the database of the run
+    pub async fn teardown(&self) -> Result<()> {
+        let config = get_config();
+        Client::with_uri_str(&config.mongo_con_str)
+            .await?
+            .database(&config.mongo_db_name)
+            .drop(None)
+            .await?;
+        Ok(())
+    }
+
+    /// Submit a message with CRLF line endings over SMTP
+    pub async fn send(&self, rcpt: &str, message: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.smtp_port)).await?;
+        selftest::submit(&mut stream, "sender@example.org", rcpt, message).await
+    }
+
+    /// Body of a web route
+    pub async fn fetch(&self, path: &str) -> Result<String> {
+        let url = format!("http://127.0.0.1:{}{}", self.web_port, path);
+        Ok(http_client()
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    /// Body of a web route once it has `needle`, as items are stored in the
+    /// background
+    pub async fn wait_for(&self, path: &str, needle: &str) -> Result<String> {
+        let started = Instant::now();
+        loop {
+            // The web server may still be starting
+            if let Ok(body) = self.fetch(path).await {
+                if body.contains(needle) {
+                    return Ok(body);
+                }
+            }
+            if started.elapsed() > READY_TIMEOUT {
+                bail!("{} not found on {}", needle, path);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Message from a list to `to`
+pub fn message(to: &str, subject: &str, html: &str) -> String {
+    format!(
+        "From: Example List <list@example.org>\r\nTo: <{to}>\r\nSubject: {subject}\r\n\
+         Message-ID: <{id}@example.org>\r\nContent-Type: text/html; charset=utf-8\r\n\
+         \r\n{html}\r\n",
+        to = to,
+        subject = subject,
+        id = nanoid::nanoid!(16),
+        html = html,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::AssertUnwindSafe;
+
+    use futures::FutureExt;
+
+    use super::*;
+
+    async fn rss(harness: &Harness) {
+        let subject = format!("Weekly {}", nanoid::nanoid!(8));
+        let raw = message("news@example.com", &subject, "<p>Hello</p>");
+        harness.send("news@example.com", &raw).await.unwrap();
+
+        let rss = harness
+            .wait_for("/rss/news@example.com", &subject)
+            .await
+            .unwrap();
+        assert!(rss.contains("<rss"));
+        let list = harness.fetch("/feeds").await.unwrap();
+        assert!(list.contains(&subject));
+    }
+
+    async fn plus_address(harness: &Harness) {
+        let subject = format!("Tagged {}", nanoid::nanoid!(8));
+        let raw = message("letters+rust@example.com", &subject, "<p>Hi</p>");
+        harness
+            .send("letters+rust@example.com", &raw)
+            .await
+            .unwrap();
+
+        harness
+            .wait_for("/rss/letters@example.com", &subject)
+            .await
+            .unwrap();
+        let list = harness.fetch("/feeds?address_tag=rust").await.unwrap();
+        assert!(list.contains(&subject));
+    }
+
+    /// Scenarios share one service, so they run in one test dropping the
+    /// database at the end, failed or not
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a MongoDB at TEST_MONGO_CON_STR"]
+    async fn test_end_to_end() {
+        let harness = Harness::start().await;
+        let res = AssertUnwindSafe(async {
+            rss(&harness).await;
+            plus_address(&harness).await;
+        })
+        .catch_unwind()
+        .await;
+        harness.teardown().await.unwrap();
+        if let Err(e) = res {
+            std::panic::resume_unwind(e);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+/// Header section of a raw message, up to the first empty line
+pub fn header_section(raw: &str) -> &str {
+    let end = [raw.find("\r\n\r\n"), raw.find("\n\n")]
+        .into_iter()
+        .flatten()
+        .min();
+    end.map_or(raw, |x| &raw[..x])
+}
+
+/// All headers of a raw message in order, as `(name, unfolded value)`
+pub fn parse_headers(raw: &str) -> Vec<(String, String)> {
+    let mut ret = Vec::<(String, String)>::new();
+    for line in header_section(raw).lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = ret.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            ret.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    ret
+}
+
+/// Values of every header named `name`, case-insensitive
+pub fn header_values(raw: &str, name: &str) -> Vec<String> {
+    parse_headers(raw)
+        .into_iter()
+        .filter(|(x, _)| x.eq_ignore_ascii_case(name))
+        .map(|(_, x)| x)
+        .collect()
+}
+
+/// Addresses in an address list header value, e.g.
+/// `"Doe, Jane" <jane@example.com>, bob@example.com`
+pub fn addresses(value: &str) -> Vec<String> {
+    let mut ret = vec![];
+    let mut rest = value;
+    while let Some(at) = rest.find('@') {
+        let start = rest[..at]
+            .rfind(|x: char| x.is_whitespace() || "<,;:\"".contains(x))
+            .map_or(0, |x| x + 1);
+        let end = rest[at..]
+            .find(|x: char| x.is_whitespace() || ">,;\"".contains(x))
+            .map_or(rest.len(), |x| at + x);
+        ret.push(rest[start..end].to_owned());
+        rest = &rest[end..];
+    }
+    ret
+}
+
+/// Kind of automatic message, from `Auto-Submitted` (e.g. `auto-replied`) or
+/// `Precedence` (`bulk` or `junk`) headers
+pub fn auto_submitted(raw: &str) -> Option<String> {
+    let headers = parse_headers(raw);
+    let value = |name: &str| {
+        headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .and_then(|(_, x)| x.split(|c: char| c == ';' || c.is_whitespace()).next())
+            .map(str::to_ascii_lowercase)
+    };
+    value("Auto-Submitted")
+        .filter(|x| x != "no")
+        .or_else(|| value("Precedence").filter(|x| x == "bulk" || x == "junk"))
+}
+
+/// First value of header `name` in `headers`, case-insensitive
+fn first<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(name))
+        .map(|(_, x)| x.as_str())
+}
+
+/// Result of an authentication `method` (`spf`, `dkim`, `dmarc`...) recorded
+/// in `Authentication-Results` by the receiving server, or in `Received-SPF`
+pub fn auth_result(headers: &[(String, String)], method: &str) -> Option<String> {
+    let ret = headers
+        .iter()
+        .filter(|(x, _)| x.eq_ignore_ascii_case("Authentication-Results"))
+        .flat_map(|(_, x)| x.split(';'))
+        .filter_map(|x| x.trim().split_once('='))
+        .find(|(x, _)| x.trim().eq_ignore_ascii_case(method))
+        .and_then(|(_, x)| x.split_whitespace().next())
+        .map(str::to_ascii_lowercase);
+    match ret {
+        None if method.eq_ignore_ascii_case("spf") => first(headers, "Received-SPF")
+            .and_then(|x| x.split_whitespace().next())
+            .map(str::to_ascii_lowercase),
+        x => x,
+    }
+}
+
+/// Score given by a spam filter, from `X-Spam-Score` or `X-Spam-Status`
+pub fn spam_score(headers: &[(String, String)]) -> Option<f64> {
+    first(headers, "X-Spam-Score")
+        .and_then(|x| x.trim().parse().ok())
+        .or_else(|| {
+            first(headers, "X-Spam-Status")?
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .find_map(|x| x.strip_prefix("score="))?
+                .parse()
+                .ok()
+        })
+}
+
+/// Whether a spam filter flagged the message, from `X-Spam-Flag` or
+/// `X-Spam-Status`
+pub fn spam_flagged(headers: &[(String, String)]) -> Option<bool> {
+    let value = first(headers, "X-Spam-Flag").or_else(|| first(headers, "X-Spam-Status"))?;
+    let word = value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .next()?
+        .to_ascii_lowercase();
+    match word.as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_values() {
+        const RAW: &str = include_str!("../sample.txt");
+        let received = header_values(RAW, "received");
+        assert_eq!(received.len(), 6);
+        assert!(received[0].starts_with(
+            "from compute2.internal (compute2.nyi.internal [10.202.2.46]) by sloti45n30"
+        ));
+        assert_eq!(header_values(RAW, "X-Spam-score"), vec!["0.0"]);
+    }
+
+    #[test]
+    fn test_checks() {
+        let headers = parse_headers(include_str!("../sample.txt"));
+        assert_eq!(auth_result(&headers, "spf"), Some("softfail".to_owned()));
+        assert_eq!(auth_result(&headers, "dkim"), Some("none".to_owned()));
+        assert_eq!(auth_result(&headers, "dmarc"), Some("none".to_owned()));
+        assert_eq!(spam_score(&headers), Some(0.0));
+
+        let headers = parse_headers("X-Spam-Status: Yes, score=7.3 required=5.0\n\n");
+        assert_eq!(spam_score(&headers), Some(7.3));
+        assert_eq!(spam_flagged(&headers), Some(true));
+    }
+
+    #[test]
+    fn test_addresses() {
+        assert_eq!(
+            addresses("\"Doe, Jane\" <jane@example.com>, bob@example.org"),
+            vec!["jane@example.com", "bob@example.org"]
+        );
+        assert!(addresses("undisclosed-recipients:;").is_empty());
+    }
+
+    #[test]
+    fn test_auto_submitted() {
+        assert_eq!(
+            auto_submitted("Auto-Submitted: auto-replied (vacation)\r\n\r\nbody"),
+            Some("auto-replied".to_owned())
+        );
+        assert_eq!(auto_submitted("Auto-Submitted: no\n\n"), None);
+        assert_eq!(
+            auto_submitted("Precedence: Bulk\n\n"),
+            Some("bulk".to_owned())
+        );
+        assert_eq!(auto_submitted("Precedence: list\n\n"), None);
+    }
+}
@@ -0,0 +1,219 @@
+//! JSON Feed 1.1 (https://jsonfeed.org/version/1.1) rendering of items, and
+//! picking a feed format by the `Accept` header
+
+use serde::Serialize;
+
+use crate::{channel::Channel, config::get_config, db::Feed};
+
+const VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+/// Formats feeds can be served in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    JsonFeed,
+}
+
+impl FeedFormat {
+    /// Short name, e.g. to tell representations apart in `ETag`s
+    pub fn name(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "rss",
+            FeedFormat::Atom => "atom",
+            FeedFormat::JsonFeed => "json",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/xml; charset=utf-8",
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::JsonFeed => "application/feed+json; charset=utf-8",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/rss+xml" | "application/xml" | "text/xml" => Some(FeedFormat::Rss),
+            "application/atom+xml" => Some(FeedFormat::Atom),
+            "application/feed+json" | "application/json" => Some(FeedFormat::JsonFeed),
+            _ => None,
+        }
+    }
+
+    /// Preferred format of an `Accept` header, RSS unless another one is
+    /// ranked strictly higher
+    pub fn negotiate(accept: &str) -> Self {
+        let mut best = (FeedFormat::Rss, 0.0);
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let mut q = params
+                .filter_map(|x| x.strip_prefix("q="))
+                .find_map(|x| x.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_type.as_str() {
+                "*/*" | "application/*" => {
+                    // Specific types win ties
+                    q -= 0.001;
+                    Some(FeedFormat::Rss)
+                }
+                x => FeedFormat::from_media_type(x),
+            };
+            if let Some(format) = format {
+                if q > best.1 {
+                    best = (format, q);
+                }
+            }
+        }
+        best.0
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+    version: &'a str,
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'a str>,
+    home_page_url: String,
+    feed_url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hubs: Vec<JsonHub<'a>>,
+    items: Vec<JsonItem>,
+}
+
+#[derive(Serialize)]
+struct JsonHub<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    url: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonItem {
+    id: String,
+    url: String,
+    title: String,
+    content_html: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+    date_published: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_modified: Option<String>,
+    authors: Vec<JsonAuthor>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<JsonAttachment>,
+}
+
+#[derive(Serialize)]
+struct JsonAttachment {
+    url: String,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    size_in_bytes: usize,
+}
+
+#[derive(Serialize)]
+struct JsonAuthor {
+    name: String,
+}
+
+impl Feed {
+    fn into_json_item(self) -> JsonItem {
+        let url = self.link();
+        let feed = self.redacted();
+        let attachments = feed
+            .attachments
+            .iter()
+            .enumerate()
+            .map(|(n, x)| JsonAttachment {
+                url: feed.attachment_url(n),
+                mime_type: x.mime_type().to_owned(),
+                title: x.name.clone(),
+                size_in_bytes: x.size,
+            })
+            .collect();
+        JsonItem {
+            url,
+            title: feed.display_title(),
+            date_published: feed.created_at.to_rfc3339(),
+            date_modified: feed.last_seen_at.map(|x| x.to_rfc3339()),
+            id: feed.guid().to_owned(),
+            content_html: feed.content,
+            summary: feed.summary,
+            authors: vec![JsonAuthor { name: feed.author }],
+            tags: feed.tags,
+            attachments,
+        }
+    }
+}
+
+/// Render `items` as a JSON Feed, `links` being `(rel, href)` of the page as
+/// for Atom
+pub fn render_json_feed(channel: &Channel, links: &[(&str, String)], items: Vec<Feed>) -> String {
+    let config = get_config();
+    let link = |rel: &str| {
+        links
+            .iter()
+            .find(|(x, _)| *x == rel)
+            .map(|(_, x)| x.as_str())
+    };
+    let feed = JsonFeed {
+        version: VERSION,
+        title: &channel.title,
+        description: channel.description.as_deref(),
+        language: channel.language.as_deref(),
+        home_page_url: format!("https://{}/", config.web_domain),
+        feed_url: link("self").unwrap_or_default(),
+        next_url: link("next"),
+        icon: channel.image.as_deref(),
+        hubs: link("hub")
+            .map(|url| JsonHub {
+                kind: "WebSub",
+                url,
+            })
+            .into_iter()
+            .collect(),
+        items: items.into_iter().map(Feed::into_json_item).collect(),
+    };
+    serde_json::to_string(&feed).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(FeedFormat::negotiate(""), FeedFormat::Rss);
+        assert_eq!(FeedFormat::negotiate("*/*"), FeedFormat::Rss);
+        assert_eq!(
+            FeedFormat::negotiate("application/atom+xml"),
+            FeedFormat::Atom
+        );
+        assert_eq!(
+            FeedFormat::negotiate("application/rss+xml;q=0.5, application/feed+json"),
+            FeedFormat::JsonFeed
+        );
+        assert_eq!(
+            FeedFormat::negotiate("application/atom+xml;q=0.9, */*;q=0.1"),
+            FeedFormat::Atom
+        );
+        assert_eq!(
+            FeedFormat::negotiate("*/*, application/atom+xml"),
+            FeedFormat::Atom
+        );
+        assert_eq!(FeedFormat::negotiate("text/html"), FeedFormat::Rss);
+    }
+}
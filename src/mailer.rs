@@ -0,0 +1,52 @@
+use anyhow::{bail, Result};
+use lettre::{
+    address::Envelope, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Tokio1Executor,
+};
+use once_cell::sync::Lazy;
+use tracing::warn;
+
+use crate::config::get_config;
+
+type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+static TRANSPORT: Lazy<Option<Transport>> = Lazy::new(|| {
+    let config = get_config();
+    let host = config.smarthost.as_deref()?;
+    let builder = if config.smarthost_starttls {
+        match Transport::starttls_relay(host) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: "Mailer", "Bad smarthost {}: {}", host, e);
+                return None;
+            }
+        }
+    } else {
+        Transport::builder_dangerous(host)
+    };
+    let builder = builder.port(config.smarthost_port);
+    let builder = match (&config.smarthost_username, &config.smarthost_password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+    Some(builder.build())
+});
+
+/// Whether a smarthost is configured for outgoing mail
+pub fn enabled() -> bool {
+    TRANSPORT.is_some()
+}
+
+/// Send a pre-built message through the smarthost. `from: None` is the null
+/// reverse path, used for bounces.
+pub async fn send_raw(from: Option<&str>, to: &str, message: &[u8]) -> Result<()> {
+    let transport = match TRANSPORT.as_ref() {
+        Some(x) => x,
+        None => bail!("No smarthost configured"),
+    };
+    let envelope = Envelope::new(from.map(|x| x.parse()).transpose()?, vec![to.parse()?])?;
+    transport.send_raw(&envelope, message).await?;
+    Ok(())
+}
@@ -1,20 +1,80 @@
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use crossfire::mpsc::{bounded_tx_blocking_rx_future, RxFuture, SharedSenderBRecvF, TxBlocking};
-use mongodb::{options::ClientOptions, Client};
-use tracing::{info, Level};
+use mongodb::{options::ClientOptions, Client, Database};
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+mod admin;
+mod alert;
+mod analytics;
+mod apikeys;
+mod atom;
+mod audit;
+mod auth;
+mod blob;
+mod boxes;
+mod channel;
+mod client;
 mod config;
 mod db;
+mod digest;
+mod dsn;
+mod epub;
+mod error;
+mod events;
+mod export;
+mod favicon;
+mod feedcache;
+mod fulltext;
+mod graphql;
+#[cfg(all(test, feature = "test-support"))]
+mod harness;
+mod headers;
+mod jsonfeed;
+mod mailer;
+mod metrics;
+mod milter;
+mod mirror;
+mod notify;
+mod openapi;
+mod opml;
+mod pdf;
+mod pipeline;
+mod proxy;
+mod ratelimit;
+mod reader;
+mod registry;
 mod rule;
+mod selftest;
+mod sieve;
+mod sitemap;
 mod smtp;
+mod stats;
+mod store;
+mod summarize;
+mod templates;
+mod text;
+mod tls;
+mod tombstone;
+mod translate;
+mod validator;
+mod verify;
 mod web;
+mod websub;
+mod welcome;
 
+use analytics::Hit;
+use apikeys::ApiKey;
+use audit::AuditEntry;
+use blob::Chunk;
 use config::*;
 use db::*;
+use favicon::Favicon;
+use registry::BoxRecord;
 use smtp::*;
+use tombstone::Tombstone;
 use web::*;
 
 type TX = TxBlocking<Feed, SharedSenderBRecvF>;
@@ -22,13 +82,16 @@ type RX = RxFuture<Feed, SharedSenderBRecvF>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)?;
+    let subscriber = FmtSubscriber::builder().with_max_level(Level::DEBUG);
+    // Not in `Config`, as reading it logs
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing::subscriber::set_global_default(subscriber.json().finish())?,
+        Ok("text") | Err(_) => tracing::subscriber::set_global_default(subscriber.finish())?,
+        Ok(x) => bail!("Bad LOG_FORMAT {}, expecting text or json", x),
+    }
 
     let config = get_config();
+    templates::init();
 
     let mongo_client = {
         let mut opt = ClientOptions::parse(&config.mongo_con_str).await?;
@@ -44,12 +107,84 @@ async fn main() -> Result<()> {
     info!(db = db_names.as_str(), "Databases");
 
     let db = mongo_client.database(&config.mongo_db_name);
+    if std::env::args().nth(1).as_deref() == Some("restore-from-s3") {
+        let feeds = db.collection::<Feed>("feed");
+        let blobs = db.collection::<Chunk>("blobs");
+        let tombstones = db.collection::<Tombstone>("tombstones");
+        if let Err(e) = ensure_indexes(&feeds).await {
+            warn!(target: "Database", "Error creating indexes: {}", e)
+        }
+        let restored = mirror::restore(&feeds, &blobs, &tombstones).await?;
+        info!(target: "Mirror", "Restored {} items", restored);
+        return Ok(());
+    }
+
+    serve(&db).await
+}
+
+/// Run the SMTP and web servers on `db` until the SMTP server stops
+async fn serve(db: &Database) -> Result<()> {
+    let config = get_config();
     let feeds = db.collection::<Feed>("feed");
+    let audit = db.collection::<AuditEntry>("audit");
+    let hits = db.collection::<Hit>("hits");
+    let favicons = db.collection::<Favicon>("favicons");
+    let blobs = db.collection::<Chunk>("blobs");
+    let registry = db.collection::<BoxRecord>("boxes");
+    let keys = db.collection::<ApiKey>("api_keys");
+    let tombstones = db.collection::<Tombstone>("tombstones");
+
+    if let Err(e) = ensure_indexes(&feeds).await {
+        warn!(target: "Database", "Error creating indexes: {}", e)
+    }
+    if let Err(e) = blob::ensure_indexes(&blobs).await {
+        warn!(target: "Database", "Error creating indexes: {}", e)
+    }
+    if let Err(e) = tombstone::ensure_indexes(&tombstones).await {
+        warn!(target: "Database", "Error creating indexes: {}", e)
+    }
+    if let Err(e) = registry::load(&registry).await {
+        warn!(target: "Database", "Error loading boxes: {}", e)
+    }
+    if let Err(e) = apikeys::load(&keys).await {
+        warn!(target: "Database", "Error loading API keys: {}", e)
+    }
+    if let Err(e) = schedule_delayed(feeds.clone()).await {
+        warn!(target: "Database", "Error loading delayed items: {}", e)
+    }
+    let (header_feeds, raw_blobs) = (feeds.clone(), blobs.clone());
+    tokio::spawn(async move {
+        if let Err(e) = backfill_headers(header_feeds.clone()).await {
+            warn!(target: "Database", "Error storing headers: {}", e)
+        }
+        // Headers are parsed from the raw source, so it has to be in place
+        if let Err(e) = blob::backfill_raw(header_feeds, raw_blobs).await {
+            warn!(target: "Database", "Error moving raw sources: {}", e)
+        }
+    });
 
     let (tx, rx) = bounded_tx_blocking_rx_future::<Feed>(10);
 
-    let bg = tokio::spawn(database_servo(feeds.clone(), rx));
-    let server = tokio::spawn(web_server(feeds));
+    let index_feeds = feeds.clone();
+    tokio::spawn(async move {
+        if let Err(e) = fulltext::backfill(index_feeds).await {
+            warn!(target: "Search", "Error building index: {}", e)
+        }
+    });
+
+    let pipeline = pipeline::Pipeline::new(feeds.clone(), blobs.clone(), &config.pipeline)?;
+    let bg = tokio::spawn(database_servo(pipeline, rx));
+    let server = tokio::spawn(web_server(
+        feeds,
+        audit,
+        hits,
+        favicons,
+        blobs,
+        registry,
+        keys,
+        tombstones,
+        tx.clone(),
+    ));
 
     smtp_server(tx).await?;
 
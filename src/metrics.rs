@@ -0,0 +1,64 @@
+//! Gauges of the channel from SMTP to the database worker, served on
+//! `/metrics` in the Prometheus text format
+
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use once_cell::sync::Lazy;
+
+/// Enqueue times of messages received but not stored yet, by item id, as
+/// messages may fail to be handed over or be stored out of order
+static QUEUED: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Default::default);
+
+/// Count message `id` handed to the channel, including while waiting for room
+pub fn enqueued(id: &str) {
+    QUEUED
+        .lock()
+        .expect("queue gauge poisoned")
+        .insert(id.to_owned(), Instant::now());
+}
+
+/// Held while message `.0` is handed over or stored, counting it done when
+/// dropped
+pub struct Dequeue(pub String);
+
+impl Drop for Dequeue {
+    fn drop(&mut self) {
+        QUEUED.lock().expect("queue gauge poisoned").remove(&self.0);
+    }
+}
+
+/// Prometheus exposition of the gauges
+pub fn render() -> String {
+    let (depth, oldest) = {
+        let queued = QUEUED.lock().expect("queue gauge poisoned");
+        let oldest = queued
+            .values()
+            .min()
+            .map_or(0.0, |x| x.elapsed().as_secs_f64());
+        (queued.len(), oldest)
+    };
+    format!(
+        "# HELP mail_list_rss_ingest_queue_depth Messages received over SMTP and not stored yet\n\
+        # TYPE mail_list_rss_ingest_queue_depth gauge\n\
+        mail_list_rss_ingest_queue_depth {}\n\
+        # HELP mail_list_rss_ingest_queue_oldest_seconds Age of the oldest message not stored yet\n\
+        # TYPE mail_list_rss_ingest_queue_oldest_seconds gauge\n\
+        mail_list_rss_ingest_queue_oldest_seconds {:.3}\n",
+        depth, oldest
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dequeue_by_id() {
+        enqueued("metrics-first");
+        enqueued("metrics-second");
+        drop(Dequeue("metrics-second".to_owned()));
+        let queued = QUEUED.lock().unwrap();
+        assert!(queued.contains_key("metrics-first"));
+        assert!(!queued.contains_key("metrics-second"));
+    }
+}
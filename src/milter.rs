@@ -0,0 +1,327 @@
+//! Client side of the milter protocol (version 6), passing incoming mail
+//! through external filters such as rspamd or OpenDKIM before acceptance
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use tracing::{debug, info, warn};
+
+use crate::{config::get_config, headers::parse_headers};
+
+const VERSION: u32 = 6;
+/// Milters may only add headers
+const SMFIF_ADDHDRS: u32 = 0x01;
+
+// Steps a milter may ask to skip
+const NO_CONNECT: u32 = 0x01;
+const NO_HELO: u32 = 0x02;
+const NO_MAIL: u32 = 0x04;
+const NO_RCPT: u32 = 0x08;
+const NO_BODY: u32 = 0x10;
+const NO_HDRS: u32 = 0x20;
+const NO_EOH: u32 = 0x40;
+const NO_UNKNOWN: u32 = 0x100;
+const NO_DATA: u32 = 0x200;
+// Steps a milter may ask not to reply to
+const NR_HDR: u32 = 0x80;
+const NR_CONN: u32 = 0x1000;
+const NR_HELO: u32 = 0x2000;
+const NR_MAIL: u32 = 0x4000;
+const NR_RCPT: u32 = 0x8000;
+const NR_DATA: u32 = 0x10000;
+const NR_EOH: u32 = 0x40000;
+const NR_BODY: u32 = 0x80000;
+
+/// Protocol flags offered during negotiation
+const PROTOCOL: u32 = NO_CONNECT
+    | NO_HELO
+    | NO_MAIL
+    | NO_RCPT
+    | NO_BODY
+    | NO_HDRS
+    | NO_EOH
+    | NO_UNKNOWN
+    | NO_DATA
+    | NR_HDR
+    | NR_CONN
+    | NR_HELO
+    | NR_MAIL
+    | NR_RCPT
+    | NR_DATA
+    | NR_EOH
+    | NR_BODY;
+
+const MAX_PACKET: usize = 1 << 20;
+const BODY_CHUNK: usize = 65535;
+
+/// SMTP session the message arrived in
+pub struct Envelope<'a> {
+    pub ip: Option<IpAddr>,
+    pub helo: &'a str,
+    pub from: &'a str,
+    pub rcpts: &'a [String],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Accept, adding these headers
+    Accept(Vec<(String, String)>),
+    Reject(String),
+    TempFail(String),
+    Discard,
+}
+
+/// Pass the message through every milter in `MILTERS` in order, stopping at
+/// the first one that does not accept it. Unreachable milters are skipped.
+pub fn check(envelope: &Envelope, data: &[u8]) -> Verdict {
+    let mut added = vec![];
+    for address in &get_config().milters {
+        match Session::connect(address).and_then(|mut x| x.run(envelope, data)) {
+            Ok(Verdict::Accept(headers)) => added.extend(headers),
+            Ok(verdict) => {
+                info!(target: "Milter", milter = address.as_str(), "{:?}", verdict);
+                return verdict;
+            }
+            Err(e) => warn!(target: "Milter", "Error talking to {}: {}", address, e),
+        }
+    }
+    Verdict::Accept(added)
+}
+
+/// Null-terminated strings
+fn cstr(parts: &[&str]) -> Vec<u8> {
+    parts
+        .iter()
+        .flat_map(|x| x.bytes().chain(std::iter::once(0)))
+        .collect()
+}
+
+fn split_cstr(data: &[u8]) -> Vec<String> {
+    data.split(|x| *x == 0)
+        .filter(|x| !x.is_empty())
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+        .collect()
+}
+
+/// Body of a raw message, as sent, after the first empty line
+fn body(data: &[u8]) -> &[u8] {
+    let find = |sep: &[u8]| {
+        data.windows(sep.len())
+            .position(|x| x == sep)
+            .map(|x| (x, x + sep.len()))
+    };
+    [find(b"\r\n\r\n"), find(b"\n\n")]
+        .into_iter()
+        .flatten()
+        .min()
+        .map_or(&[][..], |(_, start)| &data[start..])
+}
+
+struct Session {
+    stream: TcpStream,
+    /// Flags requested by the milter
+    protocol: u32,
+}
+
+impl Session {
+    fn connect(address: &str) -> Result<Self> {
+        let timeout = Duration::from_secs(get_config().milter_timeout);
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Cannot resolve {}", address))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut ret = Self {
+            stream,
+            protocol: 0,
+        };
+        let options = [VERSION, SMFIF_ADDHDRS, PROTOCOL]
+            .iter()
+            .flat_map(|x| x.to_be_bytes())
+            .collect::<Vec<_>>();
+        ret.send(b'O', &options)?;
+        let (command, data) = ret.receive()?;
+        if command != b'O' || data.len() < 12 {
+            bail!("Unexpected reply to negotiation");
+        }
+        let protocol = u32::from_be_bytes(data[8..12].try_into()?);
+        if protocol & !PROTOCOL != 0 {
+            bail!("Unsupported protocol flags {:#x}", protocol & !PROTOCOL);
+        }
+        ret.protocol = protocol;
+        Ok(ret)
+    }
+
+    fn send(&mut self, command: u8, data: &[u8]) -> Result<()> {
+        let len = (data.len() + 1) as u32;
+        self.stream.write_all(&len.to_be_bytes())?;
+        self.stream.write_all(&[command])?;
+        self.stream.write_all(data)?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<(u8, Vec<u8>)> {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_PACKET {
+            bail!("Invalid packet length {}", len);
+        }
+        let mut buf = vec![0; len];
+        self.stream.read_exact(&mut buf)?;
+        let data = buf.split_off(1);
+        Ok((buf[0], data))
+    }
+
+    fn run(&mut self, envelope: &Envelope, data: &[u8]) -> Result<Verdict> {
+        let mut added = vec![];
+        let verdict = self.steps(envelope, data, &mut added);
+        // The conversation is over anyway
+        let _ = self.send(b'Q', &[]);
+        Ok(match verdict? {
+            None | Some(Verdict::Accept(_)) => Verdict::Accept(added),
+            Some(x) => x,
+        })
+    }
+
+    /// Walk through the SMTP session, returning the verdict if the milter
+    /// made one before the end of message
+    fn steps(
+        &mut self,
+        envelope: &Envelope,
+        data: &[u8],
+        added: &mut Vec<(String, String)>,
+    ) -> Result<Option<Verdict>> {
+        let config = get_config();
+        let raw = String::from_utf8_lossy(data);
+
+        let ip = envelope.ip.map(|x| x.to_string()).unwrap_or_default();
+        let family = match envelope.ip {
+            Some(IpAddr::V4(_)) => b'4',
+            Some(IpAddr::V6(_)) => b'6',
+            None => b'U',
+        };
+        let mut connect = cstr(&[ip.as_str()]);
+        connect.push(family);
+        if envelope.ip.is_some() {
+            connect.extend(0u16.to_be_bytes());
+            connect.extend(cstr(&[ip.as_str()]));
+        }
+
+        let mut packets = vec![
+            (b'C', connect, NO_CONNECT, NR_CONN),
+            (b'H', cstr(&[envelope.helo]), NO_HELO, NR_HELO),
+            (
+                b'M',
+                cstr(&[format!("<{}>", envelope.from.trim_matches(&['<', '>'][..])).as_str()]),
+                NO_MAIL,
+                NR_MAIL,
+            ),
+        ];
+        for rcpt in envelope.rcpts {
+            let rcpt = format!("<{}>", rcpt.trim_matches(&['<', '>'][..]));
+            packets.push((b'R', cstr(&[rcpt.as_str()]), NO_RCPT, NR_RCPT));
+        }
+        packets.push((b'T', vec![], NO_DATA, NR_DATA));
+        for (name, value) in parse_headers(&raw) {
+            packets.push((
+                b'L',
+                cstr(&[name.as_str(), value.as_str()]),
+                NO_HDRS,
+                NR_HDR,
+            ));
+        }
+        packets.push((b'N', vec![], NO_EOH, NR_EOH));
+        for chunk in body(data).chunks(BODY_CHUNK) {
+            packets.push((b'B', chunk.to_vec(), NO_BODY, NR_BODY));
+        }
+        packets.push((b'E', vec![], 0, 0));
+
+        // Macros commonly expected by milters, sent along with the connect step
+        let mut macros = vec![b'C'];
+        macros.extend(cstr(&[
+            "j",
+            config.domain.as_str(),
+            "{daemon_name}",
+            "mail-list-rss",
+        ]));
+        self.send(b'D', &macros)?;
+
+        for (command, data, skip, no_reply) in packets {
+            if self.protocol & skip != 0 {
+                continue;
+            }
+            self.send(command, &data)?;
+            if self.protocol & no_reply != 0 {
+                continue;
+            }
+            if let Some(verdict) = self.reply(added)? {
+                return Ok(Some(verdict));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read replies to a step, collecting added headers, until the milter
+    /// continues (`None`) or decides
+    fn reply(&mut self, added: &mut Vec<(String, String)>) -> Result<Option<Verdict>> {
+        loop {
+            let (command, data) = self.receive()?;
+            let verdict = match command {
+                b'c' => return Ok(None),
+                b'a' => Verdict::Accept(vec![]),
+                b'r' => Verdict::Reject("Rejected by milter".to_owned()),
+                b't' => Verdict::TempFail("Temporarily rejected by milter".to_owned()),
+                b'd' | b'q' => Verdict::Discard,
+                b'y' => {
+                    let text = split_cstr(&data).join(" ");
+                    match text.starts_with('4') {
+                        true => Verdict::TempFail(text),
+                        false => Verdict::Reject(text),
+                    }
+                }
+                b'h' | b'i' => {
+                    // Insertions carry an index first, headers are always prepended
+                    let skip = if command == b'i' { 4 } else { 0 };
+                    if let [name, value, ..] =
+                        split_cstr(data.get(skip..).unwrap_or_default()).as_slice()
+                    {
+                        added.push((name.to_owned(), value.to_owned()));
+                    }
+                    continue;
+                }
+                other => {
+                    debug!(target: "Milter", "Ignoring action {}", other as char);
+                    continue;
+                }
+            };
+            return Ok(Some(verdict));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cstr() {
+        let data = cstr(&["X-Spam", "yes"]);
+        assert_eq!(data, b"X-Spam\0yes\0");
+        assert_eq!(split_cstr(&data), vec!["X-Spam", "yes"]);
+    }
+
+    #[test]
+    fn test_body() {
+        assert_eq!(body(b"Subject: x\r\n\r\n\xffbody\r\n"), b"\xffbody\r\n");
+        assert_eq!(body(b"Subject: x\n\nbody"), b"body");
+        assert_eq!(body(b"Subject: x"), b"");
+    }
+}
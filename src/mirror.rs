@@ -0,0 +1,333 @@
+//! Copies of received messages in S3-compatible object storage, see
+//! `S3_BUCKET`, and restoring the archive from them with `restore-from-s3`.
+//! Each item is kept as `<id>.eml`, its raw source, and `<id>.json`, the item
+//! as stored without its content.
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use mail_parser::Message;
+use mongodb::bson::{doc, Document};
+use reqwest::{Method, Url};
+use ring::{digest, hmac};
+use tracing::{info, warn};
+
+use crate::{
+    blob::{self, Blobs},
+    client::http_client,
+    config::get_config,
+    db::{Feed, Feeds},
+    fulltext,
+    text::percent_encode,
+    tombstone::{self, Tombstones},
+};
+
+pub fn enabled() -> bool {
+    let config = get_config();
+    config.s3_endpoint.is_some() && config.s3_bucket.is_some()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, data))
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+/// Key deriving AWS Signature Version 4 signatures of a day
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    hmac_sha256(&key, "aws4_request")
+}
+
+/// Object path in the bucket, each segment encoded as signatures expect
+fn object_path(bucket: &str, key: &str) -> String {
+    std::iter::once(bucket)
+        .chain(key.split('/'))
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Send a request signed with AWS Signature Version 4, with path-style URLs
+/// so that any S3-compatible storage works
+async fn request(
+    method: Method,
+    key: &str,
+    query: &[(&str, &str)],
+    content_type: &str,
+    body: Vec<u8>,
+) -> Result<reqwest::Response> {
+    let config = get_config();
+    let (endpoint, bucket) = match (&config.s3_endpoint, &config.s3_bucket) {
+        (Some(endpoint), Some(bucket)) => (endpoint, bucket),
+        _ => bail!("S3_ENDPOINT and S3_BUCKET are not set"),
+    };
+    let endpoint = Url::parse(endpoint)?;
+    let host = match (endpoint.host_str(), endpoint.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        _ => bail!("S3_ENDPOINT has no host"),
+    };
+    let path = format!(
+        "{}/{}",
+        endpoint.path().trim_end_matches('/'),
+        object_path(bucket, key)
+    )
+    .trim_end_matches('/')
+    .to_owned();
+    let mut query = query
+        .iter()
+        .map(|(k, v)| format!("{}={}", percent_encode(k), percent_encode(v)))
+        .collect::<Vec<_>>();
+    query.sort();
+    let query = query.join("&");
+
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = sha256_hex(&body);
+    let canonical_request = format!(
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n\
+         host;x-amz-content-sha256;x-amz-date\n{}",
+        method, path, query, host, payload_hash, amz_date, payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, config.s3_region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let secret = config.s3_secret_key.as_deref().unwrap_or_default();
+    let signature = hex::encode(hmac_sha256(
+        &signing_key(secret, &date, &config.s3_region, "s3"),
+        &string_to_sign,
+    ));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, \
+         SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        config.s3_access_key.as_deref().unwrap_or_default(),
+        scope,
+        signature
+    );
+
+    let mut url = format!("{}://{}{}", endpoint.scheme(), host, path);
+    if !query.is_empty() {
+        url = format!("{}?{}", url, query);
+    }
+    let mut req = http_client()
+        .request(method, url)
+        .header("x-amz-content-sha256", payload_hash)
+        .header("x-amz-date", amz_date)
+        .header("Authorization", authorization);
+    if !body.is_empty() {
+        req = req.header("Content-Type", content_type).body(body);
+    }
+    Ok(req.send().await?.error_for_status()?)
+}
+
+fn key(id: &str, extension: &str) -> String {
+    format!("{}{}.{}", get_config().s3_prefix, id, extension)
+}
+
+/// Upload the raw source and metadata of a stored item
+pub async fn put(feed: &Feed) -> Result<()> {
+    let sidecar = Feed {
+        raw: String::new(),
+        content: String::new(),
+        text: String::new(),
+        headers: vec![],
+        overflow: false,
+        raw_chunked: false,
+        ..feed.clone()
+    };
+    request(
+        Method::PUT,
+        &key(&feed.id, "eml"),
+        &[],
+        "message/rfc822",
+        feed.raw.as_bytes().to_vec(),
+    )
+    .await?;
+    request(
+        Method::PUT,
+        &key(&feed.id, "json"),
+        &[],
+        "application/json",
+        serde_json::to_vec(&sidecar)?,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Upload an item in the background, if enabled
+pub fn mirror(feed: &Feed) {
+    if !enabled() {
+        return;
+    }
+    let feed = feed.clone();
+    tokio::spawn(async move {
+        if let Err(e) = put(&feed).await {
+            warn!(target: "Mirror", "Error mirroring {}: {}", feed.id, e)
+        }
+    });
+}
+
+/// Delete the copies of an item
+pub async fn delete(id: &str) -> Result<()> {
+    request(Method::DELETE, &key(id, "eml"), &[], "", vec![]).await?;
+    request(Method::DELETE, &key(id, "json"), &[], "", vec![]).await?;
+    Ok(())
+}
+
+/// Delete the copies of removed items in the background, if enabled
+pub fn unmirror(ids: &[String]) {
+    if !enabled() || ids.is_empty() {
+        return;
+    }
+    let ids = ids.to_vec();
+    tokio::spawn(async move {
+        for id in ids {
+            if let Err(e) = delete(&id).await {
+                warn!(target: "Mirror", "Error deleting the copy of {}: {}", id, e)
+            }
+        }
+    });
+}
+
+/// Text of every `<name>` element, enough for listings of S3 whose values
+/// are plain ids
+fn xml_values<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    xml.split(&open)
+        .skip(1)
+        .filter_map(|x| x.split_once(&close).map(|(value, _)| value))
+        .collect()
+}
+
+/// Ids of mirrored items
+async fn list() -> Result<Vec<String>> {
+    let prefix = &get_config().s3_prefix;
+    let mut ids = vec![];
+    let mut token = None;
+    loop {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+        if let Some(token) = &token {
+            query.push(("continuation-token", token.as_str()));
+        }
+        let xml = request(Method::GET, "", &query, "", vec![])
+            .await?
+            .text()
+            .await?;
+        ids.extend(
+            xml_values(&xml, "Key")
+                .into_iter()
+                .filter_map(|x| x.strip_prefix(prefix.as_str())?.strip_suffix(".json"))
+                .map(ToOwned::to_owned),
+        );
+        token = match xml_values(&xml, "NextContinuationToken").first() {
+            Some(x) => Some(x.replace("&amp;", "&")),
+            None => break,
+        };
+    }
+    Ok(ids)
+}
+
+async fn get(key: &str) -> Result<Vec<u8>> {
+    Ok(request(Method::GET, key, &[], "", vec![])
+        .await?
+        .bytes()
+        .await?
+        .to_vec())
+}
+
+/// Store every mirrored item missing from the database, rebuilt from its raw
+/// source and metadata. Items deleted on purpose, which have tombstones, stay
+/// deleted. Returns the number of items restored.
+pub async fn restore(feeds: &Feeds, blobs: &Blobs, tombstones: &Tombstones) -> Result<u64> {
+    let ids = list().await?;
+    info!(target: "Mirror", "{} items in the bucket", ids.len());
+    let mut restored = 0;
+    for id in ids {
+        if feeds.count_documents(doc! { "id": &id }, None).await? > 0
+            || tombstone::find(tombstones, &id).await?.is_some()
+        {
+            continue;
+        }
+        let sidecar: Feed = serde_json::from_slice(&get(&key(&id, "json")).await?)?;
+        let raw = get(&key(&id, "eml")).await?;
+        let parsed = match Message::parse(&raw) {
+            Some(x) => x,
+            None => {
+                warn!(target: "Mirror", "Cannot parse {}, skipped", id);
+                continue;
+            }
+        };
+        let fresh = Feed::from_message(
+            &raw,
+            parsed,
+            sidecar.from_box.clone(),
+            sidecar.address_tag.clone(),
+        )?;
+        let mut feed = Feed {
+            raw: fresh.raw,
+            content: fresh.content,
+            text: fresh.text,
+            headers: fresh.headers,
+            overflow: false,
+            raw_chunked: false,
+            ..sidecar
+        };
+        blob::overflow(blobs, &mut feed).await?;
+        blob::put_raw(blobs, &mut feed).await?;
+        if let Err(e) = feeds
+            .clone_with_type::<Document>()
+            .insert_one(blob::stored(&feed)?, None)
+            .await
+        {
+            blob::remove(blobs, &[feed.id.clone()]).await?;
+            return Err(e.into());
+        }
+        if let Some(index) = fulltext::index() {
+            tokio::task::block_in_place(|| index.add(&[feed]))?;
+        }
+        restored += 1;
+        if restored % 1000 == 0 {
+            info!(target: "Mirror", "Restored {} items", restored);
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // Example of the AWS documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_xml_values() {
+        let xml = "<ListBucketResult><Contents><Key>a.json</Key></Contents>\
+                   <Contents><Key>b.eml</Key></Contents></ListBucketResult>";
+        assert_eq!(xml_values(xml, "Key"), vec!["a.json", "b.eml"]);
+        assert!(xml_values(xml, "NextContinuationToken").is_empty());
+    }
+}
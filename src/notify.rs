@@ -0,0 +1,187 @@
+//! Notifications of new items to webhooks and Telegram chats, set per box with
+//! `notify` in `BOX_FILE`
+
+use chrono::Utc;
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use crate::{
+    client::http_client,
+    config::get_config,
+    db::{published, Feed, Feeds},
+    selftest,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Target {
+    /// POST to `url`, the body being `template` or else the item as JSON
+    Webhook {
+        url: String,
+        #[serde(default)]
+        template: Option<String>,
+    },
+    /// Message to `chat_id` by the bot with `token`
+    Telegram {
+        token: String,
+        chat_id: String,
+        #[serde(default)]
+        template: Option<String>,
+    },
+}
+
+const TELEGRAM_TEMPLATE: &str = "{{title}}\n{{author}}\n{{link}}";
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'a str,
+    id: &'a str,
+    #[serde(rename = "box")]
+    from_box: &'a str,
+    title: &'a str,
+    author: &'a str,
+    link: &'a str,
+    created_at: String,
+}
+
+/// Values of template placeholders
+fn fields(feed: &Feed, link: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("id", feed.id.clone()),
+        ("box", feed.from_box.clone()),
+        ("title", feed.title.clone()),
+        ("author", feed.author.clone()),
+        ("link", link.to_owned()),
+        ("created_at", feed.created_at.to_rfc3339()),
+        ("tag", feed.address_tag.clone().unwrap_or_default()),
+    ]
+}
+
+/// Replace `{{name}}` placeholders with values of `fields` passed through
+/// `escape`. Unknown names are replaced with nothing.
+fn render(template: &str, fields: &[(&str, String)], escape: impl Fn(&str) -> String) -> String {
+    let mut ret = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(x) => start + x,
+            None => break,
+        };
+        ret.push_str(&rest[..start]);
+        let name = rest[start + 2..end].trim();
+        if let Some((_, value)) = fields.iter().find(|(k, _)| *k == name) {
+            ret.push_str(&escape(value));
+        }
+        rest = &rest[end + 2..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/// Contents of a JSON string, without the quotes
+fn escape_json(s: &str) -> String {
+    let quoted = serde_json::to_string(s).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_owned()
+}
+
+/// Notify the targets of the box of `feed`, without waiting for them. Items
+/// held for moderation and self-test probes are left out, and items published
+/// later are announced then if not deleted meanwhile. Delays do not outlive
+/// the process.
+pub fn send(feeds: &Feeds, feed: &Feed) {
+    let targets = match get_config().box_config(&feed.from_box) {
+        Some(x) if !x.notify.is_empty() => &x.notify,
+        _ => return,
+    };
+    if feed.pending || selftest::is_probe_feed(feed) {
+        return;
+    }
+    let delay = match feed.publish_at.and_then(|x| (x - Utc::now()).to_std().ok()) {
+        Some(x) => x,
+        None => return deliver(targets, feed),
+    };
+    let (feeds, feed) = (feeds.clone(), feed.clone());
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        match feeds
+            .count_documents(published(doc! { "id": &feed.id }), None)
+            .await
+        {
+            Ok(0) => {}
+            Ok(_) => deliver(targets, &feed),
+            Err(e) => warn!(target: "Notify", "Error finding {}: {}", feed.id, e),
+        }
+    });
+}
+
+fn deliver(targets: &[Target], feed: &Feed) {
+    let link = feed.link();
+    let fields = fields(feed, &link);
+    for target in targets {
+        let req = match target {
+            Target::Webhook {
+                url,
+                template: Some(template),
+            } => http_client()
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(render(template, &fields, escape_json)),
+            Target::Webhook {
+                url,
+                template: None,
+            } => http_client().post(url).json(&Event {
+                event: "new_item",
+                id: &feed.id,
+                from_box: &feed.from_box,
+                title: &feed.title,
+                author: &feed.author,
+                link: &link,
+                created_at: feed.created_at.to_rfc3339(),
+            }),
+            Target::Telegram {
+                token,
+                chat_id,
+                template,
+            } => {
+                let text = render(
+                    template.as_deref().unwrap_or(TELEGRAM_TEMPLATE),
+                    &fields,
+                    |x| x.to_owned(),
+                );
+                http_client()
+                    .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+                    .json(&json!({ "chat_id": chat_id, "text": text }))
+            }
+        };
+        let from_box = feed.from_box.clone();
+        tokio::spawn(async move {
+            if let Err(e) = req.send().await.and_then(|x| x.error_for_status()) {
+                warn!(target: "Notify", "Error notifying for {}: {}", from_box, e)
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let fields = vec![
+            ("title", "Issue \"42\"".to_owned()),
+            ("link", "https://example.com/feeds/abc".to_owned()),
+        ];
+        assert_eq!(
+            render("{{ title }} at {{link}}{{nope}}", &fields, |x| x.to_owned()),
+            "Issue \"42\" at https://example.com/feeds/abc"
+        );
+        assert_eq!(
+            render(r#"{"text": "{{title}}"}"#, &fields, escape_json),
+            r#"{"text": "Issue \"42\""}"#
+        );
+        assert_eq!(render("{{title", &fields, escape_json), "{{title");
+    }
+}
@@ -0,0 +1,577 @@
+//! OpenAPI 3.0 description of the web routes, served on `/openapi.json` for
+//! generating clients. Written by hand alongside the handlers in `web`, so
+//! changes to their JSON shapes should be mirrored here.
+
+use serde_json::{json, Map, Value};
+
+use crate::config::get_config;
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+fn nullable(kind: &str) -> Value {
+    json!({ "type": kind, "nullable": true })
+}
+
+fn object(properties: Value) -> Value {
+    let required = properties
+        .as_object()
+        .map(|x| x.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": string(),
+    })
+}
+
+fn query(name: &str, kind: Value, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "description": description, "schema": kind })
+}
+
+fn content(media_type: &str, schema: Value) -> Value {
+    json!({ media_type: { "schema": schema } })
+}
+
+fn ok(description: &str, media_type: &str, body: Value) -> Value {
+    json!({ "200": { "description": description, "content": content(media_type, body) } })
+}
+
+fn ok_json(body: Value) -> Value {
+    ok("Success", "application/json", body)
+}
+
+fn ok_text() -> Value {
+    ok("Success", "text/plain", string())
+}
+
+/// Operation answering `responses`, with errors as `Error`
+fn operation(summary: &str, parameters: Vec<Value>, responses: Value) -> Value {
+    let mut responses = responses;
+    responses["default"] = json!({
+        "description": "Error",
+        "content": content("application/json", schema("Error")),
+    });
+    json!({ "summary": summary, "parameters": parameters, "responses": responses })
+}
+
+fn with_body(mut operation: Value, body: Value) -> Value {
+    operation["requestBody"] = json!({ "required": true, "content": body });
+    operation
+}
+
+fn key() -> Value {
+    path_param("key", "Item id")
+}
+
+fn box_name() -> Value {
+    path_param("box", "Box address")
+}
+
+fn time_range() -> Vec<Value> {
+    vec![
+        query(
+            "since",
+            string(),
+            "Only items created from this time, RFC 3339 or unix milliseconds",
+        ),
+        query(
+            "until",
+            string(),
+            "Only items created before this time, RFC 3339 or unix milliseconds",
+        ),
+    ]
+}
+
+fn feed_params() -> Vec<Value> {
+    let mut ret = vec![
+        query("page", integer(), "1-based page number, see RFC 5005"),
+        query("limit", integer(), "Items per page, up to MAX_PER_PAGE"),
+        query(
+            "skip",
+            integer(),
+            "Newest items left out before the first page",
+        ),
+        query(
+            "archive",
+            integer(),
+            "0-based archive number from the oldest items",
+        ),
+        query("lang", string(), "Language of stored translations to use"),
+        query("tag", string(), "Only items with this tag"),
+        query("author", string(), "Only items sent from this address"),
+    ];
+    ret.extend(time_range());
+    ret
+}
+
+fn feed(summary: &str, media_type: &str, in_box: bool) -> Value {
+    let mut params = feed_params();
+    if in_box {
+        params.insert(0, box_name());
+        params.push(query(
+            "token",
+            string(),
+            "Feed token of the box, instead of credentials",
+        ));
+    }
+    let responses = ok("Feed", media_type, string());
+    json!({ "get": operation(summary, params, responses) })
+}
+
+fn paths() -> Value {
+    let mut list_params = vec![
+        query("limit", integer(), "Items to return"),
+        query("skip", integer(), "Items to skip"),
+        query(
+            "after",
+            string(),
+            "`next` of the previous page, instead of `skip`",
+        ),
+        query(
+            "address_tag",
+            string(),
+            "Only items sent to the plus-addressed recipient with this tag",
+        ),
+        query("tag", string(), "Only items with this tag"),
+        query("author", string(), "Only items sent from this address"),
+        query(
+            "sort",
+            json!({ "type": "string", "enum": ["created_at", "title", "author"] }),
+            "Field to sort by, created_at unless given",
+        ),
+        query(
+            "order",
+            json!({ "type": "string", "enum": ["asc", "desc"] }),
+            "desc for created_at and asc for the others unless given",
+        ),
+    ];
+    list_params.extend(time_range());
+    let tags_body = content("application/json", array(string()));
+
+    json!({
+        "/feeds": {
+            "get": operation("List published items, newest first unless sorted otherwise", list_params, ok_json(schema("List"))),
+        },
+        "/feeds/{key}": {
+            "get": operation(
+                "Item as a page",
+                vec![
+                    key(),
+                    query("lang", string(), "Language to show the item in"),
+                    query(
+                        "variant",
+                        json!({ "type": "string", "enum": ["html", "text", "reader"] }),
+                        "Representation of the item",
+                    ),
+                    query("bare", string(), "1 for the message alone, without the page around it"),
+                ],
+                ok("Item", "text/html", string()),
+            ),
+            "patch": with_body(
+                operation("Correct the title, box or tags of an item", vec![key()], ok_json(schema("ItemDetail"))),
+                content("application/json", schema("ItemEdit")),
+            ),
+            "delete": operation("Delete an item", vec![key()], ok_json(schema("Erased"))),
+        },
+        "/feeds/{key}/{slug}": {
+            "get": operation(
+                "Item as a page at its permalink, redirecting there from other slugs",
+                vec![
+                    key(),
+                    path_param("slug", "Slug of the title, as in `link` of feeds"),
+                    query("lang", string(), "Language to show the item in"),
+                    query("bare", string(), "1 for the message alone, without the page around it"),
+                ],
+                ok("Item", "text/html", string()),
+            ),
+        },
+        "/feeds/{key}/json": {
+            "get": operation("Item with its headers and attachments", vec![key()], ok_json(schema("ItemDetail"))),
+        },
+        "/feeds/{key}/attachments/{n}": {
+            "get": operation(
+                "Attachment of an item, as linked from enclosures",
+                vec![key(), path_param("n", "0-based number of the attachment")],
+                ok("Attachment", "application/octet-stream", string()),
+            ),
+        },
+        "/feeds/{key}/raw": {
+            "get": operation("Raw source of an item", vec![key()], ok("Message", "message/rfc822", string())),
+        },
+        "/feeds/{key}/eml": {
+            "get": operation(
+                "Raw source of an item as a file download named after its subject",
+                vec![key()],
+                ok("Message", "message/rfc822", string()),
+            ),
+        },
+        "/feeds/{key}/tags": {
+            "get": operation("Tags of an item", vec![key()], ok_json(array(string()))),
+            "put": with_body(
+                operation("Replace the tags of an item", vec![key()], ok_json(array(string()))),
+                tags_body,
+            ),
+        },
+        "/search": {
+            "get": operation(
+                "Items containing every term, best matches first",
+                vec![
+                    json!({ "name": "q", "in": "query", "required": true, "schema": string() }),
+                    query("limit", integer(), "Items to return"),
+                    query("skip", integer(), "Items to skip"),
+                ],
+                ok_json(schema("List")),
+            ),
+        },
+        "/rss": feed("RSS feed of all boxes", "application/xml", false),
+        "/rss/{box}": feed("RSS feed of a box", "application/xml", true),
+        "/atom": feed("Atom feed of all boxes", "application/atom+xml", false),
+        "/atom/{box}": feed("Atom feed of a box", "application/atom+xml", true),
+        "/boxes": {
+            "get": operation("Names of boxes", vec![], ok_json(array(string()))),
+        },
+        "/boxes/{box}": {
+            "get": operation(
+                "Items of a box as a page, newest first",
+                [vec![box_name()], feed_params()].concat(),
+                ok("Page", "text/html", string()),
+            ),
+        },
+        "/tags": {
+            "get": operation("Tags with their number of items", vec![], ok_json(array(schema("TagCount")))),
+        },
+        "/events": {
+            "get": operation(
+                "Server-sent `item` events as items are stored",
+                vec![query("box", string(), "Only items of this box")],
+                ok("Event stream of NewItem", "text/event-stream", string()),
+            ),
+        },
+        "/ingest": {
+            "post": with_body(
+                operation(
+                    "Handle a raw message as if received through SMTP",
+                    vec![query("box", string(), "Box to file the message into")],
+                    json!({
+                        "200": { "description": "Discarded", "content": content("application/json", schema("Ingested")) },
+                        "202": { "description": "Accepted", "content": content("application/json", schema("Ingested")) },
+                    }),
+                ),
+                content("message/rfc822", string()),
+            ),
+        },
+        "/admin/feeds/{key}": {
+            "get": operation(
+                "Item with the SMTP envelope it was received with",
+                vec![key()],
+                ok_json(schema("AdminDetail")),
+            ),
+        },
+        "/admin/boxes": {
+            "get": operation("Box records", vec![], ok_json(array(schema("BoxRecord")))),
+            "post": with_body(
+                operation(
+                    "Create a box ahead of mail",
+                    vec![],
+                    json!({ "201": { "description": "Created", "content": content("application/json", schema("BoxRecord")) } }),
+                ),
+                content("application/json", object(json!({ "name": string() }))),
+            ),
+        },
+        "/admin/boxes/{box}": {
+            "delete": operation("Delete a box with its items", vec![box_name()], ok_json(schema("Erased"))),
+        },
+        "/admin/boxes/{box}/rename": {
+            "post": with_body(
+                operation("Rename a box, redirecting the old name", vec![box_name()], ok_json(schema("Moved"))),
+                content("application/json", object(json!({ "to": string() }))),
+            ),
+        },
+        "/admin/boxes/{box}/merge": {
+            "post": with_body(
+                operation("Move the items of a box into another one", vec![box_name()], ok_json(schema("Moved"))),
+                content("application/json", object(json!({ "into": string() }))),
+            ),
+        },
+        "/admin/boxes/{box}/archive": {
+            "post": operation("Stop accepting mail for a box", vec![box_name()], ok_text()),
+            "delete": operation("Accept mail for an archived box again", vec![box_name()], ok_text()),
+        },
+        "/admin/boxes/{box}/token": {
+            "post": operation("Give a box a new feed token", vec![box_name()], ok_json(schema("FeedToken"))),
+            "delete": operation("Revoke the feed token of a box", vec![box_name()], ok_text()),
+        },
+        "/admin/keys": {
+            "get": operation("API keys, without the keys themselves", vec![], ok_json(array(schema("ApiKey")))),
+            "post": with_body(
+                operation(
+                    "Create an API key",
+                    vec![],
+                    json!({ "201": { "description": "Created, with the key shown only once", "content": content("application/json", schema("CreatedKey")) } }),
+                ),
+                content("application/json", object(json!({ "name": string(), "scopes": array(schema("Scope")) }))),
+            ),
+        },
+        "/admin/keys/{id}": {
+            "delete": operation("Revoke an API key", vec![path_param("id", "Key id")], ok_text()),
+        },
+        "/admin/pending": {
+            "get": operation(
+                "Items waiting for approval, oldest first",
+                vec![query("box", string(), "Only items of this box")],
+                ok_json(schema("List")),
+            ),
+        },
+        "/admin/pending/{key}": {
+            "delete": operation("Reject a pending item", vec![key()], ok_text()),
+        },
+        "/admin/pending/{key}/approve": {
+            "post": operation("Approve a pending item", vec![key()], ok_text()),
+        },
+        "/admin/senders/{address}": {
+            "delete": operation(
+                "Delete every item sent from an address",
+                vec![path_param("address", "Sender address")],
+                ok_json(schema("Erased")),
+            ),
+        },
+        "/admin/verify": {
+            "get": operation("State of the latest archive check", vec![], ok_json(schema("VerifyStatus"))),
+            "post": operation(
+                "Start checking the archive in the background",
+                vec![query("repair", json!({ "type": "boolean" }), "Also repair what can be")],
+                ok_json(schema("VerifyStatus")),
+            ),
+        },
+        "/admin/selftest": {
+            "get": operation("Send a probe through SMTP and time its stages", vec![], ok_json(schema("SelftestReport"))),
+        },
+    })
+}
+
+fn schemas() -> Value {
+    let item_detail = json!({
+        "id": string(),
+        "title": string(),
+        "author": string(),
+        "from_box": string(),
+        "created_at": string(),
+        "last_seen_at": nullable("string"),
+        "publish_at": nullable("string"),
+        "occurrences": integer(),
+        "address_tag": nullable("string"),
+        "auto_submitted": nullable("string"),
+        "summary": nullable("string"),
+        "tags": array(string()),
+        "translations": array(string()),
+        "permalink": string(),
+        "guid": string(),
+        "content": string(),
+        "overflow": { "type": "boolean" },
+        "text": string(),
+        "headers": array(object(json!({ "name": string(), "value": string() }))),
+        "attachments": array(object(json!({
+            "name": nullable("string"),
+            "content_type": nullable("string"),
+            "size": integer(),
+        }))),
+        "spam": object(json!({ "flagged": nullable("boolean"), "score": nullable("number") })),
+        "auth": object(json!({
+            "spf": nullable("string"),
+            "dkim": nullable("string"),
+            "dmarc": nullable("string"),
+        })),
+    });
+    let mut admin_detail = item_detail.clone();
+    admin_detail["envelope"] = json!({
+        "allOf": [object(json!({
+            "mail_from": string(),
+            "rcpt_to": array(string()),
+            "client_ip": nullable("string"),
+            "helo": string(),
+            "tls": { "type": "boolean" },
+        }))],
+        "nullable": true,
+    });
+    let ids = array(string());
+    let api_key = json!({
+        "id": string(),
+        "name": string(),
+        "scopes": array(schema("Scope")),
+        "created_at": { "type": "integer", "description": "Unix milliseconds" },
+        "last_used_at": { "type": "integer", "nullable": true, "description": "Unix milliseconds, to the minute" },
+    });
+    let mut created_key = api_key.clone();
+    created_key["key"] = string();
+
+    json!({
+        "Error": object(json!({ "status": integer(), "error": string() })),
+        "Summary": {
+            "type": "object",
+            "properties": {
+                "title": string(),
+                "create_at": string(),
+                "id": string(),
+                "snippet": string(),
+                "summary": string(),
+                "tags": array(string()),
+            },
+            "required": ["title", "create_at", "id"],
+        },
+        "List": {
+            "type": "object",
+            "description": "Pagination fields are only given on /feeds",
+            "properties": {
+                "items": array(schema("Summary")),
+                "total": integer(),
+                "limit": integer(),
+                "skip": integer(),
+                "has_more": { "type": "boolean" },
+                "next": string(),
+            },
+            "required": ["items"],
+        },
+        "ItemDetail": object(item_detail),
+        "AdminDetail": object(admin_detail),
+        "ItemEdit": {
+            "type": "object",
+            "properties": {
+                "title": string(),
+                "from_box": string(),
+                "tags": array(string()),
+            },
+        },
+        "NewItem": object(json!({ "id": string(), "title": string(), "box": string(), "link": string() })),
+        "TagCount": object(json!({ "_id": string(), "count": integer() })),
+        "BoxRecord": {
+            "type": "object",
+            "properties": {
+                "name": string(),
+                "created_at": { "type": "integer", "description": "Unix milliseconds" },
+                "archived": { "type": "boolean" },
+                "renamed_to": nullable("string"),
+                "token": nullable("string"),
+            },
+            "required": ["name", "created_at", "archived", "renamed_to", "token"],
+        },
+        "FeedToken": object(json!({ "token": string(), "rss": string(), "atom": string() })),
+        "Scope": { "type": "string", "enum": ["read", "ingest", "admin"] },
+        "ApiKey": object(api_key),
+        "CreatedKey": object(created_key),
+        "Erased": object(json!({ "deleted": integer() })),
+        "Moved": object(json!({ "moved": integer() })),
+        "Ingested": object(json!({
+            "result": { "type": "string", "enum": ["accepted", "discarded"] },
+        })),
+        "VerifyReport": object(json!({
+            "scanned": integer(),
+            "missing_raw": ids,
+            "undecodable": ids,
+            "duplicate_ids": ids,
+            "orphaned_chunks": ids,
+            "missing_chunks": ids,
+            "repaired": integer(),
+        })),
+        "VerifyStatus": object(json!({
+            "running": { "type": "boolean" },
+            "repair": { "type": "boolean" },
+            "started_at": nullable("string"),
+            "finished_at": nullable("string"),
+            "report": schema("VerifyReport"),
+            "error": nullable("string"),
+        })),
+        "SelftestReport": {
+            "type": "object",
+            "properties": {
+                "ok": { "type": "boolean" },
+                "stages": array(object(json!({ "name": string(), "millis": integer() }))),
+                "error": string(),
+            },
+            "required": ["ok", "stages"],
+        },
+    })
+}
+
+/// The whole document
+pub fn spec() -> Value {
+    let config = get_config();
+    let mut security = Map::new();
+    if config.username.is_some() {
+        security.insert(
+            "basicAuth".to_owned(),
+            json!({ "type": "http", "scheme": "basic" }),
+        );
+        security.insert(
+            "apiKey".to_owned(),
+            json!({ "type": "http", "scheme": "bearer" }),
+        );
+    }
+    let mut ret = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "mail-list-rss",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": format!("https://{}", config.web_domain) }],
+        "paths": paths(),
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": security,
+        },
+    });
+    if config.username.is_some() {
+        ret["security"] = json!([{ "basicAuth": [] }, { "apiKey": [] }]);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every `$ref` should point to a schema
+    #[test]
+    fn test_refs() {
+        fn refs(value: &Value, out: &mut Vec<String>) {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(x)) = map.get("$ref") {
+                        out.push(x.clone());
+                    }
+                    map.values().for_each(|x| refs(x, out));
+                }
+                Value::Array(items) => items.iter().for_each(|x| refs(x, out)),
+                _ => {}
+            }
+        }
+        let schemas = schemas();
+        let mut found = vec![];
+        refs(&paths(), &mut found);
+        refs(&schemas, &mut found);
+        assert!(!found.is_empty());
+        for x in found {
+            let name = x.trim_start_matches("#/components/schemas/");
+            assert!(schemas.get(name).is_some(), "{} is not defined", x);
+        }
+    }
+}
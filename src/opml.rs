@@ -0,0 +1,32 @@
+//! OPML 2.0 (http://opml.org/spec2.opml) list of box feeds, for importing all
+//! of them into a reader at once
+
+use chrono::Utc;
+
+use crate::{channel::Channel, config::get_config, text::escape_xml};
+
+/// Render an outline per box, pointing at its feed
+pub fn render_opml(boxes: &[String]) -> String {
+    let config = get_config();
+    let mut ret = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    ret.push_str("<opml version=\"2.0\">\n<head>\n");
+    ret.push_str(&format!(
+        "<title>{}</title>\n",
+        escape_xml(&config.channel_title)
+    ));
+    ret.push_str(&format!(
+        "<dateCreated>{}</dateCreated>\n",
+        Utc::now().to_rfc2822()
+    ));
+    ret.push_str("</head>\n<body>\n");
+    for name in boxes {
+        ret.push_str(&format!(
+            "<outline type=\"rss\" text=\"{title}\" title=\"{title}\" xmlUrl=\"{}\" htmlUrl=\"{}\"/>\n",
+            escape_xml(&format!("https://{}/rss/{}", config.web_domain, name)),
+            escape_xml(&format!("https://{}/", config.web_domain)),
+            title = escape_xml(&Channel::of(Some(name)).title),
+        ));
+    }
+    ret.push_str("</body>\n</opml>\n");
+    ret
+}
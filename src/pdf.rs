@@ -0,0 +1,323 @@
+//! Minimal PDF writer for printable copies of items. Text is set in the
+//! standard Helvetica fonts, so characters outside WinAnsi become `?`.
+
+use crate::{
+    config::get_config,
+    db::Feed,
+    text::{html_paragraphs, obfuscate_emails},
+};
+
+/// A4 in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+const LEADING: f32 = 1.4;
+
+/// Advance widths of Helvetica for ASCII 32..=126, in 1/1000 em
+const HELVETICA: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+#[derive(Clone, Copy)]
+enum Font {
+    Regular,
+    Bold,
+}
+
+impl Font {
+    fn name(self) -> &'static str {
+        match self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+        }
+    }
+
+    fn width(self, text: &str, size: f32) -> f32 {
+        let em = text
+            .chars()
+            .map(|x| match x as u32 {
+                x @ 32..=126 => HELVETICA[x as usize - 32] as f32,
+                _ => 556.0,
+            })
+            .sum::<f32>();
+        let scale = match self {
+            Font::Regular => 1.0,
+            Font::Bold => 1.06,
+        };
+        em * scale * size / 1000.0
+    }
+}
+
+struct Line {
+    font: Font,
+    size: f32,
+    text: String,
+    /// Extra space above the line
+    gap: f32,
+}
+
+/// A document of wrapped lines flowing over as many pages as needed
+pub struct Document {
+    title: String,
+    lines: Vec<Line>,
+}
+
+impl Document {
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_owned(),
+            lines: vec![],
+        }
+    }
+
+    fn push(&mut self, font: Font, size: f32, text: &str, gap: f32) {
+        for (idx, line) in wrap(font, size, text).into_iter().enumerate() {
+            self.lines.push(Line {
+                font,
+                size,
+                text: line,
+                gap: if idx == 0 { gap } else { 0.0 },
+            });
+        }
+    }
+
+    pub fn heading(&mut self, text: &str) {
+        self.push(Font::Bold, 16.0, text, 0.0);
+    }
+
+    pub fn meta(&mut self, text: &str) {
+        self.push(Font::Regular, 9.0, text, 0.0);
+    }
+
+    pub fn paragraph(&mut self, text: &str) {
+        self.push(Font::Regular, 11.0, text, 6.0);
+    }
+
+    /// Content streams of each page
+    fn layout(&self) -> Vec<Vec<u8>> {
+        let mut pages = vec![];
+        let mut page = vec![];
+        let mut y = PAGE_HEIGHT - MARGIN;
+        for line in &self.lines {
+            let height = line.size * LEADING + line.gap;
+            if y - height < MARGIN && !page.is_empty() {
+                pages.push(std::mem::take(&mut page));
+                y = PAGE_HEIGHT - MARGIN;
+            }
+            y -= height;
+            page.extend(
+                format!(
+                    "BT /{} {} Tf {} {:.2} Td (",
+                    line.font.name(),
+                    line.size,
+                    MARGIN,
+                    y
+                )
+                .into_bytes(),
+            );
+            page.extend(encode(&line.text));
+            page.extend(b") Tj ET\n");
+        }
+        pages.push(page);
+        pages
+    }
+
+    pub fn render(&self) -> Vec<u8> {
+        let pages = self.layout();
+        let mut writer = Writer::default();
+
+        // Objects 1 to 5 are fixed, pages and their contents follow in pairs
+        let kids = (0..pages.len())
+            .map(|x| format!("{} 0 R", 6 + x * 2))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writer.object(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+        writer.object(
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids, pages.len()).into_bytes(),
+        );
+        for font in ["Helvetica", "Helvetica-Bold"] {
+            writer.object(
+                format!(
+                    "<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>",
+                    font
+                )
+                .into_bytes(),
+            );
+        }
+        let mut info = b"<< /Producer (mail-list-rss) /Title (".to_vec();
+        info.extend(encode(&self.title));
+        info.extend(b") >>");
+        writer.object(info);
+
+        for (idx, content) in pages.into_iter().enumerate() {
+            writer.object(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                    /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                    PAGE_WIDTH,
+                    PAGE_HEIGHT,
+                    7 + idx * 2
+                )
+                .into_bytes(),
+            );
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend(content);
+            stream.extend(b"\nendstream");
+            writer.object(stream);
+        }
+        writer.finish()
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    out: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl Writer {
+    fn object(&mut self, body: Vec<u8>) {
+        if self.out.is_empty() {
+            self.out.extend(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n");
+        }
+        self.offsets.push(self.out.len());
+        self.out
+            .extend(format!("{} 0 obj\n", self.offsets.len()).into_bytes());
+        self.out.extend(body);
+        self.out.extend(b"\nendobj\n");
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let xref = self.out.len();
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            table.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        table.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info 5 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1,
+            xref
+        ));
+        self.out.extend(table.into_bytes());
+        self.out
+    }
+}
+
+/// Break `text` into lines fitting the page width, cutting overlong words
+fn wrap(font: Font, size: f32, text: &str) -> Vec<String> {
+    let max = PAGE_WIDTH - MARGIN * 2.0;
+    let mut ret = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let candidate = match line.is_empty() {
+            true => word.to_owned(),
+            false => format!("{} {}", line, word),
+        };
+        if font.width(&candidate, size) <= max {
+            line = candidate;
+            continue;
+        }
+        if !line.is_empty() {
+            ret.push(std::mem::take(&mut line));
+        }
+        for x in word.chars() {
+            if font.width(&format!("{}{}", line, x), size) > max && !line.is_empty() {
+                ret.push(std::mem::take(&mut line));
+            }
+            line.push(x);
+        }
+    }
+    if !line.is_empty() || ret.is_empty() {
+        ret.push(line);
+    }
+    ret
+}
+
+/// Text as the content of a PDF literal string in WinAnsi
+fn encode(text: &str) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(text.len());
+    for x in text.chars() {
+        match x {
+            '\\' | '(' | ')' => ret.extend([b'\\', x as u8]),
+            ' '..='~' => ret.push(x as u8),
+            '\u{a0}'..='\u{ff}' => ret.push(x as u32 as u8),
+            '…' => ret.push(0x85),
+            '‘' => ret.push(0x91),
+            '’' => ret.push(0x92),
+            '“' => ret.push(0x93),
+            '”' => ret.push(0x94),
+            '•' => ret.push(0x95),
+            '–' => ret.push(0x96),
+            '—' => ret.push(0x97),
+            '€' => ret.push(0x80),
+            _ => ret.push(b'?'),
+        }
+    }
+    ret
+}
+
+/// Printable copy of an item: title, metadata and body text
+pub fn render_feed(feed: &Feed) -> Vec<u8> {
+    let config = get_config();
+    let mut doc = Document::new(&feed.title);
+    doc.heading(&feed.display_title());
+    doc.meta(&format!("From: {}", feed.author));
+    doc.meta(&format!("Date: {}", feed.created_at.to_rfc2822()));
+    doc.meta(&format!("Box: {}", feed.from_box));
+    doc.meta(&format!(
+        "Link: https://{}/feeds/{}",
+        config.web_domain, feed.id
+    ));
+
+    let mut paragraphs = html_paragraphs(&feed.content);
+    if paragraphs.is_empty() {
+        // Unlike `content`, the text is not redacted with the feed
+        let text = match config.obfuscate_emails {
+            true => obfuscate_emails(&feed.plain_text()),
+            false => feed.plain_text(),
+        };
+        paragraphs = text
+            .split("\n\n")
+            .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|x| !x.is_empty())
+            .collect();
+    }
+    for paragraph in paragraphs {
+        doc.paragraph(&paragraph);
+    }
+    doc.render()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let lines = wrap(Font::Regular, 11.0, &"lorem ipsum ".repeat(50));
+        assert!(lines.len() > 1);
+        assert!(lines
+            .iter()
+            .all(|x| Font::Regular.width(x, 11.0) <= PAGE_WIDTH - MARGIN * 2.0));
+        assert_eq!(wrap(Font::Regular, 11.0, ""), vec![""]);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut doc = Document::new("Weekly (42)");
+        doc.heading("Weekly (42)");
+        for _ in 0..100 {
+            doc.paragraph("Café — “quoted” text");
+        }
+        let pdf = doc.render();
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.starts_with("%PDF-1.4"));
+        assert!(text.ends_with("%%EOF\n"));
+        assert!(text.contains("/Title (Weekly \\(42\\))"));
+        assert!(text.contains("/Count 3"));
+    }
+}
@@ -0,0 +1,356 @@
+//! Processing of accepted messages as an ordered list of stages, set by
+//! `PIPELINE`. Stages before `store` prepare the item, stages after it act on
+//! the stored item.
+
+use anyhow::{bail, Result};
+use futures::future::BoxFuture;
+use mongodb::bson::{doc, Document};
+use tracing::{info_span, warn, Instrument};
+
+use crate::{
+    alert,
+    blob::{self, Blobs},
+    config::get_config,
+    db::{claim_id, collapse, is_duplicate_key, new_id, Feed, Feeds},
+    events, fulltext, mirror, notify, registry, selftest,
+    summarize::summarize_stored,
+    translate::translate_stored,
+    validator, websub, welcome,
+};
+
+/// Stages in their default order
+pub const DEFAULT: &[&str] = &[
+    "collapse",
+    "overflow",
+    "store",
+    "index",
+    "events",
+    "websub",
+    "mirror",
+    "welcome",
+    "notify",
+    "translate",
+    "summarize",
+];
+
+pub enum Flow {
+    Continue,
+    /// The item needs no further processing, e.g. merged into another one
+    Stop,
+}
+
+pub struct Context {
+    pub feeds: Feeds,
+    pub blobs: Blobs,
+}
+
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>>;
+}
+
+/// Count repetitions within `COLLAPSE_WINDOW_HOURS` on the earlier item
+struct Collapse;
+
+impl Stage for Collapse {
+    fn name(&self) -> &'static str {
+        "collapse"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            if collapse(&ctx.feeds, feed).await? {
+                validator::touch();
+                return Ok(Flow::Stop);
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Move content over `MAX_CONTENT_SIZE` to chunks
+struct Overflow;
+
+impl Stage for Overflow {
+    fn name(&self) -> &'static str {
+        "overflow"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            // Chunks are stored by id, so it should be free beforehand
+            claim_id(&ctx.feeds, feed).await?;
+            blob::overflow(&ctx.blobs, feed).await?;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Times an item is given a new id when its own is taken on insert
+const ID_RETRIES: usize = 5;
+
+/// Insert the item, its raw source going to chunks, with a new id if its own
+/// is taken. Self-test probes go no further, nor do items that could not be
+/// stored, which are reported, see `FAILURE_WEBHOOK`.
+struct Store;
+
+impl Stage for Store {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            match store(ctx, feed).await {
+                Ok(flow) => Ok(flow),
+                // Later stages would index and announce an item not stored
+                Err(e) => {
+                    warn!(target: "Database", "Error storing {}: {}", feed.id, e);
+                    alert::report("insert_failed", &e.to_string(), message_id(feed));
+                    Ok(Flow::Stop)
+                }
+            }
+        })
+    }
+}
+
+/// `Message-ID` of `feed`, for reports
+fn message_id(feed: &Feed) -> Option<&str> {
+    feed.headers
+        .iter()
+        .find(|x| x.name == "message-id")
+        .map(|x| x.value.as_str())
+}
+
+async fn store(ctx: &Context, feed: &mut Feed) -> Result<Flow> {
+    // Probes are deleted right after, chunks would be left behind
+    if !selftest::is_probe_feed(feed) {
+        claim_id(&ctx.feeds, feed).await?;
+        blob::put_raw(&ctx.blobs, feed).await?;
+    }
+    let span = info_span!("Database.insert");
+    let items = ctx.feeds.clone_with_type::<Document>();
+    let mut retries = 0;
+    let res = loop {
+        match items
+            .insert_one(blob::stored(feed)?, None)
+            .instrument(span.clone())
+            .await
+        {
+            Err(e) if is_duplicate_key(&e) && retries < ID_RETRIES => {
+                retries += 1;
+                let id = new_id();
+                warn!(target: "Database", "Id {} taken, retrying as {}", feed.id, id);
+                blob::rekey(&ctx.blobs, feed, id).await?;
+            }
+            res => break res,
+        }
+    };
+    if let Err(e) = res {
+        // Chunks under a taken id may be those of the item holding it
+        if (feed.overflow || feed.raw_chunked) && !is_duplicate_key(&e) {
+            if let Err(e) = blob::remove(&ctx.blobs, &[feed.id.clone()]).await {
+                warn!(target: "Database", "Error removing chunks of {}: {}", feed.id, e);
+            }
+        }
+        return Err(e.into());
+    }
+    if selftest::is_probe_feed(feed) {
+        return Ok(Flow::Stop);
+    }
+    if !feed.pending {
+        validator::schedule(feed.publish_at.unwrap_or(feed.created_at));
+    }
+    Ok(Flow::Continue)
+}
+
+/// Add the item to the search index, if any
+struct Index;
+
+impl Stage for Index {
+    fn name(&self) -> &'static str {
+        "index"
+    }
+
+    fn run<'a>(&'a self, _: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            if let Some(index) = fulltext::index() {
+                tokio::task::block_in_place(|| index.add(&[feed.clone()]))?;
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Tell subscribers of `/events` about the item
+struct Events;
+
+impl Stage for Events {
+    fn name(&self) -> &'static str {
+        "events"
+    }
+
+    fn run<'a>(&'a self, _: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            events::publish(feed);
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Ping the hub of feeds, see `WEBSUB_HUB`
+struct WebSub;
+
+impl Stage for WebSub {
+    fn name(&self) -> &'static str {
+        "websub"
+    }
+
+    fn run<'a>(&'a self, _: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            if !feed.pending {
+                websub::ping(&feed.from_box, feed.publish_at);
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Copy the item to object storage, in the background, see `S3_BUCKET`
+struct Mirror;
+
+impl Stage for Mirror {
+    fn name(&self) -> &'static str {
+        "mirror"
+    }
+
+    fn run<'a>(&'a self, _: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            mirror::mirror(feed);
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Reply with the feed URLs to the first item published in a box, see
+/// `NEW_BOX_REPLY`
+struct Welcome;
+
+impl Stage for Welcome {
+    fn name(&self) -> &'static str {
+        "welcome"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            welcome::greet(&ctx.feeds, feed).await?;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Announce the item to the `notify` targets of its box
+struct Notify;
+
+impl Stage for Notify {
+    fn name(&self) -> &'static str {
+        "notify"
+    }
+
+    fn run<'a>(&'a self, _: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            notify::send(&ctx.feeds, feed);
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Translate the stored item into `TRANSLATE_TARGET`, in the background
+struct Translate;
+
+impl Stage for Translate {
+    fn name(&self) -> &'static str {
+        "translate"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            let config = get_config();
+            if config.translate_backend.is_some() && config.translate_target.is_some() {
+                tokio::spawn(translate_stored(ctx.feeds.clone(), feed.clone()));
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Summarize the stored item with `SUMMARY_API_URL`, in the background
+struct Summarize;
+
+impl Stage for Summarize {
+    fn name(&self) -> &'static str {
+        "summarize"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a Context, feed: &'a mut Feed) -> BoxFuture<'a, Result<Flow>> {
+        Box::pin(async move {
+            if get_config().summary_api_url.is_some() {
+                tokio::spawn(summarize_stored(ctx.feeds.clone(), feed.clone()));
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+fn stage(name: &str) -> Result<Box<dyn Stage>> {
+    Ok(match name {
+        "collapse" => Box::new(Collapse),
+        "overflow" => Box::new(Overflow),
+        "store" => Box::new(Store),
+        "index" => Box::new(Index),
+        "events" => Box::new(Events),
+        "websub" => Box::new(WebSub),
+        "mirror" => Box::new(Mirror),
+        "welcome" => Box::new(Welcome),
+        "notify" => Box::new(Notify),
+        "translate" => Box::new(Translate),
+        "summarize" => Box::new(Summarize),
+        _ => bail!("Unknown pipeline stage {}", name),
+    })
+}
+
+pub struct Pipeline {
+    ctx: Context,
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// Stages named in `names`, in order. `store` is required.
+    pub fn new(feeds: Feeds, blobs: Blobs, names: &[String]) -> Result<Self> {
+        if !names.iter().any(|x| x == "store") {
+            bail!("PIPELINE needs the store stage");
+        }
+        Ok(Self {
+            ctx: Context { feeds, blobs },
+            stages: names.iter().map(|x| stage(x)).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Run the stages on `feed` until one stops. Failed stages are skipped,
+    /// but for `store`, which stops when the item could not be stored.
+    pub async fn process(&self, mut feed: Feed) {
+        for stage in &self.stages {
+            match stage.run(&self.ctx, &mut feed).await {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Stop) => break,
+                Err(e) => warn!(
+                    target: "Pipeline",
+                    stage = stage.name(),
+                    id = feed.id.as_str(),
+                    "Stage failed: {}",
+                    e
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Real client addresses behind reverse proxies, taken from the PROXY protocol
+//! header of accepted connections or `X-Forwarded-For` set by trusted proxies
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{extract::connect_info::Connected, http::HeaderMap};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::config::get_config;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header, including CRLF
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address range in CIDR notation, a bare address is a range of one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            bail!("Invalid prefix length {} in {}", prefix, s);
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (range, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                (u32::from(range) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range), u128::from(ip), 128),
+            (IpAddr::V6(range), IpAddr::V4(ip)) => match range.to_ipv4() {
+                // IPv4-mapped ranges
+                Some(range) if self.prefix >= 96 => {
+                    return Cidr {
+                        addr: IpAddr::V4(range),
+                        prefix: self.prefix - 96,
+                    }
+                    .contains(IpAddr::V4(ip))
+                }
+                _ => return false,
+            },
+            (IpAddr::V4(_), IpAddr::V6(ip)) => {
+                return ip.to_ipv4().map_or(false, |x| self.contains(IpAddr::V4(x)))
+            }
+        };
+        let shift = bits - self.prefix as u32;
+        shift >= bits || range >> shift == ip >> shift
+    }
+}
+
+pub fn is_trusted(ip: IpAddr) -> bool {
+    get_config().trusted_proxies.iter().any(|x| x.contains(ip))
+}
+
+/// Address of the client a request from `peer` was made by, walking
+/// `X-Forwarded-For` from the right for as long as hops are trusted proxies
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let mut ret = peer;
+    for hop in forwarded.iter().rev() {
+        if !is_trusted(ret) {
+            break;
+        }
+        match hop.parse() {
+            Ok(ip) => ret = ip,
+            Err(_) => break,
+        }
+    }
+    ret
+}
+
+/// Scheme the client reached the proxies in front of `peer` with, lowercase,
+/// from the first value of `X-Forwarded-Proto`. It is only believed from
+/// `TRUSTED_PROXIES`, or from anyone when none are set.
+pub fn forwarded_proto(peer: IpAddr, headers: &HeaderMap) -> Option<String> {
+    if !get_config().trusted_proxies.is_empty() && !is_trusted(peer) {
+        return None;
+    }
+    headers
+        .get("x-forwarded-proto")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()
+        .map(|x| x.trim().to_ascii_lowercase())
+}
+
+/// Client address of a request, resolved through trusted proxies
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+/// A connection with the client address it proxies, if any, e.g. a TCP
+/// connection or TLS over one
+pub struct ProxiedStream<S = TcpStream> {
+    inner: S,
+    remote_addr: SocketAddr,
+}
+
+impl<S> ProxiedStream<S> {
+    pub fn new(inner: S, remote_addr: SocketAddr) -> Self {
+        Self { inner, remote_addr }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl<'a, S> Connected<&'a ProxiedStream<S>> for SocketAddr {
+    fn connect_info(target: &'a ProxiedStream<S>) -> Self {
+        target.remote_addr
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ProxiedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ProxiedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Consume the PROXY protocol header of a connection from a trusted proxy.
+/// Connections from anywhere else are taken as they are.
+pub async fn accept(mut stream: TcpStream, peer: SocketAddr) -> Result<ProxiedStream> {
+    if !is_trusted(peer.ip()) {
+        return Ok(ProxiedStream::new(stream, peer));
+    }
+    let remote_addr = timeout(HEADER_TIMEOUT, read_header(&mut stream))
+        .await
+        .map_err(|_| anyhow!("Timed out reading PROXY header from {}", peer))??
+        .unwrap_or(peer);
+    Ok(ProxiedStream::new(stream, remote_addr))
+}
+
+/// Source address in a PROXY header, `None` for local or unknown connections
+async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    let mut signature = [0; 12];
+    stream.read_exact(&mut signature).await?;
+
+    if &signature == V2_SIGNATURE {
+        let mut head = [0; 4];
+        stream.read_exact(&mut head).await?;
+        let mut data = vec![0; u16::from_be_bytes([head[2], head[3]]) as usize];
+        stream.read_exact(&mut data).await?;
+        return parse_v2(head[0], head[1], &data);
+    }
+
+    if !signature.starts_with(b"PROXY ") {
+        bail!("Missing PROXY header");
+    }
+    let mut line = signature.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY header too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(std::str::from_utf8(&line)?)
+}
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let parts = line.trim_end().split(' ').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            Ok(Some(SocketAddr::new(src.parse()?, sport.parse()?)))
+        }
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => bail!("Invalid PROXY header {:?}", line),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, data: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        bail!("Unsupported PROXY protocol version {}", ver_cmd >> 4);
+    }
+    // LOCAL connections are health checks of the proxy itself
+    if ver_cmd & 0xF == 0 {
+        return Ok(None);
+    }
+    let port = |x: &[u8]| u16::from_be_bytes([x[0], x[1]]);
+    match family >> 4 {
+        1 if data.len() >= 12 => {
+            let src: [u8; 4] = data[0..4].try_into()?;
+            Ok(Some(SocketAddr::new(
+                Ipv4Addr::from(src).into(),
+                port(&data[8..10]),
+            )))
+        }
+        2 if data.len() >= 36 => {
+            let src: [u8; 16] = data[0..16].try_into()?;
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(src).into(),
+                port(&data[32..34]),
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let range = "10.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("11.1.2.3".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+
+        let single = "fd00::1".parse::<Cidr>().unwrap();
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1("PROXY TCP4 nonsense\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let data = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        assert_eq!(
+            parse_v2(0x21, 0x11, &data).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Limits on web requests per client, with separate budgets for polling feeds
+//! (`RATE_LIMIT_FEEDS`), everything else (`RATE_LIMIT_API`) and items
+//! translated on demand (`RATE_LIMIT_TRANSLATE`). Clients are told apart by
+//! the credentials or feed token `auth` let them in with, or else by address,
+//! IPv6 ones by their /64. Refused credentials are counted by address alone
+//! (`RATE_LIMIT_AUTH`).
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::Request;
+use once_cell::sync::Lazy;
+
+use crate::{auth::Credential, config::get_config};
+
+/// Clients tracked before idle ones are forgotten, and then the least recently
+/// seen ones down to three quarters of it
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Budget {
+    /// `/rss` and `/atom` feeds
+    Feeds,
+    /// Any other route
+    Api,
+    /// Items translated on demand with `?lang=`, on top of `Api`
+    Translate,
+    /// Requests refused for their credentials, before any other budget
+    Auth,
+}
+
+impl Budget {
+    fn of(path: &str) -> Self {
+        match path.split('/').nth(1) {
+            Some("rss" | "atom") => Budget::Feeds,
+            _ => Budget::Api,
+        }
+    }
+
+    /// Requests allowed per minute, 0 for no limit
+    fn per_minute(self) -> u32 {
+        let config = get_config();
+        match self {
+            Budget::Feeds => config.rate_limit_feeds,
+            Budget::Api => config.rate_limit_api,
+            Budget::Translate => config.rate_limit_translate,
+            Budget::Auth => config.rate_limit_auth,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Credential(Credential),
+    Ip(IpAddr),
+}
+
+/// Token bucket of `per_minute` requests, full at first
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            updated: now,
+        }
+    }
+
+    fn tokens_at(&self, per_minute: u32, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64)
+    }
+
+    /// Tell how long until a request is allowed, if not now
+    fn wait(&self, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        let tokens = self.tokens_at(per_minute, now);
+        if tokens >= 1.0 {
+            return Ok(());
+        }
+        let wait = (1.0 - tokens) * 60.0 / per_minute as f64;
+        Err(Duration::from_secs_f64(wait))
+    }
+
+    /// Take a request, or tell how long until one is allowed
+    fn take(&mut self, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        self.tokens = self.tokens_at(per_minute, now);
+        self.updated = now;
+        self.wait(per_minute, now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn is_full(&self, per_minute: u32, now: Instant) -> bool {
+        self.tokens_at(per_minute, now) >= per_minute as f64
+    }
+}
+
+static BUCKETS: Lazy<Mutex<HashMap<(Budget, Client), Bucket>>> = Lazy::new(Default::default);
+
+/// Address `ip` is counted under: IPv6 ones by their /64, within which a
+/// single client may pick any address, and IPv4-mapped ones as IPv4
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(x) => {
+            let bits = u128::from(x);
+            match bits >> 32 == 0xffff {
+                true => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+                false => IpAddr::V6(Ipv6Addr::from(bits & (!0 << 64))),
+            }
+        }
+    }
+}
+
+fn client(credential: Option<Credential>, ip: IpAddr) -> Client {
+    match credential {
+        Some(x) => Client::Credential(x),
+        None => Client::Ip(network(ip)),
+    }
+}
+
+/// Count a request from `ip`, or tell how long until it is allowed
+pub fn check<B>(req: &Request<B>, ip: IpAddr) -> Result<(), Duration> {
+    let credential = req.extensions().get::<Credential>().copied();
+    take(Budget::of(req.uri().path()), client(credential, ip))
+}
+
+/// Count an item translated on demand for a client, or tell how long until
+/// one is allowed
+pub fn check_translate(credential: Option<Credential>, ip: IpAddr) -> Result<(), Duration> {
+    take(Budget::Translate, client(credential, ip))
+}
+
+/// Tell how long until `ip` may present credentials again, if too many were
+/// refused lately
+pub fn check_refused(ip: IpAddr) -> Result<(), Duration> {
+    let per_minute = Budget::Auth.per_minute();
+    if per_minute == 0 {
+        return Ok(());
+    }
+    let buckets = BUCKETS.lock().expect("rate limits poisoned");
+    match buckets.get(&(Budget::Auth, client(None, ip))) {
+        Some(x) => x.wait(per_minute, Instant::now()),
+        None => Ok(()),
+    }
+}
+
+/// Count credentials refused to `ip`
+pub fn refused(ip: IpAddr) {
+    // Already answered, the wait is told on the next request
+    let _ = take(Budget::Auth, client(None, ip));
+}
+
+fn take(budget: Budget, client: Client) -> Result<(), Duration> {
+    let per_minute = budget.per_minute();
+    if per_minute == 0 {
+        return Ok(());
+    }
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().expect("rate limits poisoned");
+    if buckets.len() >= MAX_TRACKED {
+        evict(&mut buckets, now);
+    }
+    buckets
+        .entry((budget, client))
+        .or_insert_with(|| Bucket::new(per_minute, now))
+        .take(per_minute, now)
+}
+
+/// Forget idle clients, then the least recently seen ones if still over three
+/// quarters of `MAX_TRACKED`, so that this is rare even when none are idle
+fn evict(buckets: &mut HashMap<(Budget, Client), Bucket>, now: Instant) {
+    buckets.retain(|(budget, _), x| !x.is_full(budget.per_minute(), now));
+    let keep = MAX_TRACKED * 3 / 4;
+    if buckets.len() <= keep {
+        return;
+    }
+    let mut seen = buckets.values().map(|x| x.updated).collect::<Vec<_>>();
+    let n = seen.len() - keep;
+    let cutoff = *seen.select_nth_unstable(n).1;
+    buckets.retain(|_, x| x.updated >= cutoff);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2, start);
+        assert!(bucket.take(2, start).is_ok());
+        assert!(bucket.take(2, start).is_ok());
+        assert_eq!(bucket.take(2, start), Err(Duration::from_secs(30)));
+        assert!(bucket.take(2, start + Duration::from_secs(30)).is_ok());
+        assert!(!bucket.is_full(2, start + Duration::from_secs(30)));
+        assert!(bucket.is_full(2, start + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn test_budget() {
+        assert_eq!(Budget::of("/rss/a@example.com"), Budget::Feeds);
+        assert_eq!(Budget::of("/atom"), Budget::Feeds);
+        assert_eq!(Budget::of("/feeds"), Budget::Api);
+        assert_eq!(Budget::of("/admin/boxes"), Budget::Api);
+    }
+
+    #[test]
+    fn test_network() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert_eq!(network(ip("192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(network(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(network(ip("2001:db8:1:2:a:b:c:d")), ip("2001:db8:1:2::"));
+        assert_eq!(
+            network(ip("2001:db8:1:2::1")),
+            network(ip("2001:db8:1:2::2"))
+        );
+        assert_ne!(
+            network(ip("2001:db8:1:2::1")),
+            network(ip("2001:db8:1:3::1"))
+        );
+    }
+}
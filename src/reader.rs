@@ -0,0 +1,79 @@
+//! Reader mode of items: the text blocks of a message, without layout,
+//! images and newsletter boilerplate
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{templates, text::html_paragraphs};
+
+/// Phrases of header and footer blocks of newsletters
+const BOILERPLATE: &[&str] = &[
+    "unsubscribe",
+    "view in browser",
+    "view in your browser",
+    "view this email",
+    "view online",
+    "update your preferences",
+    "manage your subscription",
+    "manage preferences",
+    "forward to a friend",
+    "all rights reserved",
+    "you are receiving this",
+    "you received this",
+    "this email was sent to",
+    "add us to your address book",
+    "privacy policy",
+];
+/// Blocks longer than this are kept even with boilerplate phrases, being
+/// likely prose mentioning them
+const BOILERPLATE_MAX_LEN: usize = 200;
+
+fn is_boilerplate(block: &str) -> bool {
+    let lower = block.to_lowercase();
+    lower.starts_with('©')
+        || lower.starts_with("copyright")
+        || lower.chars().count() <= BOILERPLATE_MAX_LEN
+            && BOILERPLATE.iter().any(|x| lower.contains(x))
+}
+
+#[derive(Serialize)]
+struct ReaderPage<'a> {
+    title: &'a str,
+    paragraphs: Vec<String>,
+}
+
+/// Text blocks of `html` worth reading
+pub fn extract(html: &str) -> Vec<String> {
+    html_paragraphs(html)
+        .into_iter()
+        .filter(|x| !is_boilerplate(x))
+        .collect()
+}
+
+/// Page of the reader mode of a message
+pub fn render(title: &str, html: &str) -> Result<String> {
+    templates::render(
+        "reader.html",
+        &ReaderPage {
+            title,
+            paragraphs: extract(html),
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(
+            extract(
+                "<p><a href=\"#\">View this email in your browser</a></p>\
+                <h2>News</h2><p>Something happened today.</p>\
+                <p>© 2022 Example Inc.</p><p>Unsubscribe | Manage preferences</p>"
+            ),
+            vec!["News".to_owned(), "Something happened today.".to_owned()]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Boxes managed through `/admin/boxes`. Boxes receiving mail need no record;
+//! records are for boxes created ahead of mail, archived or renamed.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use anyhow::Result;
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{bson::doc, options::ReplaceOptions, Collection};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{db::Feeds, validator};
+
+pub type Registry = Collection<BoxRecord>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BoxRecord {
+    pub name: String,
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: DateTime<Utc>,
+    /// No longer accepting mail, still served
+    #[serde(default)]
+    pub archived: bool,
+    /// Name the box was renamed to, kept so that old URLs redirect
+    #[serde(default)]
+    pub renamed_to: Option<String>,
+    /// Secret opening the feeds of the box without credentials, see `auth`
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl BoxRecord {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            created_at: Utc::now(),
+            archived: false,
+            renamed_to: None,
+            token: None,
+        }
+    }
+}
+
+/// Records by name, read on every message so that SMTP needs no query
+static RECORDS: Lazy<RwLock<HashMap<String, BoxRecord>>> = Lazy::new(Default::default);
+
+/// Read all records into memory, after any change
+pub async fn load(registry: &Registry) -> Result<()> {
+    let records = registry
+        .find(None, None)
+        .await?
+        .map_ok(|x| (x.name.clone(), x))
+        .try_collect::<HashMap<_, _>>()
+        .await?;
+    *RECORDS.write().expect("registry poisoned") = records;
+    Ok(())
+}
+
+fn get(name: &str) -> Option<BoxRecord> {
+    RECORDS
+        .read()
+        .expect("registry poisoned")
+        .get(name)
+        .cloned()
+}
+
+/// Records of boxes in use, i.e. not renamed
+pub fn active() -> Vec<BoxRecord> {
+    let mut ret = RECORDS
+        .read()
+        .expect("registry poisoned")
+        .values()
+        .filter(|x| x.renamed_to.is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    ret.sort_by(|a, b| a.name.cmp(&b.name));
+    ret
+}
+
+/// New name of a renamed box
+pub fn renamed_to(name: &str) -> Option<String> {
+    get(name).and_then(|x| x.renamed_to)
+}
+
+/// Current name of a box, following renames
+pub fn resolve(name: &str) -> String {
+    renamed_to(name).unwrap_or_else(|| name.to_owned())
+}
+
+/// Feed token of a box
+pub fn token(name: &str) -> Option<String> {
+    get(name).and_then(|x| x.token)
+}
+
+pub fn is_archived(name: &str) -> bool {
+    get(name).map_or(false, |x| x.archived)
+}
+
+/// Names of boxes with items, and of boxes created ahead of any mail
+pub async fn names(feeds: &Feeds) -> Result<Vec<String>> {
+    let mut ret = feeds
+        .distinct("from_box", None, None)
+        .await?
+        .into_iter()
+        .filter_map(|x| x.as_str().map(ToOwned::to_owned))
+        .collect::<Vec<_>>();
+    for record in active() {
+        if !ret.contains(&record.name) {
+            ret.push(record.name);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Whether a box has a record, renamed ones aside
+pub fn is_registered(name: &str) -> bool {
+    get(name).map_or(false, |x| x.renamed_to.is_none())
+}
+
+/// Whether a box has a record or items, renamed ones aside
+pub async fn exists(feeds: &Feeds, name: &str) -> Result<bool> {
+    Ok(is_registered(name)
+        || feeds
+            .find_one(doc! { "from_box": name }, None)
+            .await?
+            .is_some())
+}
+
+/// Whether `name` may name a box
+pub fn is_valid_name(name: &str) -> bool {
+    name.contains('@') && !name.contains('/') && name.trim() == name
+}
+
+async fn save(registry: &Registry, record: &BoxRecord) -> Result<()> {
+    registry
+        .replace_one(
+            doc! { "name": &record.name },
+            record,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn create(registry: &Registry, name: &str) -> Result<BoxRecord> {
+    let record = BoxRecord::new(name);
+    save(registry, &record).await?;
+    load(registry).await?;
+    Ok(record)
+}
+
+pub async fn set_archived(registry: &Registry, name: &str, archived: bool) -> Result<()> {
+    let mut record = get(name).unwrap_or_else(|| BoxRecord::new(name));
+    record.archived = archived;
+    save(registry, &record).await?;
+    load(registry).await?;
+    validator::touch();
+    Ok(())
+}
+
+/// Give a box a feed token, replacing any earlier one, or revoke it with
+/// `None`
+pub async fn set_token(registry: &Registry, name: &str, token: Option<String>) -> Result<()> {
+    let mut record = get(name).unwrap_or_else(|| BoxRecord::new(name));
+    record.token = token;
+    save(registry, &record).await?;
+    load(registry).await
+}
+
+async fn move_items(feeds: &Feeds, from: &str, to: &str) -> Result<u64> {
+    Ok(feeds
+        .update_many(
+            doc! { "from_box": from },
+            doc! { "$set": { "from_box": to } },
+            None,
+        )
+        .await?
+        .modified_count)
+}
+
+/// Make `from` redirect to `to`, along with names redirecting to `from`
+async fn redirect(registry: &Registry, old: BoxRecord, to: &str) -> Result<()> {
+    let from = old.name.clone();
+    save(
+        registry,
+        &BoxRecord {
+            renamed_to: Some(to.to_owned()),
+            archived: false,
+            ..old
+        },
+    )
+    .await?;
+    // Names redirecting to the old one skip it
+    registry
+        .update_many(
+            doc! { "renamed_to": &from },
+            doc! { "$set": { "renamed_to": to } },
+            None,
+        )
+        .await?;
+    load(registry).await?;
+    validator::touch();
+    Ok(())
+}
+
+/// Rename a box, moving its items and redirecting the old name. Returns the
+/// number of items moved.
+pub async fn rename(registry: &Registry, feeds: &Feeds, from: &str, to: &str) -> Result<u64> {
+    let moved = move_items(feeds, from, to).await?;
+    let old = get(from).unwrap_or_else(|| BoxRecord::new(from));
+    save(
+        registry,
+        &BoxRecord {
+            name: to.to_owned(),
+            renamed_to: None,
+            ..old.clone()
+        },
+    )
+    .await?;
+    redirect(registry, old, to).await?;
+    Ok(moved)
+}
+
+/// Merge a box into an existing one, moving its items and redirecting its
+/// name. The record of `into` is kept as is. Returns the number of items moved.
+pub async fn merge(registry: &Registry, feeds: &Feeds, from: &str, into: &str) -> Result<u64> {
+    let moved = move_items(feeds, from, into).await?;
+    let old = get(from).unwrap_or_else(|| BoxRecord::new(from));
+    redirect(registry, old, into).await?;
+    Ok(moved)
+}
+
+/// Delete a box with all its items, and names redirecting to it. Returns the
+/// number of items deleted. What is kept of the items besides them is left to
+/// the caller, see `admin::delete_box`.
+pub async fn delete(registry: &Registry, feeds: &Feeds, name: &str) -> Result<u64> {
+    let deleted = feeds
+        .delete_many(doc! { "from_box": name }, None)
+        .await?
+        .deleted_count;
+    registry
+        .delete_many(
+            doc! { "$or": [ { "name": name }, { "renamed_to": name } ] },
+            None,
+        )
+        .await?;
+    load(registry).await?;
+    Ok(deleted)
+}
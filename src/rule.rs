@@ -7,6 +7,16 @@ use crate::db::ToVec;
 pub struct Rule {
     pub to_box: String,
     pub filter: Vec<RuleFilter>,
+    /// Tags given to every matching message, whichever box it goes to
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Rule {
+    /// Whether any filter of the rule matches the message
+    pub fn matches(&self, msg: &Message) -> bool {
+        self.filter.iter().any(|x| x.matches(msg))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
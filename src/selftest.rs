@@ -0,0 +1,193 @@
+//! End-to-end probe sending a message to our own SMTP listener and waiting for
+//! it to be stored, see `/admin/selftest`
+
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use mongodb::bson::doc;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    config::get_config,
+    db::{Feed, Feeds},
+    headers::header_values,
+};
+
+/// Header carrying the token of a probe
+pub const HEADER: &str = "X-Mail-List-Rss-Selftest";
+const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+const STORE_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tokens of probes in flight, so that forged headers are not believed
+static TOKENS: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Whether a raw message is one of our probes
+pub fn is_probe(raw: &str) -> bool {
+    let tokens = TOKENS.lock().expect("selftest tokens poisoned");
+    header_values(raw, HEADER)
+        .iter()
+        .any(|x| tokens.contains(x.trim()))
+}
+
+/// Whether an item was made of one of our probes
+pub fn is_probe_feed(feed: &Feed) -> bool {
+    let tokens = TOKENS.lock().expect("selftest tokens poisoned");
+    feed.headers
+        .iter()
+        .any(|x| x.name.eq_ignore_ascii_case(HEADER) && tokens.contains(x.value.trim()))
+}
+
+#[derive(Serialize)]
+pub struct Stage {
+    pub name: &'static str,
+    pub millis: u128,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub stages: Vec<Stage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct Timer {
+    last: Instant,
+    stages: Vec<Stage>,
+}
+
+impl Timer {
+    fn lap(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.stages.push(Stage {
+            name,
+            millis: (now - self.last).as_millis(),
+        });
+        self.last = now;
+    }
+}
+
+/// Send a probe through SMTP, wait for it in the store and delete it
+pub async fn run(feeds: Feeds) -> Report {
+    let token = nanoid::nanoid!(16);
+    TOKENS
+        .lock()
+        .expect("selftest tokens poisoned")
+        .insert(token.clone());
+    let mut timer = Timer {
+        last: Instant::now(),
+        stages: vec![],
+    };
+    let res = probe(&feeds, &token, &mut timer).await;
+    TOKENS
+        .lock()
+        .expect("selftest tokens poisoned")
+        .remove(&token);
+    Report {
+        ok: res.is_ok(),
+        stages: timer.stages,
+        error: res.err().map(|e| e.to_string()),
+    }
+}
+
+async fn probe(feeds: &Feeds, token: &str, timer: &mut Timer) -> Result<()> {
+    let config = get_config();
+    let mut stream = timeout(
+        SMTP_TIMEOUT,
+        TcpStream::connect(("127.0.0.1", config.smtp_port)),
+    )
+    .await??;
+    timer.lap("connect");
+
+    let rcpt = format!("selftest@{}", config.domain);
+    let message = format!(
+        "From: <{rcpt}>\r\nTo: <{rcpt}>\r\nSubject: Self-test {token}\r\n\
+        Message-ID: <{token}@{domain}>\r\n{header}: {token}\r\n\
+        Content-Type: text/html; charset=utf-8\r\n\r\n<p>Self-test</p>\r\n",
+        rcpt = rcpt,
+        token = token,
+        domain = config.domain,
+        header = HEADER
+    );
+    timeout(SMTP_TIMEOUT, submit(&mut stream, &rcpt, &rcpt, &message)).await??;
+    timer.lap("smtp");
+
+    let filter = doc! {
+        "headers": { "$elemMatch": { "name": HEADER.to_ascii_lowercase(), "value": token } }
+    };
+    let started = Instant::now();
+    let feed = loop {
+        if let Some(feed) = feeds.find_one(filter.clone(), None).await? {
+            break feed;
+        }
+        if started.elapsed() > STORE_TIMEOUT {
+            bail!("Not stored within {} seconds", STORE_TIMEOUT.as_secs());
+        }
+        sleep(POLL_INTERVAL).await;
+    };
+    timer.lap("store");
+
+    feeds.delete_one(doc! { "id": &feed.id }, None).await?;
+    timer.lap("delete");
+    Ok(())
+}
+
+/// Send `message`, with CRLF line endings, through an SMTP session on `stream`
+pub async fn submit(stream: &mut TcpStream, from: &str, rcpt: &str, message: &str) -> Result<()> {
+    // Lines starting with a dot are escaped, as it ends the data
+    let mut data = message.replace("\r\n.", "\r\n..");
+    if data.starts_with('.') {
+        data.insert(0, '.');
+    }
+    if !data.ends_with("\r\n") {
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+
+    let (read, mut write) = stream.split();
+    let mut read = BufReader::new(read);
+    expect(&mut read, "220").await?;
+    for (command, code) in [
+        ("HELO localhost\r\n".to_owned(), "250"),
+        (format!("MAIL FROM:<{}>\r\n", from), "250"),
+        (format!("RCPT TO:<{}>\r\n", rcpt), "250"),
+        ("DATA\r\n".to_owned(), "354"),
+        (data, "250"),
+        ("QUIT\r\n".to_owned(), "221"),
+    ] {
+        write.write_all(command.as_bytes()).await?;
+        write.flush().await?;
+        expect(&mut read, code).await?;
+    }
+    Ok(())
+}
+
+/// Read a reply, multi-line ones included, failing unless it has `code`
+async fn expect<R: AsyncBufReadExt + Unpin>(read: &mut R, code: &str) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read.read_line(&mut line).await? == 0 {
+            bail!("Connection closed, expecting {}", code);
+        }
+        // `250-` continues, `250 ` ends a reply
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+    if !line.starts_with(code) {
+        bail!("Expecting {}, got {}", code, line.trim_end());
+    }
+    Ok(())
+}
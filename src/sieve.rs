@@ -0,0 +1,651 @@
+//! A subset of Sieve (RFC 5228) used as an alternative to `RULE_FILE`.
+//!
+//! Supported tests are `address`, `header`, `body`, `exists`, `size`, `allof`,
+//! `anyof`, `not`, `true` and `false`, with `:is`, `:contains` and `:matches`
+//! compared case-insensitively. Supported actions are `fileinto` (into a box),
+//! `keep`, `discard`, `reject` and `stop`.
+
+use std::{iter::Peekable, str::Chars};
+
+use anyhow::{bail, Result};
+
+use crate::headers::addresses;
+
+/// Outcome of running a script against a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Route as usual
+    Keep,
+    /// Store into the given box
+    FileInto(String),
+    /// Accept and drop silently
+    Discard,
+    /// Refuse with the given reason
+    Reject(String),
+}
+
+/// What tests are run against
+pub struct Mail<'a> {
+    pub headers: &'a [(String, String)],
+    pub body: &'a str,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Script {
+    commands: Vec<Command>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        Ok(Self {
+            commands: parser.commands(false)?,
+        })
+    }
+
+    /// The first action taken, `Keep` if none
+    pub fn evaluate(&self, mail: &Mail) -> Verdict {
+        let mut verdict = None;
+        run(&self.commands, mail, &mut verdict);
+        verdict.unwrap_or(Verdict::Keep)
+    }
+}
+
+/// Returns whether `stop` was reached
+fn run(commands: &[Command], mail: &Mail, verdict: &mut Option<Verdict>) -> bool {
+    for command in commands {
+        match command {
+            Command::If(branches, otherwise) => {
+                let block = branches
+                    .iter()
+                    .find(|(test, _)| test.eval(mail))
+                    .map(|(_, block)| block)
+                    .or(otherwise.as_ref());
+                if let Some(block) = block {
+                    if run(block, mail, verdict) {
+                        return true;
+                    }
+                }
+            }
+            Command::Action(action) => {
+                verdict.get_or_insert_with(|| action.clone());
+            }
+            Command::Stop => return true,
+            Command::Require => {}
+        }
+    }
+    false
+}
+
+#[derive(Clone, Debug)]
+enum Command {
+    If(Vec<(Test, Vec<Command>)>, Option<Vec<Command>>),
+    Action(Verdict),
+    Stop,
+    Require,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+impl MatchType {
+    fn matches(self, value: &str, key: &str) -> bool {
+        match self {
+            MatchType::Is => value.eq_ignore_ascii_case(key),
+            MatchType::Contains => value
+                .to_ascii_lowercase()
+                .contains(&key.to_ascii_lowercase()),
+            MatchType::Matches => glob(
+                &value.to_ascii_lowercase().chars().collect::<Vec<_>>(),
+                &key.to_ascii_lowercase().chars().collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    fn any(self, values: &[String], keys: &[String]) -> bool {
+        values
+            .iter()
+            .any(|value| keys.iter().any(|key| self.matches(value, key)))
+    }
+}
+
+/// Wildcard match with `*` and `?`
+fn glob(value: &[char], pattern: &[char]) -> bool {
+    let (mut v, mut p) = (0, 0);
+    // Position of the last `*` in the pattern and of the value it was retried at
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(x) if *x == '?' || *x == value[v] => {
+                v += 1;
+                p += 1;
+            }
+            _ => match star {
+                Some((sp, sv)) => {
+                    star = Some((sp, sv + 1));
+                    p = sp + 1;
+                    v = sv + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+#[derive(Clone, Copy, Debug)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+#[derive(Clone, Debug)]
+enum Test {
+    Address {
+        match_type: MatchType,
+        part: AddressPart,
+        headers: Vec<String>,
+        keys: Vec<String>,
+    },
+    Header {
+        match_type: MatchType,
+        headers: Vec<String>,
+        keys: Vec<String>,
+    },
+    Body {
+        match_type: MatchType,
+        keys: Vec<String>,
+    },
+    Exists(Vec<String>),
+    Size {
+        over: bool,
+        limit: u64,
+    },
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Not(Box<Test>),
+    True,
+    False,
+}
+
+impl Test {
+    fn eval(&self, mail: &Mail) -> bool {
+        let values = |names: &[String]| {
+            mail.headers
+                .iter()
+                .filter(|(name, _)| names.iter().any(|x| x.eq_ignore_ascii_case(name)))
+                .map(|(_, value)| value.clone())
+                .collect::<Vec<_>>()
+        };
+        match self {
+            Test::Address {
+                match_type,
+                part,
+                headers,
+                keys,
+            } => {
+                let parts = values(headers)
+                    .iter()
+                    .flat_map(|x| addresses(x))
+                    .map(|x| {
+                        let (local, domain) = x.rsplit_once('@').unwrap_or((&x, ""));
+                        match part {
+                            AddressPart::All => x.clone(),
+                            AddressPart::LocalPart => local.to_owned(),
+                            AddressPart::Domain => domain.to_owned(),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                match_type.any(&parts, keys)
+            }
+            Test::Header {
+                match_type,
+                headers,
+                keys,
+            } => match_type.any(&values(headers), keys),
+            Test::Body { match_type, keys } => match match_type {
+                // A body is too long to match as a whole
+                MatchType::Is | MatchType::Contains => {
+                    MatchType::Contains.any(&[mail.body.to_owned()], keys)
+                }
+                MatchType::Matches => MatchType::Matches.any(&[mail.body.to_owned()], keys),
+            },
+            Test::Exists(names) => names.iter().all(|name| {
+                mail.headers
+                    .iter()
+                    .any(|(x, _)| x.eq_ignore_ascii_case(name))
+            }),
+            Test::Size { over, limit } => match over {
+                true => mail.size as u64 > *limit,
+                false => (mail.size as u64) < *limit,
+            },
+            Test::AllOf(tests) => tests.iter().all(|x| x.eval(mail)),
+            Test::AnyOf(tests) => tests.iter().any(|x| x.eval(mail)),
+            Test::Not(test) => !test.eval(mail),
+            Test::True => true,
+            Test::False => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Tag(String),
+    Str(String),
+    Num(u64),
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Semicolon,
+}
+
+fn take_word(chars: &mut Peekable<Chars>) -> String {
+    let mut ret = String::new();
+    while let Some(&x) = chars.peek() {
+        if !(x.is_ascii_alphanumeric() || x == '_') {
+            break;
+        }
+        ret.push(x);
+        chars.next();
+    }
+    ret
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut ret = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&x) = chars.peek() {
+        let punct = match x {
+            '[' => Some(Token::LBracket),
+            ']' => Some(Token::RBracket),
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+            '{' => Some(Token::LBrace),
+            '}' => Some(Token::RBrace),
+            ',' => Some(Token::Comma),
+            ';' => Some(Token::Semicolon),
+            _ => None,
+        };
+        if let Some(punct) = punct {
+            chars.next();
+            ret.push(punct);
+            continue;
+        }
+        match x {
+            x if x.is_whitespace() => {
+                chars.next();
+            }
+            '#' => {
+                for x in chars.by_ref() {
+                    if x == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' => {
+                chars.next();
+                if chars.next() != Some('*') {
+                    bail!("Unexpected character /");
+                }
+                let mut prev = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == '*' => break,
+                        Some(x) => prev = x,
+                        None => bail!("Unterminated comment"),
+                    }
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => string.extend(chars.next()),
+                        Some('"') => break,
+                        Some(x) => string.push(x),
+                        None => bail!("Unterminated string"),
+                    }
+                }
+                ret.push(Token::Str(string));
+            }
+            ':' => {
+                chars.next();
+                ret.push(Token::Tag(take_word(&mut chars).to_ascii_lowercase()));
+            }
+            x if x.is_ascii_digit() => {
+                let word = take_word(&mut chars);
+                let (num, unit) = match word.char_indices().last() {
+                    Some((idx, 'K' | 'k')) => (&word[..idx], 1 << 10),
+                    Some((idx, 'M' | 'm')) => (&word[..idx], 1 << 20),
+                    Some((idx, 'G' | 'g')) => (&word[..idx], 1 << 30),
+                    _ => (word.as_str(), 1),
+                };
+                ret.push(Token::Num(num.parse::<u64>()? * unit));
+            }
+            x if x.is_ascii_alphabetic() || x == '_' => {
+                ret.push(Token::Ident(take_word(&mut chars).to_ascii_lowercase()))
+            }
+            x => bail!("Unexpected character {}", x),
+        }
+    }
+    Ok(ret)
+}
+
+enum Arg {
+    Tag(String),
+    Strings(Vec<String>),
+    Num(u64),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let ret = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        ret
+    }
+
+    fn expect(&mut self, token: Token) -> Result<()> {
+        match self.next() {
+            Some(x) if x == token => Ok(()),
+            other => bail!("Expected {:?} but found {:?}", token, other),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(x)) => Ok(x),
+            other => bail!("Expected identifier but found {:?}", other),
+        }
+    }
+
+    fn commands(&mut self, in_block: bool) -> Result<Vec<Command>> {
+        let mut ret = vec![];
+        loop {
+            match self.peek() {
+                None if !in_block => return Ok(ret),
+                None => bail!("Unexpected end of script"),
+                Some(Token::RBrace) if in_block => {
+                    self.pos += 1;
+                    return Ok(ret);
+                }
+                _ => ret.push(self.command()?),
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Command>> {
+        self.expect(Token::LBrace)?;
+        self.commands(true)
+    }
+
+    fn command(&mut self) -> Result<Command> {
+        let name = self.ident()?;
+        if name == "if" {
+            let mut branches = vec![(self.test()?, self.block()?)];
+            let mut otherwise = None;
+            loop {
+                match self.peek() {
+                    Some(Token::Ident(x)) if x == "elsif" => {
+                        self.pos += 1;
+                        branches.push((self.test()?, self.block()?));
+                    }
+                    Some(Token::Ident(x)) if x == "else" => {
+                        self.pos += 1;
+                        otherwise = Some(self.block()?);
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            return Ok(Command::If(branches, otherwise));
+        }
+
+        let args = self.args()?;
+        self.expect(Token::Semicolon)?;
+        let string = args.iter().rev().find_map(|x| match x {
+            Arg::Strings(x) => x.first().cloned(),
+            _ => None,
+        });
+        match name.as_str() {
+            "require" => Ok(Command::Require),
+            "stop" => Ok(Command::Stop),
+            "keep" => Ok(Command::Action(Verdict::Keep)),
+            "discard" => Ok(Command::Action(Verdict::Discard)),
+            "reject" | "ereject" => {
+                Ok(Command::Action(Verdict::Reject(string.unwrap_or_default())))
+            }
+            "fileinto" => match string {
+                Some(x) => Ok(Command::Action(Verdict::FileInto(x))),
+                None => bail!("fileinto requires a box"),
+            },
+            other => bail!("Unsupported command {}", other),
+        }
+    }
+
+    fn args(&mut self) -> Result<Vec<Arg>> {
+        let mut ret = vec![];
+        loop {
+            match self.peek() {
+                Some(Token::Tag(x)) => {
+                    ret.push(Arg::Tag(x.clone()));
+                    self.pos += 1;
+                }
+                Some(Token::Num(x)) => {
+                    ret.push(Arg::Num(*x));
+                    self.pos += 1;
+                }
+                Some(Token::Str(_) | Token::LBracket) => ret.push(Arg::Strings(self.strings()?)),
+                _ => return Ok(ret),
+            }
+        }
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        match self.next() {
+            Some(Token::Str(x)) => Ok(vec![x]),
+            Some(Token::LBracket) => {
+                let mut ret = vec![];
+                loop {
+                    match self.next() {
+                        Some(Token::Str(x)) => ret.push(x),
+                        other => bail!("Expected string but found {:?}", other),
+                    }
+                    match self.next() {
+                        Some(Token::Comma) => {}
+                        Some(Token::RBracket) => return Ok(ret),
+                        other => bail!("Expected , or ] but found {:?}", other),
+                    }
+                }
+            }
+            other => bail!("Expected string but found {:?}", other),
+        }
+    }
+
+    fn test(&mut self) -> Result<Test> {
+        let name = self.ident()?;
+        match name.as_str() {
+            "true" => return Ok(Test::True),
+            "false" => return Ok(Test::False),
+            "not" => return Ok(Test::Not(Box::new(self.test()?))),
+            "allof" | "anyof" => {
+                self.expect(Token::LParen)?;
+                let mut tests = vec![self.test()?];
+                loop {
+                    match self.next() {
+                        Some(Token::Comma) => tests.push(self.test()?),
+                        Some(Token::RParen) => break,
+                        other => bail!("Expected , or ) but found {:?}", other),
+                    }
+                }
+                return Ok(match name.as_str() {
+                    "allof" => Test::AllOf(tests),
+                    _ => Test::AnyOf(tests),
+                });
+            }
+            _ => {}
+        }
+
+        let mut match_type = MatchType::Is;
+        let mut part = AddressPart::All;
+        let mut over = None;
+        let mut limit = None;
+        let mut lists = vec![];
+        let mut args = self.args()?.into_iter();
+        while let Some(arg) = args.next() {
+            match arg {
+                Arg::Tag(x) => match x.as_str() {
+                    "is" => match_type = MatchType::Is,
+                    "contains" => match_type = MatchType::Contains,
+                    "matches" => match_type = MatchType::Matches,
+                    "all" => part = AddressPart::All,
+                    "localpart" => part = AddressPart::LocalPart,
+                    "domain" => part = AddressPart::Domain,
+                    "over" => over = Some(true),
+                    "under" => over = Some(false),
+                    // Only the default `i;ascii-casemap` is supported
+                    "comparator" => {
+                        args.next();
+                    }
+                    // Body transforms, the text of the body is always used
+                    "text" | "raw" | "content" => {}
+                    other => bail!("Unsupported tag :{}", other),
+                },
+                Arg::Strings(x) => lists.push(x),
+                Arg::Num(x) => limit = Some(x),
+            }
+        }
+
+        let mut lists = lists.into_iter();
+        let mut list = |what: &str| match lists.next() {
+            Some(x) => Ok(x),
+            None => bail!("{} requires {}", name, what),
+        };
+        Ok(match name.as_str() {
+            "address" => Test::Address {
+                match_type,
+                part,
+                headers: list("header names")?,
+                keys: list("keys")?,
+            },
+            "header" => Test::Header {
+                match_type,
+                headers: list("header names")?,
+                keys: list("keys")?,
+            },
+            "body" => Test::Body {
+                match_type,
+                keys: list("keys")?,
+            },
+            "exists" => Test::Exists(list("header names")?),
+            "size" => match (over, limit) {
+                (Some(over), Some(limit)) => Test::Size { over, limit },
+                _ => bail!("size requires :over or :under and a limit"),
+            },
+            other => bail!("Unsupported test {}", other),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+require ["fileinto", "reject"];
+# Newsletters
+if address :domain "from" "substack.com" {
+    fileinto "substack@example.com";
+    stop;
+} elsif allof (header :contains "subject" "[cron]", not exists "x-keep") {
+    discard;
+} elsif anyof (body :contains "unsubscribe me", size :over 1M) {
+    reject "Not here";
+} else {
+    keep;
+}
+"#;
+
+    fn verdict(headers: &[(&str, &str)], body: &str, size: usize) -> Verdict {
+        let headers = headers
+            .iter()
+            .map(|(x, y)| (x.to_string(), y.to_string()))
+            .collect::<Vec<_>>();
+        Script::parse(SCRIPT).unwrap().evaluate(&Mail {
+            headers: &headers,
+            body,
+            size,
+        })
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(
+            verdict(&[("From", "Writer <writer@Substack.com>")], "", 10),
+            Verdict::FileInto("substack@example.com".to_owned())
+        );
+        assert_eq!(
+            verdict(&[("Subject", "[CRON] backup")], "", 10),
+            Verdict::Discard
+        );
+        assert_eq!(
+            verdict(&[("Subject", "[cron] backup"), ("X-Keep", "1")], "", 10),
+            Verdict::Keep
+        );
+        assert_eq!(
+            verdict(&[], "please UNSUBSCRIBE ME now", 10),
+            Verdict::Reject("Not here".to_owned())
+        );
+        assert_eq!(
+            verdict(&[], "", 2 << 20),
+            Verdict::Reject("Not here".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_glob() {
+        let chars = |x: &str| x.chars().collect::<Vec<_>>();
+        assert!(glob(&chars("weekly digest #12"), &chars("weekly*#??")));
+        assert!(!glob(&chars("weekly digest"), &chars("daily*")));
+        assert!(glob(&chars(""), &chars("**")));
+        assert!(glob(&chars("abcbc"), &chars("a*bc")));
+        assert!(!glob(
+            &chars(&"a".repeat(100)),
+            &chars(&format!("{}b", "*a".repeat(30)))
+        ));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(Script::parse("fileinto;").is_err());
+        assert!(Script::parse("if true { keep;").is_err());
+        assert!(Script::parse("vacation \"away\";").is_err());
+    }
+}
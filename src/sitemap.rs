@@ -0,0 +1,64 @@
+//! Sitemap (https://www.sitemaps.org/protocol.html) of box archives and item
+//! pages, for crawlers of public archives
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::{
+    config::get_config,
+    text::{escape_xml, percent_encode},
+};
+
+/// URLs a sitemap may list
+pub const MAX_URLS: usize = 50_000;
+
+fn url(ret: &mut String, path: &str, modified: Option<DateTime<Utc>>) {
+    let config = get_config();
+    ret.push_str(&format!(
+        "<url><loc>{}</loc>",
+        escape_xml(&format!("https://{}{}", config.web_domain, path))
+    ));
+    if let Some(x) = modified {
+        ret.push_str(&format!(
+            "<lastmod>{}</lastmod>",
+            x.to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+    }
+    ret.push_str("</url>\n");
+}
+
+/// Render the front page, the page of each box and of each `(id, created_at)`
+/// item
+pub fn render_sitemap(boxes: &[String], items: &[(String, DateTime<Utc>)]) -> String {
+    let mut ret = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    ret.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    url(&mut ret, "/", None);
+    for name in boxes {
+        url(&mut ret, &format!("/boxes/{}", percent_encode(name)), None);
+    }
+    for (id, created_at) in items {
+        url(
+            &mut ret,
+            &format!("/feeds/{}", percent_encode(id)),
+            Some(*created_at),
+        );
+    }
+    ret.push_str("</urlset>\n");
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_render_sitemap() {
+        let sitemap = render_sitemap(
+            &["a@example.com".to_owned()],
+            &[("abc".to_owned(), Utc.timestamp(1646092800, 0))],
+        );
+        assert!(sitemap.contains("/boxes/a%40example.com</loc></url>"));
+        assert!(sitemap.contains("/feeds/abc</loc><lastmod>2022-03-01T00:00:00Z</lastmod></url>"));
+    }
+}
@@ -1,6 +1,9 @@
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use mail_parser::Message;
 use mailin::{response, Handler, Response, SessionBuilder};
 use tokio::{
@@ -9,42 +12,303 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
-use crate::{config::get_config, db::Feed, TX};
+use crate::{
+    alert,
+    boxes::AutoSubmittedAction,
+    config::get_config,
+    db::{body_text, Feed, SmtpEnvelope, ToVec},
+    dsn,
+    headers::{auto_submitted, header_values, parse_headers},
+    mailer, metrics, milter, registry, selftest,
+    sieve::{Mail, Verdict},
+    TX,
+};
 
 struct SmtpConnection {
     data: Option<Vec<u8>>,
+    ip: Option<IpAddr>,
+    helo: String,
+    from: Option<String>,
+    rcpts: Vec<String>,
     tx: TX,
 }
 
 impl SmtpConnection {
     pub fn new(tx: TX) -> Self {
-        Self { data: None, tx }
-    }
-    pub fn end(&self) -> Result<()> {
-        let data = self.data.to_owned().expect("data should be initialized");
-        match Message::parse(&data) {
-            Some(parsed) => {
-                let feed: Feed = (&data, parsed).try_into()?;
-                self.tx.send(feed)?;
-                Ok(())
+        Self {
+            data: None,
+            ip: None,
+            helo: String::new(),
+            from: None,
+            rcpts: vec![],
+            tx,
+        }
+    }
+    pub fn end(&self) -> Result<Response> {
+        let config = get_config();
+        let mut data = self.data.to_owned().expect("data should be initialized");
+        let message_id = self.message_id();
+        let message_id = message_id.as_deref();
+        if is_looping(&String::from_utf8_lossy(&data)) {
+            alert::rejected("Mail loop", message_id);
+            return Ok(response::NO_SERVICE);
+        }
+        if !config.milters.is_empty() {
+            let envelope = milter::Envelope {
+                ip: self.ip,
+                helo: &self.helo,
+                from: self.from.as_deref().unwrap_or_default(),
+                rcpts: &self.rcpts,
+            };
+            // Milters are spoken to synchronously, `handle` runs this off the runtime
+            match milter::check(&envelope, &data) {
+                milter::Verdict::Accept(headers) => {
+                    let mut prefixed = headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                        .collect::<String>()
+                        .into_bytes();
+                    prefixed.extend(data);
+                    data = prefixed;
+                }
+                milter::Verdict::Discard => return Ok(response::OK),
+                milter::Verdict::Reject(reason) => {
+                    warn!(target: "SMTP", reason = reason.as_str(), "Rejected by milter");
+                    alert::rejected(&format!("Milter: {}", reason), message_id);
+                    return Ok(response::NO_SERVICE);
+                }
+                milter::Verdict::TempFail(reason) => {
+                    warn!(target: "SMTP", reason = reason.as_str(), "Deferred by milter");
+                    return Ok(response::INTERNAL_ERROR);
+                }
+            }
+        }
+        match accept(
+            &data,
+            self.from.as_deref(),
+            None,
+            Some(self.envelope()),
+            &self.tx,
+        )? {
+            Outcome::Accepted | Outcome::Discarded => Ok(response::OK),
+            Outcome::Rejected(_) => Ok(response::NO_SERVICE),
+            // Taken, as sending it again would not help, and bounced
+            Outcome::Unparsable(reason) => {
+                warn!(target: "SMTP", reason = reason.as_str(), "Cannot parse message");
+                alert::report("parse_failed", &reason, message_id);
+                self.bounce(&reason);
+                Ok(response::OK)
             }
-            None => {
-                bail!("Parse failed")
+        }
+    }
+
+    /// `Message-ID` of the received message, if any
+    fn message_id(&self) -> Option<String> {
+        let data = self.data.as_ref()?;
+        header_values(&String::from_utf8_lossy(data), "Message-ID")
+            .into_iter()
+            .next()
+    }
+
+    /// Report a failed delivery to the return path, if enabled by `SEND_DSN`.
+    /// Null return paths and automatic messages are never bounced.
+    fn bounce(&self, reason: &str) {
+        let config = get_config();
+        if !config.send_dsn || !mailer::enabled() {
+            return;
+        }
+        let return_path = match self
+            .from
+            .as_deref()
+            .map(|x| x.trim_matches(&['<', '>'][..]))
+        {
+            Some(x) if !x.is_empty() => x.to_owned(),
+            _ => return,
+        };
+        let raw = match &self.data {
+            Some(data) => String::from_utf8_lossy(data),
+            None => return,
+        };
+        if auto_submitted(&raw).is_some() {
+            return;
+        }
+
+        let report = dsn::build(&return_path, &self.rcpts, reason, &raw);
+        tokio::spawn(async move {
+            match mailer::send_raw(None, &return_path, report.as_bytes()).await {
+                Ok(()) => info!(target: "SMTP", "DSN sent to {}", return_path),
+                Err(e) => warn!(target: "SMTP", "Error sending DSN to {}: {}", return_path, e),
+            }
+        });
+    }
+}
+
+/// What became of a received message
+pub enum Outcome {
+    /// Queued for storage
+    Accepted,
+    /// Dropped on purpose, e.g. by the sieve script
+    Discarded,
+    Rejected(String),
+    /// Not a message that can be made into an item
+    Unparsable(String),
+}
+
+/// Route a received message with the sieve script and box settings and queue
+/// it for storage, as done for mail received through SMTP. `from` is the
+/// envelope sender, and `from_box` overrides the box found from headers.
+pub fn accept(
+    data: &[u8],
+    from: Option<&str>,
+    from_box: Option<String>,
+    envelope: Option<SmtpEnvelope>,
+    tx: &TX,
+) -> Result<Outcome> {
+    let config = get_config();
+    let raw = String::from_utf8_lossy(data);
+    let message_id = header_values(&raw, "Message-ID").into_iter().next();
+    let message_id = message_id.as_deref();
+    let reject = |reason: String| -> Result<Outcome> {
+        alert::rejected(&reason, message_id);
+        Ok(Outcome::Rejected(reason))
+    };
+    let auto = auto_submitted(&raw);
+    let parsed = match Message::parse(data) {
+        Some(x) => x,
+        None => return Ok(Outcome::Unparsable("Parse failed".to_owned())),
+    };
+    let mut senders = parsed.get_from().to_vec();
+    senders.extend(from.map(ToOwned::to_owned));
+    let verdict = config.sieve.as_ref().map(|script| {
+        script.evaluate(&Mail {
+            headers: &parse_headers(&raw),
+            body: &body_text(&parsed),
+            size: data.len(),
+        })
+    });
+    let built: Result<Feed> = match (verdict, from_box) {
+        (Some(Verdict::Discard), _) => {
+            info!(target: "SMTP", "Discarded by sieve script");
+            return Ok(Outcome::Discarded);
+        }
+        (Some(Verdict::Reject(reason)), _) => {
+            warn!(target: "SMTP", reason = reason.as_str(), "Rejected by sieve script");
+            return reject(format!("Sieve: {}", reason));
+        }
+        (_, Some(from_box)) | (Some(Verdict::FileInto(from_box)), None) => {
+            Feed::from_message(data, parsed, from_box, None)
+        }
+        (Some(Verdict::Keep) | None, None) => (data, parsed).try_into(),
+    };
+    let mut feed = match built {
+        Ok(x) => x,
+        Err(e) => return Ok(Outcome::Unparsable(e.to_string())),
+    };
+    feed.from_box = registry::resolve(&feed.from_box);
+    if registry::is_archived(&feed.from_box) {
+        warn!(
+            target: "SMTP",
+            from_box = feed.from_box.as_str(),
+            "Box archived, rejected"
+        );
+        return reject(format!("Box {} archived", feed.from_box));
+    }
+    let box_config = config.box_config(&feed.from_box);
+    if let Some(kind) = auto {
+        match box_config
+            .and_then(|x| x.auto_submitted)
+            .unwrap_or(config.auto_submitted)
+        {
+            AutoSubmittedAction::Keep => {}
+            AutoSubmittedAction::Tag => feed.auto_submitted = Some(kind),
+            AutoSubmittedAction::Drop => {
+                info!(
+                    target: "SMTP",
+                    from_box = feed.from_box.as_str(),
+                    kind = kind.as_str(),
+                    "Automatic message dropped"
+                );
+                return Ok(Outcome::Discarded);
             }
         }
     }
+    if let Some(box_config) = box_config {
+        if !box_config.accepts(&senders) {
+            warn!(
+                target: "SMTP",
+                from_box = feed.from_box.as_str(),
+                senders = senders.join(", ").as_str(),
+                "Sender not allowed, rejected"
+            );
+            return reject(format!("Sender not allowed into {}", feed.from_box));
+        }
+    }
+    feed.envelope = envelope;
+    // Kept out of feeds for the short time it is stored
+    if selftest::is_probe(&raw) {
+        feed.pending = true;
+    }
+    metrics::enqueued(&feed.id);
+    let id = feed.id.clone();
+    if let Err(e) = tx.send(feed) {
+        drop(metrics::Dequeue(id));
+        return Err(e.into());
+    }
+    Ok(Outcome::Accepted)
+}
+
+/// Host in the `by` clause of a `Received` header
+fn received_by(value: &str) -> Option<&str> {
+    let mut words = value.split_whitespace();
+    words.find(|x| x.eq_ignore_ascii_case("by"))?;
+    words.next()
+}
+
+/// Detect mail loops by the number of `Received` headers, and of those
+/// stamped by hosts of our own domain
+fn is_looping(raw: &str) -> bool {
+    let config = get_config();
+    let received = header_values(raw, "Received");
+    let domain = config.domain.to_ascii_lowercase();
+    let own = received
+        .iter()
+        .filter_map(|x| received_by(x))
+        .map(|x| x.to_ascii_lowercase())
+        .filter(|x| *x == domain || x.ends_with(&format!(".{}", domain)))
+        .count();
+    let looping = received.len() > config.max_hops || own > 1;
+    if looping {
+        warn!(
+            target: "SMTP",
+            hops = received.len(),
+            own,
+            "Mail loop suspected, rejected"
+        );
+    }
+    looping
 }
 
 impl Handler for SmtpConnection {
+    fn mail(&mut self, ip: IpAddr, domain: &str, from: &str) -> Response {
+        self.ip = Some(ip);
+        self.helo = domain.to_owned();
+        self.from = Some(from.to_owned());
+        self.rcpts.clear();
+        response::OK
+    }
+
     fn rcpt(&mut self, to: &str) -> Response {
         let conf = &get_config();
         if conf.disable_rcpt_filter {
+            self.rcpts.push(to.to_owned());
             return response::OK;
         }
         //  Block any rcpt that's not on my domain
         if to.contains(&conf.domain) {
+            self.rcpts.push(to.to_owned());
             response::OK
         } else {
+            alert::rejected(&format!("Recipient {} not in {}", to, conf.domain), None);
             response::NO_SERVICE
         }
     }
@@ -62,8 +326,11 @@ impl Handler for SmtpConnection {
     }
 
     fn data_end(&mut self) -> Response {
-        self.end().unwrap_or_else(|e| warn!("{}", e));
-        response::OK
+        // Failing to queue, the sender is told to try again later
+        self.end().unwrap_or_else(|e| {
+            warn!(target: "SMTP", "Error accepting message: {}", e);
+            response::INTERNAL_ERROR
+        })
     }
 }
 
@@ -91,7 +358,20 @@ async fn handle(mut stream: TcpStream, addr: SocketAddr, tx: TX) -> Result<()> {
         }
 
         debug!(target: "SMTP", "   >>> IN:  {}", buf.replace("\r\n", ""));
-        let resp = session.process(buf.as_bytes());
+        let resp = if buf.trim_end() == "." {
+            // Ends DATA, which talks to milters and stores the message
+            // synchronously
+            let line = buf.clone();
+            let (back, resp) = tokio::task::spawn_blocking(move || {
+                let resp = session.process(line.as_bytes());
+                (session, resp)
+            })
+            .await?;
+            session = back;
+            resp
+        } else {
+            session.process(buf.as_bytes())
+        };
         debug!(target: "SMTP", "   >>> OUT: {:?}", resp);
         resp.write_to_async(&mut write).await?;
         write.flush().await?;
@@ -103,14 +383,19 @@ async fn handle(mut stream: TcpStream, addr: SocketAddr, tx: TX) -> Result<()> {
     Ok(())
 }
 
+/// Whether the SMTP listener is accepting connections, see `/health`
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_listening() -> bool {
+    LISTENING.load(Ordering::Relaxed)
+}
+
 pub async fn smtp_server(tx: TX) -> Result<()> {
     info!(target: "SMTP", "Starting");
     let config = get_config();
-    while let Ok((stream, addr)) = TcpListener::bind(format!("0.0.0.0:{}", config.smtp_port))
-        .await?
-        .accept()
-        .await
-    {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", config.smtp_port)).await?;
+    LISTENING.store(true, Ordering::Relaxed);
+    while let Ok((stream, addr)) = listener.accept().await {
         let tx = tx.clone();
         tokio::spawn(async move {
             if let Err(e) = handle(stream, addr, tx).await {
@@ -118,6 +403,7 @@ pub async fn smtp_server(tx: TX) -> Result<()> {
             }
         });
     }
+    LISTENING.store(false, Ordering::Relaxed);
     info!(target: "SMTP", "Stopping");
     Ok(())
 }
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, from_document};
+use serde::{Deserialize, Serialize};
+
+use crate::db::Feeds;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Count {
+    #[serde(rename = "_id")]
+    pub name: String,
+    pub count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Top {
+    pub authors: Vec<Count>,
+    pub boxes: Vec<Count>,
+}
+
+/// Parse periods like `30d`, `12h` or `4w`
+pub fn parse_period(period: &str) -> Option<Duration> {
+    let (num, unit) = period.split_at(period.char_indices().last()?.0);
+    let num = num.parse::<i64>().ok().filter(|x| *x > 0)?;
+    let hours = match unit {
+        "h" => 1,
+        "d" => 24,
+        "w" => 24 * 7,
+        _ => return None,
+    };
+    let period = Duration::milliseconds(num.checked_mul(hours * 3600 * 1000)?);
+    // The start of the period has to be a date too
+    Utc::now().checked_sub_signed(period)?;
+    Some(period)
+}
+
+/// User-assigned tags with the number of items of each, most used first
+pub async fn tags(feeds: Feeds) -> Result<Vec<Count>> {
+    let pipeline = vec![
+        doc! { "$match": { "tags.0": { "$exists": true } } },
+        doc! { "$unwind": "$tags" },
+        doc! { "$group": { "_id": "$tags", "count": { "$sum": 1 } } },
+        doc! { "$sort": { "count": -1, "_id": 1 } },
+    ];
+    let ret = feeds
+        .aggregate(pipeline, None)
+        .await?
+        .map_err(anyhow::Error::from)
+        .and_then(|x| async move { Ok(from_document::<Count>(x)?) })
+        .try_collect()
+        .await?;
+    Ok(ret)
+}
+
+/// Most active authors and boxes within `period` till now
+pub async fn top(feeds: Feeds, period: Duration, limit: i64) -> Result<Top> {
+    let since = Utc::now() - period;
+    let group = |field: &str| {
+        vec![
+            doc! { "$group": { "_id": field, "count": { "$sum": 1 } } },
+            doc! { "$sort": { "count": -1, "_id": 1 } },
+            doc! { "$limit": limit },
+        ]
+    };
+    let pipeline = vec![
+        doc! { "$match": { "created_at": { "$gte": since.timestamp_millis() } } },
+        doc! {
+            "$facet": {
+                "authors": group("$author"),
+                "boxes": group("$from_box"),
+            }
+        },
+    ];
+
+    let ret = feeds
+        .aggregate(pipeline, None)
+        .await?
+        .try_next()
+        .await?
+        .map(from_document::<Top>)
+        .transpose()?
+        .unwrap_or_default();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_period() {
+        assert_eq!(parse_period("30d"), Some(Duration::days(30)));
+        assert_eq!(parse_period("12h"), Some(Duration::hours(12)));
+        assert_eq!(parse_period("2w"), Some(Duration::weeks(2)));
+        assert_eq!(parse_period("0d"), None);
+        assert_eq!(parse_period("d"), None);
+        assert_eq!(parse_period(""), None);
+        assert_eq!(parse_period("3y"), None);
+        assert_eq!(parse_period("9223372036854775807w"), None);
+        assert_eq!(parse_period("99999999999d"), None);
+    }
+}
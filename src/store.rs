@@ -0,0 +1,102 @@
+//! Reads of items fetching only the fields a route needs. Most only need
+//! metadata, some the content, few the raw source, which is the bulk of an
+//! item and kept apart in chunks, see `blob::put_raw`. Items whose raw source
+//! is not moved yet still have it inline, left out by the projections here.
+
+use anyhow::Result;
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOneOptions,
+};
+
+use crate::{
+    blob::{self, Blobs},
+    db::{published, Feed, Feeds},
+};
+
+/// Projection of items without the raw source
+pub fn without_raw() -> Document {
+    doc! { "raw": 0 }
+}
+
+/// Projection of items without the raw source, content, text or
+/// translations, e.g. for listings
+pub fn meta_only() -> Document {
+    doc! { "raw": 0, "content": 0, "text": 0, "translations": 0 }
+}
+
+async fn find_one(feeds: &Feeds, id: &str, projection: Option<Document>) -> Result<Option<Feed>> {
+    let option = FindOneOptions::builder().projection(projection).build();
+    Ok(feeds.find_one(doc! { "id": id }, option).await?)
+}
+
+/// Item without its raw source or content
+pub async fn get_meta(feeds: &Feeds, id: &str) -> Result<Option<Feed>> {
+    find_one(feeds, id, Some(meta_only())).await
+}
+
+/// Item with its content, without its raw source
+pub async fn get_content(feeds: &Feeds, id: &str) -> Result<Option<Feed>> {
+    find_one(feeds, id, Some(without_raw())).await
+}
+
+/// Raw source of a published item, from chunks if kept there
+pub async fn get_raw(feeds: &Feeds, blobs: &Blobs, id: &str) -> Result<Option<String>> {
+    let option = FindOneOptions::builder()
+        .projection(doc! { "raw": 1, "raw_chunked": 1 })
+        .build();
+    let found = feeds
+        .clone_with_type::<Document>()
+        .find_one(published(doc! { "id": id }), option)
+        .await?;
+    match found {
+        Some(x) if x.get_bool("raw_chunked").unwrap_or(false) => {
+            Ok(Some(blob::load_raw(blobs, id).await?))
+        }
+        Some(x) => Ok(Some(x.get_str("raw").unwrap_or_default().to_owned())),
+        None => Ok(None),
+    }
+}
+
+/// Item with its raw source filled in from chunks if kept there
+async fn find_with_raw(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    id: &str,
+    projection: Option<Document>,
+) -> Result<Option<Feed>> {
+    let mut found = find_one(feeds, id, projection).await?;
+    if let Some(feed) = &mut found {
+        blob::fill_raw(blobs, feed).await?;
+    }
+    Ok(found)
+}
+
+/// Item with its raw source, without its content, e.g. to name the source
+pub async fn get_source(feeds: &Feeds, blobs: &Blobs, id: &str) -> Result<Option<Feed>> {
+    let projection = doc! { "content": 0, "text": 0, "translations": 0 };
+    find_with_raw(feeds, blobs, id, Some(projection)).await
+}
+
+/// Item with every field
+pub async fn get(feeds: &Feeds, blobs: &Blobs, id: &str) -> Result<Option<Feed>> {
+    find_with_raw(feeds, blobs, id, None).await
+}
+
+/// Published items of the box of `item` right before and after it, without
+/// their raw source or content
+pub async fn neighbours(feeds: &Feeds, item: &Feed) -> Result<(Option<Feed>, Option<Feed>)> {
+    let created_at = item.created_at.timestamp_millis();
+    let adjacent = |op: &str, order: i32| {
+        let filter = published(doc! {
+            "from_box": &item.from_box,
+            "created_at": { op: created_at },
+        });
+        let option = FindOneOptions::builder()
+            .projection(meta_only())
+            .sort(doc! { "created_at": order })
+            .build();
+        feeds.find_one(filter, option)
+    };
+    Ok((adjacent("$lt", -1).await?, adjacent("$gt", 1).await?))
+}
@@ -0,0 +1,100 @@
+//! Short summaries of new items from an OpenAI-compatible chat completion API,
+//! enabled by `SUMMARY_API_URL`
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use mongodb::bson::doc;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    client::http_client,
+    config::get_config,
+    db::{Feed, Feeds},
+    text::strip_html,
+    validator,
+};
+
+/// Characters of the body sent to the model, long digests are cut
+const MAX_INPUT_CHARS: usize = 12_000;
+const TIMEOUT: Duration = Duration::from_secs(60);
+const PROMPT: &str = "Summarize the following mailing list message in 2 to 3 sentences. \
+    Reply with the summary only, in the language of the message.";
+
+#[derive(Deserialize)]
+struct Completion {
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+}
+
+#[derive(Deserialize)]
+struct ChoiceMessage {
+    content: String,
+}
+
+/// Summary of `feed` by the configured model
+pub async fn summarize(feed: &Feed) -> Result<String> {
+    let config = get_config();
+    let url = config
+        .summary_api_url
+        .as_ref()
+        .ok_or_else(|| anyhow!("Summaries are not enabled"))?;
+
+    let mut text = strip_html(&feed.content);
+    if text.trim().is_empty() {
+        text = feed.plain_text();
+    }
+    let text = text.chars().take(MAX_INPUT_CHARS).collect::<String>();
+
+    let mut req = http_client()
+        .post(format!("{}/chat/completions", url.trim_end_matches('/')))
+        .timeout(TIMEOUT)
+        .json(&json!({
+            "model": config.summary_model,
+            "messages": [
+                { "role": "system", "content": PROMPT },
+                { "role": "user", "content": format!("Subject: {}\n\n{}", feed.title, text) },
+            ],
+        }));
+    if let Some(key) = &config.summary_api_key {
+        req = req.bearer_auth(key);
+    }
+    let res = req
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Completion>()
+        .await?;
+    res.choices
+        .into_iter()
+        .next()
+        .map(|x| x.message.content.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .ok_or_else(|| anyhow!("Empty completion"))
+}
+
+/// Summarize a stored item and save the summary, logging failures
+pub async fn summarize_stored(feeds: Feeds, feed: Feed) {
+    match summarize(&feed).await {
+        Ok(summary) => {
+            let update = doc! { "$set": { "summary": &summary } };
+            match feeds
+                .update_one(doc! { "id": &feed.id }, update, None)
+                .await
+            {
+                Ok(_) => {
+                    validator::touch();
+                    info!(target: "Summary", id = feed.id.as_str(), "Summarized")
+                }
+                Err(e) => warn!(target: "Summary", "Error saving summary: {}", e),
+            }
+        }
+        Err(e) => warn!(target: "Summary", "Error summarizing {}: {}", feed.id, e),
+    }
+}
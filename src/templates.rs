@@ -0,0 +1,93 @@
+//! HTML pages rendered with Tera templates, embedded from `templates/` and
+//! overridable by files of the same name in `TEMPLATE_DIR`, e.g. a `base.html`
+//! with the branding and navigation of an instance
+
+use std::fs;
+
+use anyhow::{Context as _, Result};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tera::{Context, Tera};
+use tracing::info;
+
+use crate::config::get_config;
+
+const EMBEDDED: &[(&str, &str)] = &[
+    ("base.html", include_str!("../templates/base.html")),
+    ("box.html", include_str!("../templates/box.html")),
+    ("error.html", include_str!("../templates/error.html")),
+    ("item.html", include_str!("../templates/item.html")),
+    ("reader.html", include_str!("../templates/reader.html")),
+    ("sandbox.html", include_str!("../templates/sandbox.html")),
+];
+
+static TEMPLATES: Lazy<Tera> = Lazy::new(|| load().unwrap());
+
+/// Embedded templates, then every `.html` file of `TEMPLATE_DIR`
+fn load() -> Result<Tera> {
+    let mut templates = EMBEDDED
+        .iter()
+        .map(|(name, text)| (name.to_string(), text.to_string()))
+        .collect::<Vec<_>>();
+    if let Some(dir) = &get_config().template_dir {
+        for entry in fs::read_dir(dir).with_context(|| format!("Cannot read {}", dir))? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|x| x.to_str()) {
+                Some(x) if x.ends_with(".html") => x.to_owned(),
+                _ => continue,
+            };
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Cannot read {}", path.display()))?;
+            info!(target: "web", "Using template {}", path.display());
+            templates.push((name, text));
+        }
+    }
+    let mut tera = Tera::default();
+    // Added at once, as templates may extend ones added after them
+    tera.add_raw_templates(templates)?;
+    Ok(tera)
+}
+
+/// Load the templates, so that broken ones fail at startup
+pub fn init() {
+    Lazy::force(&TEMPLATES);
+}
+
+/// Whether there is a template `name`, e.g. an `index.html` in `TEMPLATE_DIR`
+pub fn exists(name: &str) -> bool {
+    TEMPLATES.get_template_names().any(|x| x == name)
+}
+
+/// Render template `name`, with the fields of `context` as its variables
+pub fn render(name: &str, context: &impl Serialize) -> Result<String> {
+    let context = Context::from_serialize(context)?;
+    TEMPLATES
+        .render(name, &context)
+        .with_context(|| format!("Cannot render {}", name))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let page = render(
+            "error.html",
+            &json!({ "title": "404 Not Found", "message": "Cannot find <abc>" }),
+        )
+        .unwrap();
+        assert!(page.contains("<title>404 Not Found</title>"));
+        assert!(page.contains("<p>Cannot find &lt;abc&gt;</p>"));
+
+        let page = render(
+            "sandbox.html",
+            &json!({ "title": "News", "tags": ["a b"], "src": "/feeds/abc/full" }),
+        )
+        .unwrap();
+        assert!(page.contains(r#"<a href="/rss?tag=a%20b">#a b</a>"#));
+        assert!(page.contains(r#"src="&#x2F;feeds&#x2F;abc&#x2F;full""#));
+    }
+}
@@ -0,0 +1,683 @@
+/// Tags whose content is never visible and should be dropped entirely
+const INVISIBLE_TAGS: [&str; 3] = ["head", "script", "style"];
+
+/// Tags ending a line of text
+const BLOCK_ENDS: [&str; 13] = [
+    "<br",
+    "</p",
+    "</div",
+    "</li",
+    "</tr",
+    "</table",
+    "</blockquote",
+    "</h1",
+    "</h2",
+    "</h3",
+    "</h4",
+    "</h5",
+    "</h6",
+];
+/// Tags tried in order when deriving a title from HTML
+const TITLE_TAGS: [&str; 4] = ["title", "h1", "h2", "h3"];
+
+const TITLE_LEN: usize = 80;
+
+/// Common words ignored when comparing titles
+const STOP_WORDS: [&str; 24] = [
+    "about",
+    "after",
+    "also",
+    "been",
+    "from",
+    "have",
+    "into",
+    "just",
+    "like",
+    "more",
+    "most",
+    "news",
+    "newsletter",
+    "only",
+    "over",
+    "some",
+    "than",
+    "that",
+    "their",
+    "there",
+    "this",
+    "what",
+    "when",
+    "with",
+];
+
+/// Convert HTML into plain text with collapsed whitespace
+pub fn strip_html(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut ret = String::with_capacity(html.len());
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        ret.push_str(&html[pos..start]);
+
+        let end = match INVISIBLE_TAGS
+            .iter()
+            .find(|tag| is_tag(&lower[start..], tag))
+        {
+            Some(tag) => lower[start..]
+                .find(&format!("</{}", tag))
+                .and_then(|x| lower[start + x..].find('>').map(|y| start + x + y)),
+            None => lower[start..].find('>').map(|x| start + x),
+        };
+
+        match end {
+            Some(end) => {
+                ret.push(' ');
+                pos = end + 1;
+            }
+            None => pos = html.len(),
+        }
+    }
+    ret.push_str(&html[pos..]);
+
+    decode_entities(&ret)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Plain text of each block of `html`, split at line and block ends
+pub fn html_paragraphs(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut ret = vec![];
+    let mut pos = 0;
+    for (idx, _) in lower.match_indices('<') {
+        if idx > pos && BLOCK_ENDS.iter().any(|tag| lower[idx..].starts_with(tag)) {
+            ret.push(strip_html(&html[pos..idx]));
+            pos = idx;
+        }
+    }
+    ret.push(strip_html(&html[pos..]));
+    ret.retain(|x| !x.is_empty());
+    ret
+}
+
+/// Text of the first `tag` element in `html`, if any and not blank
+fn tag_text(html: &str, tag: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower
+        .match_indices('<')
+        .map(|(x, _)| x)
+        .find(|x| is_tag(&lower[*x..], tag))?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find(&format!("</{}", tag))?;
+    Some(strip_html(&html[start..end])).filter(|x| !x.is_empty())
+}
+
+/// Title for messages without a subject: the HTML `<title>` or first heading,
+/// otherwise the first non-blank line of the body
+pub fn derive_title(html: &str, text: &str) -> Option<String> {
+    TITLE_TAGS
+        .iter()
+        .find_map(|tag| tag_text(html, tag))
+        .or_else(|| {
+            text.lines()
+                .map(str::trim)
+                .find(|x| !x.is_empty())
+                .map(ToOwned::to_owned)
+        })
+        .or_else(|| Some(strip_html(html)).filter(|x| !x.is_empty()))
+        .map(|x| truncate(&x, TITLE_LEN))
+}
+
+/// Lowercased subject without reply/forward prefixes and redundant whitespace
+pub fn normalize_subject(subject: &str) -> String {
+    let mut ret = subject.trim().to_lowercase();
+    while let Some(rest) = ["re:", "fwd:", "fw:"]
+        .iter()
+        .find_map(|prefix| ret.strip_prefix(prefix))
+    {
+        ret = rest.trim_start().to_owned();
+    }
+    ret.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Part of `text` around the first of `terms`, HTML escaped, with every term
+/// wrapped in `<mark>`. Matching is ASCII case-insensitive.
+pub fn snippet(text: &str, terms: &[&str], radius: usize) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    let terms = terms
+        .iter()
+        .map(|x| x.to_ascii_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    let first = terms.iter().filter_map(|x| lower.find(x.as_str())).min()?;
+
+    let start = char_boundary(text, first.saturating_sub(radius));
+    let end = char_boundary(text, (first + radius).min(text.len()));
+    let (window, lower) = (&text[start..end], &lower[start..end]);
+
+    let mut ret = String::with_capacity(window.len() + 32);
+    if start > 0 {
+        ret.push('…');
+    }
+    let mut pos = 0;
+    while let Some((at, len)) = terms
+        .iter()
+        .filter_map(|x| lower[pos..].find(x.as_str()).map(|at| (pos + at, x.len())))
+        .min_by_key(|(at, len)| (*at, usize::MAX - len))
+    {
+        ret.push_str(&escape_html(&window[pos..at]));
+        ret.push_str("<mark>");
+        ret.push_str(&escape_html(&window[at..at + len]));
+        ret.push_str("</mark>");
+        pos = at + len;
+    }
+    ret.push_str(&escape_html(&window[pos..]));
+    if end < text.len() {
+        ret.push('…');
+    }
+    Some(ret)
+}
+
+/// Closest char boundary at or before `idx`
+fn char_boundary(text: &str, mut idx: usize) -> usize {
+    while !text.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// Distinctive lowercased words of `text`, at most `max` of them
+pub fn significant_terms(text: &str, max: usize) -> Vec<String> {
+    let mut ret = Vec::<String>::new();
+    for word in text
+        .split(|x: char| !x.is_alphanumeric())
+        .map(str::to_lowercase)
+    {
+        if ret.len() >= max {
+            break;
+        }
+        if word.chars().count() >= 4
+            && !word.chars().all(|x| x.is_ascii_digit())
+            && !STOP_WORDS.contains(&word.as_str())
+            && !ret.contains(&word)
+        {
+            ret.push(word);
+        }
+    }
+    ret
+}
+
+/// Whether `text` starts with the opening tag `tag`, e.g. `<head>` but not `<header>`
+fn is_tag(text: &str, tag: &str) -> bool {
+    text[1..].starts_with(tag)
+        && matches!(
+            text.as_bytes().get(tag.len() + 1),
+            Some(b'>' | b'/' | b' ' | b'\t' | b'\r' | b'\n')
+        )
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Escape text for XML, dropping control characters XML does not allow
+pub fn escape_xml(text: &str) -> String {
+    escape_html(
+        &text
+            .chars()
+            .filter(|x| !x.is_control() || matches!(x, '\t' | '\n' | '\r'))
+            .collect::<String>(),
+    )
+}
+
+/// Escape regex metacharacters so `text` matches literally in a MongoDB `$regex`
+pub fn escape_regex(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for x in text.chars() {
+        if "\\.+*?()[]{}|^$".contains(x) {
+            ret.push('\\');
+        }
+        ret.push(x);
+    }
+    ret
+}
+
+/// Cut `text` down to at most `len` characters, marking the cut with an ellipsis
+pub fn truncate(text: &str, len: usize) -> String {
+    match text.char_indices().nth(len) {
+        Some((idx, _)) => format!("{}…", text[..idx].trim_end()),
+        None => text.to_owned(),
+    }
+}
+
+/// Hide the domain of email addresses, e.g. `user@example.com` -> `user@example…`
+pub fn obfuscate_emails(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut pos = 0;
+
+    for (at, _) in text.match_indices('@') {
+        if at < pos {
+            continue;
+        }
+        let local_len = text[..at]
+            .bytes()
+            .rev()
+            .take_while(|x| x.is_ascii_alphanumeric() || b"._%+-".contains(x))
+            .count();
+        let domain_len = text[at + 1..]
+            .bytes()
+            .take_while(|x| x.is_ascii_alphanumeric() || b".-".contains(x))
+            .count();
+        let domain = text[at + 1..at + 1 + domain_len].trim_end_matches('.');
+        let label = domain.split('.').next().unwrap_or_default();
+        if local_len == 0 || label.is_empty() || !domain.contains('.') {
+            continue;
+        }
+        ret.push_str(&text[pos..=at]);
+        ret.push_str(label);
+        ret.push('…');
+        pos = at + 1 + domain.len();
+    }
+    ret.push_str(&text[pos..]);
+    ret
+}
+
+/// Percent-encode everything but unreserved characters of RFC 3986
+pub fn percent_encode(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for x in text.bytes() {
+        if x.is_ascii_alphanumeric() || b"-._~".contains(&x) {
+            ret.push(x as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", x));
+        }
+    }
+    ret
+}
+
+/// Characters kept of a title in download file names
+const FILENAME_LEN: usize = 100;
+
+/// `Content-Disposition` parameters naming a download after `title`, e.g.
+/// `filename="Hello_.eml"; filename*=UTF-8''Hello%E2%9C%A8.eml`, with an
+/// ASCII name for clients not reading the RFC 6266 one
+pub fn download_filename(title: &str, extension: &str) -> String {
+    let title = title.trim().chars().take(FILENAME_LEN).collect::<String>();
+    let title = match title.is_empty() {
+        true => "message".to_owned(),
+        false => title,
+    };
+    let ascii = title
+        .chars()
+        .map(|x| match x {
+            ' '..='~' if !matches!(x, '"' | '\\' | '/') => x,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!(
+        "filename=\"{}.{}\"; filename*=UTF-8''{}.{}",
+        ascii,
+        extension,
+        percent_encode(&title),
+        extension
+    )
+}
+
+/// Characters kept of a title in its slug
+const SLUG_LEN: usize = 60;
+
+/// URL slug of a title, e.g. `rust-1-58-is-out` for `Rust 1.58 is out!`
+pub fn slugify(title: &str) -> String {
+    let mut ret = String::new();
+    for x in title.chars().flat_map(char::to_lowercase) {
+        if x.is_alphanumeric() {
+            ret.push(x);
+        } else if !ret.is_empty() && !ret.ends_with('-') {
+            ret.push('-');
+        }
+    }
+    let mut ret = ret.chars().take(SLUG_LEN).collect::<String>();
+    while ret.ends_with('-') {
+        ret.pop();
+    }
+    ret
+}
+
+/// Decode `%XX` sequences, leaving malformed ones as they are
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(x)) => {
+                ret.push(x);
+                i += 3;
+            }
+            (x, _) => {
+                ret.push(x);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Phrases of links to the web version of a newsletter
+const WEB_VERSION_PHRASES: &[&str] = &[
+    "view in browser",
+    "view in your browser",
+    "view it in your browser",
+    "view this email in your browser",
+    "view online",
+    "view this email online",
+    "read online",
+    "web version",
+    "open in browser",
+];
+
+/// Value of an attribute of an HTML start tag, e.g. `href` of `<a href="…">`
+fn attr_value(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let pattern = format!("{}=", name);
+    let (idx, _) = lower
+        .match_indices(&pattern)
+        .find(|(idx, _)| lower[..*idx].ends_with(char::is_whitespace))?;
+    let start = idx + pattern.len();
+    let value = match tag.as_bytes().get(start) {
+        Some(quote @ (b'"' | b'\'')) => {
+            let rest = &tag[start + 1..];
+            &rest[..rest.find(*quote as char).unwrap_or(rest.len())]
+        }
+        _ => {
+            let rest = &tag[start..];
+            &rest[..rest.find(char::is_whitespace).unwrap_or(rest.len())]
+        }
+    };
+    Some(decode_entities(value))
+}
+
+/// URL of the link to the web version of a newsletter, e.g. "View in browser"
+pub fn web_version_link(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(offset) = lower[pos..].find("<a") {
+        let start = pos + offset;
+        let tag_end = start + lower[start..].find('>')?;
+        pos = tag_end;
+        // Other tags starting with `a`, e.g. `<abbr>`
+        if !lower[start + 2..].starts_with(char::is_whitespace) {
+            continue;
+        }
+        let close = lower[tag_end..]
+            .find("</a>")
+            .map_or(lower.len(), |x| tag_end + x);
+        let text = strip_html(&html[tag_end + 1..close])
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !WEB_VERSION_PHRASES.iter().any(|x| text.contains(x)) {
+            continue;
+        }
+        let url = attr_value(&html[start..tag_end], "href")
+            .filter(|x| x.starts_with("http://") || x.starts_with("https://"));
+        if url.is_some() {
+            return url;
+        }
+    }
+    None
+}
+
+/// Point remote images of `html` at `proxy`, which gets the percent-encoded
+/// original URL appended
+pub fn proxy_images(html: &str, proxy: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut ret = String::with_capacity(html.len());
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find("<img") {
+        let start = pos + offset;
+        let end = match lower[start..].find('>') {
+            Some(x) => start + x,
+            None => break,
+        };
+        let tag = &lower[start..end];
+        let attr = tag
+            .match_indices("src=")
+            .find(|(idx, _)| tag[..*idx].ends_with(char::is_whitespace));
+        let value_start = match attr {
+            Some((idx, _)) => start + idx + 4,
+            None => {
+                ret.push_str(&html[pos..end]);
+                pos = end;
+                continue;
+            }
+        };
+        let (value_start, value_end) = match html.as_bytes().get(value_start) {
+            Some(quote @ (b'"' | b'\'')) => (
+                value_start + 1,
+                lower[value_start + 1..end]
+                    .find(*quote as char)
+                    .map_or(end, |x| value_start + 1 + x),
+            ),
+            _ => (
+                value_start,
+                lower[value_start..end]
+                    .find(char::is_whitespace)
+                    .map_or(end, |x| value_start + x),
+            ),
+        };
+
+        ret.push_str(&html[pos..value_start]);
+        let url = decode_entities(&html[value_start..value_end]);
+        let scheme = url.split(':').next().unwrap_or_default();
+        if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") {
+            ret.push_str(&escape_html(&format!("{}{}", proxy, percent_encode(&url))));
+        } else {
+            ret.push_str(&html[value_start..value_end]);
+        }
+        pos = value_end;
+    }
+    ret.push_str(&html[pos..]);
+    ret
+}
+
+/// Longest tag kept by `normalize_tags`, in characters
+const MAX_TAG_LEN: usize = 64;
+
+/// Trimmed, non-empty and distinct tags, in the order given
+pub fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut ret: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN || ret.iter().any(|x| x == tag) {
+            continue;
+        }
+        ret.push(tag.to_owned());
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = vec![" rust ", "", "worth rereading", "rust", &"x".repeat(65)]
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect();
+        assert_eq!(normalize_tags(tags), vec!["rust", "worth rereading"]);
+    }
+
+    #[test]
+    fn test_strip_html() {
+        let html = r#"<html><head><title>T</title><style>p { color: red }</style></head>
+<body><header>Hi&nbsp;there</header><p>A &amp; B</p><script>alert(1)</script></body></html>"#;
+        assert_eq!(strip_html(html), "Hi there A & B");
+    }
+
+    #[test]
+    fn test_derive_title() {
+        assert_eq!(
+            derive_title("<html><head><title> Weekly </title></head></html>", ""),
+            Some("Weekly".to_owned())
+        );
+        assert_eq!(
+            derive_title("<body><h1>Alert <b>fired</b></h1></body>", ""),
+            Some("Alert fired".to_owned())
+        );
+        assert_eq!(
+            derive_title("", "\n  \nDisk full on host-1\nmore"),
+            Some("Disk full on host-1".to_owned())
+        );
+        assert_eq!(derive_title("", " "), None);
+    }
+
+    #[test]
+    fn test_normalize_subject() {
+        assert_eq!(
+            normalize_subject("Re: FWD:  [cron]   Backup  failed "),
+            "[cron] backup failed"
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text = "The quick brown fox jumps over the lazy dog";
+        assert_eq!(
+            snippet(text, &["FOX", "dog"], 12).as_deref(),
+            Some("…quick brown <mark>fox</mark> jumps ov…")
+        );
+        assert_eq!(
+            snippet("a < b & c", &["b"], 20).as_deref(),
+            Some("a &lt; <mark>b</mark> &amp; c")
+        );
+        assert_eq!(snippet(text, &["cat"], 10), None);
+    }
+
+    #[test]
+    fn test_significant_terms() {
+        assert_eq!(
+            significant_terms("This week in Rust: async traits, async closures & 2022", 3),
+            vec!["week", "rust", "async"]
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("hello world", 20), "hello world");
+        assert_eq!(truncate("hello world", 6), "hello…");
+        assert_eq!(truncate("你好世界", 2), "你好…");
+    }
+
+    #[test]
+    fn test_obfuscate_emails() {
+        assert_eq!(
+            obfuscate_emails("Mail user@example.com or <a@b.example.org>."),
+            "Mail user@example… or <a@b…>."
+        );
+        assert_eq!(
+            obfuscate_emails("@handle and a@localhost"),
+            "@handle and a@localhost"
+        );
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("news%40example.com"), "news@example.com");
+        assert_eq!(percent_decode(&percent_encode("a b/ü")), "a b/ü");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Rust 1.58 is out!"), "rust-1-58-is-out");
+        assert_eq!(
+            slugify("  [News] Übersicht -- März  "),
+            "news-übersicht-märz"
+        );
+        assert_eq!(slugify("!!!"), "");
+        assert_eq!(slugify(&"a ".repeat(40)).len(), 59);
+    }
+
+    #[test]
+    fn test_download_filename() {
+        assert_eq!(
+            download_filename("Hello✨", "eml"),
+            "filename=\"Hello_.eml\"; filename*=UTF-8''Hello%E2%9C%A8.eml"
+        );
+        assert_eq!(
+            download_filename(" a/\"b\" ", "eml"),
+            "filename=\"a__b_.eml\"; filename*=UTF-8''a%2F%22b%22.eml"
+        );
+        assert_eq!(
+            download_filename("", "eml"),
+            "filename=\"message.eml\"; filename*=UTF-8''message.eml"
+        );
+    }
+
+    #[test]
+    fn test_web_version_link() {
+        assert_eq!(
+            web_version_link(
+                r#"<p><abbr>x</abbr><a href="https://example.com/x">Unsubscribe</a></p>
+                <a class="v" href="https://example.com/issue/1?a=1&amp;b=2"><span>View this email
+                in your browser</span></a>"#
+            ),
+            Some("https://example.com/issue/1?a=1&b=2".to_owned())
+        );
+        assert_eq!(
+            web_version_link(r#"<a href=mailto:x@example.com>Read online</a>"#),
+            None
+        );
+        assert_eq!(web_version_link("<p>No links</p>"), None);
+    }
+
+    #[test]
+    fn test_proxy_images() {
+        assert_eq!(
+            proxy_images(
+                r#"<p><IMG alt="x" SRC="https://t.example.com/a.png?x=1&amp;y=2"><img src=cid:logo>"#,
+                "https://proxy.example.org/?url="
+            ),
+            r#"<p><IMG alt="x" SRC="https://proxy.example.org/?url=https%3A%2F%2Ft.example.com%2Fa.png%3Fx%3D1%26y%3D2"><img src=cid:logo>"#
+        );
+        assert_eq!(
+            proxy_images("<img data-src='a'>", "p"),
+            "<img data-src='a'>"
+        );
+    }
+
+    #[test]
+    fn test_html_paragraphs() {
+        assert_eq!(
+            html_paragraphs("<p>Hello <b>world</b></p><p>Second<br>line</p><div></div>"),
+            vec!["Hello world", "Second", "line"]
+        );
+        assert!(html_paragraphs("").is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+//! HTTPS terminated by the web server itself, with the certificate chain and
+//! key at `TLS_CERT` and `TLS_KEY`, for deployments without a reverse proxy
+
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls_pemfile::Item;
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{
+    rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::{config::get_config, proxy::ProxiedStream};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type TlsConnection = ProxiedStream<TlsStream<ProxiedStream<TcpStream>>>;
+
+fn read_pem(path: &str) -> Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("Cannot open {}", path))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Cannot read {}", path))
+}
+
+/// Acceptor of TLS connections, if `TLS_CERT` and `TLS_KEY` are set
+pub fn acceptor() -> Result<Option<TlsAcceptor>> {
+    let config = get_config();
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let certs = read_pem(cert)?
+        .into_iter()
+        .filter_map(|x| match x {
+            Item::X509Certificate(x) => Some(Certificate(x)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("No certificate in {}", cert);
+    }
+    let key = read_pem(key)?
+        .into_iter()
+        .find_map(|x| match x {
+            Item::PKCS8Key(x) | Item::RSAKey(x) => Some(PrivateKey(x)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key in {}", key))?;
+
+    let mut server = ServerConfig::new(NoClientAuth::new());
+    server.set_single_cert(certs, key)?;
+    server.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(Some(TlsAcceptor::from(Arc::new(server))))
+}
+
+/// Complete the TLS handshake of a connection, keeping its client address
+pub async fn accept(acceptor: &TlsAcceptor, stream: ProxiedStream) -> Result<TlsConnection> {
+    let remote_addr = stream.remote_addr();
+    let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| anyhow!("Timed out in TLS handshake with {}", remote_addr))?
+        .with_context(|| format!("TLS handshake with {} failed", remote_addr))?;
+    Ok(ProxiedStream::new(stream, remote_addr))
+}
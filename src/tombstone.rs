@@ -0,0 +1,56 @@
+//! Records of deleted items, so that their routes answer `410 Gone` instead of
+//! `404 Not Found` and readers and caches drop them for good
+
+use anyhow::Result;
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use mongodb::{
+    bson::doc,
+    options::{IndexOptions, UpdateOptions},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+
+pub type Tombstones = Collection<Tombstone>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tombstone {
+    /// Id of the deleted item
+    pub id: String,
+    #[serde(with = "ts_milliseconds")]
+    pub deleted_at: DateTime<Utc>,
+    /// Action that deleted it, as recorded in the `audit` collection
+    pub action: String,
+}
+
+pub async fn ensure_indexes(tombstones: &Tombstones) -> Result<()> {
+    tombstones
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Record items `ids` as deleted by `action`, keeping the first record of each
+pub async fn bury(tombstones: &Tombstones, ids: &[String], action: &str) -> Result<()> {
+    let now = Utc::now().timestamp_millis();
+    for id in ids {
+        tombstones
+            .update_one(
+                doc! { "id": id },
+                doc! { "$setOnInsert": { "deleted_at": now, "action": action } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Record of item `id`, if it was deleted
+pub async fn find(tombstones: &Tombstones, id: &str) -> Result<Option<Tombstone>> {
+    Ok(tombstones.find_one(doc! { "id": id }, None).await?)
+}
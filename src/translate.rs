@@ -0,0 +1,196 @@
+//! Machine translation of items through DeepL or LibreTranslate, enabled by
+//! `TRANSLATE_BACKEND`. Translations are stored in the item by language.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use mongodb::bson::{doc, to_bson, Document};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    client::http_client,
+    config::get_config,
+    db::{Feed, Feeds, Translation},
+    validator,
+};
+
+const DEEPL_URL: &str = "https://api-free.deepl.com/v2/translate";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    DeepL,
+    LibreTranslate,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "deepl" => Ok(Backend::DeepL),
+            "libretranslate" => Ok(Backend::LibreTranslate),
+            _ => bail!("Unknown translation backend {}", s),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeepLResponse {
+    translations: Vec<DeepLTranslation>,
+}
+
+#[derive(Deserialize)]
+struct DeepLTranslation {
+    detected_source_language: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LibreResponse {
+    translated_text: String,
+    detected_language: Option<LibreDetected>,
+}
+
+#[derive(Deserialize)]
+struct LibreDetected {
+    language: String,
+}
+
+/// Whether two language codes name the same language, ignoring regions
+fn same_language(a: &str, b: &str) -> bool {
+    let base = |x: &str| {
+        x.split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    base(a) == base(b)
+}
+
+/// Translate `text` (HTML when `html`) into `lang`, returning the detected
+/// source language with the translation
+async fn translate(text: &str, html: bool, lang: &str) -> Result<(String, String)> {
+    let config = get_config();
+    let backend = config
+        .translate_backend
+        .ok_or_else(|| anyhow!("Translation is not enabled"))?;
+    match backend {
+        Backend::DeepL => {
+            let url = config.translate_api_url.as_deref().unwrap_or(DEEPL_URL);
+            let target = lang.to_uppercase();
+            let mut form = vec![("text", text), ("target_lang", target.as_str())];
+            if html {
+                form.push(("tag_handling", "html"));
+            }
+            let mut req = http_client().post(url).form(&form);
+            if let Some(key) = &config.translate_api_key {
+                req = req.header("Authorization", format!("DeepL-Auth-Key {}", key));
+            }
+            let res = req
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<DeepLResponse>()
+                .await?;
+            res.translations
+                .into_iter()
+                .next()
+                .map(|x| (x.detected_source_language, x.text))
+                .ok_or_else(|| anyhow!("Empty translation"))
+        }
+        Backend::LibreTranslate => {
+            let url = config
+                .translate_api_url
+                .as_ref()
+                .ok_or_else(|| anyhow!("TRANSLATE_API_URL is required for LibreTranslate"))?;
+            let res = http_client()
+                .post(format!("{}/translate", url.trim_end_matches('/')))
+                .json(&json!({
+                    "q": text,
+                    "source": "auto",
+                    "target": lang,
+                    "format": if html { "html" } else { "text" },
+                    "api_key": config.translate_api_key,
+                }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<LibreResponse>()
+                .await?;
+            let detected = res
+                .detected_language
+                .map(|x| x.language)
+                .unwrap_or_default();
+            Ok((detected, res.translated_text))
+        }
+    }
+}
+
+/// Translation of `feed` into `lang`, `None` if it is already in `lang`
+pub async fn translate_feed(feed: &Feed, lang: &str) -> Result<Option<Translation>> {
+    let (source, title) = translate(&feed.title, false, lang).await?;
+    if same_language(&source, lang) {
+        return Ok(None);
+    }
+    let content = match feed.content.is_empty() {
+        true => String::new(),
+        false => translate(&feed.content, true, lang).await?.1,
+    };
+    Ok(Some(Translation { title, content }))
+}
+
+/// Translate `feed` into `lang` and store the translation. Items already in
+/// `lang` are stored with their own title and content, so they are not tried
+/// again.
+pub async fn store_translation(feeds: &Feeds, feed: &mut Feed, lang: &str) -> Result<()> {
+    let lang = lang.to_lowercase();
+    // Used in a field path below
+    if lang.is_empty()
+        || lang.len() > 16
+        || !lang.chars().all(|x| x.is_ascii_alphanumeric() || x == '-')
+    {
+        bail!("Invalid language {}", lang);
+    }
+    let translation = translate_feed(feed, &lang)
+        .await?
+        .unwrap_or_else(|| Translation {
+            title: feed.title.clone(),
+            content: feed.content.clone(),
+        });
+    let mut set = Document::new();
+    set.insert(format!("translations.{}", lang), to_bson(&translation)?);
+    feeds
+        .update_one(doc! { "id": &feed.id }, doc! { "$set": set }, None)
+        .await?;
+    validator::touch();
+    info!(target: "Translate", id = feed.id.as_str(), lang = lang.as_str(), "Translated");
+    feed.translations.insert(lang, translation);
+    Ok(())
+}
+
+/// Translate a new item into `TRANSLATE_TARGET`, logging failures
+pub async fn translate_stored(feeds: Feeds, mut feed: Feed) {
+    let lang = match &get_config().translate_target {
+        Some(x) => x,
+        None => return,
+    };
+    if let Err(e) = store_translation(&feeds, &mut feed, lang).await {
+        warn!(target: "Translate", "Error translating {}: {}", feed.id, e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_same_language() {
+        assert!(same_language("EN", "en-US"));
+        assert!(same_language("pt_BR", "PT"));
+        assert!(!same_language("JA", "en"));
+        assert!(!same_language("", "en"));
+    }
+}
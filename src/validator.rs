@@ -0,0 +1,135 @@
+//! `ETag` and `Last-Modified` of feeds, changed on every write to items so
+//! that polls of unchanged feeds are answered with 304 without querying
+
+use std::sync::Mutex;
+
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+
+struct State {
+    /// Start of the process, since the counter is not persisted
+    boot: DateTime<Utc>,
+    generation: u64,
+    modified: DateTime<Utc>,
+    /// Times items already stored show up in feeds, see `publish_delay`
+    scheduled: Vec<DateTime<Utc>>,
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| {
+    let now = Utc::now();
+    Mutex::new(State {
+        boot: now,
+        generation: 0,
+        modified: now,
+        scheduled: vec![],
+    })
+});
+
+/// Count a change of items shown in feeds
+pub fn touch() {
+    let mut state = STATE.lock().expect("validator poisoned");
+    state.generation += 1;
+    state.modified = Utc::now();
+}
+
+/// Count a change taking effect at `at`, e.g. a delayed item being published
+pub fn schedule(at: DateTime<Utc>) {
+    if at <= Utc::now() {
+        return touch();
+    }
+    STATE.lock().expect("validator poisoned").scheduled.push(at);
+}
+
+pub struct Validator {
+    tag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Validator of feeds as of now
+pub fn current() -> Validator {
+    let now = Utc::now();
+    let mut state = STATE.lock().expect("validator poisoned");
+    let due = state.scheduled.iter().filter(|x| **x <= now).max().copied();
+    if let Some(due) = due {
+        state.scheduled.retain(|x| *x > now);
+        state.generation += 1;
+        state.modified = state.modified.max(due);
+    }
+    Validator {
+        tag: format!("{:x}-{}", state.boot.timestamp_millis(), state.generation),
+        last_modified: state.modified,
+    }
+}
+
+impl Validator {
+    /// Changes with every write to items, unlike `etag` the same for all
+    /// representations
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// `ETag` of a representation, e.g. one of several formats of a feed.
+    /// Weak, as responses may be compressed.
+    pub fn etag(&self, variant: &str) -> String {
+        format!(r#"W/"{}-{}""#, self.tag, variant)
+    }
+
+    pub fn http_date(&self) -> String {
+        self.last_modified
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    /// Whether the client already has `etag`, by `If-None-Match`, or else by
+    /// `If-Modified-Since`
+    pub fn is_fresh(&self, headers: &HeaderMap, etag: &str) -> bool {
+        let value = |name| headers.get(name).and_then(|x| x.to_str().ok());
+        if let Some(x) = value(header::IF_NONE_MATCH) {
+            return x
+                .split(',')
+                .map(|x| x.trim().trim_start_matches("W/"))
+                .any(|x| x == "*" || x == etag.trim_start_matches("W/"));
+        }
+        match value(header::IF_MODIFIED_SINCE).map(DateTime::parse_from_rfc2822) {
+            // Dates in headers are precise to the second
+            Some(Ok(since)) => self.last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_is_fresh() {
+        let validator = current();
+        let etag = validator.etag("rss");
+        let with = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert!(!validator.is_fresh(&HeaderMap::new(), &etag));
+        assert!(validator.is_fresh(&with(header::IF_NONE_MATCH, &etag), &etag));
+        assert!(validator.is_fresh(
+            &with(
+                header::IF_NONE_MATCH,
+                &format!(r#""x", {}"#, etag.trim_start_matches("W/"))
+            ),
+            &etag
+        ));
+        assert!(!validator.is_fresh(&with(header::IF_NONE_MATCH, r#""x""#), &etag));
+        assert!(validator.is_fresh(
+            &with(header::IF_MODIFIED_SINCE, &validator.http_date()),
+            &etag
+        ));
+        assert!(!validator.is_fresh(
+            &with(header::IF_MODIFIED_SINCE, "Sat, 01 Jan 2000 00:00:00 GMT"),
+            &etag
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! Consistency check of the archive, see `/admin/verify`: items without raw
+//! source or whose source cannot be decoded, duplicate ids, and content chunks
+//! without their item or items without their chunks. Checks run in the
+//! background, one at a time, and report through `status`.
+
+use std::{collections::HashSet, sync::Mutex};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mail_parser::Message;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{self, AuditLog},
+    blob::{self, Blobs},
+    db::{new_id, Feed, Feeds},
+    validator,
+};
+
+/// Chunks are stored before their item, so younger chunks without an item
+/// may belong to one being stored
+const ORPHAN_GRACE_SECS: i64 = 3600;
+
+#[derive(Serialize, Default, Clone)]
+pub struct Report {
+    /// Items looked at
+    pub scanned: u64,
+    /// Ids of items without raw source
+    pub missing_raw: Vec<String>,
+    /// Ids of items whose raw source cannot be made into an item again
+    pub undecodable: Vec<String>,
+    /// Ids shared by several items
+    pub duplicate_ids: Vec<String>,
+    /// Ids of missing items content chunks are left of
+    pub orphaned_chunks: Vec<String>,
+    /// Ids of items over `MAX_CONTENT_SIZE` without content chunks
+    pub missing_chunks: Vec<String>,
+    /// Problems fixed, when repairing
+    pub repaired: u64,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.missing_raw.is_empty()
+            && self.undecodable.is_empty()
+            && self.duplicate_ids.is_empty()
+            && self.orphaned_chunks.is_empty()
+            && self.missing_chunks.is_empty()
+    }
+}
+
+/// State of the latest check
+#[derive(Serialize, Default, Clone)]
+pub struct Status {
+    pub running: bool,
+    /// Whether the check repairs what it can
+    pub repair: bool,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Findings, once finished
+    pub report: Option<Report>,
+    /// Why the check stopped, if it failed
+    pub error: Option<String>,
+}
+
+static STATUS: Lazy<Mutex<Status>> = Lazy::new(Default::default);
+
+pub fn status() -> Status {
+    STATUS.lock().expect("verify status poisoned").clone()
+}
+
+/// Start a check in the background, see `run`. Returns `false` without
+/// starting one while another is running.
+pub fn start(feeds: Feeds, blobs: Blobs, audit: AuditLog, repair: bool) -> bool {
+    {
+        let mut status = STATUS.lock().expect("verify status poisoned");
+        if status.running {
+            return false;
+        }
+        *status = Status {
+            running: true,
+            repair,
+            started_at: Some(Utc::now()),
+            ..Default::default()
+        };
+    }
+    tokio::spawn(async move {
+        let result = run(&feeds, &blobs, repair).await;
+        if let (true, Ok(report)) = (repair, &result) {
+            audit::record(&audit, "repair", "archive", report.repaired).await;
+        }
+        let mut status = STATUS.lock().expect("verify status poisoned");
+        status.running = false;
+        status.finished_at = Some(Utc::now());
+        match result {
+            Ok(report) => status.report = Some(report),
+            Err(e) => {
+                warn!(target: "Verify", "Check failed: {}", e);
+                status.error = Some(e.to_string());
+            }
+        }
+    });
+    true
+}
+
+/// Ids of items with chunks matching `filter`
+async fn chunked_ids(blobs: &Blobs, filter: Document) -> Result<HashSet<String>> {
+    Ok(blobs
+        .distinct("id", filter, None)
+        .await?
+        .into_iter()
+        .filter_map(|x| x.as_str().map(ToOwned::to_owned))
+        .collect())
+}
+
+/// Lowest object id generated at `secs` since the epoch
+fn oid_at(secs: i64) -> ObjectId {
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&(secs.max(0) as u32).to_be_bytes());
+    ObjectId::from_bytes(bytes)
+}
+
+/// Build the item again from the raw source, as if just received
+fn reprocess(raw: &str, from_box: &str) -> Option<Feed> {
+    let parsed = Message::parse(raw.as_bytes())?;
+    Feed::from_message(raw.as_bytes(), parsed, from_box.to_owned(), None).ok()
+}
+
+/// Scan every item. With `repair`, duplicate ids past the first are replaced
+/// with new ones, orphaned chunks are deleted and items missing their chunks
+/// are reprocessed from the raw source. Missing or undecodable sources cannot
+/// be repaired and are only reported. Chunks younger than
+/// `ORPHAN_GRACE_SECS` are never taken for orphans.
+async fn run(feeds: &Feeds, blobs: &Blobs, repair: bool) -> Result<Report> {
+    let mut report = Report::default();
+    let cutoff = oid_at(Utc::now().timestamp() - ORPHAN_GRACE_SECS);
+    let settled = chunked_ids(blobs, doc! { "_id": { "$lt": cutoff } }).await?;
+    let content_chunk = |id: Option<&str>| {
+        let mut filter = doc! { "raw": { "$ne": true } };
+        if let Some(id) = id {
+            filter.insert("id", id);
+        }
+        filter
+    };
+    let chunked = chunked_ids(blobs, content_chunk(None)).await?;
+    let mut seen = HashSet::new();
+
+    let option = FindOptions::builder()
+        .projection(doc! { "id": 1, "raw": 1, "from_box": 1, "overflow": 1, "raw_chunked": 1 })
+        .sort(doc! { "_id": 1 })
+        .build();
+    let mut cursor = feeds
+        .clone_with_type::<Document>()
+        .find(None, option)
+        .await?;
+    while let Some(item) = cursor.try_next().await? {
+        report.scanned += 1;
+        let mut id = item.get_str("id").unwrap_or_default().to_owned();
+        let raw = match item.get_bool("raw_chunked").unwrap_or(false) {
+            true => blob::load_raw(blobs, &id).await?,
+            false => item.get_str("raw").unwrap_or_default().to_owned(),
+        };
+        let from_box = item.get_str("from_box").unwrap_or_default();
+        let overflow = item.get_bool("overflow").unwrap_or(false);
+
+        if !seen.insert(id.clone()) {
+            report.duplicate_ids.push(id.clone());
+            if repair {
+                let new_id = new_id();
+                feeds
+                    .update_one(
+                        doc! { "_id": item.get("_id").cloned() },
+                        doc! { "$set": { "id": &new_id } },
+                        None,
+                    )
+                    .await?;
+                info!(target: "Verify", "Item {} given new id {}", id, new_id);
+                report.repaired += 1;
+                seen.insert(new_id.clone());
+                id = new_id;
+            }
+        }
+
+        let decoded = match raw.is_empty() {
+            true => {
+                report.missing_raw.push(id.clone());
+                None
+            }
+            false => {
+                let decoded = reprocess(&raw, from_box);
+                if decoded.is_none() {
+                    report.undecodable.push(id.clone());
+                }
+                decoded
+            }
+        };
+
+        // Stored since the chunks were listed, or really missing them
+        let missing = overflow
+            && !chunked.contains(&id)
+            && blobs
+                .count_documents(content_chunk(Some(&id)), None)
+                .await?
+                == 0;
+        if missing {
+            report.missing_chunks.push(id.clone());
+            if let (true, Some(mut fresh)) = (repair, decoded) {
+                fresh.id = id.clone();
+                // Only the content is missing its chunks
+                fresh.raw.clear();
+                blob::overflow(blobs, &mut fresh).await?;
+                feeds
+                    .update_one(
+                        doc! { "_id": item.get("_id").cloned() },
+                        doc! { "$set": {
+                            "content": &fresh.content,
+                            "text": &fresh.text,
+                            "overflow": fresh.overflow,
+                        } },
+                        None,
+                    )
+                    .await?;
+                report.repaired += 1;
+            }
+        }
+    }
+
+    report.orphaned_chunks = settled.into_iter().filter(|x| !seen.contains(x)).collect();
+    report.orphaned_chunks.sort();
+    if repair && !report.orphaned_chunks.is_empty() {
+        blob::remove(blobs, &report.orphaned_chunks).await?;
+        report.repaired += report.orphaned_chunks.len() as u64;
+    }
+
+    if report.repaired > 0 {
+        validator::touch();
+    }
+    if !report.is_clean() {
+        warn!(
+            target: "Verify",
+            missing_raw = report.missing_raw.len(),
+            undecodable = report.undecodable.len(),
+            duplicate_ids = report.duplicate_ids.len(),
+            orphaned_chunks = report.orphaned_chunks.len(),
+            missing_chunks = report.missing_chunks.len(),
+            repaired = report.repaired,
+            "Archive inconsistent"
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_oid_at() {
+        assert_eq!(oid_at(0x01020304).bytes()[..4], [1, 2, 3, 4]);
+        assert!(oid_at(1000) < oid_at(1001));
+        assert_eq!(oid_at(-5), oid_at(0));
+    }
+}
@@ -1,37 +1,111 @@
-use std::{collections::HashMap, net::SocketAddr, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::Result;
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{
-    extract::{Extension, Path, Query},
+    body::{boxed, Body, Bytes, Empty, StreamBody},
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Extension, FromRequest, Path, Query, RequestParts,
+    },
     handler::Handler,
     http::{
-        header::{self, HeaderName, CONTENT_TYPE},
+        header::{
+            self, HeaderName, CACHE_CONTROL, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+            REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, USER_AGENT, X_CONTENT_TYPE_OPTIONS,
+            X_FRAME_OPTIONS,
+        },
         uri::{Authority, Scheme},
-        HeaderValue, Request, StatusCode,
+        HeaderMap, HeaderValue, Method, Request, StatusCode, Uri,
     },
-    response::{Headers, Html, IntoResponse, Redirect, Response},
-    routing::{any, get},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Headers, Html, IntoResponse, Redirect, Response,
+    },
+    routing::{any, delete, get, post, put},
     AddExtensionLayer, Json, Router,
 };
 use axum_extra::middleware::{middleware_fn, Next};
-use chrono::Utc;
-use futures::{StreamExt, TryStreamExt};
+use chrono::{NaiveDate, TimeZone, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use mail_parser::Message;
 use mongodb::{
     bson::{doc, Document},
-    options::{DistinctOptions, FindOptions},
+    options::FindOptions,
+};
+use rss::{
+    extension::{
+        atom::{AtomExtension, Link},
+        Extension as RssExtension,
+    },
+    ImageBuilder,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener},
+    sync::mpsc,
 };
-use serde::Deserialize;
+use tower::ServiceExt;
 use tower_http::{
     auth::RequireAuthorizationLayer,
+    compression::CompressionLayer,
     cors,
+    services::ServeDir,
     set_header::SetResponseHeaderLayer,
-    trace::{OnRequest, OnResponse, TraceLayer},
+    trace::{MakeSpan, OnRequest, OnResponse, TraceLayer},
 };
 use tracing::{info, log::warn, Level};
 
 use crate::{
+    admin::{self, ItemEdit},
+    analytics::{self, Hits},
+    apikeys::{self, ApiKey, ApiKeys, Scope},
+    atom::{render_atom, AtomFeed, HISTORY_NAMESPACE},
+    audit::{self, AuditLog},
+    auth::{self, Credential, Restricted},
+    blob::{self, Blobs},
+    channel::Channel,
     config::get_config,
-    db::{Feeds, List, Summary},
+    db::{
+        attachment, attachments, body_text, created_between, published, sender_filter, Attachment,
+        Cursor, Feed, Feeds, List, Pagination, SmtpEnvelope, Sort, SortField, SortOrder,
+        StoredHeader, Summary,
+    },
+    digest::{render_digest, Period},
+    epub::render_epub,
+    error::{ApiError, ApiResult, PageError, PageResult},
+    events::{self as item_events, NewItem},
+    export,
+    favicon::{self, Favicons},
+    feedcache, fulltext,
+    graphql::{self, ApiSchema},
+    headers::{auth_result, parse_headers, spam_flagged, spam_score},
+    jsonfeed::{render_json_feed, FeedFormat},
+    metrics, openapi,
+    opml::render_opml,
+    pdf,
+    proxy::{self, ClientIp, ProxiedStream},
+    ratelimit, reader,
+    registry::{self, BoxRecord, Registry},
+    selftest,
+    sitemap::{self, render_sitemap},
+    smtp::{self, Outcome},
+    stats, store, templates,
+    text::{
+        download_filename, escape_regex, obfuscate_emails, percent_decode, percent_encode,
+        proxy_images, significant_terms, snippet, strip_html,
+    },
+    tls,
+    tombstone::{self, Tombstones},
+    translate, validator, verify, websub, TX,
 };
 
 fn utf8_header(res: &Response) -> Option<HeaderValue> {
@@ -47,30 +121,259 @@ fn utf8_header(res: &Response) -> Option<HeaderValue> {
     None
 }
 
-async fn http_rediretor<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+/// Redirect requests proxies received over plain HTTP to HTTPS, see
+/// `HTTPS_REDIRECT`
+async fn https_redirector<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
     let config = get_config();
-
-    match req
-        .headers()
-        .get(HeaderName::from_lowercase(b"x-forwarded-proto").unwrap())
-    {
-        Some(schema) if schema.to_str().map(|x| x != "https").unwrap_or(true) => {
+    if !config.https_redirect {
+        return Ok(next.run(req).await);
+    }
+    match proxy::forwarded_proto(peer_ip(&req), req.headers()) {
+        Some(proto) if proto != "https" => {
             let mut parts = req.uri().clone().into_parts();
             parts.scheme = Some(Scheme::HTTPS);
-            parts.authority = Some(Authority::from_str(&config.web_domain).expect("Bad domain"));
-            Err(Redirect::permanent(parts.try_into().unwrap()))
+            parts.authority = Authority::from_str(&config.web_domain).ok();
+            match Uri::from_parts(parts) {
+                Ok(uri) => Err(Redirect::permanent(uri)),
+                Err(_) => Ok(next.run(req).await),
+            }
         }
         _ => Ok(next.run(req).await),
     }
 }
 
+/// Redirect URLs of renamed boxes, e.g. `/rss/old@example.com/digest`, to the
+/// new name
+async fn box_redirector<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let mut segments = req.uri().path().splitn(4, '/').skip(1);
+    if let (Some(kind @ ("rss" | "atom" | "boxes")), Some(name)) =
+        (segments.next(), segments.next())
+    {
+        if let Some(to) = registry::renamed_to(&percent_decode(name)) {
+            let mut location = format!("/{}/{}", kind, percent_encode(&to));
+            if let Some(rest) = segments.next() {
+                location.push('/');
+                location.push_str(rest);
+            }
+            if let Some(query) = req.uri().query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            if let Ok(uri) = location.parse() {
+                return Err(Redirect::permanent(uri));
+            }
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+/// Address the connection of a request comes from, e.g. a proxy
+fn peer_ip<B>(req: &Request<B>) -> IpAddr {
+    // Connections over `WEB_SOCKET` have no address, and come from a local proxy
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(Ipv4Addr::LOCALHOST.into(), |x| x.0.ip())
+}
+
+/// Resolve the client address through trusted proxies, see `TRUSTED_PROXIES`
+async fn client_addr<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let client = proxy::client_ip(peer_ip(&req), req.headers());
+    req.extensions_mut().insert(ClientIp(client));
+    next.run(req).await
+}
+
+/// Routes whose `HEAD` responses carry the `Content-Length` of the `GET` one:
+/// feeds, items and their sources
+fn is_measured(path: &str) -> bool {
+    let mut segments = path.split('/').skip(1);
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("rss" | "atom"), _, _) => true,
+        // Including permalinks, `/feeds/:key/:slug`
+        (Some("feeds"), Some(_), Some("pdf" | "json" | "related" | "tags")) => false,
+        (Some("feeds"), Some(_), _) => segments.next().is_none(),
+        _ => false,
+    }
+}
+
+/// Item whose routes `path` is under, e.g. `/feeds/:key/raw`
+fn item_key(path: &str) -> Option<String> {
+    let mut segments = path.split('/').skip(1);
+    match (segments.next(), segments.next()) {
+        (Some("feeds"), Some(key)) if !key.is_empty() => Some(percent_decode(key)),
+        _ => None,
+    }
+}
+
+/// Answer `410 Gone` instead of `404 Not Found` on the routes of deleted items,
+/// as a page or as JSON like the route would
+async fn gone<B>(req: Request<B>, next: Next<B>) -> Response {
+    let key = item_key(req.uri().path());
+    let tombstones = req.extensions().get::<Tombstones>().cloned();
+    let res = next.run(req).await;
+    let (key, tombstones) = match (key, tombstones) {
+        (Some(key), Some(tombstones)) if res.status() == StatusCode::NOT_FOUND => (key, tombstones),
+        _ => return res,
+    };
+    let found = match tombstone::find(&tombstones, &key).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return res,
+        Err(e) => return ApiError::from(e).into_response(),
+    };
+    let is_page = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .map_or(false, |x| x.as_bytes().starts_with(b"text/html"));
+    let error = ApiError::gone(format!(
+        "{} was deleted on {}",
+        key,
+        found.deleted_at.format("%Y-%m-%d")
+    ));
+    match is_page {
+        true => PageError(error).into_response(),
+        false => error.into_response(),
+    }
+}
+
+/// Answer `HEAD` on feeds and items as `GET`, with the length of the body that
+/// would be sent but not the body, as some feed fetchers probe with it first.
+/// The body is produced and measured, so this saves the transfer but not the
+/// work, short of feeds in `feedcache` and of `304`s.
+async fn head<B>(mut req: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    if req.method() != Method::HEAD || !is_measured(req.uri().path()) {
+        return Ok(next.run(req).await);
+    }
+    *req.method_mut() = Method::GET;
+    let (mut parts, body) = next.run(req).await.into_parts();
+    let length = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| anyhow::anyhow!("Error measuring the response: {}", e))?
+        .len();
+    if parts.status != StatusCode::NOT_MODIFIED {
+        parts.headers.insert(header::CONTENT_LENGTH, length.into());
+    }
+    Ok(Response::from_parts(parts, boxed(Empty::new())))
+}
+
+/// Without admin credentials, refuse admin routes and anything but reading,
+/// and GraphQL mutations by marking queries `Restricted`
+async fn read_only<B>(mut req: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    let (method, path) = (req.method(), req.uri().path());
+    if auth::is_query(method, path) {
+        req.extensions_mut().insert(Restricted);
+    } else if !auth::is_reading(method, path) && method != Method::OPTIONS {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This needs AUTH_USERNAME and AUTH_PASSWORD",
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+/// Answer 429 to clients over their budget, see `ratelimit`
+async fn rate_limit<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let ip = req
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(Ipv4Addr::LOCALHOST.into(), |x| x.0);
+    if let Err(wait) = ratelimit::check(&req, ip) {
+        return Err(too_many(wait, "Too many requests"));
+    }
+    Ok(next.run(req).await)
+}
+
+/// Answer 429 to addresses refused credentials too often, see `ratelimit`
+async fn auth_limit<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let ip = req
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(Ipv4Addr::LOCALHOST.into(), |x| x.0);
+    if let Err(wait) = ratelimit::check_refused(ip) {
+        return Err(too_many(wait, "Too many refused credentials"));
+    }
+    let res = next.run(req).await;
+    if res.status() == StatusCode::UNAUTHORIZED {
+        ratelimit::refused(ip);
+    }
+    Ok(res)
+}
+
+/// 429 telling to wait `wait`
+fn too_many(wait: Duration, message: &'static str) -> Response {
+    let secs = wait.as_secs_f64().ceil() as u64;
+    (
+        Headers(vec![(header::RETRY_AFTER, secs.to_string())]),
+        ApiError::new(StatusCode::TOO_MANY_REQUESTS, message),
+    )
+        .into_response()
+}
+
+/// Answer 504 to requests outlasting their timeout, e.g. stuck on the
+/// database, see `Config::timeout_for`
+async fn timeout<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let path = req.uri().path().to_owned();
+    let secs = get_config().timeout_for(&path);
+    if secs == 0 {
+        return Ok(next.run(req).await);
+    }
+    tokio::time::timeout(Duration::from_secs(secs), next.run(req))
+        .await
+        .map_err(|_| {
+            warn!(target: "web", "Request to {} timed out after {}s", path, secs);
+            ApiError::new(StatusCode::GATEWAY_TIMEOUT, "Request timed out")
+        })
+}
+
+/// Header carrying the ID of a request, for finding its log lines
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// Characters of generated request IDs
+const REQUEST_ID_LENGTH: usize = 16;
+
+/// Tag each request with an `x-request-id`, kept from the client or proxy if
+/// it looks sane, and answer with it
+async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let given = req.headers().get(X_REQUEST_ID).filter(|x| {
+        !x.is_empty()
+            && x.len() <= 64
+            && x.as_bytes()
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || b"-_.".contains(c))
+    });
+    let id = match given {
+        Some(x) => x.clone(),
+        None => HeaderValue::from_str(&nanoid::nanoid!(REQUEST_ID_LENGTH))
+            .expect("request IDs should make a header"),
+    };
+    req.headers_mut().insert(X_REQUEST_ID, id.clone());
+    let mut res = next.run(req).await;
+    res.headers_mut().insert(X_REQUEST_ID, id);
+    res
+}
+
 #[derive(Copy, Clone)]
 struct Logger {}
 
+impl<B> MakeSpan<B> for Logger {
+    /// Span of a request, so that every event it causes has its ID
+    fn make_span(&mut self, request: &axum::http::Request<B>) -> tracing::Span {
+        let id = request
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        tracing::info_span!(target: "web", "request", id)
+    }
+}
+
 impl<B> OnRequest<B> for Logger {
     fn on_request(&mut self, request: &axum::http::Request<B>, _: &tracing::Span) {
         let route = request.uri().path();
-        tracing::event!(target: "web", Level::INFO, route);
+        let client = request
+            .extensions()
+            .get::<ClientIp>()
+            .map(|x| x.0.to_string())
+            .unwrap_or_default();
+        tracing::event!(target: "web", Level::INFO, route, client = client.as_str());
     }
 }
 
@@ -87,43 +390,211 @@ impl<B> OnResponse<B> for Logger {
     }
 }
 
-pub async fn web_server(collection: Feeds) -> Result<()> {
+/// Time the database may take to answer health checks
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(res: Result<()>) -> Self {
+        match res {
+            Ok(()) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Self {
+                ok: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    ok: bool,
+    mongodb: Check,
+    smtp: Check,
+}
+
+/// State of the database and the SMTP listener, 503 unless both are up
+async fn health(feeds: Feeds) -> impl IntoResponse {
+    let mongodb = Check::new(
+        match tokio::time::timeout(HEALTH_TIMEOUT, feeds.estimated_document_count(None)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow::anyhow!("No answer within {:?}", HEALTH_TIMEOUT)),
+        },
+    );
+    let smtp = Check::new(match smtp::is_listening() {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!("Not listening")),
+    });
+    let ok = mongodb.ok && smtp.ok;
+    (
+        match ok {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        },
+        Json(Health { ok, mongodb, smtp }),
+    )
+}
+
+/// Handshaken connections waiting for the server to take them
+const PENDING_HANDSHAKES: usize = 64;
+/// Pause after failing to accept a connection
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[allow(clippy::too_many_arguments)]
+pub async fn web_server(
+    collection: Feeds,
+    audit: AuditLog,
+    hits: Hits,
+    favicons: Favicons,
+    blobs: Blobs,
+    registry: Registry,
+    keys: ApiKeys,
+    tombstones: Tombstones,
+    tx: TX,
+) -> Result<()> {
     let logger = Logger {};
 
     let utf8_layer = SetResponseHeaderLayer::overriding(CONTENT_TYPE, utf8_header);
     let config = get_config();
+    let health_feeds = collection.clone();
+    let schema = graphql::schema(
+        collection.clone(),
+        blobs.clone(),
+        registry.clone(),
+        audit.clone(),
+        tombstones.clone(),
+    );
 
     let mut app = Router::new()
         .route("/", get(index))
-        .route("/feeds/:key", get(rendered_html))
+        .route(
+            "/feeds/:key",
+            get(rendered_html).patch(edit_item).delete(delete_item),
+        )
+        .route("/feeds/:key/full", get(full))
         .route("/feeds/:key/raw", get(raw))
+        .route("/feeds/:key/eml", get(eml))
+        .route("/feeds/:key/pdf", get(pdf))
+        .route("/feeds/:key/json", get(item_json))
+        .route("/feeds/:key/attachments/:n", get(item_attachment))
+        .route("/feeds/:key/related", get(related))
+        .route("/feeds/:key/tags", get(tags).put(put_tags))
         .route("/feeds", get(list.layer(utf8_layer)))
+        .route("/events", get(events))
+        .route("/ws", get(ws))
+        .route("/search", get(search))
+        .route("/search/headers", get(search_headers))
         .route("/rss", get(rss))
         .route("/rss/:box", get(rss_box))
+        .route("/rss/:box/digest", get(rss_digest))
+        .route("/atom", get(atom))
+        .route("/atom/:box", get(atom_box))
         .route("/boxes", get(boxes))
+        .route("/opml", get(opml))
+        .route("/sitemap.xml", get(sitemap_xml))
+        .route("/openapi.json", get(|| async { Json(openapi::spec()) }))
+        .route("/graphql", post(graphql))
+        .route("/export", get(export_all))
+        .route("/export/:box", get(export_box))
+        .route("/tags", get(tags_list))
+        .route("/boxes/:box", get(box_page))
+        .route("/boxes/:box/icon", get(box_icon))
+        .route("/boxes/:box/epub", get(box_epub))
+        .route("/stats/readers", get(readers))
+        .route("/stats/top", get(top))
+        .route("/metrics", get(prometheus))
+        .route("/admin/senders/:address", delete(erase_sender))
+        .route("/ingest", post(ingest))
+        .route("/admin/feeds/:key", get(admin_item))
+        .route("/admin/selftest", get(selftest))
+        .route("/admin/verify", get(verify).post(start_verify))
+        .route("/admin/boxes", get(registered_boxes).post(create_box))
+        .route("/admin/boxes/:box", delete(delete_box))
+        .route("/admin/boxes/:box/rename", post(rename_box))
+        .route("/admin/boxes/:box/merge", post(merge_box))
+        .route(
+            "/admin/boxes/:box/token",
+            post(create_token).delete(revoke_token),
+        )
+        .route(
+            "/admin/boxes/:box/archive",
+            post(archive_box).delete(unarchive_box),
+        )
+        .route("/admin/keys", get(api_keys).post(create_key))
+        .route("/admin/keys/:id", delete(revoke_key))
+        .route("/admin/pending", get(pending))
+        .route("/admin/pending/:key", delete(reject))
+        .route("/admin/pending/:key/approve", post(approve));
+
+    if let Some(dir) = &config.static_dir {
+        info!(target: "web", "Serving frontend assets from {}", dir);
+    }
+    app = app.fallback(fallback.into_service());
+
+    app = app
+        .layer(middleware_fn::from_fn(gone))
         .layer(AddExtensionLayer::new(collection))
+        .layer(AddExtensionLayer::new(audit))
+        .layer(AddExtensionLayer::new(hits))
+        .layer(AddExtensionLayer::new(favicons))
+        .layer(AddExtensionLayer::new(blobs))
+        .layer(AddExtensionLayer::new(registry))
+        .layer(AddExtensionLayer::new(keys.clone()))
+        .layer(AddExtensionLayer::new(tombstones))
+        .layer(AddExtensionLayer::new(schema))
+        .layer(AddExtensionLayer::new(tx))
+        .layer(middleware_fn::from_fn(timeout))
         .layer(
             TraceLayer::new_for_http()
+                .make_span_with(logger)
                 .on_request(logger)
                 .on_response(logger),
-        );
+        )
+        .layer(middleware_fn::from_fn(box_redirector))
+        .layer(middleware_fn::from_fn(rate_limit));
 
     if config.username.is_some() {
         info!(
             target: "web",
             "Using basic auth"
         );
-        app = app.layer(RequireAuthorizationLayer::basic(
-            config.username.as_ref().unwrap(),
-            config.password.as_ref().unwrap(),
-        ))
+        let reader = config
+            .reader_username
+            .as_deref()
+            .zip(config.reader_password.as_deref());
+        app = app.layer(RequireAuthorizationLayer::custom(auth::Authorize::new(
+            (
+                config.username.as_ref().unwrap(),
+                config.password.as_ref().unwrap(),
+            ),
+            reader,
+            keys.clone(),
+        )))
     } else {
-        warn!(target: "web", "No auth configured, this can be dangerous and should only be used in development");
+        warn!(target: "web", "No auth configured, only reading is allowed");
+        app = app.layer(middleware_fn::from_fn(read_only));
     }
+    // Outside auth, so that refused credentials are counted
+    app = app
+        .layer(middleware_fn::from_fn(auth_limit))
+        .layer(middleware_fn::from_fn(client_addr));
+    app = app.layer(middleware_fn::from_fn(request_id));
 
     app = app
-        .route("/health", any(|| async { "OK" }))
-        .route_layer(middleware_fn::from_fn(http_rediretor))
+        .route("/health", any(move || health(health_feeds.clone())))
+        .route("/robots.txt", get(robots_txt))
+        .route("/favicon.ico", get(favicon_ico))
+        .route_layer(middleware_fn::from_fn(https_redirector))
         .route_layer(
             cors::CorsLayer::new()
                 .allow_headers(cors::any())
@@ -131,205 +602,2572 @@ pub async fn web_server(collection: Feeds) -> Result<()> {
                 .allow_origin(cors::any()),
         );
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.web_port));
+    if config.security_headers {
+        let headers = [
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            (REFERRER_POLICY, config.referrer_policy.clone()),
+            (X_FRAME_OPTIONS, config.frame_options.clone()),
+            (
+                STRICT_TRANSPORT_SECURITY,
+                match config.hsts_max_age {
+                    0 => String::new(),
+                    x => format!("max-age={}", x),
+                },
+            ),
+        ];
+        for (name, value) in headers {
+            if !value.is_empty() {
+                // Routes may set their own, e.g. pages meant to be framed
+                app = app.layer(SetResponseHeaderLayer::if_not_present(
+                    name,
+                    HeaderValue::from_str(&value)?,
+                ));
+            }
+        }
+    }
+
+    // gzip or Brotli, as accepted by the client
+    app = app.layer(CompressionLayer::new());
+    // Outside compression, so that lengths are of what `GET` would send
+    app = app.layer(middleware_fn::from_fn(head));
+
+    let addr = SocketAddr::new(config.web_bind, config.web_port);
 
     info!(target: "web", "Starting");
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    if let Some(path) = &config.web_socket {
+        // Left over by an earlier run
+        if std::fs::metadata(path).map_or(false, |x| x.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        info!(target: "web", "Listening on {}", path);
+        let incoming = stream::unfold(listener, |listener| async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => return Some((Ok::<_, std::io::Error>(stream), listener)),
+                    Err(e) => accept_failed(e).await,
+                }
+            }
+        });
+        axum::Server::builder(hyper::server::accept::from_stream(Box::pin(incoming)))
+            .serve(app.into_make_service())
+            .await?;
+    } else if let Some(acceptor) = tls::acceptor()? {
+        info!(target: "web", "Serving HTTPS");
+        let listener = TcpListener::bind(addr).await?;
+        serve_handshaken(app, listener, move |stream, peer| {
+            let acceptor = acceptor.clone();
+            async move {
+                let stream = match get_config().proxy_protocol {
+                    true => proxy::accept(stream, peer).await?,
+                    false => ProxiedStream::new(stream, peer),
+                };
+                tls::accept(&acceptor, stream).await
+            }
+        })
+        .await?;
+    } else if config.proxy_protocol {
+        let listener = TcpListener::bind(addr).await?;
+        serve_handshaken(app, listener, proxy::accept).await?;
+    } else {
+        // Accept errors are logged and waited out by hyper itself
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+            .await?;
+    }
 
     info!(target: "web", "Stopped");
 
     Ok(())
 }
 
-async fn index() -> impl IntoResponse {
-    Html(include_str!("../front/dist/index.html"))
+/// Serve `app` on connections of `listener` once through `handshake`, e.g.
+/// reading a PROXY header. Each handshake is a task of its own, so that slow
+/// peers never hold back accepting others.
+async fn serve_handshaken<S, F, Fut>(app: Router, listener: TcpListener, handshake: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(TcpStream, SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<ProxiedStream<S>>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(PENDING_HANDSHAKES);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (stream, peer) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    accept_failed(e).await;
+                    continue;
+                }
+            };
+            let handshaken = handshake(stream, peer);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match handshaken.await {
+                    Ok(x) => {
+                        let _ = tx.send(x).await;
+                    }
+                    Err(e) => warn!(target: "web", "{:#}", e),
+                }
+            });
+        }
+    });
+    let incoming = stream::unfold(rx, |mut rx| async move {
+        let stream = rx.recv().await?;
+        Some((Ok::<_, std::io::Error>(stream), rx))
+    });
+    axum::Server::builder(hyper::server::accept::from_stream(Box::pin(incoming)))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+        .await?;
+    Ok(())
+}
+
+/// Log a failure to accept a connection, e.g. when out of file descriptors or
+/// after the peer gave up, and wait a moment rather than spin on it
+async fn accept_failed(e: std::io::Error) {
+    warn!(target: "web", "Error accepting connection: {}", e);
+    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+}
+
+/// Variables of an `index.html` template replacing the frontend
+#[derive(Serialize)]
+struct IndexPage<'a> {
+    /// Domain mail is received at
+    domain: &'a str,
 }
 
-async fn rss(Extension(feed): Extension<Feeds>) -> impl IntoResponse {
+async fn index() -> PageResult<impl IntoResponse> {
     let config = get_config();
-    match render_feeds(feed, None, &format!("https://{}/rss", config.web_domain)).await {
-        Ok(content) => (
-            StatusCode::OK,
-            Headers(vec![(
-                header::CONTENT_TYPE,
-                "application/xml; charset=utf-8",
-            )]),
-            content,
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Headers(vec![]),
-            e.to_string(),
-        ),
+    if templates::exists("index.html") {
+        return Ok((
+            Headers(vec![(CONTENT_SECURITY_POLICY, config.app_csp.clone())]),
+            Html(templates::render(
+                "index.html",
+                &IndexPage {
+                    domain: &config.domain,
+                },
+            )?),
+        ));
     }
+    let page = match &config.static_dir {
+        Some(dir) => tokio::fs::read_to_string(std::path::Path::new(dir).join("index.html"))
+            .await
+            .ok(),
+        None => None,
+    };
+    Ok((
+        Headers(vec![(CONTENT_SECURITY_POLICY, config.app_csp.clone())]),
+        Html(page.unwrap_or_else(|| include_str!("../front/dist/index.html").to_owned())),
+    ))
+}
+
+fn user_agent(headers: &HeaderMap) -> &str {
+    headers
+        .get(USER_AGENT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn accept(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+}
+
+#[derive(Default, Deserialize)]
+struct RssQuery {
+    /// 1-based page number, see RFC 5005 section 3
+    page: Option<u64>,
+    /// Items per page instead of `PER_PAGE`, up to `MAX_PER_PAGE`
+    limit: Option<u64>,
+    /// Newest items left out before the first page
+    skip: Option<u64>,
+    /// 0-based archive number counting from the oldest items, see RFC 5005
+    /// section 4
+    archive: Option<u64>,
+    /// Language of stored translations to use, see `TRANSLATE_BACKEND`
+    lang: Option<String>,
+    /// Only items with this user-assigned tag
+    tag: Option<String>,
+    /// Only items sent from this address
+    author: Option<String>,
+    /// Only items created from this time, RFC 3339 or unix milliseconds
+    since: Option<String>,
+    /// Only items created before this time, RFC 3339 or unix milliseconds
+    until: Option<String>,
+    /// Feed token of the box, kept in links to other pages
+    token: Option<String>,
+}
+
+async fn rss(
+    query: Query<RssQuery>,
+    headers: HeaderMap,
+    uri: Uri,
+    Extension(ClientIp(client)): Extension<ClientIp>,
+    Extension(feed): Extension<Feeds>,
+    Extension(hits): Extension<Hits>,
+) -> impl IntoResponse {
+    let config = get_config();
+    analytics::record(hits, "rss", None, user_agent(&headers), client);
+    let format = FeedFormat::negotiate(accept(&headers));
+    let link = format!("https://{}/rss", config.web_domain);
+    feed_response(
+        &headers,
+        &uri,
+        format,
+        true,
+        render_format(feed, None, &link, &query, format),
+    )
+    .await
 }
 
 async fn rss_box(
     Path(map): Path<HashMap<String, String>>,
+    query: Query<RssQuery>,
+    headers: HeaderMap,
+    uri: Uri,
+    Extension(ClientIp(client)): Extension<ClientIp>,
     Extension(feed): Extension<Feeds>,
+    Extension(hits): Extension<Hits>,
 ) -> impl IntoResponse {
     let config = get_config();
     let email = map.get("box").expect("box name should exist");
-    match render_feeds(
-        feed,
-        Some(doc! { "from_box": email }),
-        &format!("https://{}/rss/{}", config.web_domain, email),
+    analytics::record(hits, "rss_box", Some(email), user_agent(&headers), client);
+    let format = FeedFormat::negotiate(accept(&headers));
+    let link = format!("https://{}/rss/{}", config.web_domain, email);
+    feed_response(
+        &headers,
+        &uri,
+        format,
+        true,
+        render_format(feed, Some(email), &link, &query, format),
     )
     .await
-    {
-        Ok(content) => (
-            StatusCode::OK,
-            Headers(vec![(
-                header::CONTENT_TYPE,
-                "application/xml; charset=utf-8",
-            )]),
-            content,
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Headers(vec![]),
-            e.to_string(),
-        ),
+}
+
+async fn atom(
+    query: Query<RssQuery>,
+    headers: HeaderMap,
+    uri: Uri,
+    Extension(ClientIp(client)): Extension<ClientIp>,
+    Extension(feed): Extension<Feeds>,
+    Extension(hits): Extension<Hits>,
+) -> impl IntoResponse {
+    let config = get_config();
+    analytics::record(hits, "atom", None, user_agent(&headers), client);
+    let link = format!("https://{}/atom", config.web_domain);
+    feed_response(
+        &headers,
+        &uri,
+        FeedFormat::Atom,
+        false,
+        render_atom_feed(feed, None, &link, &query),
+    )
+    .await
+}
+
+async fn atom_box(
+    Path(map): Path<HashMap<String, String>>,
+    query: Query<RssQuery>,
+    headers: HeaderMap,
+    uri: Uri,
+    Extension(ClientIp(client)): Extension<ClientIp>,
+    Extension(feed): Extension<Feeds>,
+    Extension(hits): Extension<Hits>,
+) -> impl IntoResponse {
+    let config = get_config();
+    let email = map.get("box").expect("box name should exist");
+    analytics::record(hits, "atom_box", Some(email), user_agent(&headers), client);
+    let link = format!("https://{}/atom/{}", config.web_domain, email);
+    feed_response(
+        &headers,
+        &uri,
+        FeedFormat::Atom,
+        false,
+        render_atom_feed(feed, Some(email), &link, &query),
+    )
+    .await
+}
+
+/// Respond with a feed, or with 304 without rendering it if the client has
+/// the current version, see `validator`. `negotiated` feeds vary by `Accept`.
+/// Rendered feeds are reused until items change, see `feedcache`.
+async fn feed_response(
+    headers: &HeaderMap,
+    uri: &Uri,
+    format: FeedFormat,
+    negotiated: bool,
+    render: impl Future<Output = Result<String>>,
+) -> ApiResult<(StatusCode, Headers<Vec<(HeaderName, String)>>, String)> {
+    let validator = validator::current();
+    let etag = validator.etag(format.name());
+    let mut response_headers = vec![
+        (header::ETAG, etag.clone()),
+        (header::LAST_MODIFIED, validator.http_date()),
+    ];
+    if negotiated {
+        response_headers.push((header::VARY, "Accept".to_owned()));
+    }
+    if validator.is_fresh(headers, &etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            Headers(response_headers),
+            String::new(),
+        ));
+    }
+    let key = format!("{} {}", format.name(), uri);
+    let content = match feedcache::get(validator.tag(), &key) {
+        Some(x) => x,
+        None => {
+            let content = render.await?;
+            feedcache::put(validator.tag(), key, content.clone());
+            content
+        }
+    };
+    response_headers.push((header::CONTENT_TYPE, format.content_type().to_owned()));
+    Ok((StatusCode::OK, Headers(response_headers), content))
+}
+
+#[derive(Deserialize)]
+struct DigestQuery {
+    #[serde(default)]
+    period: Period,
+}
+
+async fn rss_digest(
+    Path(map): Path<HashMap<String, String>>,
+    Query(query): Query<DigestQuery>,
+    headers: HeaderMap,
+    Extension(ClientIp(client)): Extension<ClientIp>,
+    Extension(feed): Extension<Feeds>,
+    Extension(hits): Extension<Hits>,
+) -> ApiResult<impl IntoResponse> {
+    let email = map.get("box").expect("box name should exist");
+    analytics::record(
+        hits,
+        "rss_digest",
+        Some(email),
+        user_agent(&headers),
+        client,
+    );
+    let content = render_digest(feed, email, query.period).await?;
+    Ok((
+        Headers(vec![(
+            header::CONTENT_TYPE,
+            "application/xml; charset=utf-8",
+        )]),
+        content,
+    ))
+}
+
+/// `created_between`, answering bad times with 400
+fn in_range(filter: Document, since: Option<&str>, until: Option<&str>) -> Result<Document> {
+    created_between(filter, since, until).map_err(|e| ApiError::bad_request(e.to_string()).into())
+}
+
+fn atom_link(rel: &str, href: String) -> Link {
+    let mut ret = Link::default();
+    ret.set_rel(rel);
+    ret.set_href(href);
+    ret
+}
+
+/// One page of a feed, shared by RSS and Atom
+struct FeedPage {
+    items: Vec<Feed>,
+    /// `(rel, href)` of the page and its neighbours, see RFC 5005 sections 3
+    /// and 4
+    links: Vec<(&'static str, String)>,
+    /// An archive document, whose items never change
+    archive: bool,
+}
+
+/// URL of a page of the feed at `link`, with `param` and the parameters of
+/// `query` the other pages share, e.g. the feed token, see `fetch_page`
+fn page_url(link: &str, query: &RssQuery, per_page: u64, param: Option<(&str, u64)>) -> String {
+    let params = [
+        param.map(|(k, v)| (k, v.to_string())),
+        query.limit.map(|_| ("limit", per_page.to_string())),
+        query.skip.map(|x| ("skip", x.to_string())),
+        query.lang.as_deref().map(|x| ("lang", percent_encode(x))),
+        query.tag.as_deref().map(|x| ("tag", percent_encode(x))),
+        query
+            .author
+            .as_deref()
+            .map(|x| ("author", percent_encode(x))),
+        query.since.as_deref().map(|x| ("since", percent_encode(x))),
+        query.until.as_deref().map(|x| ("until", percent_encode(x))),
+        query.token.as_deref().map(|x| ("token", percent_encode(x))),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|(k, v)| format!("{}={}", k, v))
+    .collect::<Vec<_>>();
+    match params.is_empty() {
+        true => link.to_owned(),
+        false => format!("{}?{}", link, params.join("&")),
     }
 }
 
-async fn render_feeds(feeds: Feeds, filter: Option<Document>, link: &str) -> Result<String> {
+async fn fetch_page(
+    feeds: Feeds,
+    from_box: Option<&str>,
+    link: &str,
+    query: &RssQuery,
+) -> Result<FeedPage> {
     let config = get_config();
+    let box_config = from_box.and_then(|x| config.box_config(x));
+    let per_page = match query.limit {
+        Some(x) => x.clamp(1, config.max_per_page),
+        None => box_config
+            .and_then(|x| x.per_page)
+            .unwrap_or(config.per_page) as u64,
+    };
+    let skip = query.skip.unwrap_or(0);
+    let page = query.page.unwrap_or(1).max(1);
+    let mut filter = doc! {};
+    if let Some(from_box) = from_box {
+        filter.insert("from_box", from_box);
+    }
+    if let Some(tag) = &query.tag {
+        filter.insert("tags", tag);
+    }
+    if let Some(author) = &query.author {
+        for (k, v) in sender_filter(author) {
+            filter.insert(k, v);
+        }
+    }
+    let filter = in_range(filter, query.since.as_deref(), query.until.as_deref())?;
+    let lang = query.lang.as_deref();
+    let with_params = |param: Option<(&str, u64)>| page_url(link, query, per_page, param);
+    let page_link = |page: u64| with_params(Some(("page", page)).filter(|_| page > 1));
+    let archive_link = |n: u64| with_params(Some(("archive", n)));
+
+    // Archives are full pages counted from the oldest item, so that they stay
+    // the same as new items arrive
+    let archives = match query.archive.is_some() || page == 1 {
+        true => {
+            feeds
+                .count_documents(published(filter.clone()), None)
+                .await?
+                / per_page
+        }
+        false => 0,
+    };
+    if let Some(n) = query.archive {
+        if n >= archives {
+            return Err(ApiError::not_found(format!("No archive {}", n)).into());
+        }
+        let option = FindOptions::builder()
+            .limit(per_page as i64)
+            .skip(n * per_page)
+            .sort(doc! { "created_at": 1, "_id": 1 })
+            .projection(store::without_raw())
+            .build();
+        let mut items = feeds
+            .find(published(filter), option)
+            .await?
+            .map_ok(|x| x.translated(lang))
+            .try_collect::<Vec<_>>()
+            .await?;
+        items.reverse();
+        let mut links = vec![("self", archive_link(n)), ("current", page_link(1))];
+        if n > 0 {
+            links.push(("prev-archive", archive_link(n - 1)));
+        }
+        if n + 1 < archives {
+            links.push(("next-archive", archive_link(n + 1)));
+        }
+        return Ok(FeedPage {
+            items,
+            links,
+            archive: true,
+        });
+    }
+
+    let offset = (page - 1)
+        .checked_mul(per_page)
+        .and_then(|x| x.checked_add(skip))
+        .filter(|x| *x <= i64::MAX as u64)
+        .ok_or_else(|| ApiError::bad_request("page and skip go beyond any feed"))?;
+    // Fetch one more to tell whether there is a next page
     let option = FindOptions::builder()
-        .limit(config.per_page as i64)
+        .limit(per_page as i64 + 1)
+        .skip(offset)
         .sort(doc! { "created_at": -1 })
+        .projection(store::without_raw())
         .build();
-    let feeds = feeds
-        .find(filter, option)
+    let mut items = feeds
+        .find(published(filter), option)
         .await?
-        .try_fold(Vec::with_capacity(10), |mut acc, x| async move {
-            acc.push(x.into_rss());
-            Ok(acc)
-        })
+        .map_ok(|x| x.translated(lang))
+        .try_collect::<Vec<_>>()
         .await?;
+    let has_next = items.len() as u64 > per_page;
+    items.truncate(per_page as usize);
+
+    let mut links = vec![("self", page_link(page)), ("first", page_link(1))];
+    if page > 1 {
+        links.push(("previous", page_link(page - 1)));
+    }
+    if has_next {
+        links.push(("next", page_link(page + 1)));
+    }
+    if archives > 0 {
+        links.push(("prev-archive", archive_link(archives - 1)));
+    }
+    if let Some(hub) = websub::hub() {
+        links.push(("hub", hub.to_owned()));
+    }
+    Ok(FeedPage {
+        items,
+        links,
+        archive: false,
+    })
+}
+
+async fn render_feeds(
+    feeds: Feeds,
+    from_box: Option<&str>,
+    link: &str,
+    query: &RssQuery,
+) -> Result<String> {
+    let config = get_config();
+    let box_config = from_box.and_then(|x| config.box_config(x));
+    let page = fetch_page(feeds, from_box, link, query).await?;
+
+    let mut atom_ext = AtomExtension::default();
+    atom_ext.set_links(
+        page.links
+            .into_iter()
+            .map(|(rel, href)| atom_link(rel, href))
+            .collect::<Vec<_>>(),
+    );
+
+    let (namespaces, extensions) = match page.archive {
+        true => (
+            BTreeMap::from([("fh".to_owned(), HISTORY_NAMESPACE.to_owned())]),
+            BTreeMap::from([(
+                "fh".to_owned(),
+                BTreeMap::from([(
+                    "archive".to_owned(),
+                    vec![RssExtension {
+                        name: "fh:archive".to_owned(),
+                        ..Default::default()
+                    }],
+                )]),
+            )]),
+        ),
+        false => Default::default(),
+    };
+
+    let channel = Channel::of(from_box);
+    let image = channel.image.map(|x| {
+        ImageBuilder::default()
+            .url(x)
+            .title(channel.title.clone())
+            .link(link)
+            .build()
+    });
 
     let ret = rss::ChannelBuilder::default()
-        .title("Mail List")
+        .title(channel.title)
+        .description(channel.description.unwrap_or_default())
+        .language(channel.language)
         .generator(Some("http://github.com/George-Miao/mail-list-rss".into()))
         .link(link)
         .pub_date(Utc::now().to_rfc2822())
-        .items(feeds)
+        .ttl(channel.ttl.map(|x| x.to_string()))
+        .skip_hours(
+            box_config
+                .map(|x| x.skip_hours.iter().map(|x| x.to_string()).collect())
+                .unwrap_or_default(),
+        )
+        .skip_days(box_config.map(|x| x.skip_days.clone()).unwrap_or_default())
+        .atom_ext(Some(atom_ext))
+        .namespaces(namespaces)
+        .extensions(extensions)
+        .image(image)
+        .items(
+            page.items
+                .into_iter()
+                .map(Feed::into_rss)
+                .collect::<Vec<_>>(),
+        )
         .build()
         .to_string();
     Ok(ret)
 }
 
-#[derive(Deserialize)]
-struct FeedsQuery {
-    limit: Option<i64>,
-    skip: Option<u64>,
+/// Render a page of feed items in `format`
+async fn render_format(
+    feeds: Feeds,
+    from_box: Option<&str>,
+    link: &str,
+    query: &RssQuery,
+    format: FeedFormat,
+) -> Result<String> {
+    match format {
+        FeedFormat::Rss => render_feeds(feeds, from_box, link, query).await,
+        FeedFormat::Atom => render_atom_feed(feeds, from_box, link, query).await,
+        FeedFormat::JsonFeed => {
+            let page = fetch_page(feeds, from_box, link, query).await?;
+            Ok(render_json_feed(
+                &Channel::of(from_box),
+                &page.links,
+                page.items,
+            ))
+        }
+    }
 }
 
-async fn list(Extension(feeds): Extension<Feeds>, query: Query<FeedsQuery>) -> impl IntoResponse {
-    Json(render_list(feeds, query.limit, query.skip).await.unwrap())
+async fn render_atom_feed(
+    feeds: Feeds,
+    from_box: Option<&str>,
+    link: &str,
+    query: &RssQuery,
+) -> Result<String> {
+    let page = fetch_page(feeds, from_box, link, query).await?;
+    let id = page.links[0].1.clone();
+    let channel = Channel::of(from_box);
+    let feed = AtomFeed {
+        title: &channel.title,
+        subtitle: channel.description.as_deref(),
+        id: &id,
+        links: page.links,
+        icon: channel.image.clone(),
+        archive: page.archive,
+    };
+    Ok(render_atom(feed, page.items))
 }
 
-async fn render_list(feeds: Feeds, limit: Option<i64>, skip: Option<u64>) -> Result<List> {
-    let config = get_config();
-    let res = feeds
+#[derive(Deserialize, Default)]
+pub(crate) struct FeedsQuery {
+    pub(crate) limit: Option<i64>,
+    pub(crate) skip: Option<u64>,
+    /// Only items after this cursor, as given in `next`, instead of `skip`
+    pub(crate) after: Option<String>,
+    /// Newest items first unless given
+    pub(crate) sort: Option<SortField>,
+    /// Descending for `created_at`, ascending for the others unless given
+    pub(crate) order: Option<SortOrder>,
+    /// Only items sent to the plus-addressed recipient with this tag
+    pub(crate) address_tag: Option<String>,
+    /// Only items with this user-assigned tag
+    pub(crate) tag: Option<String>,
+    /// Only items sent from this address
+    pub(crate) author: Option<String>,
+    /// Only items created from this time, RFC 3339 or unix milliseconds
+    pub(crate) since: Option<String>,
+    /// Only items created before this time, RFC 3339 or unix milliseconds
+    pub(crate) until: Option<String>,
+}
+
+async fn list(
+    Extension(feeds): Extension<Feeds>,
+    query: Query<FeedsQuery>,
+) -> ApiResult<Json<List>> {
+    Ok(Json(render_list(feeds, &query).await?))
+}
+
+/// Published items matching `query`, paging aside
+pub(crate) fn list_filter(query: &FeedsQuery) -> Result<Document> {
+    let mut filter = doc! {};
+    if let Some(address_tag) = &query.address_tag {
+        filter.insert("address_tag", address_tag);
+    }
+    if let Some(tag) = &query.tag {
+        filter.insert("tags", tag);
+    }
+    if let Some(author) = &query.author {
+        for (k, v) in sender_filter(author) {
+            filter.insert(k, v);
+        }
+    }
+    Ok(published(in_range(
+        filter,
+        query.since.as_deref(),
+        query.until.as_deref(),
+    )?))
+}
+
+/// Up to `limit` items matching `filter` without their content, in `sort`,
+/// after skipping `skip` or after cursor `after`
+pub(crate) async fn list_page(
+    feeds: &Feeds,
+    filter: Document,
+    sort: Sort,
+    limit: i64,
+    skip: Option<u64>,
+    after: Option<&str>,
+) -> Result<(Vec<Feed>, Pagination)> {
+    let limit = limit.max(1);
+    let total = feeds.count_documents(filter.clone(), None).await?;
+    let (filter, skip) = match (after, skip) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::bad_request("Give either skip or after").into());
+        }
+        (Some(after), None) => {
+            let filter = Cursor::decode(after)
+                .and_then(|x| x.filter(filter, &sort))
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            (filter, 0)
+        }
+        (None, skip) => (filter, skip.unwrap_or(0)),
+    };
+    // One more than asked tells whether items are left after a cursor
+    let mut items = feeds
         .find(
-            None,
+            filter,
             FindOptions::builder()
-                .limit(limit.unwrap_or(config.default_page_limit))
+                .limit(limit.saturating_add(1))
                 .skip(skip)
-                .sort(doc! { "created_at": -1 })
+                .sort(sort.document())
+                .projection(store::meta_only())
                 .build(),
         )
         .await?
-        .filter_map(|x| async move {
-            x.ok().map(|x| Summary {
-                create_at: x.created_at.to_rfc2822(),
-                title: x.title,
-                id: x.id,
-            })
-        })
-        .collect::<Vec<_>>()
-        .await;
+        .try_collect::<Vec<_>>()
+        .await?;
+    let has_more = items.len() as i64 > limit;
+    items.truncate(limit as usize);
+    let next = match has_more {
+        true => items.last().map(|x| Cursor::of(x, &sort).encode()),
+        false => None,
+    };
+    Ok((
+        items,
+        Pagination {
+            total,
+            limit,
+            skip,
+            has_more,
+            next,
+        },
+    ))
+}
 
-    Ok(List { items: res })
+async fn render_list(feeds: Feeds, query: &FeedsQuery) -> Result<List> {
+    let limit = query.limit.unwrap_or(get_config().default_page_limit);
+    let (items, page) = list_page(
+        &feeds,
+        list_filter(query)?,
+        Sort::new(query.sort, query.order),
+        limit,
+        query.skip,
+        query.after.as_deref(),
+    )
+    .await?;
+    let items = items.into_iter().map(|x| public_summary(x, &[])).collect();
+    Ok(List {
+        page: Some(page),
+        items,
+    })
 }
 
-async fn rendered_html(
-    Path(map): Path<HashMap<String, String>>,
-    Extension(feeds): Extension<Feeds>,
-) -> impl IntoResponse {
-    let key = map.get("key").expect("key should exist");
-    let res = feeds.find_one(doc! { "id" : key }, None).await;
-    match res {
-        Ok(Some(res)) => (
-            StatusCode::OK,
-            Headers(vec![(header::CONTENT_TYPE, "text/html; charset=utf-8")]),
-            res.content,
-        ),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Headers(vec![]),
-            format!("Cannot find {}", key),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Headers(vec![]),
-            e.to_string(),
-        ),
+/// Characters of context on each side of the first match in snippets
+const SNIPPET_RADIUS: usize = 80;
+
+/// Entry of `feed` in public listings, redacted, with a snippet of its content
+/// around the first of `terms` if any
+fn public_summary(feed: Feed, terms: &[&str]) -> Summary {
+    let x = feed.redacted();
+    Summary {
+        create_at: x.created_at.to_rfc2822(),
+        title: x.display_title(),
+        snippet: match terms.is_empty() {
+            true => None,
+            false => snippet(&strip_html(&x.content), terms, SNIPPET_RADIUS),
+        },
+        summary: x.summary,
+        tags: x.tags,
+        id: x.id,
     }
 }
 
-async fn raw(
-    Path(map): Path<HashMap<String, String>>,
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    skip: Option<u64>,
+}
+
+async fn search(
     Extension(feeds): Extension<Feeds>,
-) -> impl IntoResponse {
-    let key = map.get("key").expect("key should exist");
-    let res = feeds.find_one(doc! { "id" : key }, None).await;
-    match res {
-        Ok(Some(res)) => (
-            StatusCode::OK,
-            Headers(vec![(header::CONTENT_TYPE, "text/plain; charset=utf-8")]),
-            res.raw,
-        ),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Headers(vec![]),
-            format!("Cannot find {}", key),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Headers(vec![]),
-            e.to_string(),
-        ),
-    }
+    query: Query<SearchQuery>,
+) -> ApiResult<Json<List>> {
+    Ok(Json(render_search(feeds, &query).await?))
 }
 
-async fn boxes(Extension(feed): Extension<Feeds>) -> impl IntoResponse {
-    let option = DistinctOptions::builder().build();
-    let emails = feed.distinct("from_box", None, option).await;
-    match emails {
-        Ok(content) => {
-            let emails_text = content
-                .iter()
-                .map(|f| f.as_str().unwrap())
-                .collect::<Vec<_>>();
-            (
+async fn render_search(feeds: Feeds, query: &SearchQuery) -> Result<List> {
+    let config = get_config();
+    let terms = query.q.split_whitespace().collect::<Vec<_>>();
+    let limit = query.limit.unwrap_or(config.default_page_limit);
+    let items = search_items(&feeds, &query.q, limit, query.skip.unwrap_or(0))
+        .await?
+        .into_iter()
+        .map(|x| public_summary(x, &terms))
+        .collect();
+    Ok(List { items, page: None })
+}
+
+/// Search results skipped at most, as ranking them all gets costly
+const MAX_SEARCH_SKIP: u64 = 10_000;
+
+/// `limit` and `skip` of searches within `1..=max_limit` and
+/// `0..=MAX_SEARCH_SKIP`
+fn search_window(limit: i64, skip: u64, max_limit: u64) -> (i64, u64) {
+    (
+        limit.clamp(1, max_limit.max(1) as i64),
+        skip.min(MAX_SEARCH_SKIP),
+    )
+}
+
+/// Items containing every whitespace separated term of `q` in title, author
+/// or content, best matches first, without their source
+pub(crate) async fn search_items(
+    feeds: &Feeds,
+    q: &str,
+    limit: i64,
+    skip: u64,
+) -> Result<Vec<Feed>> {
+    let terms = q.split_whitespace().collect::<Vec<_>>();
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+    let (limit, skip) = search_window(limit, skip, get_config().max_per_page);
+
+    if let Some(index) = fulltext::index() {
+        let ids = tokio::task::block_in_place(|| index.search(q, limit as usize, skip as usize))?;
+        let mut found = feeds
+            .find(
+                published(doc! { "id": { "$in": &ids } }),
+                FindOptions::builder()
+                    .projection(store::without_raw())
+                    .build(),
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        found.sort_by_key(|x| ids.iter().position(|id| *id == x.id));
+        return Ok(found);
+    }
+
+    // Quoting every term makes the text index match all of them, not any
+    let search = terms
+        .iter()
+        .map(|x| x.replace('"', ""))
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("\"{}\"", x))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let score = doc! { "$meta": "textScore" };
+    let res = feeds
+        .find(
+            published(doc! { "$text": { "$search": search } }),
+            FindOptions::builder()
+                .limit(limit)
+                .skip(skip)
+                .projection(doc! { "score": score.clone(), "raw": 0 })
+                .sort(doc! { "score": score, "created_at": -1 })
+                .build(),
+        )
+        .await?
+        .filter_map(|x| async move { x.ok() })
+        .collect::<Vec<_>>()
+        .await;
+    Ok(res)
+}
+
+/// Number of candidates scored and number of related items returned
+const RELATED_CANDIDATES: i64 = 50;
+const RELATED_LIMIT: usize = 10;
+
+#[derive(Deserialize)]
+struct HeaderSearchQuery {
+    name: String,
+    /// Case-insensitive substring of the value, any value if not given
+    value: Option<String>,
+    limit: Option<i64>,
+    skip: Option<u64>,
+}
+
+async fn search_headers(
+    Extension(feeds): Extension<Feeds>,
+    query: Query<HeaderSearchQuery>,
+) -> ApiResult<Json<List>> {
+    Ok(Json(render_header_search(feeds, &query).await?))
+}
+
+async fn render_header_search(feeds: Feeds, query: &HeaderSearchQuery) -> Result<List> {
+    let config = get_config();
+    let mut header = doc! { "name": query.name.trim().to_ascii_lowercase() };
+    if let Some(value) = query.value.as_deref().filter(|x| !x.is_empty()) {
+        header.insert(
+            "value",
+            doc! { "$regex": escape_regex(value), "$options": "i" },
+        );
+    }
+    let (limit, skip) = search_window(
+        query.limit.unwrap_or(config.default_page_limit),
+        query.skip.unwrap_or(0),
+        config.max_per_page,
+    );
+    let res = feeds
+        .find(
+            published(doc! { "headers": { "$elemMatch": header } }),
+            FindOptions::builder()
+                .limit(limit)
+                .skip(skip)
+                .sort(doc! { "created_at": -1 })
+                .projection(store::meta_only())
+                .build(),
+        )
+        .await?
+        .filter_map(|x| async move { x.ok().map(|x| public_summary(x, &[])) })
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok(List {
+        items: res,
+        page: None,
+    })
+}
+
+async fn related(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+) -> ApiResult<Json<List>> {
+    let key = map.get("key").expect("key should exist");
+    render_related(feeds, key)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Cannot find {}", key)))
+}
+
+/// Items in the same thread (by normalized subject), from the same author or
+/// sharing significant title terms, best matches first
+async fn render_related(feeds: Feeds, key: &str) -> Result<Option<List>> {
+    let feed = match store::get_meta(&feeds, key).await? {
+        Some(x) if x.is_published() => x,
+        _ => return Ok(None),
+    };
+    let terms = significant_terms(&feed.title, 8);
+
+    let mut conditions = vec![doc! { "author": &feed.author }];
+    if !feed.subject_key.is_empty() {
+        conditions.push(doc! { "subject_key": &feed.subject_key });
+    }
+    conditions.extend(terms.iter().map(|x| {
+        doc! { "title": { "$regex": escape_regex(x), "$options": "i" } }
+    }));
+
+    let mut candidates = feeds
+        .find(
+            published(doc! { "id": { "$ne": key }, "$or": conditions }),
+            FindOptions::builder()
+                .limit(RELATED_CANDIDATES)
+                .sort(doc! { "created_at": -1 })
+                .projection(store::meta_only())
+                .build(),
+        )
+        .await?
+        .filter_map(|x| async move { x.ok() })
+        .map(|x| {
+            let title = x.title.to_lowercase();
+            let score = terms.iter().filter(|t| title.contains(t.as_str())).count()
+                + if x.subject_key == feed.subject_key {
+                    3
+                } else {
+                    0
+                }
+                + if x.author == feed.author { 2 } else { 0 };
+            (score, x)
+        })
+        .collect::<Vec<_>>()
+        .await;
+    // Stable sort keeps newer items first among equal scores
+    candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    let items = candidates
+        .into_iter()
+        .take(RELATED_LIMIT)
+        .map(|(_, x)| public_summary(x, &[]))
+        .collect();
+    Ok(Some(List { items, page: None }))
+}
+
+/// `feed` if published, or the 404 error of a missing item, so that items
+/// waiting for approval or their publication time are not shown
+fn published_item(feed: Option<Feed>, key: &str) -> ApiResult<Feed> {
+    feed.filter(Feed::is_published)
+        .ok_or_else(|| ApiError::not_found(format!("Cannot find {}", key)))
+}
+
+/// Published item `key` with its content, without its raw source, or a 404
+/// error
+async fn find_item(feeds: &Feeds, key: &str) -> ApiResult<Feed> {
+    published_item(store::get_content(feeds, key).await?, key)
+}
+
+/// Published item `key` with every field, or a 404 error
+async fn find_full_item(feeds: &Feeds, blobs: &Blobs, key: &str) -> ApiResult<Feed> {
+    published_item(store::get(feeds, blobs, key).await?, key)
+}
+
+#[derive(Deserialize)]
+struct ItemQuery {
+    /// Language to show the item in, translated on demand
+    lang: Option<String>,
+    #[serde(default)]
+    variant: Variant,
+    /// The message alone, without the page around it, e.g. `?bare=1`
+    bare: Option<String>,
+}
+
+/// Representations of an item on `/feeds/:key`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Variant {
+    Html,
+    /// Plain text of the message
+    Text,
+    /// Text blocks without layout and boilerplate, see `reader`
+    Reader,
+}
+
+impl Default for Variant {
+    fn default() -> Self {
+        Variant::Html
+    }
+}
+
+async fn rendered_html(
+    Path(map): Path<HashMap<String, String>>,
+    Query(query): Query<ItemQuery>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    ip: Option<Extension<ClientIp>>,
+    credential: Option<Extension<Credential>>,
+) -> PageResult<Response> {
+    let config = get_config();
+    let key = map.get("key").expect("key should exist");
+    let mut res = find_item(&feeds, key).await?;
+    let lang = query.lang.as_deref();
+    // Only languages in `TRANSLATE_LANGUAGES` are translated on demand, at most
+    // `RATE_LIMIT_TRANSLATE` items a minute per client
+    let wanted = lang
+        .map(|x| x.to_lowercase())
+        .filter(|x| config.translate_backend.is_some() && config.translate_languages.contains(x));
+    if let Some(lang) = wanted {
+        if !res.translations.contains_key(&lang) {
+            let ip = ip.map_or(Ipv4Addr::LOCALHOST.into(), |x| x.0 .0);
+            if let Err(wait) = ratelimit::check_translate(credential.map(|x| x.0), ip) {
+                return Ok(too_many(wait, "Too many translations"));
+            }
+            if let Err(e) = translate::store_translation(&feeds, &mut res, &lang).await {
+                warn!(target: "Translate", "Error translating {}: {}", res.id, e)
+            }
+        }
+    }
+    let translated = lang.map_or(false, |x| res.translations.contains_key(&x.to_lowercase()));
+    match query.variant {
+        Variant::Html => {}
+        Variant::Text => {
+            if !translated && res.text.is_empty() {
+                // Older and oversized items have their text taken from the source
+                res.raw = store::get_raw(&feeds, &blobs, &res.id)
+                    .await?
+                    .unwrap_or_default();
+            }
+            let text = match translated {
+                true => strip_html(&res.translated(lang).content),
+                false => res.plain_text(),
+            };
+            return Ok((
                 StatusCode::OK,
-                Headers(vec![(
-                    header::CONTENT_TYPE,
-                    "application/json; charset=utf-8",
-                )]),
-                serde_json::to_string(&emails_text).unwrap(),
+                Headers(vec![(header::CONTENT_TYPE, "text/plain; charset=utf-8")]),
+                served_text(&text),
+            )
+                .into_response());
+        }
+        Variant::Reader => {
+            let res = res.translated(lang);
+            let content = match res.overflow && !translated {
+                true => blob::load(&blobs, &res.id).await?,
+                false => res.content.clone(),
+            };
+            return Ok((
+                StatusCode::OK,
+                Headers(vec![
+                    (header::CONTENT_TYPE, "text/html; charset=utf-8".to_owned()),
+                    (CONTENT_SECURITY_POLICY, config.content_csp.clone()),
+                ]),
+                reader::render(&res.display_title(), &served_text(&content))?,
             )
+                .into_response());
         }
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Headers(vec![]),
-            e.to_string(),
+    }
+    let bare = query
+        .bare
+        .as_deref()
+        .map_or(false, |x| x != "0" && x != "false");
+    // Translations are made of the preview only
+    let overflow = res.overflow && !translated;
+    if bare && overflow {
+        return match config.render_mode {
+            RenderMode::Direct => full_content(&blobs, res).await,
+            RenderMode::Sandbox => Ok((
+                StatusCode::OK,
+                Headers(vec![
+                    (header::CONTENT_TYPE, "text/html; charset=utf-8".to_owned()),
+                    (CONTENT_SECURITY_POLICY, config.content_csp.clone()),
+                ]),
+                sandbox_page(
+                    &res.display_title(),
+                    &res.tags,
+                    &format!("/feeds/{}/full", percent_encode(&res.id)),
+                )?,
+            )
+                .into_response()),
+        };
+    }
+    let res = res.translated(lang);
+    let headers = Headers(vec![
+        (header::CONTENT_TYPE, "text/html; charset=utf-8".to_owned()),
+        // Inherited by the iframe in sandbox mode
+        (CONTENT_SECURITY_POLICY, config.content_csp.clone()),
+    ]);
+    if bare {
+        let content = served_content(&res.content);
+        let page = match config.render_mode {
+            RenderMode::Direct => content,
+            RenderMode::Sandbox => sandboxed(&res.display_title(), &res.tags, &content)?,
+        };
+        return Ok((StatusCode::OK, headers, page).into_response());
+    }
+
+    let (content, frame) = match (config.render_mode, overflow) {
+        (RenderMode::Direct, false) => (Some(served_content(&res.content)), None),
+        (RenderMode::Direct, true) => (
+            Some(served_content(&blob::load(&blobs, &res.id).await?)),
+            None,
         ),
+        (RenderMode::Sandbox, false) => (
+            None,
+            Some(Frame {
+                src: None,
+                srcdoc: Some(format!(
+                    r#"<base target="_blank">{}"#,
+                    served_content(&res.content)
+                )),
+            }),
+        ),
+        (RenderMode::Sandbox, true) => (
+            None,
+            Some(Frame {
+                src: Some(format!("/feeds/{}/full", percent_encode(&res.id))),
+                srcdoc: None,
+            }),
+        ),
+    };
+    let (older, newer) = store::neighbours(&feeds, &res).await?;
+    let link_to = |x: Feed| {
+        let mut link = x.permalink();
+        if let Some(lang) = lang {
+            link.push_str(&format!("?lang={}", percent_encode(lang)));
+        }
+        ItemLink {
+            title: x.translated(lang).display_title(),
+            link,
+        }
+    };
+    let page = templates::render(
+        "item.html",
+        &ItemPage {
+            title: res.display_title(),
+            author: &res.author,
+            date: res.created_at.to_rfc3339(),
+            from_box: &res.from_box,
+            box_link: format!("/boxes/{}", percent_encode(&res.from_box)),
+            raw: format!("/feeds/{}/raw", percent_encode(&res.id)),
+            tags: &res.tags,
+            previous: older.map(link_to),
+            next: newer.map(link_to),
+            content,
+            frame,
+        },
+    )?;
+    Ok((StatusCode::OK, headers, page).into_response())
+}
+
+/// Key and slug of a permalink, `/feeds/:key/:slug`
+fn permalink_parts(path: &str) -> Option<(String, String)> {
+    let mut segments = path.split('/').skip(1);
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some("feeds"), Some(key), Some(slug), None) if !key.is_empty() => {
+            Some((percent_decode(key), percent_decode(slug)))
+        }
+        _ => None,
+    }
+}
+
+/// Item page at its permalink, redirecting there from other slugs, e.g. of an
+/// edited title
+async fn item_permalink(key: String, slug: String, req: Request<Body>) -> PageResult<Response> {
+    let feeds = req
+        .extensions()
+        .get::<Feeds>()
+        .cloned()
+        .expect("feeds should be added");
+    let blobs = req
+        .extensions()
+        .get::<Blobs>()
+        .cloned()
+        .expect("blobs should be added");
+    let ip = req.extensions().get::<ClientIp>().copied().map(Extension);
+    let credential = req.extensions().get::<Credential>().copied().map(Extension);
+    let item = published_item(store::get_meta(&feeds, &key).await?, &key)?;
+    if item.slug() != slug {
+        let mut location = item.permalink();
+        if let Some(query) = req.uri().query() {
+            location.push('?');
+            location.push_str(query);
+        }
+        return Ok(
+            Redirect::permanent(location.parse().map_err(anyhow::Error::from)?).into_response(),
+        );
+    }
+    let query = match Query::<ItemQuery>::from_request(&mut RequestParts::new(req)).await {
+        Ok(x) => x,
+        Err(e) => return Ok(e.into_response()),
+    };
+    let map = HashMap::from([("key".to_owned(), key)]);
+    rendered_html(
+        Path(map),
+        query,
+        Extension(feeds),
+        Extension(blobs),
+        ip,
+        credential,
+    )
+    .await
+}
+
+/// Requests without a route: item permalinks, which cannot be routes next to
+/// the other ones under `/feeds/:key`, then files of `STATIC_DIR`
+async fn fallback(req: Request<Body>) -> Response {
+    let is_get = req.method() == Method::GET || req.method() == Method::HEAD;
+    if let Some((key, slug)) = permalink_parts(req.uri().path()).filter(|_| is_get) {
+        return item_permalink(key, slug, req).await.into_response();
+    }
+    match &get_config().static_dir {
+        Some(dir) => match ServeDir::new(dir).oneshot(req).await {
+            Ok(res) => res.map(boxed),
+            Err(e) => ApiError::from(anyhow::Error::from(e)).into_response(),
+        },
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Sandboxed iframe of an item page, `src` or `srcdoc` being set
+#[derive(Serialize)]
+struct Frame {
+    src: Option<String>,
+    srcdoc: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ItemLink {
+    title: String,
+    link: String,
+}
+
+/// Variables of the page around an item
+#[derive(Serialize)]
+struct ItemPage<'a> {
+    title: String,
+    author: &'a str,
+    /// RFC 3339
+    date: String,
+    from_box: &'a str,
+    box_link: String,
+    raw: String,
+    tags: &'a [String],
+    /// Older item of the same box
+    previous: Option<ItemLink>,
+    /// Newer item of the same box
+    next: Option<ItemLink>,
+    /// Content shown in the page itself, in `direct` render mode
+    content: Option<String>,
+    /// Content shown in an iframe, in `sandbox` render mode
+    frame: Option<Frame>,
+}
+
+/// Text as served, with emails obfuscated as configured
+fn served_text(text: &str) -> String {
+    match get_config().obfuscate_emails {
+        true => obfuscate_emails(text),
+        false => text.to_owned(),
+    }
+}
+
+/// Content as served, with emails obfuscated and images proxied as configured
+fn served_content(content: &str) -> String {
+    let config = get_config();
+    let content = served_text(content);
+    match &config.image_proxy {
+        Some(proxy) => proxy_images(&content, proxy),
+        None => content,
+    }
+}
+
+/// Full content of an item, streamed from chunks if over `MAX_CONTENT_SIZE`
+async fn full(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> PageResult<Response> {
+    let key = map.get("key").expect("key should exist");
+    full_content(&blobs, find_item(&feeds, key).await?).await
+}
+
+async fn full_content(blobs: &Blobs, feed: Feed) -> PageResult<Response> {
+    let headers = Headers(vec![
+        (header::CONTENT_TYPE, "text/html; charset=utf-8".to_owned()),
+        (CONTENT_SECURITY_POLICY, get_config().content_csp.clone()),
+        // Framed by the item page in sandbox mode
+        (X_FRAME_OPTIONS, "SAMEORIGIN".to_owned()),
+    ]);
+    if !feed.overflow {
+        return Ok((StatusCode::OK, headers, served_content(&feed.content)).into_response());
+    }
+    let chunks = blob::stream(blobs, &feed.id).await?;
+    // Chunks are cut between tags, so each can be processed on its own
+    Ok((
+        StatusCode::OK,
+        headers,
+        StreamBody::new(chunks.map_ok(|x| served_content(&x))),
+    )
+        .into_response())
+}
+
+async fn pdf(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> PageResult<impl IntoResponse> {
+    let key = map.get("key").expect("key should exist");
+    let res = find_full_item(&feeds, &blobs, key).await?;
+    Ok((
+        Headers(vec![
+            (header::CONTENT_TYPE, "application/pdf".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("inline; filename=\"{}.pdf\"", res.id),
+            ),
+        ]),
+        pdf::render_feed(&res.redacted()),
+    ))
+}
+
+/// How `/feeds/:key` serves mail, see `RENDER_MODE`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    /// The mail itself
+    Direct,
+    /// A wrapper page embedding the mail in a sandboxed iframe
+    Sandbox,
+}
+
+impl FromStr for RenderMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(RenderMode::Direct),
+            "sandbox" => Ok(RenderMode::Sandbox),
+            _ => anyhow::bail!("Unknown render mode {}", s),
+        }
+    }
+}
+
+/// Wrap `content` in an iframe without scripts, same origin or top navigation,
+/// so hostile mail cannot reach the archive's cookies or credentials. Tags are
+/// listed above, linking to their feeds.
+#[derive(Serialize)]
+struct SandboxPage<'a> {
+    title: &'a str,
+    tags: &'a [String],
+    src: Option<&'a str>,
+    srcdoc: Option<String>,
+}
+
+fn sandboxed(title: &str, tags: &[String], content: &str) -> Result<String> {
+    templates::render(
+        "sandbox.html",
+        &SandboxPage {
+            title,
+            tags,
+            src: None,
+            srcdoc: Some(format!(r#"<base target="_blank">{}"#, content)),
+        },
+    )
+}
+
+/// Page around a sandboxed iframe of the full content of an item
+fn sandbox_page(title: &str, tags: &[String], src: &str) -> Result<String> {
+    templates::render(
+        "sandbox.html",
+        &SandboxPage {
+            title,
+            tags,
+            src: Some(src),
+            srcdoc: None,
+        },
+    )
+}
+
+#[derive(Serialize)]
+struct SpamCheck {
+    flagged: Option<bool>,
+    score: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct AuthResults {
+    spf: Option<String>,
+    dkim: Option<String>,
+    dmarc: Option<String>,
+}
+
+/// Structured representation of an item, see `/feeds/:key/json`
+#[derive(Serialize)]
+struct ItemDetail {
+    id: String,
+    title: String,
+    author: String,
+    from_box: String,
+    created_at: String,
+    last_seen_at: Option<String>,
+    publish_at: Option<String>,
+    occurrences: u32,
+    address_tag: Option<String>,
+    auto_submitted: Option<String>,
+    summary: Option<String>,
+    tags: Vec<String>,
+    /// Languages translations are stored in
+    translations: Vec<String>,
+    /// Permalink of the item page, `/feeds/:key/:slug`
+    permalink: String,
+    /// GUID of the item in feeds
+    guid: String,
+    /// HTML body, as served on `/feeds/:key`
+    content: String,
+    /// `content` is a preview of one over `MAX_CONTENT_SIZE`, served in full on
+    /// `/feeds/:key/full`
+    overflow: bool,
+    text: String,
+    headers: Vec<StoredHeader>,
+    attachments: Vec<Attachment>,
+    spam: SpamCheck,
+    auth: AuthResults,
+}
+
+impl ItemDetail {
+    fn new(feed: Feed) -> Self {
+        Self::build(feed, get_config().obfuscate_emails)
+    }
+
+    /// Detail of `feed`, with email addresses hidden if `obfuscate`
+    fn build(feed: Feed, obfuscate: bool) -> Self {
+        let config = get_config();
+        let redact = |x: String| match obfuscate {
+            true => obfuscate_emails(&x),
+            false => x,
+        };
+        let (text, attachments) = match Message::parse(feed.raw.as_bytes()) {
+            Some(parsed) => (redact(body_text(&parsed)), attachments(&parsed)),
+            None => (String::new(), vec![]),
+        };
+        let headers = parse_headers(&feed.raw);
+        let feed = feed.redact(obfuscate);
+        let mut translations = feed.translations.keys().cloned().collect::<Vec<_>>();
+        translations.sort();
+        Self {
+            content: match &config.image_proxy {
+                Some(proxy) => proxy_images(&feed.content, proxy),
+                None => feed.content.clone(),
+            },
+            text,
+            attachments,
+            spam: SpamCheck {
+                flagged: spam_flagged(&headers),
+                score: spam_score(&headers),
+            },
+            auth: AuthResults {
+                spf: auth_result(&headers, "spf"),
+                dkim: auth_result(&headers, "dkim"),
+                dmarc: auth_result(&headers, "dmarc"),
+            },
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| StoredHeader {
+                    name: name.to_ascii_lowercase(),
+                    value: redact(value),
+                })
+                .collect(),
+            title: feed.display_title(),
+            created_at: feed.created_at.to_rfc3339(),
+            last_seen_at: feed.last_seen_at.map(|x| x.to_rfc3339()),
+            publish_at: feed.publish_at.map(|x| x.to_rfc3339()),
+            translations,
+            permalink: feed.permalink(),
+            guid: feed.guid().to_owned(),
+            id: feed.id,
+            author: feed.author,
+            from_box: feed.from_box,
+            occurrences: feed.occurrences,
+            address_tag: feed.address_tag,
+            auto_submitted: feed.auto_submitted,
+            summary: feed.summary,
+            tags: feed.tags,
+            overflow: feed.overflow,
+        }
+    }
+}
+
+async fn item_json(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> ApiResult<Json<ItemDetail>> {
+    let key = map.get("key").expect("key should exist");
+    Ok(Json(ItemDetail::new(
+        find_full_item(&feeds, &blobs, key).await?,
+    )))
+}
+
+async fn tags(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+) -> ApiResult<Json<Vec<String>>> {
+    let key = map.get("key").expect("key should exist");
+    let res = published_item(store::get_meta(&feeds, key).await?, key)?;
+    Ok(Json(res.tags))
+}
+
+/// Replace user-assigned tags of an item with the JSON array in the body
+async fn put_tags(
+    Path(map): Path<HashMap<String, String>>,
+    Json(tags): Json<Vec<String>>,
+    Extension(feeds): Extension<Feeds>,
+) -> ApiResult<Json<Vec<String>>> {
+    let key = map.get("key").expect("key should exist");
+    Ok(Json(admin::set_tags(&feeds, key, tags).await?))
+}
+
+async fn raw(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> PageResult<impl IntoResponse> {
+    let key = map.get("key").expect("key should exist");
+    Ok((
+        Headers(vec![(header::CONTENT_TYPE, "text/plain; charset=utf-8")]),
+        store::get_raw(&feeds, &blobs, key)
+            .await?
+            .ok_or_else(|| ApiError::not_found(format!("Cannot find {}", key)))?,
+    ))
+}
+
+/// Raw source of an item as a file to open in or import into mail clients
+async fn eml(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> PageResult<impl IntoResponse> {
+    let key = map.get("key").expect("key should exist");
+    let feed = published_item(store::get_source(&feeds, &blobs, key).await?, key)?;
+    Ok((
+        Headers(vec![
+            (header::CONTENT_TYPE, "message/rfc822".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; {}",
+                    download_filename(&feed.display_title(), "eml")
+                ),
+            ),
+        ]),
+        feed.raw,
+    ))
+}
+
+/// Attachment `n` of an item, linked from feeds as an enclosure
+async fn item_attachment(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> ApiResult<impl IntoResponse> {
+    let key = map.get("key").expect("key should exist");
+    let n = map
+        .get("n")
+        .and_then(|x| x.parse::<usize>().ok())
+        .ok_or_else(|| ApiError::bad_request("Bad attachment number"))?;
+    let raw = store::get_raw(&feeds, &blobs, key)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Cannot find {}", key)))?;
+    let (info, contents) = Message::parse(raw.as_bytes())
+        .and_then(|x| attachment(&x, n))
+        .ok_or_else(|| ApiError::not_found(format!("No attachment {} in {}", n, key)))?;
+    let name = info
+        .name
+        .as_deref()
+        .unwrap_or("attachment")
+        .replace(|x: char| x == '"' || x == '\\' || x.is_control(), "_");
+    Ok((
+        Headers(vec![
+            (header::CONTENT_TYPE, info.mime_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("inline; filename=\"{}\"", name),
+            ),
+            // Attachments may be HTML or SVG from anyone
+            (
+                CONTENT_SECURITY_POLICY,
+                "default-src 'none'; sandbox".to_owned(),
+            ),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        ]),
+        contents,
+    ))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Only items of this box
+    #[serde(rename = "box")]
+    from_box: Option<String>,
+}
+
+/// Server-sent events of items as they are stored
+async fn events(Query(query): Query<EventsQuery>) -> impl IntoResponse {
+    let items = item_events::subscribe();
+    let events = items
+        .filter(move |item: &NewItem| {
+            let wanted = query
+                .from_box
+                .as_ref()
+                .map_or(true, |x| *x == item.from_box);
+            async move { wanted }
+        })
+        .map(|item| {
+            Event::default()
+                .event("item")
+                .id(item.id.clone())
+                .json_data(item)
+        });
+    (
+        // Kept out of compression, which would hold events back
+        Headers(vec![(header::CONTENT_ENCODING, "identity")]),
+        Sse::new(events).keep_alive(KeepAlive::default()),
+    )
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    /// Only items of this box
+    #[serde(rename = "box")]
+    from_box: Option<String>,
+    /// Send items as on `/feeds/:key/json` too
+    #[serde(default)]
+    full: bool,
+}
+
+#[derive(Serialize)]
+struct WsItem {
+    #[serde(flatten)]
+    item: NewItem,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<ItemDetail>,
+}
+
+/// Items as they are stored, over WebSocket
+async fn ws(
+    upgrade: WebSocketUpgrade,
+    Query(query): Query<WsQuery>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> Response {
+    upgrade.on_upgrade(move |socket| push_items(socket, query, feeds, blobs))
+}
+
+/// Send items to the socket until it is closed
+async fn push_items(mut socket: WebSocket, query: WsQuery, feeds: Feeds, blobs: Blobs) {
+    let mut items = Box::pin(item_events::subscribe());
+    loop {
+        tokio::select! {
+            item = items.next() => {
+                let item = match item {
+                    Some(x) if query.from_box.as_ref().map_or(true, |b| *b == x.from_box) => x,
+                    Some(_) => continue,
+                    None => break,
+                };
+                let detail = match query.full {
+                    true => match store::get(&feeds, &blobs, &item.id).await {
+                        Ok(x) => x.map(ItemDetail::new),
+                        Err(e) => {
+                            warn!(target: "web", "Error loading {}: {}", item.id, e);
+                            None
+                        }
+                    },
+                    false => None,
+                };
+                let text = match serde_json::to_string(&WsItem { item, detail }) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                if socket.send(WsMessage::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                // Pings are answered by the library
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Queries, and mutations unless `Restricted`, see `graphql`
+async fn graphql(
+    Extension(schema): Extension<ApiSchema>,
+    restricted: Option<Extension<Restricted>>,
+    headers: HeaderMap,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    // Other sites can make browsers send forms with the credentials they
+    // keep, but not JSON
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .map_or(false, |x| x.as_bytes().starts_with(b"application/json"));
+    let mut req = req.into_inner();
+    if restricted.is_some() || !is_json {
+        req = req.data(Restricted);
+    }
+    schema.execute(req).await.into()
+}
+
+async fn boxes(Extension(feeds): Extension<Feeds>) -> ApiResult<Json<Vec<String>>> {
+    Ok(Json(registry::names(&feeds).await?))
+}
+
+async fn opml(Extension(feeds): Extension<Feeds>) -> ApiResult<impl IntoResponse> {
+    let names = registry::names(&feeds).await?;
+    Ok((
+        Headers(vec![(header::CONTENT_TYPE, "text/x-opml; charset=utf-8")]),
+        render_opml(&names),
+    ))
+}
+
+/// `robots.txt` of `ROBOTS_FILE`, or else one keeping crawlers to items and
+/// box pages
+async fn robots_txt() -> impl IntoResponse {
+    let config = get_config();
+    let text = match &config.robots_txt {
+        Some(x) => x.clone(),
+        None => format!(
+            "User-agent: *\n\
+            Disallow: /admin/\n\
+            Disallow: /export\n\
+            Disallow: /ingest\n\
+            Disallow: /metrics\n\
+            Disallow: /search\n\
+            Disallow: /stats/\n\
+            Sitemap: https://{}/sitemap.xml\n",
+            config.web_domain
+        ),
+    };
+    (
+        Headers(vec![(header::CONTENT_TYPE, "text/plain; charset=utf-8")]),
+        text,
+    )
+}
+
+async fn favicon_ico() -> ApiResult<impl IntoResponse> {
+    let (content_type, data) = match &get_config().favicon_file {
+        Some(path) => {
+            let content_type = match path.rsplit('.').next() {
+                Some("png") => "image/png",
+                Some("svg") => "image/svg+xml",
+                _ => "image/x-icon",
+            };
+            (
+                content_type,
+                tokio::fs::read(path).await.map_err(anyhow::Error::from)?,
+            )
+        }
+        None => (
+            "image/svg+xml",
+            include_bytes!("../assets/favicon.svg").to_vec(),
+        ),
+    };
+    Ok((
+        Headers(vec![
+            (CONTENT_TYPE, content_type),
+            (CACHE_CONTROL, "public, max-age=86400"),
+        ]),
+        data,
+    ))
+}
+
+async fn sitemap_xml(Extension(feeds): Extension<Feeds>) -> ApiResult<impl IntoResponse> {
+    let names = registry::names(&feeds).await?;
+    let option = FindOptions::builder()
+        .limit(sitemap::MAX_URLS.saturating_sub(names.len() + 1) as i64)
+        .sort(doc! { "created_at": -1 })
+        .projection(doc! { "id": 1, "created_at": 1 })
+        .build();
+    let items = feeds
+        .clone_with_type::<Document>()
+        .find(published(doc! {}), option)
+        .await?
+        .try_filter_map(|x| async move {
+            Ok(x.get_str("id")
+                .ok()
+                .zip(x.get_i64("created_at").ok())
+                .map(|(id, at)| (id.to_owned(), Utc.timestamp_millis(at))))
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok((
+        Headers(vec![(
+            header::CONTENT_TYPE,
+            "application/xml; charset=utf-8",
+        )]),
+        render_sitemap(&names, &items),
+    ))
+}
+
+async fn tags_list(Extension(feeds): Extension<Feeds>) -> ApiResult<impl IntoResponse> {
+    Ok(Json(stats::tags(feeds).await?))
+}
+
+#[derive(Serialize)]
+struct Erased {
+    deleted: u64,
+}
+
+async fn erase_sender(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(audit): Extension<AuditLog>,
+    Extension(tombstones): Extension<Tombstones>,
+) -> ApiResult<Json<Erased>> {
+    let address = map.get("address").expect("address should exist");
+    let deleted = admin::erase_sender(&feeds, &blobs, &audit, &tombstones, address).await?;
+    Ok(Json(Erased { deleted }))
+}
+
+/// Send a message through SMTP to the store and back, with per-stage timings
+async fn selftest(Extension(feeds): Extension<Feeds>) -> impl IntoResponse {
+    let report = selftest::run(feeds).await;
+    (
+        match report.ok {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        },
+        Json(report),
+    )
+}
+
+async fn delete_item(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(audit): Extension<AuditLog>,
+    Extension(tombstones): Extension<Tombstones>,
+) -> ApiResult<Json<Erased>> {
+    let key = map.get("key").expect("key should exist");
+    let deleted = admin::delete_item(&feeds, &blobs, &audit, &tombstones, key).await?;
+    Ok(Json(Erased { deleted }))
+}
+
+async fn edit_item(
+    Path(map): Path<HashMap<String, String>>,
+    Json(body): Json<ItemEdit>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<Json<ItemDetail>> {
+    let key = map.get("key").expect("key should exist");
+    let feed = admin::edit_item(&feeds, &blobs, &audit, key, body).await?;
+    Ok(Json(ItemDetail::new(feed)))
+}
+
+#[derive(Serialize)]
+struct AdminDetail {
+    #[serde(flatten)]
+    item: ItemDetail,
+    envelope: Option<SmtpEnvelope>,
+}
+
+/// Item as on `/feeds/:key/json`, with the SMTP envelope it was received with
+async fn admin_item(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> ApiResult<Json<AdminDetail>> {
+    let key = map.get("key").expect("key should exist");
+    let mut feed = store::get(&feeds, &blobs, key)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Cannot find {}", key)))?;
+    let envelope = feed.envelope.take();
+    Ok(Json(AdminDetail {
+        item: ItemDetail::new(feed),
+        envelope,
+    }))
+}
+
+#[derive(Deserialize)]
+struct IngestQuery {
+    /// Box to file the message into, instead of the one found from headers
+    #[serde(rename = "box")]
+    from_box: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Ingested {
+    result: &'static str,
+}
+
+/// Take a raw RFC 822 message as if received through SMTP, e.g. to backfill
+/// old mail or from providers delivering over HTTP
+async fn ingest(
+    Query(query): Query<IngestQuery>,
+    body: Bytes,
+    Extension(tx): Extension<TX>,
+) -> ApiResult<(StatusCode, Json<Ingested>)> {
+    if let Some(from_box) = &query.from_box {
+        if !registry::is_valid_name(from_box) {
+            return Err(ApiError::bad_request(format!(
+                "{} is not an address",
+                from_box
+            )));
+        }
+    }
+    // Queueing blocks while the database is behind
+    let outcome =
+        tokio::task::block_in_place(|| smtp::accept(&body, None, query.from_box, None, &tx))?;
+    match outcome {
+        Outcome::Accepted => Ok((StatusCode::ACCEPTED, Json(Ingested { result: "accepted" }))),
+        Outcome::Discarded => Ok((
+            StatusCode::OK,
+            Json(Ingested {
+                result: "discarded",
+            }),
+        )),
+        Outcome::Rejected(reason) => Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, reason)),
+        Outcome::Unparsable(reason) => Err(ApiError::bad_request(format!(
+            "Cannot parse message: {}",
+            reason
+        ))),
+    }
+}
+
+/// State of the latest archive check, see `verify`
+async fn verify() -> Json<verify::Status> {
+    Json(verify::status())
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    repair: Option<bool>,
+}
+
+/// Start checking the archive, fixing what can be fixed with `repair=true`
+async fn start_verify(
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(audit): Extension<AuditLog>,
+    Query(query): Query<VerifyQuery>,
+) -> ApiResult<impl IntoResponse> {
+    let repair = query.repair.unwrap_or(false);
+    if !verify::start(feeds, blobs, audit, repair) {
+        return Err(ApiError::conflict("A check is already running"));
+    }
+    Ok((StatusCode::ACCEPTED, Json(verify::status())))
+}
+
+#[derive(Deserialize)]
+struct PendingQuery {
+    #[serde(rename = "box")]
+    from_box: Option<String>,
+}
+
+/// Items of moderated boxes waiting for approval, oldest first
+async fn pending(
+    Extension(feeds): Extension<Feeds>,
+    Query(query): Query<PendingQuery>,
+) -> ApiResult<Json<List>> {
+    Ok(Json(render_pending(feeds, &query).await?))
+}
+
+async fn render_pending(feeds: Feeds, query: &PendingQuery) -> Result<List> {
+    let mut filter = doc! { "pending": true };
+    if let Some(from_box) = &query.from_box {
+        filter.insert("from_box", from_box);
+    }
+    let items = feeds
+        .find(
+            filter,
+            FindOptions::builder()
+                .sort(doc! { "created_at": 1 })
+                .projection(store::meta_only())
+                .build(),
+        )
+        .await?
+        .map_ok(|x| Summary {
+            create_at: x.created_at.to_rfc2822(),
+            title: x.display_title(),
+            id: x.id,
+            snippet: None,
+            summary: x.summary,
+            tags: x.tags,
+        })
+        .try_collect()
+        .await?;
+    Ok(List { items, page: None })
+}
+
+async fn approve(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<&'static str> {
+    let key = map.get("key").expect("key should exist");
+    admin::approve(&feeds, &blobs, &audit, key).await?;
+    Ok("OK")
+}
+
+async fn reject(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(audit): Extension<AuditLog>,
+    Extension(tombstones): Extension<Tombstones>,
+) -> ApiResult<&'static str> {
+    let key = map.get("key").expect("key should exist");
+    admin::reject(&feeds, &blobs, &audit, &tombstones, key).await?;
+    Ok("OK")
+}
+
+async fn registered_boxes() -> impl IntoResponse {
+    Json(registry::active())
+}
+
+#[derive(Deserialize)]
+struct NewBox {
+    name: String,
+}
+
+async fn create_box(
+    Json(body): Json<NewBox>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<(StatusCode, Json<BoxRecord>)> {
+    let record = admin::create_box(&feeds, &registry, &audit, &body.name).await?;
+    Ok((StatusCode::CREATED, Json(record)))
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    to: String,
+}
+
+#[derive(Serialize)]
+struct Moved {
+    moved: u64,
+}
+
+async fn rename_box(
+    Path(map): Path<HashMap<String, String>>,
+    Json(body): Json<Rename>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<Json<Moved>> {
+    let from = map.get("box").expect("box name should exist");
+    let moved = admin::rename_box(&feeds, &registry, &audit, from, &body.to).await?;
+    Ok(Json(Moved { moved }))
+}
+
+#[derive(Deserialize)]
+struct Merge {
+    into: String,
+}
+
+async fn merge_box(
+    Path(map): Path<HashMap<String, String>>,
+    Json(body): Json<Merge>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<Json<Moved>> {
+    let from = map.get("box").expect("box name should exist");
+    let moved = admin::merge_box(&feeds, &registry, &audit, from, &body.into).await?;
+    Ok(Json(Moved { moved }))
+}
+
+async fn archive_box(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> impl IntoResponse {
+    set_archived(map, feeds, registry, audit, true).await
+}
+
+async fn unarchive_box(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> impl IntoResponse {
+    set_archived(map, feeds, registry, audit, false).await
+}
+
+async fn set_archived(
+    map: HashMap<String, String>,
+    feeds: Feeds,
+    registry: Registry,
+    audit: AuditLog,
+    archived: bool,
+) -> ApiResult<&'static str> {
+    let name = map.get("box").expect("box name should exist");
+    admin::set_archived(&feeds, &registry, &audit, name, archived).await?;
+    Ok("OK")
+}
+
+async fn api_keys() -> impl IntoResponse {
+    Json(apikeys::list())
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct CreatedKey {
+    #[serde(flatten)]
+    info: ApiKey,
+    /// Only shown once
+    key: String,
+}
+
+async fn create_key(
+    Json(body): Json<NewKey>,
+    Extension(keys): Extension<ApiKeys>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<(StatusCode, Json<CreatedKey>)> {
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("A key needs a name"));
+    }
+    if body.scopes.is_empty() {
+        return Err(ApiError::bad_request("A key needs a scope"));
+    }
+    let (info, key) = apikeys::create(&keys, name, body.scopes).await?;
+    audit::record(&audit, "create_key", &info.id, 1).await;
+    Ok((StatusCode::CREATED, Json(CreatedKey { info, key })))
+}
+
+async fn revoke_key(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(keys): Extension<ApiKeys>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<&'static str> {
+    let id = map.get("id").expect("id should exist");
+    if !apikeys::revoke(&keys, id).await? {
+        return Err(ApiError::not_found(format!("No key {}", id)));
+    }
+    audit::record(&audit, "revoke_key", id, 1).await;
+    Ok("OK")
+}
+
+#[derive(Serialize)]
+struct FeedToken {
+    token: String,
+    rss: String,
+    atom: String,
+}
+
+/// Give a box a new feed token, replacing any earlier one
+async fn create_token(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<Json<FeedToken>> {
+    let name = map.get("box").expect("box name should exist");
+    if !registry::exists(&feeds, name).await? {
+        return Err(ApiError::not_found(format!("Cannot find box {}", name)));
+    }
+    let token = auth::new_token();
+    registry::set_token(&registry, name, Some(token.clone())).await?;
+    audit::record(&audit, "create_token", name, 1).await;
+    let config = get_config();
+    let url = |kind: &str| {
+        format!(
+            "https://{}/{}/{}?token={}",
+            config.web_domain,
+            kind,
+            percent_encode(name),
+            token
+        )
+    };
+    Ok(Json(FeedToken {
+        rss: url("rss"),
+        atom: url("atom"),
+        token,
+    }))
+}
+
+async fn revoke_token(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+) -> ApiResult<&'static str> {
+    let name = map.get("box").expect("box name should exist");
+    if registry::token(name).is_none() {
+        return Err(ApiError::not_found(format!("Box {} has no token", name)));
+    }
+    registry::set_token(&registry, name, None).await?;
+    audit::record(&audit, "revoke_token", name, 1).await;
+    Ok("OK")
+}
+
+async fn delete_box(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+    Extension(registry): Extension<Registry>,
+    Extension(audit): Extension<AuditLog>,
+    Extension(tombstones): Extension<Tombstones>,
+) -> ApiResult<Json<Erased>> {
+    let name = map.get("box").expect("box name should exist");
+    let deleted = admin::delete_box(&feeds, &blobs, &registry, &audit, &tombstones, name).await?;
+    Ok(Json(Erased { deleted }))
+}
+
+#[derive(Deserialize)]
+struct ReadersQuery {
+    days: Option<i64>,
+}
+
+async fn readers(
+    Extension(hits): Extension<Hits>,
+    Query(query): Query<ReadersQuery>,
+) -> ApiResult<impl IntoResponse> {
+    Ok(Json(
+        analytics::readers(hits, query.days.unwrap_or(30)).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct EpubQuery {
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+}
+
+async fn box_epub(
+    Path(map): Path<HashMap<String, String>>,
+    Query(query): Query<EpubQuery>,
+    Extension(feeds): Extension<Feeds>,
+) -> PageResult<impl IntoResponse> {
+    let email = map.get("box").expect("box name should exist");
+    if !registry::is_valid_name(email) || !registry::exists(&feeds, email).await? {
+        return Err(ApiError::not_found(format!("Cannot find box {}", email)).into());
+    }
+    let content = render_epub(feeds, email, query.since, query.until).await?;
+    Ok((
+        Headers(vec![
+            (header::CONTENT_TYPE, "application/epub+zip".to_owned()),
+            (header::CONTENT_DISPOSITION, attachment(email, "epub")),
+        ]),
+        content,
+    ))
+}
+
+/// `Content-Disposition` of a download named after `name`, e.g. a box whose
+/// quoted local part may hold quotes or semicolons: replaced in the plain
+/// filename, percent-encoded in the UTF-8 one
+fn attachment(name: &str, extension: &str) -> String {
+    let plain = name
+        .chars()
+        .map(|x| match x.is_ascii_alphanumeric() || "@.+-_".contains(x) {
+            true => x,
+            false => '_',
+        })
+        .collect::<String>();
+    format!(
+        "attachment; filename=\"{}.{}\"; filename*=UTF-8''{}.{}",
+        plain,
+        extension,
+        percent_encode(name),
+        extension
+    )
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: export::Format,
+}
+
+/// Every stored item, for backups
+async fn export_all(
+    Query(query): Query<ExportQuery>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> ApiResult<Response> {
+    render_export(&feeds, &blobs, None, query.format).await
+}
+
+async fn export_box(
+    Path(map): Path<HashMap<String, String>>,
+    Query(query): Query<ExportQuery>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(blobs): Extension<Blobs>,
+) -> ApiResult<Response> {
+    let email = map.get("box").expect("box name should exist");
+    if !registry::exists(&feeds, email).await? {
+        return Err(ApiError::not_found(format!("Cannot find box {}", email)));
+    }
+    render_export(&feeds, &blobs, Some(email), query.format).await
+}
+
+async fn render_export(
+    feeds: &Feeds,
+    blobs: &Blobs,
+    from_box: Option<&str>,
+    format: export::Format,
+) -> ApiResult<Response> {
+    let items = export::stream(feeds, blobs, from_box, format).await?;
+    let name = from_box.unwrap_or("archive");
+    Ok((
+        Headers(vec![
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                attachment(name, format.extension()),
+            ),
+        ]),
+        StreamBody::new(items),
+    )
+        .into_response())
+}
+
+#[derive(Serialize)]
+struct BoxPageItem {
+    title: String,
+    author: String,
+    /// RFC 3339
+    date: String,
+    link: String,
+}
+
+#[derive(Serialize)]
+struct BoxPage<'a> {
+    name: &'a str,
+    items: Vec<BoxPageItem>,
+    rss: String,
+    atom: String,
+    /// Newer items
+    previous: Option<String>,
+    /// Older items
+    next: Option<String>,
+}
+
+/// Items of a box as a page, e.g. to share an archive with people without a
+/// feed reader. Takes the paging parameters of `/rss/:box`.
+async fn box_page(
+    Path(map): Path<HashMap<String, String>>,
+    Query(query): Query<RssQuery>,
+    Extension(feeds): Extension<Feeds>,
+) -> PageResult<impl IntoResponse> {
+    let name = map.get("box").expect("box name should exist");
+    if !registry::exists(&feeds, name).await? {
+        return Err(ApiError::not_found(format!("No box {}", name)).into());
+    }
+    let link = format!("/boxes/{}", percent_encode(name));
+    let page = fetch_page(feeds, Some(name), &link, &query).await?;
+    let link_to = |rel: &str| {
+        page.links
+            .iter()
+            .find(|(x, _)| *x == rel)
+            .map(|(_, href)| href.clone())
+    };
+    let rendered = templates::render(
+        "box.html",
+        &BoxPage {
+            name,
+            items: page
+                .items
+                .iter()
+                .map(|x| BoxPageItem {
+                    title: x.display_title(),
+                    author: x.author.clone(),
+                    date: x.created_at.to_rfc3339(),
+                    link: x.permalink(),
+                })
+                .collect(),
+            rss: format!("/rss/{}", percent_encode(name)),
+            atom: format!("/atom/{}", percent_encode(name)),
+            previous: link_to("previous"),
+            next: link_to("next"),
+        },
+    )?;
+    Ok((
+        Headers(vec![(
+            CONTENT_SECURITY_POLICY,
+            get_config().app_csp.clone(),
+        )]),
+        Html(rendered),
+    ))
+}
+
+async fn box_icon(
+    Path(map): Path<HashMap<String, String>>,
+    Extension(feeds): Extension<Feeds>,
+    Extension(favicons): Extension<Favicons>,
+) -> ApiResult<impl IntoResponse> {
+    let email = map.get("box").expect("box name should exist");
+    let icon = favicon::for_box(&feeds, &favicons, email)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("No icon for {}", email)))?;
+    Ok((
+        Headers(vec![
+            (CONTENT_TYPE, icon.content_type),
+            (CACHE_CONTROL, "public, max-age=86400".to_owned()),
+        ]),
+        icon.data.bytes,
+    ))
+}
+
+async fn prometheus() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Headers(vec![(header::CONTENT_TYPE, "text/plain; version=0.0.4")]),
+        metrics::render(),
+    )
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    period: Option<String>,
+    limit: Option<i64>,
+}
+
+async fn top(
+    Extension(feeds): Extension<Feeds>,
+    Query(query): Query<TopQuery>,
+) -> ApiResult<impl IntoResponse> {
+    let period = query.period.as_deref().unwrap_or("30d");
+    let duration = stats::parse_period(period).ok_or_else(|| {
+        ApiError::bad_request(format!(
+            "Bad period {}, expecting e.g. 30d, 12h or 4w",
+            period
+        ))
+    })?;
+    let limit = query.limit.unwrap_or(10);
+    if limit < 1 {
+        return Err(ApiError::bad_request("limit must be at least 1"));
+    }
+    Ok(Json(stats::top(feeds, duration, limit).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_item_detail_obfuscated() {
+        let raw = b"From: Alice <alice@secret.test>\r\n\
+            To: list@example.org\r\n\
+            Cc: carol@hidden.test\r\n\
+            Subject: Hello\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Write to bob@hidden.test\r\n";
+        let parsed = Message::parse(raw).unwrap();
+        let mut feed =
+            Feed::from_message(raw, parsed, "list@example.org".to_owned(), None).unwrap();
+        feed.summary = Some("Alice asks to write to bob@hidden.test".to_owned());
+
+        let detail = serde_json::to_string(&ItemDetail::build(feed.clone(), true)).unwrap();
+        assert!(!detail.contains("secret.test"));
+        assert!(!detail.contains("hidden.test"));
+        assert!(detail.contains("bob@hidden…"));
+
+        let detail = serde_json::to_string(&ItemDetail::build(feed, false)).unwrap();
+        assert!(detail.contains("bob@hidden.test"));
+    }
+
+    #[test]
+    fn test_page_url_keeps_token() {
+        let query = RssQuery {
+            token: Some("secret".to_owned()),
+            tag: Some("a b".to_owned()),
+            ..Default::default()
+        };
+        let link = "https://example.com/rss/news@example.com";
+        assert_eq!(
+            page_url(link, &query, 20, Some(("page", 2))),
+            format!("{}?page=2&tag=a%20b&token=secret", link)
+        );
+        assert_eq!(
+            page_url(link, &query, 20, None),
+            format!("{}?tag=a%20b&token=secret", link)
+        );
+    }
+
+    #[test]
+    fn test_attachment() {
+        assert_eq!(
+            attachment("news@example.com", "epub"),
+            "attachment; filename=\"news@example.com.epub\"; \
+             filename*=UTF-8''news%40example.com.epub"
+        );
+        assert_eq!(
+            attachment("\"a\";b\"@example.com", "mbox"),
+            "attachment; filename=\"_a__b_@example.com.mbox\"; \
+             filename*=UTF-8''%22a%22%3Bb%22%40example.com.mbox"
+        );
+    }
+
+    #[test]
+    fn test_search_window() {
+        assert_eq!(search_window(0, 0, 100), (1, 0));
+        assert_eq!(search_window(-5, 3, 100), (1, 3));
+        assert_eq!(search_window(30, 3, 100), (30, 3));
+        assert_eq!(
+            search_window(i64::MAX, u64::MAX, 100),
+            (100, MAX_SEARCH_SKIP)
+        );
     }
 }
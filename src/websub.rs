@@ -0,0 +1,69 @@
+//! WebSub (https://www.w3.org/TR/websub/) publishing: feeds advertise
+//! `WEBSUB_HUB`, which is pinged as items show up in them so that subscribers
+//! need not poll
+
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use crate::{client::http_client, config::get_config};
+
+pub fn hub() -> Option<&'static str> {
+    get_config().websub_hub.as_deref()
+}
+
+/// Feeds an item of `from_box` shows up in, as given in their `self` links
+fn topics(domain: &str, from_box: &str) -> Vec<String> {
+    ["rss", "atom"]
+        .iter()
+        .flat_map(|kind| {
+            [
+                format!("https://{}/{}", domain, kind),
+                format!("https://{}/{}/{}", domain, kind, from_box),
+            ]
+        })
+        .collect()
+}
+
+/// Tell the hub that feeds of `from_box` changed, or will at `at` for delayed
+/// items, without waiting for it. Delays do not outlive the process.
+pub fn ping(from_box: &str, at: Option<DateTime<Utc>>) {
+    let hub = match hub() {
+        Some(x) => x,
+        None => return,
+    };
+    let topics = topics(&get_config().web_domain, from_box);
+    tokio::spawn(async move {
+        if let Some(delay) = at.and_then(|x| (x - Utc::now()).to_std().ok()) {
+            tokio::time::sleep(delay).await;
+        }
+        for topic in topics {
+            let res = http_client()
+                .post(hub)
+                .form(&[("hub.mode", "publish"), ("hub.url", topic.as_str())])
+                .send()
+                .await
+                .and_then(|x| x.error_for_status());
+            if let Err(e) = res {
+                warn!(target: "WebSub", "Error pinging hub for {}: {}", topic, e)
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(
+            topics("example.com", "news@example.com"),
+            vec![
+                "https://example.com/rss",
+                "https://example.com/rss/news@example.com",
+                "https://example.com/atom",
+                "https://example.com/atom/news@example.com",
+            ]
+        );
+    }
+}
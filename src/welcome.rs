@@ -0,0 +1,121 @@
+//! Reply announcing the feed of a box the first time mail to it is published,
+//! see `NEW_BOX_REPLY`
+
+use anyhow::Result;
+use chrono::Utc;
+use mongodb::{bson::doc, options::CountOptions};
+use tracing::{info, warn};
+
+use crate::{
+    config::get_config,
+    db::{published, Feed, Feeds},
+    headers::{addresses, auto_submitted, header_values},
+    mailer, registry, selftest,
+};
+
+/// Recipient of the reply to `feed`: its `Reply-To` or `From` address with
+/// `sender`, or else the configured address
+fn recipient(feed: &Feed, setting: &str) -> Option<String> {
+    if !setting.eq_ignore_ascii_case("sender") {
+        return Some(setting.to_owned());
+    }
+    header_values(&feed.raw, "Reply-To")
+        .iter()
+        .chain(header_values(&feed.raw, "From").iter())
+        .flat_map(|x| addresses(x))
+        .next()
+}
+
+fn build(to: &str, from_box: &str) -> String {
+    let config = get_config();
+    format!(
+        "From: <noreply@{domain}>\r\n\
+         To: <{to}>\r\n\
+         Subject: New feed for {from_box}\r\n\
+         Date: {now}\r\n\
+         Message-ID: <{id}@{domain}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         Mail to {from_box} is now archived at {domain}.\r\n\
+         \r\n\
+         RSS: https://{web_domain}/rss/{from_box}\r\n\
+         Atom: https://{web_domain}/atom/{from_box}\r\n",
+        domain = config.domain,
+        web_domain = config.web_domain,
+        to = to,
+        from_box = from_box,
+        now = Utc::now().to_rfc2822(),
+        id = nanoid::nanoid!(16),
+    )
+}
+
+/// Send the reply for `feed`, the first item of its box, if enabled.
+/// Automatic messages and self-test probes are never replied to.
+pub fn send(feed: &Feed) {
+    let config = get_config();
+    let setting = match config.new_box_reply.as_deref() {
+        Some(x) if mailer::enabled() => x,
+        _ => return,
+    };
+    if feed.pending || selftest::is_probe_feed(feed) || auto_submitted(&feed.raw).is_some() {
+        return;
+    }
+    let to = match recipient(feed, setting) {
+        Some(x) => x,
+        None => return,
+    };
+    let message = build(&to, &feed.from_box);
+    let from = format!("noreply@{}", config.domain);
+    tokio::spawn(async move {
+        match mailer::send_raw(Some(&from), &to, message.as_bytes()).await {
+            Ok(()) => info!(target: "Mailer", "New box reply sent to {}", to),
+            Err(e) => warn!(target: "Mailer", "Error sending new box reply to {}: {}", to, e),
+        }
+    });
+}
+
+/// Send the reply if `feed` is the first item published in its box, when
+/// stored or else when approved, and the box was not registered beforehand.
+/// Items published later are checked for then, if not deleted meanwhile;
+/// items pending moderation neither count nor get the reply.
+pub async fn greet(feeds: &Feeds, feed: &Feed) -> Result<()> {
+    if feed.pending
+        || get_config().new_box_reply.is_none()
+        || registry::is_registered(&feed.from_box)
+    {
+        return Ok(());
+    }
+    let delay = match feed.publish_at.and_then(|x| (x - Utc::now()).to_std().ok()) {
+        Some(x) => x,
+        None => return greet_published(feeds, feed).await,
+    };
+    let (feeds, feed) = (feeds.clone(), feed.clone());
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        if let Err(e) = greet_published(&feeds, &feed).await {
+            warn!(target: "Mailer", "Error checking for a new box reply to {}: {}", feed.id, e)
+        }
+    });
+    Ok(())
+}
+
+async fn greet_published(feeds: &Feeds, feed: &Feed) -> Result<()> {
+    let found = feeds
+        .count_documents(published(doc! { "id": &feed.id }), None)
+        .await?;
+    if found == 0 {
+        return Ok(());
+    }
+    let count = feeds
+        .count_documents(
+            published(doc! { "from_box": &feed.from_box }),
+            CountOptions::builder().limit(2).build(),
+        )
+        .await?;
+    if count == 1 {
+        send(feed);
+    }
+    Ok(())
+}